@@ -1,7 +1,7 @@
 //! Performance benchmarks for the record store.
 
 use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
-use record_store::{
+use chronicle::{
     RecordInput, StateOperation, StateRegistration, StateStrategy, Store, StoreConfig,
 };
 use serde_json::json;
@@ -12,6 +12,7 @@ fn create_store(dir: &TempDir) -> Store {
         path: dir.path().join("store"),
         blob_cache_size: 1000,
         create_if_missing: true,
+        ..Default::default()
     })
     .unwrap()
 }
@@ -124,6 +125,13 @@ fn bench_record_append(c: &mut Criterion) {
             black_box(store.append(input).unwrap());
         });
     });
+
+    c.bench_function("record_append_fast", |b| {
+        b.iter(|| {
+            let input = RecordInput::json("event", &json!({"data": "test"})).unwrap();
+            black_box(store.append_fast(input).unwrap());
+        });
+    });
 }
 
 /// Benchmark blob operations
@@ -0,0 +1,248 @@
+//! Bounded in-memory cache of blob contents.
+
+use crate::types::Hash;
+use std::collections::{BTreeMap, HashMap};
+
+/// Which blob the cache evicts when it is over a limit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BlobCachePolicy {
+    /// Evict the least recently read blob.
+    #[default]
+    Lru,
+    /// Evict the least frequently read blob, breaking ties by recency.
+    /// Keeps a small set of hot blobs cached through scans of cold ones.
+    Lfu,
+}
+
+/// Blob cache limits and eviction policy (`StoreConfig::blob_cache`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlobCacheConfig {
+    /// Most blobs held at once.
+    pub max_entries: usize,
+
+    /// Most content bytes held at once. Blobs larger than this are never
+    /// cached. `None` bounds the cache by entry count only.
+    pub max_bytes: Option<u64>,
+
+    /// Which blob to evict when over either limit.
+    pub policy: BlobCachePolicy,
+}
+
+impl Default for BlobCacheConfig {
+    fn default() -> Self {
+        Self {
+            max_entries: 1000,
+            max_bytes: None,
+            policy: BlobCachePolicy::default(),
+        }
+    }
+}
+
+/// Blob cache counters (`Store::blob_cache_stats`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlobCacheStats {
+    /// Reads served from the cache.
+    pub hits: u64,
+    /// Reads that had to go to disk.
+    pub misses: u64,
+    /// Blobs dropped to stay within the limits.
+    pub evictions: u64,
+    /// Content bytes currently cached.
+    pub bytes: u64,
+    /// Blobs currently cached.
+    pub entries: u64,
+}
+
+/// Cached blob data (content + content_type).
+#[derive(Clone)]
+pub(super) struct CachedBlob {
+    pub(super) content: Vec<u8>,
+    pub(super) content_type: String,
+}
+
+struct Entry {
+    blob: CachedBlob,
+    /// Position in `order`: (read count, tick) under LFU, (0, tick) under LRU.
+    rank: (u64, u64),
+}
+
+/// Blob cache bounded by entry count and content bytes.
+///
+/// Entries are ordered by rank, so the next eviction is always the first
+/// key of `order`.
+pub(super) struct BlobCache {
+    config: BlobCacheConfig,
+    entries: HashMap<Hash, Entry>,
+    order: BTreeMap<(u64, u64), Hash>,
+    tick: u64,
+    stats: BlobCacheStats,
+}
+
+impl BlobCache {
+    pub(super) fn new(config: BlobCacheConfig) -> Self {
+        Self {
+            config,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            stats: BlobCacheStats::default(),
+        }
+    }
+
+    /// Look up a blob, counting a hit or miss and refreshing its rank.
+    pub(super) fn get(&mut self, hash: &Hash) -> Option<&CachedBlob> {
+        let Some(entry) = self.entries.get_mut(hash) else {
+            self.stats.misses += 1;
+            return None;
+        };
+        self.stats.hits += 1;
+        self.tick += 1;
+        self.order.remove(&entry.rank);
+        entry.rank = match self.config.policy {
+            BlobCachePolicy::Lru => (0, self.tick),
+            BlobCachePolicy::Lfu => (entry.rank.0 + 1, self.tick),
+        };
+        self.order.insert(entry.rank, *hash);
+        Some(&entry.blob)
+    }
+
+    /// Look up a blob without counting it or changing its rank.
+    pub(super) fn peek(&self, hash: &Hash) -> Option<&CachedBlob> {
+        self.entries.get(hash).map(|entry| &entry.blob)
+    }
+
+    pub(super) fn contains(&self, hash: &Hash) -> bool {
+        self.entries.contains_key(hash)
+    }
+
+    /// Cache a blob, evicting others until both limits hold.
+    pub(super) fn put(&mut self, hash: Hash, blob: CachedBlob) {
+        let size = blob.content.len() as u64;
+        if self.config.max_bytes.is_some_and(|max| size > max) {
+            return;
+        }
+        self.remove(&hash);
+
+        self.tick += 1;
+        let rank = match self.config.policy {
+            BlobCachePolicy::Lru => (0, self.tick),
+            BlobCachePolicy::Lfu => (1, self.tick),
+        };
+        // Make room first so the new blob isn't its own eviction candidate
+        while self.entries.len() >= self.config.max_entries.max(1)
+            || self
+                .config
+                .max_bytes
+                .is_some_and(|max| self.stats.bytes + size > max)
+        {
+            let Some((_, victim)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&victim) {
+                self.stats.bytes -= entry.blob.content.len() as u64;
+                self.stats.evictions += 1;
+            }
+        }
+
+        self.order.insert(rank, hash);
+        self.entries.insert(hash, Entry { blob, rank });
+        self.stats.bytes += size;
+    }
+
+    /// Drop a blob from the cache. Not counted as an eviction.
+    pub(super) fn remove(&mut self, hash: &Hash) {
+        if let Some(entry) = self.entries.remove(hash) {
+            self.order.remove(&entry.rank);
+            self.stats.bytes -= entry.blob.content.len() as u64;
+        }
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.stats.bytes = 0;
+    }
+
+    pub(super) fn stats(&self) -> BlobCacheStats {
+        BlobCacheStats {
+            entries: self.entries.len() as u64,
+            ..self.stats
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::HashAlgorithm;
+
+    fn blob(size: usize) -> CachedBlob {
+        CachedBlob {
+            content: vec![0; size],
+            content_type: "application/octet-stream".into(),
+        }
+    }
+
+    fn hash(n: u8) -> Hash {
+        Hash([n; 32], HashAlgorithm::Sha256)
+    }
+
+    #[test]
+    fn test_lru_evicts_least_recently_read() {
+        let mut cache = BlobCache::new(BlobCacheConfig {
+            max_entries: 2,
+            ..Default::default()
+        });
+        cache.put(hash(1), blob(1));
+        cache.put(hash(2), blob(1));
+        assert!(cache.get(&hash(1)).is_some());
+        cache.put(hash(3), blob(1));
+
+        assert!(cache.contains(&hash(1)));
+        assert!(!cache.contains(&hash(2)));
+        assert!(cache.contains(&hash(3)));
+        assert_eq!(cache.stats().evictions, 1);
+    }
+
+    #[test]
+    fn test_lfu_evicts_least_frequently_read() {
+        let mut cache = BlobCache::new(BlobCacheConfig {
+            max_entries: 2,
+            max_bytes: None,
+            policy: BlobCachePolicy::Lfu,
+        });
+        cache.put(hash(1), blob(1));
+        cache.put(hash(2), blob(1));
+        cache.get(&hash(1));
+        cache.get(&hash(1));
+        cache.get(&hash(2));
+        cache.put(hash(3), blob(1));
+
+        assert!(cache.contains(&hash(1)));
+        assert!(!cache.contains(&hash(2)));
+        assert!(cache.contains(&hash(3)));
+    }
+
+    #[test]
+    fn test_byte_limit() {
+        let mut cache = BlobCache::new(BlobCacheConfig {
+            max_entries: 100,
+            max_bytes: Some(10),
+            policy: BlobCachePolicy::Lru,
+        });
+        cache.put(hash(1), blob(4));
+        cache.put(hash(2), blob(4));
+        cache.put(hash(3), blob(4));
+        assert!(!cache.contains(&hash(1)));
+        assert_eq!(cache.stats().bytes, 8);
+
+        // Too large to cache at all; nothing is evicted for it
+        cache.put(hash(4), blob(11));
+        assert!(!cache.contains(&hash(4)));
+        assert_eq!(cache.stats().entries, 2);
+        assert_eq!(cache.stats().evictions, 1);
+
+        cache.remove(&hash(2));
+        assert_eq!(cache.stats().bytes, 4);
+    }
+}
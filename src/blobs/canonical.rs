@@ -0,0 +1,64 @@
+//! Canonical JSON encoding for content-addressed blobs.
+//!
+//! Blobs are deduplicated by their bytes, so `{"a":1,"b":2}` and
+//! `{"b":2,"a":1}` would normally be stored twice. Encoding both the same
+//! way first lets `Store::store_json_blob` store them once.
+
+use crate::error::Result;
+use serde::Serialize;
+use serde_json::{Number, Value};
+
+/// Largest integer an `f64` holds exactly (2^53).
+const MAX_EXACT_FLOAT_INT: f64 = 9_007_199_254_740_992.0;
+
+/// Encode a value as canonical JSON.
+///
+/// Object keys are sorted, there is no whitespace, and floats with no
+/// fractional part that fit an integer exactly are written as integers, so
+/// `1.0` and `1` encode the same. Other floats use serde_json's shortest
+/// round-trip form.
+pub fn canonical_json<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>> {
+    let value = canonicalize(serde_json::to_value(value)?);
+    Ok(serde_json::to_vec(&value)?)
+}
+
+/// Normalize numbers throughout a value. Keys are already sorted by
+/// `serde_json::Map`, which is a `BTreeMap` here.
+fn canonicalize(value: Value) -> Value {
+    match value {
+        Value::Number(n) => Value::Number(canonical_number(n)),
+        Value::Array(items) => Value::Array(items.into_iter().map(canonicalize).collect()),
+        Value::Object(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(key, value)| (key, canonicalize(value)))
+                .collect(),
+        ),
+        other => other,
+    }
+}
+
+fn canonical_number(n: Number) -> Number {
+    match n.as_f64() {
+        Some(f) if n.is_f64() && f.fract() == 0.0 && f.abs() <= MAX_EXACT_FLOAT_INT => {
+            Number::from(f as i64)
+        }
+        _ => n,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_canonical_json() {
+        let a = canonical_json(&json!({"b": [1.0, {"y": 2, "x": -0.0}], "a": 1.5})).unwrap();
+        assert_eq!(a, br#"{"a":1.5,"b":[1,{"x":0,"y":2}]}"#);
+
+        // Integral floats past 2^53 stay floats
+        let big = canonical_json(&json!(1e300)).unwrap();
+        assert_eq!(big, b"1e+300");
+    }
+}
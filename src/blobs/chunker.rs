@@ -0,0 +1,123 @@
+//! Content-defined chunking for large blobs.
+//!
+//! Uses a gear rolling hash: a chunk boundary is placed wherever the top
+//! bits of the hash are zero, so boundaries depend only on nearby content.
+//! An edit in the middle of a file changes the chunks around the edit but
+//! leaves the rest of the chunk sequence (and their hashes) intact.
+
+use std::ops::Range;
+
+/// Smallest chunk emitted (except for the final chunk).
+const MIN_CHUNK_SIZE: usize = 2 * 1024;
+
+/// Largest chunk emitted; a boundary is forced here.
+const MAX_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Number of hash bits that must be zero for a boundary (~8KB average).
+const BOUNDARY_BITS: u32 = 13;
+
+/// Boundary mask over the top bits of the hash, which depend on the
+/// last 64 bytes rather than just the last one.
+const BOUNDARY_MASK: u64 = ((1u64 << BOUNDARY_BITS) - 1) << (64 - BOUNDARY_BITS);
+
+/// Per-byte random values for the gear hash (splitmix64, fixed seed).
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Split data into content-defined chunks.
+///
+/// Returns byte ranges covering `data` in order. Empty input yields no chunks.
+pub fn chunk_ranges(data: &[u8]) -> Vec<Range<usize>> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+
+    while start < data.len() {
+        let end = next_boundary(&data[start..]) + start;
+        ranges.push(start..end);
+        start = end;
+    }
+
+    ranges
+}
+
+/// Find the length of the next chunk at the start of `data`.
+fn next_boundary(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+
+    let limit = data.len().min(MAX_CHUNK_SIZE);
+    let mut hash = 0u64;
+
+    for (i, &byte) in data.iter().enumerate().take(limit) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        if i + 1 >= MIN_CHUNK_SIZE && hash & BOUNDARY_MASK == 0 {
+            return i + 1;
+        }
+    }
+
+    limit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pseudo_random(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunks_cover_input() {
+        let data = pseudo_random(300 * 1024, 1);
+        let ranges = chunk_ranges(&data);
+
+        assert_eq!(ranges.first().unwrap().start, 0);
+        assert_eq!(ranges.last().unwrap().end, data.len());
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+        }
+        for range in &ranges[..ranges.len() - 1] {
+            assert!(range.len() >= MIN_CHUNK_SIZE && range.len() <= MAX_CHUNK_SIZE);
+        }
+
+        assert!(chunk_ranges(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_boundaries_resync_after_edit() {
+        let original = pseudo_random(256 * 1024, 2);
+        let mut edited = original.clone();
+        for byte in &mut edited[128 * 1024..128 * 1024 + 100] {
+            *byte = byte.wrapping_add(1);
+        }
+
+        let a: Vec<&[u8]> = chunk_ranges(&original).into_iter().map(|r| &original[r]).collect();
+        let b: Vec<&[u8]> = chunk_ranges(&edited).into_iter().map(|r| &edited[r]).collect();
+
+        let shared = b.iter().filter(|chunk| a.contains(chunk)).count();
+        assert!(shared + 3 >= b.len(), "only {} of {} chunks shared", shared, b.len());
+    }
+}
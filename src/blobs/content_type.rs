@@ -0,0 +1,94 @@
+//! Content type (MIME) validation for blobs.
+//!
+//! Used when `StoreConfig::validate_content_type` is set. Blobs are
+//! deduplicated by content alone, so the first type a blob is stored with
+//! is the one it keeps; normalizing means `Text/Plain` and `text/plain`
+//! land on the same type, and lookups by type find both.
+
+use crate::error::{Result, StoreError};
+
+/// Normalize a content type of the form `type/subtype[; name=value]*`.
+///
+/// Surrounding whitespace is trimmed and the type, subtype and parameter
+/// names are lowercased; parameter values keep their case (a multipart
+/// boundary is case-sensitive). Returns `InvalidOperation` for anything
+/// that doesn't have that shape or uses characters outside RFC 9110 tokens.
+pub fn normalize_content_type(content_type: &str) -> Result<String> {
+    let invalid = |why: &str| {
+        StoreError::InvalidOperation(format!("Invalid content type {:?}: {}", content_type, why))
+    };
+
+    let mut parts = content_type.split(';');
+    let essence = parts.next().unwrap_or_default().trim();
+    let (kind, subtype) = essence
+        .split_once('/')
+        .ok_or_else(|| invalid("expected type/subtype"))?;
+    if !is_token(kind) || !is_token(subtype) {
+        return Err(invalid("type and subtype must be non-empty tokens"));
+    }
+
+    let mut normalized = format!("{}/{}", kind.to_ascii_lowercase(), subtype.to_ascii_lowercase());
+    for parameter in parts {
+        let (name, value) = parameter
+            .trim()
+            .split_once('=')
+            .ok_or_else(|| invalid("parameters must be name=value"))?;
+        if !is_token(name) {
+            return Err(invalid("parameter names must be tokens"));
+        }
+        let quoted = value.len() >= 2
+            && value.starts_with('"')
+            && value.ends_with('"')
+            && !value.chars().any(char::is_control);
+        if !is_token(value) && !quoted {
+            return Err(invalid("parameter values must be tokens or quoted strings"));
+        }
+        normalized.push_str("; ");
+        normalized.push_str(&name.to_ascii_lowercase());
+        normalized.push('=');
+        normalized.push_str(value);
+    }
+    Ok(normalized)
+}
+
+/// Whether `s` is a non-empty RFC 9110 token.
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_content_type() {
+        assert_eq!(normalize_content_type(" Application/JSON ").unwrap(), "application/json");
+        assert_eq!(
+            normalize_content_type("text/plain;CHARSET=utf-8").unwrap(),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            normalize_content_type("multipart/form-data; boundary=AbC").unwrap(),
+            "multipart/form-data; boundary=AbC"
+        );
+
+        assert_eq!(
+            normalize_content_type("text/plain; note=\"a b\"").unwrap(),
+            "text/plain; note=\"a b\""
+        );
+
+        let invalid = [
+            "", "json", "/json", "application/", "application json", "a/b/c",
+            "text/plain; charset", "text/plain; charset=a b",
+        ];
+        for bad in invalid {
+            assert!(
+                matches!(normalize_content_type(bad), Err(StoreError::InvalidOperation(_))),
+                "{:?} should be rejected",
+                bad
+            );
+        }
+    }
+}
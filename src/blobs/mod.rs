@@ -2,7 +2,18 @@
 //!
 //! Blobs are stored by their SHA-256 hash, sharded into directories
 //! by the first byte of the hash (like Git objects).
+//!
+//! Large blobs can optionally be stored in content-defined chunks so that
+//! near-identical files share storage for their common regions.
 
+mod cache;
+mod canonical;
+mod chunker;
+mod content_type;
 mod storage;
+mod type_index;
 
-pub use storage::BlobStorage;
+pub use cache::{BlobCacheConfig, BlobCachePolicy, BlobCacheStats};
+pub use canonical::canonical_json;
+pub use content_type::normalize_content_type;
+pub use storage::{BlobStorage, CHUNK_CONTENT_TYPE};
@@ -1,13 +1,16 @@
 //! Blob storage implementation.
 
+use super::cache::{BlobCache, BlobCacheConfig, BlobCacheStats, CachedBlob};
+use super::chunker::chunk_ranges;
+use super::type_index::BlobTypeIndex;
 use crate::error::{Result, StoreError};
-use crate::types::{Blob, Hash};
-use lru::LruCache;
-use parking_lot::Mutex;
+use crate::types::{Blob, BlobInfo, Hash, HashAlgorithm, Timestamp};
+use parking_lot::{Mutex, RwLock};
+use std::collections::HashSet;
 use std::fs::{self, File};
-use std::io::{Read, Write};
-use std::num::NonZeroUsize;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
 /// Magic bytes for blob files.
 const BLOB_MAGIC: &[u8; 4] = b"BLB\0";
@@ -15,11 +18,23 @@ const BLOB_MAGIC: &[u8; 4] = b"BLB\0";
 /// Current blob format version.
 const BLOB_VERSION: u8 = 1;
 
-/// Cached blob data (content + content_type).
-#[derive(Clone)]
-struct CachedBlob {
-    content: Vec<u8>,
+/// Format version for chunk manifests: the body lists chunk hashes
+/// instead of holding the content inline.
+const CHUNKED_BLOB_VERSION: u8 = 2;
+
+/// Content type recorded on individual chunks of a chunked blob.
+pub const CHUNK_CONTENT_TYPE: &str = "application/x-chronicle-chunk";
+
+/// File name of the content-type index inside the blob directory.
+const TYPE_INDEX_FILE: &str = "types.bin";
+
+/// Parsed blob file header.
+struct BlobHeader {
     content_type: String,
+    /// Length of the (reassembled) content.
+    size: u64,
+    /// Whether the body is a chunk manifest.
+    chunked: bool,
 }
 
 /// Content-addressed blob storage.
@@ -27,21 +42,86 @@ pub struct BlobStorage {
     /// Base directory for blobs.
     path: PathBuf,
 
-    /// LRU cache for recently accessed blobs.
-    cache: Mutex<LruCache<Hash, CachedBlob>>,
+    /// Cache for recently accessed blobs, with its hit/miss counters.
+    cache: Mutex<BlobCache>,
+
+    /// Content-type index.
+    type_index: RwLock<BlobTypeIndex>,
+
+    /// Number of hash bytes used as nested shard directories (1 or 2).
+    shard_depth: u8,
+
+    /// Hash function blobs are addressed by.
+    algorithm: HashAlgorithm,
 }
 
 impl BlobStorage {
     /// Create a new blob storage at the given path.
     pub fn new(path: impl AsRef<Path>, cache_size: usize) -> Result<Self> {
+        Self::with_shard_depth(path, cache_size, 1)
+    }
+
+    /// Create a blob storage sharded `shard_depth` directories deep.
+    ///
+    /// Depth 1 gives 256 shard directories (`ab/<hash>`), depth 2 gives
+    /// 65,536 (`ab/cd/<hash>`). The depth must match the one the blobs were
+    /// written with; blobs are not moved between layouts.
+    pub fn with_shard_depth(
+        path: impl AsRef<Path>,
+        cache_size: usize,
+        shard_depth: u8,
+    ) -> Result<Self> {
+        Self::with_hash_algorithm(path, cache_size, shard_depth, HashAlgorithm::Sha256)
+    }
+
+    /// Create a blob storage sharded `shard_depth` directories deep whose
+    /// blobs are addressed by `algorithm`.
+    ///
+    /// Hashes of any other algorithm are rejected with `InvalidOperation`.
+    /// The algorithm must match the one the blobs were written with.
+    pub fn with_hash_algorithm(
+        path: impl AsRef<Path>,
+        cache_size: usize,
+        shard_depth: u8,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self> {
+        let cache = BlobCacheConfig {
+            max_entries: cache_size,
+            ..Default::default()
+        };
+        Self::with_cache_config(path, cache, shard_depth, algorithm)
+    }
+
+    /// Create a blob storage whose cache follows `cache` instead of a plain
+    /// LRU entry count.
+    pub fn with_cache_config(
+        path: impl AsRef<Path>,
+        cache: BlobCacheConfig,
+        shard_depth: u8,
+        algorithm: HashAlgorithm,
+    ) -> Result<Self> {
+        if !(1..=2).contains(&shard_depth) {
+            return Err(StoreError::InvalidOperation(format!(
+                "Blob shard depth must be 1 or 2, got {}",
+                shard_depth
+            )));
+        }
+
         let path = path.as_ref().to_path_buf();
         fs::create_dir_all(&path)?;
 
-        let cache_size = NonZeroUsize::new(cache_size.max(1)).unwrap();
+        let index_path = path.join(TYPE_INDEX_FILE);
+        let type_index = match BlobTypeIndex::load(&index_path)? {
+            Some(index) => index,
+            None => Self::rebuild_type_index(&path, shard_depth, algorithm, index_path)?,
+        };
 
         Ok(Self {
             path,
-            cache: Mutex::new(LruCache::new(cache_size)),
+            shard_depth,
+            algorithm,
+            cache: Mutex::new(BlobCache::new(cache)),
+            type_index: RwLock::new(type_index),
         })
     }
 
@@ -49,13 +129,15 @@ impl BlobStorage {
     ///
     /// If the blob already exists, this is a no-op and returns the existing hash.
     pub fn store(&self, content: &[u8], content_type: &str) -> Result<Hash> {
-        let hash = Hash::from_bytes(content);
+        let hash = self.algorithm.digest(content);
 
         // Check if already exists
         if self.exists(&hash) {
             return Ok(hash);
         }
 
+        self.type_index.write().begin_update()?;
+
         // Create shard directory
         let shard_dir = self.shard_path(&hash);
         fs::create_dir_all(&shard_dir)?;
@@ -85,6 +167,11 @@ impl BlobStorage {
 
         file.sync_all()?;
 
+        let mut type_index = self.type_index.write();
+        type_index.insert(hash, content_type);
+        type_index.finish_update();
+        drop(type_index);
+
         // Add to cache
         self.cache.lock().put(hash, CachedBlob {
             content: content.to_vec(),
@@ -94,90 +181,200 @@ impl BlobStorage {
         Ok(hash)
     }
 
+    /// Store a blob split into content-defined chunks.
+    ///
+    /// Each chunk is stored as its own blob (content type
+    /// `CHUNK_CONTENT_TYPE`), so chunks shared between similar blobs are
+    /// written once. The returned hash is the hash of the whole content and
+    /// addresses a manifest listing the chunks; `get` reassembles it.
+    /// Content that makes a single chunk is stored inline, as by `store`.
+    pub fn store_chunked(&self, content: &[u8], content_type: &str) -> Result<Hash> {
+        let hash = self.algorithm.digest(content);
+
+        if self.exists(&hash) {
+            return Ok(hash);
+        }
+
+        // A lone chunk is the whole content, so it has the blob's own hash
+        // and its manifest would replace it
+        let ranges = chunk_ranges(content);
+        if ranges.len() <= 1 {
+            return self.store(content, content_type);
+        }
+
+        let mut chunk_hashes = Vec::new();
+        for range in ranges {
+            let chunk_hash = self.store(&content[range], CHUNK_CONTENT_TYPE)?;
+            chunk_hashes.extend_from_slice(&chunk_hash.0);
+        }
+
+        self.type_index.write().begin_update()?;
+        fs::create_dir_all(self.shard_path(&hash))?;
+        let mut file = File::create(self.blob_path(&hash))?;
+
+        // Header (same layout as inline blobs, different version)
+        file.write_all(BLOB_MAGIC)?;
+        file.write_all(&[CHUNKED_BLOB_VERSION])?;
+        let content_type_bytes = content_type.as_bytes();
+        file.write_all(&(content_type_bytes.len() as u16).to_le_bytes())?;
+        file.write_all(content_type_bytes)?;
+        file.write_all(&(content.len() as u64).to_le_bytes())?;
+
+        // Chunk list + checksum over it
+        file.write_all(&((chunk_hashes.len() / 32) as u32).to_le_bytes())?;
+        file.write_all(&chunk_hashes)?;
+        file.write_all(&crc32fast::hash(&chunk_hashes).to_le_bytes())?;
+
+        file.sync_all()?;
+
+        let mut type_index = self.type_index.write();
+        type_index.insert(hash, content_type);
+        type_index.finish_update();
+
+        Ok(hash)
+    }
+
     /// Get a blob by its hash.
     pub fn get(&self, hash: &Hash) -> Result<Option<Blob>> {
-        // Check cache first
-        if let Some(cached) = self.cache.lock().get(hash).cloned() {
-            return Ok(Some(Blob {
-                hash: *hash,
-                content: cached.content,
-                content_type: cached.content_type,
-            }));
+        self.get_tracked(hash).map(|(blob, _)| blob)
+    }
+
+    /// Read `len` bytes of a blob's content starting at `start`, without
+    /// loading the rest of it.
+    ///
+    /// A range running past the end of the blob stops at the end; one
+    /// starting past the end fails with `InvalidOperation`. For chunked
+    /// blobs only the chunks overlapping the range are read. Checksums
+    /// cover whole blobs, so partial reads don't check them (see `verify`).
+    pub fn get_range(&self, hash: &Hash, start: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        self.check_algorithm(hash)?;
+        let out_of_range = |size: u64| {
+            StoreError::InvalidOperation(format!(
+                "Range start {} is past the end of blob {} ({} bytes)",
+                start, hash, size
+            ))
+        };
+
+        if let Some(cached) = self.cache.lock().peek(hash) {
+            let size = cached.content.len() as u64;
+            if start > size {
+                return Err(out_of_range(size));
+            }
+            let end = start.saturating_add(len).min(size);
+            return Ok(Some(cached.content[start as usize..end as usize].to_vec()));
         }
 
         let blob_path = self.blob_path(hash);
         if !blob_path.exists() {
             return Ok(None);
         }
-
         let mut file = File::open(&blob_path)?;
-
-        // Read and verify magic
-        let mut magic = [0u8; 4];
-        file.read_exact(&mut magic)?;
-        if &magic != BLOB_MAGIC {
-            return Err(StoreError::InvalidFormat("Invalid blob magic".into()));
+        let header = Self::read_header(&mut file)?;
+        if start > header.size {
+            return Err(out_of_range(header.size));
         }
-
-        // Read version
-        let mut version = [0u8; 1];
-        file.read_exact(&mut version)?;
-        if version[0] != BLOB_VERSION {
-            return Err(StoreError::InvalidFormat(format!(
-                "Unsupported blob version: {}",
-                version[0]
-            )));
+        let end = start.saturating_add(len).min(header.size);
+        let mut content = Vec::with_capacity((end - start) as usize);
+
+        if !header.chunked {
+            file.seek(SeekFrom::Current(start as i64))?;
+            file.take(end - start).read_to_end(&mut content)?;
+            if content.len() as u64 != end - start {
+                return Err(StoreError::Corruption(format!("Blob {} is truncated", hash)));
+            }
+            return Ok(Some(content));
         }
 
-        // Read content type
-        let mut content_type_len_bytes = [0u8; 2];
-        file.read_exact(&mut content_type_len_bytes)?;
-        let content_type_len = u16::from_le_bytes(content_type_len_bytes) as usize;
-
-        let mut content_type_bytes = vec![0u8; content_type_len];
-        file.read_exact(&mut content_type_bytes)?;
-        let content_type = String::from_utf8_lossy(&content_type_bytes).into_owned();
+        let mut offset = 0;
+        for chunk_hash in self.read_chunk_list_of(hash, &mut file)? {
+            if offset >= end {
+                break;
+            }
+            let chunk_path = self.blob_path(&chunk_hash);
+            let chunk_size = match File::open(chunk_path) {
+                Ok(mut chunk) => Self::read_header(&mut chunk)?.size,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    return Err(StoreError::BlobNotFound(chunk_hash));
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let chunk_end = offset + chunk_size;
+            if chunk_end > start {
+                let from = start.saturating_sub(offset);
+                let to = end.min(chunk_end) - offset;
+                let part = self
+                    .get_range(&chunk_hash, from, to - from)?
+                    .ok_or(StoreError::BlobNotFound(chunk_hash))?;
+                content.extend_from_slice(&part);
+            }
+            offset = chunk_end;
+        }
+        Ok(Some(content))
+    }
 
-        // Read content
-        let mut content_len_bytes = [0u8; 8];
-        file.read_exact(&mut content_len_bytes)?;
-        let content_len = u64::from_le_bytes(content_len_bytes) as usize;
+    /// Get a blob, also reporting whether it was served from the cache.
+    pub(crate) fn get_tracked(&self, hash: &Hash) -> Result<(Option<Blob>, bool)> {
+        self.check_algorithm(hash)?;
+        // Check cache first
+        if let Some(cached) = self.cache.lock().get(hash).cloned() {
+            let blob = Blob {
+                hash: *hash,
+                content: cached.content,
+                content_type: cached.content_type,
+            };
+            return Ok((Some(blob), true));
+        }
+        let blob = match self.read_from_disk(hash)? {
+            Some(blob) => blob,
+            None => return Ok((None, false)),
+        };
 
-        let mut content = vec![0u8; content_len];
-        file.read_exact(&mut content)?;
+        // Add to cache
+        self.cache.lock().put(*hash, CachedBlob {
+            content: blob.content.clone(),
+            content_type: blob.content_type.clone(),
+        });
 
-        // Read and verify checksum
-        let mut checksum_bytes = [0u8; 4];
-        file.read_exact(&mut checksum_bytes)?;
-        let stored_checksum = u32::from_le_bytes(checksum_bytes);
-        let computed_checksum = crc32fast::hash(&content);
+        Ok((Some(blob), false))
+    }
 
-        if stored_checksum != computed_checksum {
-            return Err(StoreError::ChecksumMismatch {
-                expected: stored_checksum,
-                got: computed_checksum,
-            });
+    /// Re-read a blob from disk, bypassing the cache, and check its
+    /// checksum and content hash.
+    ///
+    /// Returns `BlobNotFound` if the file is missing.
+    pub fn verify(&self, hash: &Hash) -> Result<()> {
+        self.check_algorithm(hash)?;
+        match self.read_from_disk(hash)? {
+            Some(_) => Ok(()),
+            None => Err(StoreError::BlobNotFound(*hash)),
         }
+    }
 
-        // Verify hash
-        let computed_hash = Hash::from_bytes(&content);
-        if &computed_hash != hash {
-            return Err(StoreError::HashMismatch {
-                expected: *hash,
-                got: computed_hash,
-            });
+    /// Get a blob's size, content type and creation time without reading its content.
+    ///
+    /// Blob files are written once and never modified, so the file's
+    /// modification time is used as the creation time.
+    pub fn info(&self, hash: &Hash) -> Result<Option<BlobInfo>> {
+        self.check_algorithm(hash)?;
+        let blob_path = self.blob_path(hash);
+        if !blob_path.exists() {
+            return Ok(None);
         }
 
-        // Add to cache
-        self.cache.lock().put(*hash, CachedBlob {
-            content: content.clone(),
-            content_type: content_type.clone(),
-        });
+        let mut file = File::open(&blob_path)?;
+        let header = Self::read_header(&mut file)?;
 
-        Ok(Some(Blob {
+        let modified = file.metadata()?.modified()?;
+        let micros = modified
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_micros() as i64)
+            .unwrap_or(0);
+
+        Ok(Some(BlobInfo {
             hash: *hash,
-            content,
-            content_type,
+            size: header.size,
+            content_type: header.content_type,
+            created: Timestamp(micros),
         }))
     }
 
@@ -186,68 +383,400 @@ impl BlobStorage {
         if self.cache.lock().contains(hash) {
             return true;
         }
-        self.blob_path(hash).exists()
+        hash.1 == self.algorithm && self.blob_path(hash).exists()
+    }
+
+    /// Check if a blob's file exists, without consulting or touching the cache.
+    pub fn exists_on_disk(&self, hash: &Hash) -> bool {
+        hash.1 == self.algorithm && self.blob_path(hash).is_file()
+    }
+
+    /// Drop every cached blob. Blobs on disk are unaffected.
+    pub fn clear_cache(&self) {
+        self.cache.lock().clear();
     }
 
     /// Delete a blob (for garbage collection).
     pub fn delete(&self, hash: &Hash) -> Result<bool> {
-        self.cache.lock().pop(hash);
+        self.check_algorithm(hash)?;
+        self.cache.lock().remove(hash);
+        let mut type_index = self.type_index.write();
+        type_index.begin_update()?;
+        type_index.remove(hash);
 
         let blob_path = self.blob_path(hash);
-        if blob_path.exists() {
+        let deleted = if blob_path.exists() {
             fs::remove_file(&blob_path)?;
-            Ok(true)
+            true
         } else {
-            Ok(false)
+            false
+        };
+        type_index.finish_update();
+        Ok(deleted)
+    }
+
+    /// Every blob not in `keep`, other than chunks of blobs that are kept,
+    /// with the size of its file.
+    pub fn unreferenced(&self, keep: &HashSet<Hash>) -> Result<Vec<(Hash, u64)>> {
+        let mut files = Vec::new();
+        Self::walk_blobs(&self.path, self.shard_depth, self.algorithm, &mut |hash, path| {
+            files.push((hash, fs::metadata(path)?.len()));
+            Ok(())
+        })?;
+
+        let mut live = keep.clone();
+        for hash in keep {
+            let mut file = match File::open(self.blob_path(hash)) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if Self::read_header(&mut file)?.chunked {
+                live.extend(self.read_chunk_list(&mut file)?);
+            }
+        }
+
+        files.retain(|(hash, _)| !live.contains(hash));
+        Ok(files)
+    }
+
+    /// Delete every blob not in `keep`, other than chunks of blobs that are
+    /// kept. Returns how many blob files were deleted and their total size.
+    pub fn retain(&self, keep: &HashSet<Hash>) -> Result<(u64, u64)> {
+        let (mut deleted, mut bytes) = (0, 0);
+        for (hash, size) in self.unreferenced(keep)? {
+            if self.delete(&hash)? {
+                deleted += 1;
+                bytes += size;
+            }
         }
+        self.save()?;
+        Ok((deleted, bytes))
+    }
+
+    /// Copy every blob file into the blob directory `dest`.
+    ///
+    /// Blobs are only entered in the type index once their file is fully
+    /// written, so a blob that wasn't indexed when its file was copied may
+    /// have been caught mid-write; those copies are checked and dropped if
+    /// incomplete. The type index itself is left to be rebuilt on open.
+    pub fn copy_to(&self, dest: &Path) -> Result<()> {
+        let mut unindexed = Vec::new();
+        Self::walk_blobs(&self.path, self.shard_depth, self.algorithm, &mut |hash, path| {
+            let indexed = self.type_index.read().contains(&hash);
+            let target = dest.join(path.strip_prefix(&self.path).unwrap_or(path));
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            match fs::copy(path, &target) {
+                Ok(_) => File::open(&target)?.sync_all()?,
+                // Deleted since the directory was listed
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+                Err(e) => return Err(e.into()),
+            }
+            if !indexed {
+                unindexed.push(hash);
+            }
+            Ok(())
+        })?;
+
+        if !unindexed.is_empty() {
+            let copy = Self::with_hash_algorithm(dest, 1, self.shard_depth, self.algorithm)?;
+            for hash in unindexed {
+                if copy.verify(&hash).is_err() {
+                    copy.delete(&hash)?;
+                }
+            }
+            copy.save()?;
+        }
+        Ok(())
     }
 
     /// List all blob hashes.
     pub fn list(&self) -> Result<Vec<Hash>> {
         let mut hashes = Vec::new();
+        Self::walk_blobs(&self.path, self.shard_depth, self.algorithm, &mut |hash, _| {
+            hashes.push(hash);
+            Ok(())
+        })?;
+        Ok(hashes)
+    }
 
-        for entry in fs::read_dir(&self.path)? {
-            let entry = entry?;
-            if entry.file_type()?.is_dir() {
-                for blob_entry in fs::read_dir(entry.path())? {
-                    let blob_entry = blob_entry?;
-                    let filename = blob_entry.file_name();
-                    let filename_str = filename.to_string_lossy();
-                    if let Ok(hash) = Hash::from_hex(&filename_str) {
-                        hashes.push(hash);
-                    }
+    /// Count stored blobs without reading their metadata.
+    pub fn count(&self) -> Result<u64> {
+        let mut count = 0u64;
+        Self::walk_blobs(&self.path, self.shard_depth, self.algorithm, &mut |_, _| {
+            count += 1;
+            Ok(())
+        })?;
+        Ok(count)
+    }
+
+    /// Metadata of the blobs written after `since`, oldest first.
+    ///
+    /// Chunks of chunked blobs are left out; the blobs they make up are
+    /// listed instead.
+    pub fn list_since(&self, since: Timestamp) -> Result<Vec<BlobInfo>> {
+        let mut hashes = Vec::new();
+        Self::walk_blobs(&self.path, self.shard_depth, self.algorithm, &mut |hash, _| {
+            hashes.push(hash);
+            Ok(())
+        })?;
+
+        let mut infos = Vec::new();
+        for hash in hashes {
+            if let Some(info) = self.info(&hash)? {
+                if info.created > since && info.content_type != CHUNK_CONTENT_TYPE {
+                    infos.push(info);
                 }
             }
         }
-
-        Ok(hashes)
+        infos.sort_by_key(|info| info.created);
+        Ok(infos)
     }
 
     /// Get total size of all blobs.
     pub fn total_size(&self) -> Result<u64> {
         let mut total = 0u64;
+        Self::walk_blobs(&self.path, self.shard_depth, self.algorithm, &mut |_, path| {
+            total += fs::metadata(path)?.len();
+            Ok(())
+        })?;
+        Ok(total)
+    }
+
+    /// List blobs stored with a content type.
+    ///
+    /// Blobs are deduplicated by content, so a blob stored twice with
+    /// different content types is listed under the first type only.
+    pub fn list_by_type(&self, content_type: &str) -> Vec<Hash> {
+        self.type_index.read().get(content_type)
+    }
+
+    /// Persist the content-type index if it changed.
+    pub fn save(&self) -> Result<()> {
+        self.type_index.write().save()
+    }
 
-        for entry in fs::read_dir(&self.path)? {
+    /// Fraction of `get` calls served from the cache.
+    ///
+    /// Returns None until the first read.
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        let stats = self.cache_stats();
+        let total = stats.hits + stats.misses;
+        if total == 0 {
+            None
+        } else {
+            Some(stats.hits as f64 / total as f64)
+        }
+    }
+
+    /// Cache hit/miss/eviction counters and current size.
+    pub fn cache_stats(&self) -> BlobCacheStats {
+        self.cache.lock().stats()
+    }
+
+    /// Rebuild the content-type index by reading every blob header.
+    fn rebuild_type_index(
+        path: &Path,
+        shard_depth: u8,
+        algorithm: HashAlgorithm,
+        index_path: PathBuf,
+    ) -> Result<BlobTypeIndex> {
+        let mut index = BlobTypeIndex::new(index_path);
+        Self::walk_blobs(path, shard_depth, algorithm, &mut |hash, blob_path| {
+            let mut file = File::open(blob_path)?;
+            let header = Self::read_header(&mut file)?;
+            index.insert(hash, &header.content_type);
+            Ok(())
+        })?;
+        Ok(index)
+    }
+
+    /// Call `visit` for every blob file `depth` shard directories below `dir`.
+    fn walk_blobs(
+        dir: &Path,
+        depth: u8,
+        algorithm: HashAlgorithm,
+        visit: &mut dyn FnMut(Hash, &Path) -> Result<()>,
+    ) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
             let entry = entry?;
-            if entry.file_type()?.is_dir() {
-                for blob_entry in fs::read_dir(entry.path())? {
-                    let blob_entry = blob_entry?;
-                    total += blob_entry.metadata()?.len();
+            let is_dir = entry.file_type()?.is_dir();
+            if depth > 0 {
+                if is_dir {
+                    Self::walk_blobs(&entry.path(), depth - 1, algorithm, visit)?;
+                }
+            } else if !is_dir {
+                // File names are the bare digest
+                if let Ok(hash) = Hash::from_hex(&entry.file_name().to_string_lossy()) {
+                    visit(Hash(hash.0, algorithm), &entry.path())?;
                 }
             }
         }
+        Ok(())
+    }
 
-        Ok(total)
+    /// Read and validate a blob file without touching the cache.
+    fn read_from_disk(&self, hash: &Hash) -> Result<Option<Blob>> {
+        let blob_path = self.blob_path(hash);
+        if !blob_path.exists() {
+            return Ok(None);
+        }
+
+        let mut file = File::open(&blob_path)?;
+        let header = Self::read_header(&mut file)?;
+        let content_type = header.content_type;
+
+        let content = if header.chunked {
+            let chunks = self.read_chunk_list_of(hash, &mut file)?;
+            let mut content = Vec::with_capacity(header.size as usize);
+            for chunk_hash in chunks {
+                // From disk too, so a cached chunk can't hide a damaged file
+                let chunk = self
+                    .read_from_disk(&chunk_hash)?
+                    .ok_or(StoreError::BlobNotFound(chunk_hash))?;
+                content.extend_from_slice(&chunk.content);
+            }
+            content
+        } else {
+            let mut content = vec![0u8; header.size as usize];
+            file.read_exact(&mut content)?;
+
+            // Read and verify checksum
+            let mut checksum_bytes = [0u8; 4];
+            file.read_exact(&mut checksum_bytes)?;
+            let stored_checksum = u32::from_le_bytes(checksum_bytes);
+            let computed_checksum = crc32fast::hash(&content);
+
+            if stored_checksum != computed_checksum {
+                return Err(StoreError::ChecksumMismatch {
+                    expected: stored_checksum,
+                    got: computed_checksum,
+                });
+            }
+            content
+        };
+
+        // Verify hash
+        let computed_hash = self.algorithm.digest(&content);
+        if &computed_hash != hash {
+            return Err(StoreError::HashMismatch {
+                expected: *hash,
+                got: computed_hash,
+            });
+        }
+
+        Ok(Some(Blob {
+            hash: *hash,
+            content,
+            content_type,
+        }))
+    }
+
+    /// Read the blob header, leaving the file positioned at the body.
+    fn read_header(file: &mut File) -> Result<BlobHeader> {
+        // Read and verify magic
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != BLOB_MAGIC {
+            return Err(StoreError::InvalidFormat("Invalid blob magic".into()));
+        }
+
+        // Read version
+        let mut version = [0u8; 1];
+        file.read_exact(&mut version)?;
+        let chunked = match version[0] {
+            BLOB_VERSION => false,
+            CHUNKED_BLOB_VERSION => true,
+            other => {
+                return Err(StoreError::InvalidFormat(format!(
+                    "Unsupported blob version: {}",
+                    other
+                )))
+            }
+        };
+
+        // Read content type
+        let mut content_type_len_bytes = [0u8; 2];
+        file.read_exact(&mut content_type_len_bytes)?;
+        let content_type_len = u16::from_le_bytes(content_type_len_bytes) as usize;
+
+        let mut content_type_bytes = vec![0u8; content_type_len];
+        file.read_exact(&mut content_type_bytes)?;
+        let content_type = String::from_utf8_lossy(&content_type_bytes).into_owned();
+
+        // Read content length
+        let mut content_len_bytes = [0u8; 8];
+        file.read_exact(&mut content_len_bytes)?;
+
+        Ok(BlobHeader {
+            content_type,
+            size: u64::from_le_bytes(content_len_bytes),
+            chunked,
+        })
+    }
+
+    /// Read the chunk hashes from a manifest body, verifying its checksum.
+    fn read_chunk_list(&self, file: &mut File) -> Result<Vec<Hash>> {
+        let mut count_bytes = [0u8; 4];
+        file.read_exact(&mut count_bytes)?;
+        let count = u32::from_le_bytes(count_bytes) as usize;
+
+        let mut hash_bytes = vec![0u8; count * 32];
+        file.read_exact(&mut hash_bytes)?;
+
+        let mut checksum_bytes = [0u8; 4];
+        file.read_exact(&mut checksum_bytes)?;
+        let stored_checksum = u32::from_le_bytes(checksum_bytes);
+        let computed_checksum = crc32fast::hash(&hash_bytes);
+        if stored_checksum != computed_checksum {
+            return Err(StoreError::ChecksumMismatch {
+                expected: stored_checksum,
+                got: computed_checksum,
+            });
+        }
+
+        Ok(hash_bytes
+            .chunks_exact(32)
+            .map(|bytes| Hash(bytes.try_into().unwrap(), self.algorithm))
+            .collect())
+    }
+
+    /// `read_chunk_list` for the manifest of `hash`, failing with
+    /// `Corruption` if it lists itself (older builds wrote such manifests
+    /// for single-chunk content), which would otherwise recurse forever.
+    fn read_chunk_list_of(&self, hash: &Hash, file: &mut File) -> Result<Vec<Hash>> {
+        let chunks = self.read_chunk_list(file)?;
+        if chunks.contains(hash) {
+            return Err(StoreError::Corruption(format!("Chunked blob {} lists itself as a chunk", hash)));
+        }
+        Ok(chunks)
+    }
+
+    /// Fail with `InvalidOperation` for a hash of another algorithm.
+    fn check_algorithm(&self, hash: &Hash) -> Result<()> {
+        if hash.1 != self.algorithm {
+            return Err(StoreError::InvalidOperation(format!(
+                "Blob hash {} is {:?}, but this store addresses blobs by {:?}",
+                hash, hash.1, self.algorithm
+            )));
+        }
+        Ok(())
     }
 
     /// Get the shard directory for a hash.
     fn shard_path(&self, hash: &Hash) -> PathBuf {
-        self.path.join(hash.shard_prefix())
+        let mut path = self.path.join(hash.shard_prefix());
+        if self.shard_depth >= 2 {
+            path.push(hex::encode(&hash.0[1..2]));
+        }
+        path
     }
 
     /// Get the full path for a blob.
     fn blob_path(&self, hash: &Hash) -> PathBuf {
-        self.shard_path(hash).join(hash.to_hex())
+        self.shard_path(hash).join(hex::encode(hash.0))
     }
 }
 
@@ -323,4 +852,171 @@ mod tests {
         assert!(hashes.contains(&hash2));
         assert!(hashes.contains(&hash3));
     }
+
+    #[test]
+    fn test_info() {
+        let dir = TempDir::new().unwrap();
+        let storage = BlobStorage::new(dir.path().join("blobs"), 100).unwrap();
+
+        let before = Timestamp::now();
+        let content = vec![7u8; 64 * 1024];
+        let hash = storage.store(&content, "application/octet-stream").unwrap();
+
+        let info = storage.info(&hash).unwrap().unwrap();
+        assert_eq!(info.hash, hash);
+        assert_eq!(info.size, content.len() as u64);
+        assert_eq!(info.content_type, "application/octet-stream");
+        assert!(info.created.0 >= before.0 - 1_000_000);
+
+        // Doesn't go through the content cache
+        assert_eq!(storage.cache_hit_rate(), None);
+
+        assert!(storage.info(&Hash::from_bytes(b"missing")).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_list_by_type() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("blobs");
+
+        let (js, json, dup) = {
+            let storage = BlobStorage::new(&path, 100).unwrap();
+            let js = storage.store(b"console.log(1)", "application/javascript").unwrap();
+            let json = storage.store(b"{}", "application/json").unwrap();
+            // Same content again under another type: first type wins
+            let dup = storage.store(b"console.log(1)", "text/plain").unwrap();
+            storage.save().unwrap();
+            (js, json, dup)
+        };
+        assert_eq!(js, dup);
+
+        // Reload from the persisted index
+        let storage = BlobStorage::new(&path, 100).unwrap();
+        assert_eq!(storage.list_by_type("application/javascript"), vec![js]);
+        assert_eq!(storage.list_by_type("application/json"), vec![json]);
+        assert!(storage.list_by_type("text/plain").is_empty());
+
+        // Missing index is rebuilt from blob headers
+        fs::remove_file(path.join(TYPE_INDEX_FILE)).unwrap();
+        let storage = BlobStorage::new(&path, 100).unwrap();
+        assert_eq!(storage.list_by_type("application/javascript"), vec![js]);
+
+        storage.delete(&json).unwrap();
+        assert!(storage.list_by_type("application/json").is_empty());
+    }
+
+    #[test]
+    fn test_type_index_rebuilt_after_unsaved_changes() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("blobs");
+        let marker = path.join(TYPE_INDEX_FILE).with_extension("dirty");
+
+        let (saved, unsaved) = {
+            let storage = BlobStorage::new(&path, 100).unwrap();
+            let saved = storage.store(b"saved", "text/plain").unwrap();
+            assert!(marker.exists());
+            storage.save().unwrap();
+            assert!(!marker.exists());
+
+            // Stored after the last save, then the process dies
+            let unsaved = storage.store(b"unsaved", "text/plain").unwrap();
+            storage.delete(&saved).unwrap();
+            (saved, unsaved)
+        };
+
+        let storage = BlobStorage::new(&path, 100).unwrap();
+        assert_eq!(storage.list_by_type("text/plain"), vec![unsaved]);
+        assert!(!storage.exists(&saved));
+        storage.save().unwrap();
+        assert!(!marker.exists());
+    }
+
+    #[test]
+    fn test_store_chunked_shares_chunks() {
+        let dir = TempDir::new().unwrap();
+        let storage = BlobStorage::new(dir.path(), 100).unwrap();
+
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let original: Vec<u8> = (0..1024 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let mut edited = original.clone();
+        let middle = original.len() / 2;
+        for byte in &mut edited[middle..middle + 1024] {
+            *byte = !*byte;
+        }
+
+        let a = storage.store_chunked(&original, "application/octet-stream").unwrap();
+        let b = storage.store_chunked(&edited, "application/octet-stream").unwrap();
+        assert_eq!(a, Hash::from_bytes(&original));
+        assert_ne!(a, b);
+
+        // Reassembled transparently by get()
+        assert_eq!(storage.get(&a).unwrap().unwrap().content, original);
+        let blob = storage.get(&b).unwrap().unwrap();
+        assert_eq!(blob.content, edited);
+        assert_eq!(blob.content_type, "application/octet-stream");
+        assert_eq!(storage.info(&b).unwrap().unwrap().size, edited.len() as u64);
+
+        // Only the chunks around the edit are stored twice
+        let on_disk = storage.total_size().unwrap();
+        assert!(
+            on_disk < (original.len() + edited.len()) as u64 * 6 / 10,
+            "chunked storage used {} bytes",
+            on_disk
+        );
+    }
+
+    #[test]
+    fn test_verify_chunked_reads_chunks_from_disk() {
+        let dir = TempDir::new().unwrap();
+        let storage = BlobStorage::new(dir.path(), 100).unwrap();
+
+        let content: Vec<u8> = (0..200_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let hash = storage.store_chunked(&content, "application/octet-stream").unwrap();
+        let chunks = storage.list_by_type(CHUNK_CONTENT_TYPE);
+        assert!(chunks.len() > 1);
+        storage.verify(&hash).unwrap();
+
+        // Damage a chunk's content while it sits in the cache
+        let chunk = chunks[0];
+        assert!(storage.get(&chunk).unwrap().is_some());
+        let chunk_path = storage.blob_path(&chunk);
+        let mut file = fs::read(&chunk_path).unwrap();
+        let pos = file.len() - 5;
+        file[pos] ^= 0xFF;
+        fs::write(&chunk_path, file).unwrap();
+
+        assert!(storage.get(&chunk).unwrap().is_some());
+        assert!(storage.verify(&chunk).is_err());
+        assert!(storage.verify(&hash).is_err());
+    }
+
+    #[test]
+    fn test_store_chunked_single_chunk() {
+        let dir = TempDir::new().unwrap();
+        let hash = {
+            let storage = BlobStorage::new(dir.path(), 100).unwrap();
+            let hash = storage.store_chunked(b"short note", "text/plain").unwrap();
+            let blob = storage.get(&hash).unwrap().unwrap();
+            assert_eq!(blob.content_type, "text/plain");
+            assert_eq!(storage.list_by_type("text/plain"), vec![hash]);
+            storage.save().unwrap();
+            hash
+        };
+
+        // Stored inline, so it reads back from disk after a reopen
+        let storage = BlobStorage::new(dir.path(), 100).unwrap();
+        let blob = storage.get(&hash).unwrap().unwrap();
+        assert_eq!(blob.content, b"short note");
+        assert_eq!(blob.content_type, "text/plain");
+        assert_eq!(storage.list_by_type("text/plain"), vec![hash]);
+        assert!(storage.list_by_type(CHUNK_CONTENT_TYPE).is_empty());
+        storage.verify(&hash).unwrap();
+    }
 }
@@ -0,0 +1,202 @@
+//! Persisted content-type index for blobs.
+//!
+//! Maps each blob hash to the content type it was first stored with, so
+//! blobs can be listed by type without opening every shard. The index is
+//! written on `save` (called from `Store::sync`) and rebuilt from blob
+//! headers when the file is missing. A marker file exists from the first
+//! blob write or delete after a save until the next save, so an index left
+//! stale by a crash in between is rebuilt too.
+
+use crate::error::{Result, StoreError};
+use crate::types::Hash;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Magic bytes for the blob type index file.
+const TYPE_INDEX_MAGIC: &[u8; 4] = b"BTI\0";
+
+/// Current blob type index format version.
+const TYPE_INDEX_VERSION: u8 = 1;
+
+/// Extension of the marker file next to the index while it's stale on disk.
+const DIRTY_MARKER_EXTENSION: &str = "dirty";
+
+/// Content type lookups in both directions.
+pub(crate) struct BlobTypeIndex {
+    /// Path to the index file.
+    path: PathBuf,
+
+    /// Hash -> content type.
+    types: HashMap<Hash, String>,
+
+    /// Content type -> hashes, in insertion order.
+    by_type: HashMap<String, Vec<Hash>>,
+
+    /// Whether there are changes not yet written to disk.
+    dirty: bool,
+
+    /// Whether the dirty marker file exists.
+    marked: bool,
+
+    /// Blob writes and deletes begun but not finished.
+    pending: usize,
+}
+
+impl BlobTypeIndex {
+    /// Create an empty index that will be saved at `path`.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let marked = Self::marker_path(&path).exists();
+        Self {
+            path,
+            types: HashMap::new(),
+            by_type: HashMap::new(),
+            dirty: false,
+            marked,
+            pending: 0,
+        }
+    }
+
+    /// Load the index from disk. Returns None if the file doesn't exist or
+    /// the dirty marker says it may be missing changes.
+    pub fn load(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        let path = path.as_ref();
+        if !path.exists() || Self::marker_path(path).exists() {
+            return Ok(None);
+        }
+
+        let mut file = File::open(path)?;
+
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != TYPE_INDEX_MAGIC {
+            return Err(StoreError::InvalidFormat("Invalid blob type index magic".into()));
+        }
+
+        let mut version = [0u8; 1];
+        file.read_exact(&mut version)?;
+        if version[0] != TYPE_INDEX_VERSION {
+            return Err(StoreError::InvalidFormat(format!(
+                "Unsupported blob type index version: {}",
+                version[0]
+            )));
+        }
+
+        let mut len_bytes = [0u8; 8];
+        file.read_exact(&mut len_bytes)?;
+        let mut encoded = vec![0u8; u64::from_le_bytes(len_bytes) as usize];
+        file.read_exact(&mut encoded)?;
+
+        let entries: Vec<(Hash, String)> = rmp_serde::from_slice(&encoded)
+            .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+
+        let mut index = Self::new(path);
+        for (hash, content_type) in entries {
+            index.insert(hash, &content_type);
+        }
+        index.dirty = false;
+
+        Ok(Some(index))
+    }
+
+    /// Note that a blob file is about to be written or deleted, creating the
+    /// dirty marker if it isn't there yet. Pair with `finish_update` once
+    /// the index reflects the change.
+    pub fn begin_update(&mut self) -> Result<()> {
+        if !self.marked {
+            File::create(Self::marker_path(&self.path))?.sync_all()?;
+            if let Some(dir) = self.path.parent() {
+                File::open(dir)?.sync_all()?;
+            }
+            self.marked = true;
+        }
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// End an update started with `begin_update`.
+    pub fn finish_update(&mut self) {
+        self.pending = self.pending.saturating_sub(1);
+    }
+
+    /// Record a blob's content type. The first type recorded for a hash wins.
+    pub fn insert(&mut self, hash: Hash, content_type: &str) {
+        if self.types.contains_key(&hash) {
+            return;
+        }
+        self.types.insert(hash, content_type.to_string());
+        self.by_type
+            .entry(content_type.to_string())
+            .or_default()
+            .push(hash);
+        self.dirty = true;
+    }
+
+    /// Forget a blob.
+    pub fn remove(&mut self, hash: &Hash) {
+        if let Some(content_type) = self.types.remove(hash) {
+            if let Some(hashes) = self.by_type.get_mut(&content_type) {
+                hashes.retain(|h| h != hash);
+                if hashes.is_empty() {
+                    self.by_type.remove(&content_type);
+                }
+            }
+            self.dirty = true;
+        }
+    }
+
+    /// Whether a blob has been recorded.
+    pub fn contains(&self, hash: &Hash) -> bool {
+        self.types.contains_key(hash)
+    }
+
+    /// Get all hashes stored with a content type.
+    pub fn get(&self, content_type: &str) -> Vec<Hash> {
+        self.by_type.get(content_type).cloned().unwrap_or_default()
+    }
+
+    /// Write the index to disk if it has changed, then remove the dirty
+    /// marker unless a blob update is still under way.
+    pub fn save(&mut self) -> Result<()> {
+        if self.dirty {
+            self.write()?;
+        }
+        if self.marked && self.pending == 0 {
+            match std::fs::remove_file(Self::marker_path(&self.path)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+            self.marked = false;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self) -> Result<()> {
+
+        let entries: Vec<(&Hash, &String)> = self.types.iter().collect();
+        let encoded = rmp_serde::to_vec(&entries)
+            .map_err(|e| StoreError::Serialization(e.to_string()))?;
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&self.path)?;
+
+        file.write_all(TYPE_INDEX_MAGIC)?;
+        file.write_all(&[TYPE_INDEX_VERSION])?;
+        file.write_all(&(encoded.len() as u64).to_le_bytes())?;
+        file.write_all(&encoded)?;
+        file.sync_all()?;
+
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn marker_path(path: &Path) -> PathBuf {
+        path.with_extension(DIRTY_MARKER_EXTENSION)
+    }
+}
@@ -0,0 +1,106 @@
+//! Branch DAG export for visualization.
+
+use crate::error::Result;
+use crate::types::{Branch, BranchId, Sequence, Timestamp};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+
+/// A branch in the graph.
+#[derive(Clone, Debug, Serialize)]
+pub struct BranchNode {
+    pub id: BranchId,
+    pub name: String,
+    pub head: Sequence,
+    pub branch_point: Option<Sequence>,
+    pub created: Timestamp,
+    /// Whether the branch's parent has been deleted.
+    pub orphan: bool,
+}
+
+/// A child -> parent edge.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct BranchEdge {
+    pub child: BranchId,
+    pub parent: BranchId,
+}
+
+/// Snapshot of all branches and their parent relationships.
+#[derive(Clone, Debug, Serialize)]
+pub struct BranchGraph {
+    /// Nodes ordered by branch ID.
+    pub nodes: Vec<BranchNode>,
+    /// Edges ordered by child ID. Orphans have no edge.
+    pub edges: Vec<BranchEdge>,
+}
+
+impl BranchGraph {
+    /// Build the graph from a set of branches.
+    pub(crate) fn from_branches(mut branches: Vec<Branch>) -> Self {
+        branches.sort_by_key(|branch| branch.id);
+        let ids: BTreeSet<BranchId> = branches.iter().map(|branch| branch.id).collect();
+
+        let mut nodes = Vec::with_capacity(branches.len());
+        let mut edges = Vec::new();
+        for branch in branches {
+            let orphan = match branch.parent {
+                Some(parent) if ids.contains(&parent) => {
+                    edges.push(BranchEdge {
+                        child: branch.id,
+                        parent,
+                    });
+                    false
+                }
+                Some(_) => true,
+                None => false,
+            };
+            nodes.push(BranchNode {
+                id: branch.id,
+                name: branch.name,
+                head: branch.head,
+                branch_point: branch.branch_point,
+                created: branch.created,
+                orphan,
+            });
+        }
+
+        Self { nodes, edges }
+    }
+
+    /// Get a node by branch ID.
+    pub fn node(&self, id: BranchId) -> Option<&BranchNode> {
+        self.nodes.iter().find(|node| node.id == id)
+    }
+
+    /// Branches with every parent before its children.
+    ///
+    /// Ties are broken by branch ID, so the order is stable across calls.
+    pub fn topological_order(&self) -> Vec<BranchId> {
+        let mut children: HashMap<BranchId, Vec<BranchId>> = HashMap::new();
+        let mut has_parent = BTreeSet::new();
+        for edge in &self.edges {
+            children.entry(edge.parent).or_default().push(edge.child);
+            has_parent.insert(edge.child);
+        }
+
+        let mut ready: BTreeSet<BranchId> = self
+            .nodes
+            .iter()
+            .map(|node| node.id)
+            .filter(|id| !has_parent.contains(id))
+            .collect();
+
+        let mut order = Vec::with_capacity(self.nodes.len());
+        while let Some(id) = ready.pop_first() {
+            order.push(id);
+            if let Some(kids) = children.get(&id) {
+                ready.extend(kids.iter().copied());
+            }
+        }
+        order
+    }
+
+    /// Serialize the graph to JSON.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
@@ -1,13 +1,15 @@
 //! Branch manager implementation.
 
+use super::graph::BranchGraph;
 use crate::error::{Result, StoreError};
 use crate::types::{Branch, BranchId, Sequence, Timestamp};
-use parking_lot::RwLock;
+use parking_lot::{RwLock, RwLockWriteGuard};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 /// Magic bytes for branch index file.
 const BRANCH_INDEX_MAGIC: &[u8; 4] = b"BRI\0";
@@ -59,6 +61,12 @@ struct BranchIndex {
 
     /// Next branch ID to assign.
     next_id: u64,
+
+    /// Records visible from each branch, kept current on append. None
+    /// until counted, and again after changes that can't be tracked
+    /// incrementally (see `Store::branch_record_counts`).
+    #[serde(skip)]
+    record_counts: Option<HashMap<BranchId, u64>>,
 }
 
 /// Manages branches with copy-on-write semantics.
@@ -71,6 +79,10 @@ pub struct BranchManager {
 
     /// Current active branch.
     current: RwLock<BranchId>,
+
+    /// Whether the index or current branch changed since they were last
+    /// saved or loaded.
+    dirty: AtomicBool,
 }
 
 impl BranchManager {
@@ -99,6 +111,7 @@ impl BranchManager {
             path,
             index: RwLock::new(index),
             current: RwLock::new(main_id),
+            dirty: AtomicBool::new(true),
         })
     }
 
@@ -110,6 +123,7 @@ impl BranchManager {
             path: path.clone(),
             index: RwLock::new(BranchIndex::default()),
             current: RwLock::new(BranchId(1)),
+            dirty: AtomicBool::new(!path.exists()),
         };
 
         if path.exists() {
@@ -139,7 +153,7 @@ impl BranchManager {
 
     /// Create a new branch from the current head of another branch.
     pub fn create_branch(&self, name: &str, from: Option<&str>) -> Result<Branch> {
-        let mut index = self.index.write();
+        let mut index = self.index_mut();
 
         // Check if name already exists
         if index.name_to_id.contains_key(name) {
@@ -174,13 +188,18 @@ impl BranchManager {
 
         index.branches.insert(branch_id, branch.clone());
         index.name_to_id.insert(name.to_string(), branch_id);
+        // Sees exactly what its parent sees
+        if let Some(counts) = &mut index.record_counts {
+            let count = counts.get(&parent_id).copied().unwrap_or(0);
+            counts.insert(branch_id, count);
+        }
 
         Ok(branch)
     }
 
     /// Create a branch from a specific sequence number.
     pub fn create_branch_at(&self, name: &str, from: &str, at: Sequence) -> Result<Branch> {
-        let mut index = self.index.write();
+        let mut index = self.index_mut();
 
         // Check if name already exists
         if index.name_to_id.contains_key(name) {
@@ -218,6 +237,7 @@ impl BranchManager {
 
         index.branches.insert(branch_id, branch.clone());
         index.name_to_id.insert(name.to_string(), branch_id);
+        index.record_counts = None;
 
         Ok(branch)
     }
@@ -261,19 +281,52 @@ impl BranchManager {
 
         drop(index);
 
-        *self.current.write() = id;
+        let mut current = self.current.write();
+        *current = id;
+        self.dirty.store(true, Ordering::Release);
         Ok(branch)
     }
 
     /// Update the head of a branch.
+    ///
+    /// Moving the head forward counts one record per sequence towards the
+    /// branch's record count; moving it back drops the counts.
     pub fn update_head(&self, branch_id: BranchId, new_head: Sequence) -> Result<()> {
-        let mut index = self.index.write();
+        let mut index = self.index_mut();
         let branch = index
             .branches
             .get_mut(&branch_id)
             .ok_or_else(|| StoreError::BranchNotFound(format!("{:?}", branch_id)))?;
 
-        branch.head = new_head;
+        let old_head = std::mem::replace(&mut branch.head, new_head);
+        if new_head < old_head {
+            index.record_counts = None;
+        } else if let Some(counts) = &mut index.record_counts {
+            *counts.entry(branch_id).or_default() += new_head.0 - old_head.0;
+        }
+        Ok(())
+    }
+
+    /// Records visible from each branch, if they are being tracked.
+    pub fn record_counts(&self) -> Option<HashMap<BranchId, u64>> {
+        self.index.read().record_counts.clone()
+    }
+
+    /// Start tracking record counts from freshly counted values.
+    pub fn set_record_counts(&self, counts: HashMap<BranchId, u64>) {
+        self.index.write().record_counts = Some(counts);
+    }
+
+    /// Drop the record counts after records stopped being visible, so they
+    /// are counted again on next use.
+    pub fn invalidate_record_counts(&self) {
+        self.index.write().record_counts = None;
+    }
+
+    /// Read the branch index back from its file, dropping unsaved changes.
+    pub fn reload(&self) -> Result<()> {
+        self.load_from_file()?;
+        self.dirty.store(false, Ordering::Release);
         Ok(())
     }
 
@@ -295,7 +348,7 @@ impl BranchManager {
             ));
         }
 
-        let mut index = self.index.write();
+        let mut index = self.index_mut();
         let current_id = *self.current.read();
 
         let id = index
@@ -312,6 +365,8 @@ impl BranchManager {
         let id = *id;
         index.branches.remove(&id);
         index.name_to_id.remove(name);
+        // Its children no longer see what they inherited through it
+        index.record_counts = None;
 
         Ok(())
     }
@@ -338,6 +393,44 @@ impl BranchManager {
         Ok(ancestry)
     }
 
+    /// Find the lowest common ancestor of two branches.
+    ///
+    /// Returns the deepest branch present in both ancestries, together with
+    /// the sequence on it where the two histories diverge: the smaller of the
+    /// two points at which each side leaves that branch (its head, if a side
+    /// is the branch itself). Returns None when the ancestries don't meet,
+    /// e.g. for branches orphaned from different deleted parents.
+    pub fn common_ancestor(&self, a: &str, b: &str) -> Result<Option<(BranchId, Sequence)>> {
+        let ancestry_a = self.get_ancestry(a)?;
+        let ancestry_b = self.get_ancestry(b)?;
+
+        for (i, shared) in ancestry_a.iter().enumerate() {
+            let j = match ancestry_b.iter().position(|branch| branch.id == shared.id) {
+                Some(j) => j,
+                None => continue,
+            };
+
+            // Where each side's path leaves the shared branch
+            let exit_point = |ancestry: &[Branch], pos: usize| {
+                if pos == 0 {
+                    ancestry[0].head
+                } else {
+                    ancestry[pos - 1].branch_point.unwrap_or_default()
+                }
+            };
+
+            let point = exit_point(&ancestry_a, i).min(exit_point(&ancestry_b, j));
+            return Ok(Some((shared.id, point)));
+        }
+
+        Ok(None)
+    }
+
+    /// Get the branch DAG.
+    pub fn graph(&self) -> BranchGraph {
+        BranchGraph::from_branches(self.list_branches())
+    }
+
     /// Check if a sequence is visible from a branch.
     ///
     /// A sequence is visible if it's <= branch head and either:
@@ -364,6 +457,60 @@ impl BranchManager {
         Ok(true) // Simplified - would need record-to-branch mapping for full check
     }
 
+    /// Sequence ranges visible from a branch, oldest first.
+    ///
+    /// Each entry is `(branch, first, last)`, inclusive: the ancestors'
+    /// records up to each branch point, then the branch's own records after
+    /// its branch point. The walk stops at a deleted parent.
+    pub fn visible_ranges(&self, branch_id: BranchId) -> Result<Vec<(BranchId, Sequence, Sequence)>> {
+        let index = self.index.read();
+        let mut current = index
+            .branches
+            .get(&branch_id)
+            .ok_or_else(|| StoreError::BranchNotFound(format!("{:?}", branch_id)))?;
+        let mut last = current.head;
+        let mut ranges = Vec::new();
+
+        loop {
+            let first = current.branch_point.map_or(Sequence(1), |point| point.next());
+            if first <= last {
+                ranges.push((current.id, first, last));
+            }
+            match (current.parent, current.branch_point) {
+                (Some(parent), Some(point)) => match index.branches.get(&parent) {
+                    Some(parent) => {
+                        current = parent;
+                        last = point;
+                    }
+                    None => break,
+                },
+                _ => break,
+            }
+        }
+
+        ranges.reverse();
+        Ok(ranges)
+    }
+
+    /// Names of the branches whose visible ranges include `branch`'s record
+    /// at `seq`, sorted.
+    pub fn branches_containing(&self, branch: BranchId, seq: Sequence) -> Vec<String> {
+        let mut names: Vec<String> = self
+            .list_branches()
+            .into_iter()
+            .filter(|candidate| {
+                self.visible_ranges(candidate.id).is_ok_and(|ranges| {
+                    ranges
+                        .iter()
+                        .any(|&(id, first, last)| id == branch && first <= seq && seq <= last)
+                })
+            })
+            .map(|candidate| candidate.name)
+            .collect();
+        names.sort();
+        names
+    }
+
     // --- Garbage Collection ---
 
     /// Find orphaned branches (branches whose parent was deleted).
@@ -487,7 +634,7 @@ impl BranchManager {
             ));
         }
 
-        let mut index = self.index.write();
+        let mut index = self.index_mut();
         let current_id = *self.current.read();
 
         let branch_id = *index
@@ -531,6 +678,7 @@ impl BranchManager {
         // Delete the branch
         index.branches.remove(&branch_id);
         index.name_to_id.remove(name);
+        index.record_counts = None;
 
         Ok(reparented)
     }
@@ -600,12 +748,36 @@ impl BranchManager {
     }
 
     /// Save branch index to file.
+    ///
+    /// Skipped if nothing changed since the last save.
     pub fn save(&self) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        self.save_to(&self.path).inspect_err(|_| self.dirty.store(true, Ordering::Release))
+    }
+
+    /// Whether there are changes `save` would write.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Acquire)
+    }
+
+    /// Lock the index for a change, marking it dirty. The flag is set with
+    /// the lock held, so a concurrent `save` can't clear it and then write
+    /// the index from before the change.
+    fn index_mut(&self) -> RwLockWriteGuard<'_, BranchIndex> {
+        let index = self.index.write();
+        self.dirty.store(true, Ordering::Release);
+        index
+    }
+
+    /// Save the branch index to another file, leaving this one alone.
+    pub fn save_to(&self, path: &Path) -> Result<()> {
         let mut file = OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(&self.path)?;
+            .open(path)?;
 
         // Write magic
         file.write_all(BRANCH_INDEX_MAGIC)?;
@@ -810,6 +982,62 @@ mod tests {
         assert_eq!(ancestry[2].name, MAIN_BRANCH);
     }
 
+    #[test]
+    fn test_common_ancestor_siblings() {
+        let dir = TempDir::new().unwrap();
+        let manager = BranchManager::new(dir.path().join("branches.bin")).unwrap();
+
+        manager.update_head(BranchId(1), Sequence(5)).unwrap();
+        manager.create_branch("left", None).unwrap();
+        manager.update_head(BranchId(1), Sequence(8)).unwrap();
+        manager.create_branch("right", None).unwrap();
+
+        let ancestor = manager.common_ancestor("left", "right").unwrap();
+        assert_eq!(ancestor, Some((BranchId(1), Sequence(5))));
+        assert_eq!(manager.common_ancestor("right", "left").unwrap(), ancestor);
+    }
+
+    #[test]
+    fn test_common_ancestor_parent_child() {
+        let dir = TempDir::new().unwrap();
+        let manager = BranchManager::new(dir.path().join("branches.bin")).unwrap();
+
+        manager.update_head(BranchId(1), Sequence(3)).unwrap();
+        let feature = manager.create_branch("feature", None).unwrap();
+        manager.update_head(feature.id, Sequence(7)).unwrap();
+        let sub = manager.create_branch("sub-feature", Some("feature")).unwrap();
+        manager.update_head(sub.id, Sequence(9)).unwrap();
+
+        assert_eq!(
+            manager.common_ancestor("feature", "sub-feature").unwrap(),
+            Some((feature.id, Sequence(7)))
+        );
+        assert_eq!(
+            manager.common_ancestor(MAIN_BRANCH, "sub-feature").unwrap(),
+            Some((BranchId(1), Sequence(3)))
+        );
+        assert_eq!(
+            manager.common_ancestor("feature", "feature").unwrap(),
+            Some((feature.id, Sequence(7)))
+        );
+    }
+
+    #[test]
+    fn test_common_ancestor_unrelated_orphans() {
+        let dir = TempDir::new().unwrap();
+        let manager = BranchManager::new(dir.path().join("branches.bin")).unwrap();
+
+        manager.create_branch("a", None).unwrap();
+        manager.create_branch("x", Some("a")).unwrap();
+        manager.create_branch("b", None).unwrap();
+        manager.create_branch("y", Some("b")).unwrap();
+        manager.delete_branch("a").unwrap();
+        manager.delete_branch("b").unwrap();
+
+        assert_eq!(manager.common_ancestor("x", "y").unwrap(), None);
+        assert!(manager.common_ancestor("x", "missing").is_err());
+    }
+
     #[test]
     fn test_persistence() {
         let dir = TempDir::new().unwrap();
@@ -916,7 +1144,7 @@ mod tests {
 
         // Both should be deleted since both are empty, but feature first
         // sub-feature gets reparented to main, then deleted too
-        assert!(result.deleted.len() >= 1);
+        assert!(!result.deleted.is_empty());
 
         // sub-feature should now have main as parent (or be deleted)
         let remaining = manager.list_branches();
@@ -3,6 +3,8 @@
 //! Branches enable cheap forks at any point in history. They share
 //! records with their parent up to the branch point, then diverge.
 
+mod graph;
 mod manager;
 
-pub use manager::{BranchGcOptions, BranchGcResult, BranchManager};
+pub use graph::{BranchEdge, BranchGraph, BranchNode};
+pub use manager::{BranchGcOptions, BranchGcResult, BranchManager, MAIN_BRANCH};
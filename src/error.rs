@@ -1,6 +1,7 @@
 //! Error types for the record store.
 
 use crate::types::{Hash, RecordId, Sequence};
+use std::path::PathBuf;
 use thiserror::Error;
 
 /// Main error type for store operations.
@@ -45,8 +46,14 @@ pub enum StoreError {
     #[error("Store is locked by another process")]
     Locked,
 
-    #[error("Store not initialized")]
-    NotInitialized,
+    #[error("Store is opened read-only")]
+    ReadOnly,
+
+    #[error("Store not initialized at {}", .0.display())]
+    NotInitialized(PathBuf),
+
+    #[error("Invalid config: {0}")]
+    InvalidConfig(String),
 
     #[error("Invalid store format: {0}")]
     InvalidFormat(String),
@@ -65,6 +72,32 @@ pub enum StoreError {
 
     #[error("Subscription was dropped")]
     SubscriptionDropped,
+
+    #[error("History of state {state_id} before {before:?} was pruned")]
+    HistoryPruned { state_id: String, before: Sequence },
+
+    #[error("Replication conflict on branch {branch} at {sequence:?}: {details}")]
+    ReplicationConflict { branch: String, sequence: Sequence, details: String },
+
+    #[error("Concurrent modification: expected head {expected:?}, found {actual:?}")]
+    ConcurrentModification { expected: Sequence, actual: Sequence },
+
+    #[error("Payload of {size} bytes exceeds the limit of {limit}")]
+    PayloadTooLarge { size: u64, limit: u64 },
+
+    #[error("State value reached {bytes} bytes, over the reconstruction limit of {limit}")]
+    StateTooLarge { bytes: u64, limit: u64 },
+
+    #[error("Payload of {record_type} record does not match its schema: {reason}")]
+    SchemaViolation { record_type: String, reason: String },
+
+    #[error("Corrupt update record for state {state_id} at offset {offset}: {source}")]
+    CorruptStateRecord {
+        state_id: String,
+        offset: u64,
+        #[source]
+        source: serde_json::Error,
+    },
 }
 
 impl From<serde_json::Error> for StoreError {
@@ -13,7 +13,7 @@
 //! ## Example
 //!
 //! ```ignore
-//! use record_store::{Store, StoreConfig, RecordInput};
+//! use chronicle::{Store, StoreConfig, RecordInput};
 //!
 //! let store = Store::open_or_create(StoreConfig {
 //!     path: "./my-store".into(),
@@ -35,8 +35,10 @@
 pub mod blobs;
 pub mod branches;
 pub mod error;
+pub mod migrations;
 #[cfg(feature = "napi-bindings")]
 pub mod napi;
+pub mod observer;
 pub mod records;
 pub mod state;
 pub mod store;
@@ -45,18 +47,28 @@ pub mod types;
 pub mod wal;
 
 // Re-exports
-pub use blobs::BlobStorage;
-pub use branches::{BranchGcOptions, BranchGcResult, BranchManager};
+pub use blobs::{
+    canonical_json, normalize_content_type, BlobCacheConfig, BlobCachePolicy, BlobCacheStats,
+    BlobStorage,
+};
+pub use branches::{
+    BranchEdge, BranchGcOptions, BranchGcResult, BranchGraph, BranchManager, BranchNode,
+};
 pub use error::{Result, StoreError};
-pub use records::{RecordIndex, RecordLog};
+pub use observer::{NoopObserver, StoreObserver};
+pub use records::{RecordIndex, RecordLog, SyncPolicy};
 pub use state::{
-    apply_operation, ChainStats, CompactionStats, SnapshotNeeded, StateChainHead, StateIndex,
-    StateManager,
+    apply_operation, validate_operation, ChainStats, CompactionStats, SnapshotNeeded,
+    SnapshotPolicy, StateChainHead, StateIndex, StateManager,
+};
+pub use store::{
+    AutoSnapshotGuard, BranchSizeInfo, CausationNode, CausationTree, CompactOptions, CompactReport,
+    CompactionProgress, CompactionSummary, ConsistencyReport, ExportManifest, PruneReport, RepairOptions, RepairReport, SnapshotEvent, StateDiff, Store, StoreConfig, VacuumStats,
+    VerifyIssue, VerifyLocation, VerifyOptions, VerifyReport,
 };
-pub use store::{CompactionSummary, Store, StoreConfig};
 pub use subscriptions::{
-    BranchSummary, DropReason, RecordSummary, StoreEvent, SubscriptionConfig, SubscriptionFilter,
-    SubscriptionHandle, SubscriptionId, SubscriptionManager,
+    BlockingEventIter, BranchSummary, DropReason, RecordSummary, StateWatcher, StoreEvent, SubscriptionConfig,
+    SubscriptionFilter, SubscriptionHandle, SubscriptionId, SubscriptionManager,
 };
 pub use types::*;
-pub use wal::{WalEntry, WalEntryStatus, WalOperation, WriteAheadLog};
+pub use wal::{JournaledRecord, WalEntry, WalEntryStatus, WalOperation, WriteAheadLog};
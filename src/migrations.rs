@@ -0,0 +1,114 @@
+//! Upgrades between store format versions.
+//!
+//! Each `Migration` moves a store from one format version to the next and
+//! leaves the manifest at its target version, so a chain interrupted part
+//! way can resume from wherever the manifest says it stopped. `Store::open`
+//! runs the built-in chain up to the current version when
+//! `StoreConfig::auto_migrate` is set.
+
+use crate::error::{Result, StoreError};
+use crate::store::Store;
+use crate::types::HashAlgorithm;
+use std::path::Path;
+
+/// One upgrade step between store format versions.
+#[derive(Clone, Copy, Debug)]
+pub struct Migration {
+    /// Version the step upgrades from.
+    pub from: u8,
+    /// Version the store is at once the step has run.
+    pub to: u8,
+    /// What the step changes, for logs and errors.
+    pub description: &'static str,
+    /// Upgrade the store directory in place, finishing with the manifest.
+    pub apply: fn(&Path) -> Result<()>,
+}
+
+/// Built-in migrations, oldest first.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        to: 2,
+        description: "record the blob shard depth in the manifest",
+        apply: v1_to_v2,
+    },
+    Migration {
+        from: 2,
+        to: 3,
+        description: "record the blob hash algorithm in the manifest",
+        apply: v2_to_v3,
+    },
+];
+
+/// Run `steps` on the store at `path` from version `from` up to `target`.
+///
+/// Returns the version reached. Fails with `InvalidFormat` if no step
+/// starts at a version the chain needs to pass through.
+pub fn run(path: &Path, from: u8, target: u8, steps: &[Migration]) -> Result<u8> {
+    let mut version = from;
+    while version < target {
+        let step = steps
+            .iter()
+            .find(|step| step.from == version && step.to > version)
+            .ok_or_else(|| {
+                StoreError::InvalidFormat(format!(
+                    "No migration from store version {} towards {}",
+                    version, target
+                ))
+            })?;
+        (step.apply)(path).map_err(|e| {
+            StoreError::InvalidFormat(format!(
+                "Migration {} -> {} ({}) failed: {}",
+                step.from, step.to, step.description, e
+            ))
+        })?;
+        version = step.to;
+    }
+    Ok(version)
+}
+
+/// Version 1 manifests imply a shard depth of 1; version 2 spells it out.
+fn v1_to_v2(path: &Path) -> Result<()> {
+    Store::write_manifest_version(path, 2, 1, HashAlgorithm::Sha256)
+}
+
+/// Version 2 manifests imply SHA-256; version 3 spells it out.
+fn v2_to_v3(path: &Path) -> Result<()> {
+    let (_, shard_depth, _) = Store::verify_manifest(path)?;
+    Store::write_manifest_version(path, 3, shard_depth, HashAlgorithm::Sha256)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn bump(path: &Path, to: &str) -> Result<()> {
+        let mut log = fs::read_to_string(path.join("steps")).unwrap_or_default();
+        log.push_str(to);
+        fs::write(path.join("steps"), log)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_chain() {
+        let dir = TempDir::new().unwrap();
+        let steps = [
+            Migration { from: 2, to: 3, description: "three", apply: |p| bump(p, "3") },
+            Migration { from: 1, to: 2, description: "two", apply: |p| bump(p, "2") },
+            Migration { from: 3, to: 4, description: "four", apply: |_| Err(StoreError::Locked) },
+        ];
+
+        assert_eq!(run(dir.path(), 1, 3, &steps).unwrap(), 3);
+        assert_eq!(fs::read_to_string(dir.path().join("steps")).unwrap(), "23");
+
+        // Already current: nothing runs
+        assert_eq!(run(dir.path(), 3, 3, &steps).unwrap(), 3);
+
+        // Failing and missing steps surface as InvalidFormat
+        let err = run(dir.path(), 3, 4, &steps).unwrap_err();
+        assert!(matches!(err, StoreError::InvalidFormat(ref m) if m.contains("four")));
+        assert!(matches!(run(dir.path(), 4, 5, &steps), Err(StoreError::InvalidFormat(_))));
+    }
+}
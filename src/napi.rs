@@ -2,14 +2,15 @@
 
 use crate::{
     error::StoreError,
+    store::StateItemIterator,
     subscriptions::{
-        DropReason, RecordSummary, StoreEvent, SubscriptionConfig, SubscriptionFilter,
-        SubscriptionHandle, SubscriptionId,
+        StoreEvent, SubscriptionConfig, SubscriptionFilter, SubscriptionHandle, SubscriptionId,
     },
-    CompactionSummary, Record, RecordId, Sequence, StateOperation, StateRegistration,
+    CompactionSummary, FieldValue, Record, RecordId, Sequence, StateOperation, StateRegistration,
     StateStrategy, Store, StoreConfig,
 };
 use napi::bindgen_prelude::*;
+use napi::threadsafe_function::{ErrorStrategy, ThreadsafeFunction, ThreadsafeFunctionCallMode};
 use napi_derive::napi;
 use parking_lot::Mutex;
 use std::collections::HashMap;
@@ -21,6 +22,8 @@ pub struct JsStore {
     inner: Option<Arc<Store>>,
     /// Active subscription handles, stored by ID.
     subscription_handles: Mutex<HashMap<u64, SubscriptionHandle>>,
+    /// Threads forwarding events to callbacks, stored by subscription ID.
+    subscription_bridges: Mutex<HashMap<u64, std::thread::JoinHandle<()>>>,
 }
 
 /// Record returned to JavaScript.
@@ -33,6 +36,9 @@ pub struct JsRecord {
     pub timestamp: i64,
     pub caused_by: Vec<String>,
     pub linked_to: Vec<String>,
+    pub lamport: Option<i64>,
+    /// Hex hash of the blob describing the payload's type.
+    pub schema: Option<String>,
 }
 
 impl From<Record> for JsRecord {
@@ -45,6 +51,8 @@ impl From<Record> for JsRecord {
             timestamp: r.timestamp.0,
             caused_by: r.caused_by.iter().map(|id| id.0.to_string()).collect(),
             linked_to: r.linked_to.iter().map(|id| id.0.to_string()).collect(),
+            lamport: r.lamport.map(|l| l as i64),
+            schema: r.schema.map(|hash| hash.to_hex()),
         }
     }
 }
@@ -69,6 +77,7 @@ pub struct JsStoreStats {
     pub state_slot_count: i64,
     pub total_size_bytes: i64,
     pub blob_size_bytes: i64,
+    pub index_size_bytes: i64,
 }
 
 /// Configuration for creating a store.
@@ -82,9 +91,11 @@ pub struct JsStoreConfig {
 #[napi(object)]
 pub struct JsStateRegistration {
     pub id: String,
-    pub strategy: String, // "snapshot" | "append_log"
+    pub strategy: String, // "snapshot" | "append_log" | "ring_buffer"
     pub delta_snapshot_every: Option<i64>,
     pub full_snapshot_every: Option<i64>,
+    /// Item limit, required for "ring_buffer".
+    pub capacity: Option<u32>,
     pub initial_value: Option<Buffer>,
 }
 
@@ -158,6 +169,10 @@ pub struct JsSubscriptionConfig {
     pub from_sequence: Option<i64>,
     /// Filter criteria.
     pub filter: Option<JsSubscriptionFilter>,
+    /// Send one snapshot per state during catch-up instead of replaying deltas.
+    pub coalesce_catchup_state: Option<bool>,
+    /// Branch to catch up on and follow (default: current branch).
+    pub branch: Option<String>,
 }
 
 /// Filter criteria for subscriptions.
@@ -175,13 +190,36 @@ pub struct JsSubscriptionFilter {
     pub include_state_changes: Option<bool>,
     /// Include branch events.
     pub include_branch_events: Option<bool>,
+    /// Only records directly caused by one of these record IDs.
+    pub caused_by: Option<Vec<String>>,
+    /// Only records linked to one of these record IDs.
+    pub linked_to: Option<Vec<String>>,
+}
+
+impl From<JsSubscriptionFilter> for SubscriptionFilter {
+    fn from(f: JsSubscriptionFilter) -> Self {
+        Self {
+            record_types: f.record_types,
+            branch: f.branch,
+            state_ids: f.state_ids,
+            include_records: f.include_records.unwrap_or(false),
+            include_state_changes: f.include_state_changes.unwrap_or(false),
+            include_branch_events: f.include_branch_events.unwrap_or(false),
+            caused_by: f.caused_by.map(|ids| {
+                ids.iter().filter_map(|s| s.parse::<u64>().ok().map(RecordId)).collect()
+            }),
+            linked_to: f.linked_to.map(|ids| {
+                ids.iter().filter_map(|s| s.parse::<u64>().ok().map(RecordId)).collect()
+            }),
+        }
+    }
 }
 
 /// A store event.
 #[napi(object)]
 pub struct JsStoreEvent {
     /// Event type: "record", "state_snapshot", "state_delta", "branch_head",
-    /// "branch_created", "branch_deleted", "caught_up", "dropped"
+    /// "branch_created", "branch_deleted", "branch_switched", "caught_up", "dropped"
     pub event_type: String,
     /// JSON-serialized event data.
     pub data: String,
@@ -196,6 +234,7 @@ impl From<StoreEvent> for JsStoreEvent {
             StoreEvent::BranchHead { .. } => "branch_head",
             StoreEvent::BranchCreated { .. } => "branch_created",
             StoreEvent::BranchDeleted { .. } => "branch_deleted",
+            StoreEvent::BranchSwitched { .. } => "branch_switched",
             StoreEvent::CaughtUp => "caught_up",
             StoreEvent::Dropped { .. } => "dropped",
         };
@@ -223,6 +262,67 @@ fn to_napi_error(e: StoreError) -> napi::Error {
     napi::Error::from_reason(e.to_string())
 }
 
+/// Convert a JS subscription config, filling in the defaults.
+fn subscription_config(config: Option<JsSubscriptionConfig>) -> SubscriptionConfig {
+    match config {
+        Some(cfg) => {
+            let filter = cfg.filter.map(SubscriptionFilter::from);
+
+            SubscriptionConfig {
+                buffer_size: cfg.buffer_size.map(|s| s as usize).unwrap_or(1000),
+                max_snapshot_bytes: cfg
+                    .max_snapshot_bytes
+                    .map(|s| s as usize)
+                    .unwrap_or(10 * 1024 * 1024),
+                from_sequence: cfg.from_sequence.map(|s| Sequence(s as u64)),
+                filter: filter.unwrap_or_default(),
+                coalesce_catchup_state: cfg.coalesce_catchup_state.unwrap_or(false),
+                branch: cfg.branch,
+            }
+        }
+        None => SubscriptionConfig::default(),
+    }
+}
+
+/// Convert a JS count or offset, rejecting negatives instead of letting
+/// them wrap around.
+fn to_usize(value: i64, name: &str) -> Result<usize> {
+    usize::try_from(value)
+        .map_err(|_| napi::Error::from_reason(format!("{} must not be negative", name)))
+}
+
+/// Iterator over the items of an AppendLog state.
+///
+/// Items are reconstructed from the log as they're read, so large states
+/// can be walked in batches without materializing the whole array at once.
+#[napi]
+pub struct JsStateIterator {
+    inner: StateItemIterator,
+}
+
+#[napi]
+impl JsStateIterator {
+    /// Get the next item, or null once the state is exhausted.
+    #[napi]
+    pub fn next_item(&mut self) -> Result<Option<serde_json::Value>> {
+        self.inner.next().transpose().map_err(to_napi_error)
+    }
+
+    /// Get up to `max` items; an empty array once the state is exhausted.
+    ///
+    /// Reading in batches keeps each call short, so a large state can be
+    /// consumed across event loop turns.
+    #[napi]
+    pub fn next_batch(&mut self, max: i64) -> Result<Vec<serde_json::Value>> {
+        let max = to_usize(max, "max")?;
+        self.inner
+            .by_ref()
+            .take(max)
+            .collect::<crate::error::Result<_>>()
+            .map_err(to_napi_error)
+    }
+}
+
 #[napi]
 impl JsStore {
     /// Get the inner store, returning an error if closed.
@@ -239,11 +339,13 @@ impl JsStore {
             path: config.path.into(),
             blob_cache_size: config.blob_cache_size.map(|s| s as usize).unwrap_or(1000),
             create_if_missing: false,
+            ..Default::default()
         };
         let store = Store::create(store_config).map_err(to_napi_error)?;
         Ok(JsStore {
             inner: Some(Arc::new(store)),
             subscription_handles: Mutex::new(HashMap::new()),
+            subscription_bridges: Mutex::new(HashMap::new()),
         })
     }
 
@@ -254,11 +356,13 @@ impl JsStore {
             path: config.path.into(),
             blob_cache_size: config.blob_cache_size.map(|s| s as usize).unwrap_or(1000),
             create_if_missing: false,
+            ..Default::default()
         };
         let store = Store::open(store_config).map_err(to_napi_error)?;
         Ok(JsStore {
             inner: Some(Arc::new(store)),
             subscription_handles: Mutex::new(HashMap::new()),
+            subscription_bridges: Mutex::new(HashMap::new()),
         })
     }
 
@@ -269,11 +373,13 @@ impl JsStore {
             path: config.path.into(),
             blob_cache_size: config.blob_cache_size.map(|s| s as usize).unwrap_or(1000),
             create_if_missing: true,
+            ..Default::default()
         };
         let store = Store::open_or_create(store_config).map_err(to_napi_error)?;
         Ok(JsStore {
             inner: Some(Arc::new(store)),
             subscription_handles: Mutex::new(HashMap::new()),
+            subscription_bridges: Mutex::new(HashMap::new()),
         })
     }
 
@@ -282,10 +388,16 @@ impl JsStore {
     #[napi]
     pub fn close(&mut self) -> Result<()> {
         if let Some(store) = self.inner.take() {
-            // Sync before closing
-            store.sync().map_err(to_napi_error)?;
-            // Drop the Arc - if this is the last reference, the store will be dropped
-            drop(store);
+            // Stop callback subscriptions so their threads don't outlive the store
+            for (id, bridge) in self.subscription_bridges.lock().drain() {
+                store.unsubscribe(SubscriptionId(id));
+                let _ = bridge.join();
+            }
+            match Arc::try_unwrap(store) {
+                Ok(store) => store.close().map_err(to_napi_error)?,
+                // Still shared: sync now, the last reference drops the store
+                Err(store) => store.sync().map_err(to_napi_error)?,
+            }
         }
         Ok(())
     }
@@ -339,6 +451,50 @@ impl JsStore {
             .collect())
     }
 
+    /// Count records of a given type.
+    #[napi]
+    pub fn count_records_by_type(&self, record_type: String) -> Result<i64> {
+        let store = self.get_store()?;
+        Ok(store.count_records_by_type(&record_type) as i64)
+    }
+
+    /// Validate payloads of a record type against a JSON schema.
+    /// Returns the schema blob's hash as hex.
+    #[napi]
+    pub fn register_record_schema(&self, record_type: String, schema: serde_json::Value) -> Result<String> {
+        let store = self.get_store()?;
+        store
+            .register_record_schema(&record_type, &schema)
+            .map(|hash| hash.to_hex())
+            .map_err(to_napi_error)
+    }
+
+    /// Index records by a top-level payload field.
+    #[napi]
+    pub fn add_field_index(&self, field: String) -> Result<()> {
+        let store = self.get_store()?;
+        store.add_field_index(&field).map_err(to_napi_error)
+    }
+
+    /// Get record IDs whose payload field equals a string or integer value.
+    #[napi]
+    pub fn get_record_ids_by_field(
+        &self,
+        field: String,
+        value: serde_json::Value,
+    ) -> Result<Vec<String>> {
+        let store = self.get_store()?;
+        let value = FieldValue::from_json(&value).ok_or_else(|| {
+            napi::Error::from_reason("Field value must be a string or an integer")
+        })?;
+        Ok(store
+            .get_records_by_field(&field, value)
+            .map_err(to_napi_error)?
+            .into_iter()
+            .map(|id| id.0.to_string())
+            .collect())
+    }
+
     // --- Blobs ---
 
     /// Store a blob and return its hash.
@@ -445,6 +601,14 @@ impl JsStore {
         })
     }
 
+    /// Get the head sequence of a branch.
+    #[napi]
+    pub fn branch_head(&self, name: String) -> Result<i64> {
+        let store = self.get_store()?;
+        let head = store.branch_head(&name).map_err(to_napi_error)?;
+        Ok(head.0 as i64)
+    }
+
     /// List all branches.
     #[napi]
     pub fn list_branches(&self) -> Result<Vec<JsBranch>> {
@@ -482,6 +646,14 @@ impl JsStore {
                 delta_snapshot_every: registration.delta_snapshot_every.unwrap_or(100) as u64,
                 full_snapshot_every: registration.full_snapshot_every.unwrap_or(10) as u64,
             },
+            "ring_buffer" => StateStrategy::RingBuffer {
+                capacity: registration
+                    .capacity
+                    .ok_or_else(|| napi::Error::from_reason("ring_buffer requires a capacity"))?
+                    as usize,
+                delta_snapshot_every: registration.delta_snapshot_every.unwrap_or(100) as u64,
+                full_snapshot_every: registration.full_snapshot_every.unwrap_or(10) as u64,
+            },
             _ => return Err(napi::Error::from_reason("Invalid strategy")),
         };
 
@@ -549,6 +721,27 @@ impl JsStore {
         Ok(record.into())
     }
 
+    /// Append several items to an AppendLog state in one update.
+    #[napi]
+    pub fn append_many_to_state(&self, state_id: String, items: Vec<Buffer>) -> Result<JsRecord> {
+        let store = self.get_store()?;
+        let items = items.into_iter().map(|item| item.to_vec()).collect();
+        let record = store
+            .update_state(&state_id, StateOperation::AppendMany(items))
+            .map_err(to_napi_error)?;
+        Ok(record.into())
+    }
+
+    /// Insert an item at the front of an AppendLog state.
+    #[napi]
+    pub fn prepend_to_state(&self, state_id: String, item: Buffer) -> Result<JsRecord> {
+        let store = self.get_store()?;
+        let record = store
+            .update_state(&state_id, StateOperation::Prepend(item.to_vec()))
+            .map_err(to_napi_error)?;
+        Ok(record.into())
+    }
+
     /// Append JSON to an AppendLog state.
     #[napi]
     pub fn append_to_state_json(
@@ -607,7 +800,17 @@ impl JsStore {
         Ok(record.into())
     }
 
-    /// Get the length of an AppendLog state.
+    /// Empty an AppendLog state.
+    #[napi]
+    pub fn clear_state(&self, state_id: String) -> Result<JsRecord> {
+        let store = self.get_store()?;
+        let record = store
+            .update_state(&state_id, StateOperation::Clear)
+            .map_err(to_napi_error)?;
+        Ok(record.into())
+    }
+
+    /// Get the number of items in an array state.
     #[napi]
     pub fn get_state_len(&self, state_id: String) -> Result<Option<i64>> {
         let store = self.get_store()?;
@@ -627,21 +830,58 @@ impl JsStore {
     ) -> Result<Option<Buffer>> {
         let store = self.get_store()?;
         let slice = store
-            .get_state_slice(&state_id, offset as usize, limit as usize)
+            .get_state_slice(&state_id, to_usize(offset, "offset")?, to_usize(limit, "limit")?)
             .map_err(to_napi_error)?;
         Ok(slice.map(Buffer::from))
     }
 
+    /// Get a slice of an AppendLog state as an array of JSON values.
+    #[napi]
+    pub fn get_state_slice_json(
+        &self,
+        state_id: String,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Option<Vec<serde_json::Value>>> {
+        let store = self.get_store()?;
+        store
+            .get_state_slice_as(&state_id, to_usize(offset, "offset")?, to_usize(limit, "limit")?)
+            .map_err(to_napi_error)
+    }
+
     /// Get the last N items from an AppendLog state.
     #[napi]
     pub fn get_state_tail(&self, state_id: String, count: i64) -> Result<Option<Buffer>> {
         let store = self.get_store()?;
         let tail = store
-            .get_state_tail(&state_id, count as usize)
+            .get_state_tail(&state_id, to_usize(count, "count")?)
             .map_err(to_napi_error)?;
         Ok(tail.map(Buffer::from))
     }
 
+    /// Get the last N items from an AppendLog state as an array of JSON values.
+    #[napi]
+    pub fn get_state_tail_json(
+        &self,
+        state_id: String,
+        count: i64,
+    ) -> Result<Option<Vec<serde_json::Value>>> {
+        let store = self.get_store()?;
+        store
+            .get_state_tail_as(&state_id, to_usize(count, "count")?)
+            .map_err(to_napi_error)
+    }
+
+    /// Iterate over the items of an AppendLog state on the current branch.
+    ///
+    /// Returns null if the state has no history on this branch.
+    #[napi]
+    pub fn iter_state_items(&self, state_id: String) -> Result<Option<JsStateIterator>> {
+        let store = self.get_store()?;
+        let iter = store.iter_state_items(&state_id).map_err(to_napi_error)?;
+        Ok(iter.map(|inner| JsStateIterator { inner }))
+    }
+
     // --- Compaction ---
 
     /// Compact a state by creating a full snapshot.
@@ -662,6 +902,25 @@ impl JsStore {
         Ok(count as i64)
     }
 
+    /// Discard state history before a fresh full snapshot on the current
+    /// branch. Returns the number of update records pruned.
+    #[napi]
+    pub fn prune_history(&self, state_ids: Vec<String>) -> Result<i64> {
+        let store = self.get_store()?;
+        let ids: Vec<&str> = state_ids.iter().map(String::as_str).collect();
+        let report = store.prune_history(&ids).map_err(to_napi_error)?;
+        Ok(report.records_pruned as i64)
+    }
+
+    /// Remove deleted branches' records from the log. Returns the number
+    /// of records removed.
+    #[napi]
+    pub fn compact_log_by_branch(&self) -> Result<i64> {
+        let store = self.get_store()?;
+        let stats = store.compact_log_by_branch().map_err(to_napi_error)?;
+        Ok(stats.records_removed as i64)
+    }
+
     /// Get compaction summary.
     #[napi]
     pub fn get_compaction_summary(&self) -> Result<JsCompactionSummary> {
@@ -674,6 +933,13 @@ impl JsStore {
 
     // --- Stats ---
 
+    /// Get the number of indexed records without computing full stats.
+    #[napi]
+    pub fn record_count(&self) -> Result<i64> {
+        let store = self.get_store()?;
+        Ok(store.record_count() as i64)
+    }
+
     /// Get store statistics.
     #[napi]
     pub fn stats(&self) -> Result<JsStoreStats> {
@@ -686,6 +952,7 @@ impl JsStore {
             state_slot_count: stats.state_slot_count as i64,
             total_size_bytes: stats.total_size_bytes as i64,
             blob_size_bytes: stats.blob_size_bytes as i64,
+            index_size_bytes: stats.index_size_bytes as i64,
         })
     }
 
@@ -784,7 +1051,7 @@ impl JsStore {
                 to_seq,
                 fetch_limit,
                 reverse,
-                types.as_ref().map(|v| v.as_slice()),
+                types.as_deref(),
             )
             .map_err(to_napi_error)?;
 
@@ -815,6 +1082,7 @@ impl JsStore {
                     .map(|s| match s {
                         StateStrategy::Snapshot => "snapshot".to_string(),
                         StateStrategy::AppendLog { .. } => "append_log".to_string(),
+                        StateStrategy::RingBuffer { .. } => "ring_buffer".to_string(),
                         StateStrategy::Delta { .. } => "delta".to_string(),
                         StateStrategy::Struct { .. } => "struct".to_string(),
                     })
@@ -853,6 +1121,16 @@ impl JsStore {
         Ok(state.map(Buffer::from))
     }
 
+    /// Roll a state back to its value at a sequence by appending a snapshot.
+    #[napi]
+    pub fn rollback_state_to(&self, state_id: String, sequence: i64) -> Result<JsRecord> {
+        let store = self.get_store()?;
+        let record = store
+            .rollback_state_to(&state_id, Sequence(sequence as u64))
+            .map_err(to_napi_error)?;
+        Ok(record.into())
+    }
+
     /// Get state as JSON at a specific sequence (historical access).
     #[napi]
     pub fn get_state_json_at(
@@ -881,7 +1159,7 @@ impl JsStore {
         let id: u64 = record_id
             .parse()
             .map_err(|_| napi::Error::from_reason("Invalid record ID"))?;
-        let effects = store.index.get_caused_by(RecordId(id));
+        let effects = store.get_effects(RecordId(id));
         Ok(effects.iter().map(|id| id.0.to_string()).collect())
     }
 
@@ -892,7 +1170,7 @@ impl JsStore {
         let id: u64 = record_id
             .parse()
             .map_err(|_| napi::Error::from_reason("Invalid record ID"))?;
-        let links = store.index.get_linked_to(RecordId(id));
+        let links = store.get_links_to(RecordId(id));
         Ok(links.iter().map(|id| id.0.to_string()).collect())
     }
 
@@ -913,32 +1191,7 @@ impl JsStore {
     pub fn subscribe(&self, config: Option<JsSubscriptionConfig>) -> Result<String> {
         let store = self.get_store()?;
 
-        // Convert JS config to Rust config
-        let rust_config = match config {
-            Some(cfg) => {
-                let filter = cfg.filter.map(|f| SubscriptionFilter {
-                    record_types: f.record_types,
-                    branch: f.branch,
-                    state_ids: f.state_ids,
-                    include_records: f.include_records.unwrap_or(false),
-                    include_state_changes: f.include_state_changes.unwrap_or(false),
-                    include_branch_events: f.include_branch_events.unwrap_or(false),
-                });
-
-                SubscriptionConfig {
-                    buffer_size: cfg.buffer_size.map(|s| s as usize).unwrap_or(1000),
-                    max_snapshot_bytes: cfg
-                        .max_snapshot_bytes
-                        .map(|s| s as usize)
-                        .unwrap_or(10 * 1024 * 1024),
-                    from_sequence: cfg.from_sequence.map(|s| Sequence(s as u64)),
-                    filter: filter.unwrap_or_default(),
-                }
-            }
-            None => SubscriptionConfig::default(),
-        };
-
-        let handle = store.subscribe(rust_config);
+        let handle = store.subscribe(subscription_config(config));
         let id = handle.id.0;
 
         // Store the handle for later polling
@@ -947,6 +1200,31 @@ impl JsStore {
         Ok(id.to_string())
     }
 
+    /// Subscribe to store events, calling `callback` with each one.
+    ///
+    /// Events are delivered from a background thread to the JS thread, so
+    /// no polling is needed. After a "dropped" event (including the one
+    /// `unsubscribe` sends) the callback isn't called again.
+    #[napi(ts_args_type = "config: JsSubscriptionConfig | undefined | null, callback: (event: JsStoreEvent) => void")]
+    pub fn subscribe_with_callback(
+        &self,
+        config: Option<JsSubscriptionConfig>,
+        callback: ThreadsafeFunction<JsStoreEvent, ErrorStrategy::Fatal>,
+    ) -> Result<String> {
+        let store = self.get_store()?;
+
+        let handle = store.subscribe(subscription_config(config));
+        let id = handle.id.0;
+        let bridge = handle
+            .forward(move |event| {
+                callback.call(event.into(), ThreadsafeFunctionCallMode::NonBlocking);
+            })
+            .map_err(|e| napi::Error::from_reason(e.to_string()))?;
+        self.subscription_bridges.lock().insert(id, bridge);
+
+        Ok(id.to_string())
+    }
+
     /// Unsubscribe and clean up a subscription.
     #[napi]
     pub fn unsubscribe(&self, subscription_id: String) -> Result<()> {
@@ -960,9 +1238,34 @@ impl JsStore {
 
         // Unsubscribe from store
         store.unsubscribe(SubscriptionId(id));
+
+        // A callback subscription's thread ends once it has delivered the drop
+        if let Some(bridge) = self.subscription_bridges.lock().remove(&id) {
+            bridge
+                .join()
+                .map_err(|_| napi::Error::from_reason("Subscription callback thread panicked"))?;
+        }
         Ok(())
     }
 
+    /// Replace a subscription's filter. Only affects events from now on.
+    #[napi]
+    pub fn update_subscription_filter(
+        &self,
+        subscription_id: String,
+        filter: JsSubscriptionFilter,
+    ) -> Result<()> {
+        let id: u64 = subscription_id
+            .parse()
+            .map_err(|_| napi::Error::from_reason("Invalid subscription ID"))?;
+
+        let handles = self.subscription_handles.lock();
+        let handle = handles
+            .get(&id)
+            .ok_or_else(|| napi::Error::from_reason("Subscription not found"))?;
+        handle.update_filter(filter.into()).map_err(to_napi_error)
+    }
+
     /// Perform catch-up for a subscription (replay historical data).
     ///
     /// This replays state snapshots and historical records based on the
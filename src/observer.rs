@@ -0,0 +1,54 @@
+//! Metrics hooks for store operations.
+//!
+//! A `StoreObserver` set on `StoreConfig::observer` is called synchronously
+//! as operations complete, so callers can feed counters and timings into
+//! whatever metrics library they use. Every method has a no-op default;
+//! implement only the ones you need. Callbacks run on the calling thread,
+//! often while the store's write lock is held, so they should be cheap.
+
+use crate::state::SnapshotNeeded;
+use crate::subscriptions::{DropReason, SubscriptionId};
+use crate::types::{Hash, Record, RecordId};
+use std::fmt;
+use std::time::Duration;
+
+/// Receives callbacks as the store does work.
+pub trait StoreObserver: Send + Sync {
+    /// A record was appended. `elapsed` covers the log write and index update.
+    fn on_append(&self, _record: &Record, _elapsed: Duration) {}
+
+    /// A record was looked up by ID.
+    fn on_record_read(&self, _id: RecordId, _found: bool) {}
+
+    /// `operations` state updates were written to `state_id`, either by
+    /// `update_state` (one) or `update_state_batch` (the batch size).
+    fn on_state_update(&self, _state_id: &str, _operations: usize, _elapsed: Duration) {}
+
+    /// A snapshot of `size` bytes was written for `state_id`, automatically
+    /// or by compaction.
+    fn on_snapshot(&self, _state_id: &str, _kind: SnapshotNeeded, _size: usize) {}
+
+    /// A blob of `size` bytes was stored (or found already stored).
+    fn on_blob_store(&self, _hash: &Hash, _size: usize) {}
+
+    /// A blob was read; `cache_hit` is false when it came from disk.
+    fn on_blob_read(&self, _hash: &Hash, _cache_hit: bool) {}
+
+    /// Historical catch-up for a subscription finished.
+    fn on_catch_up(&self, _id: SubscriptionId, _elapsed: Duration) {}
+
+    /// A subscription was removed, by overflow or by `unsubscribe`.
+    fn on_subscription_drop(&self, _id: SubscriptionId, _reason: &DropReason) {}
+}
+
+impl fmt::Debug for dyn StoreObserver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("StoreObserver")
+    }
+}
+
+/// Observer that ignores every callback. Used when none is configured.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoopObserver;
+
+impl StoreObserver for NoopObserver {}
@@ -6,7 +6,7 @@
 
 use crate::error::Result;
 use crate::records::RecordLog;
-use crate::types::{BranchId, RecordId, Sequence};
+use crate::types::{BranchId, FieldValue, Record, RecordId, Sequence, Timestamp};
 use parking_lot::RwLock;
 use std::collections::{BTreeMap, HashMap};
 use std::path::{Path, PathBuf};
@@ -31,6 +31,15 @@ pub struct RecordIndex {
 
     /// linked_to index: record_id -> records that have it in linked_to.
     linked_to_index: RwLock<HashMap<RecordId, Vec<RecordId>>>,
+
+    /// Expiry times for records with a TTL.
+    expiry: RwLock<HashMap<RecordId, Timestamp>>,
+
+    /// Registered payload fields: field -> value -> record IDs.
+    field_index: RwLock<HashMap<String, HashMap<FieldValue, Vec<RecordId>>>>,
+
+    /// Idempotency key to the record first appended with it.
+    idempotency_keys: RwLock<HashMap<String, RecordId>>,
 }
 
 impl RecordIndex {
@@ -45,6 +54,9 @@ impl RecordIndex {
             type_index: RwLock::new(HashMap::new()),
             caused_by_index: RwLock::new(HashMap::new()),
             linked_to_index: RwLock::new(HashMap::new()),
+            expiry: RwLock::new(HashMap::new()),
+            field_index: RwLock::new(HashMap::new()),
+            idempotency_keys: RwLock::new(HashMap::new()),
         })
     }
 
@@ -52,15 +64,40 @@ impl RecordIndex {
     ///
     /// This scans the entire log sequentially and builds all indexes.
     /// For a store with 1M records, this typically takes 1-3 seconds on SSD.
+    /// Records whose TTL has already passed are left out.
     pub fn rebuild_from_log(path: impl AsRef<Path>, log: &RecordLog) -> Result<Self> {
+        Self::rebuild_from_log_with_fields(path, log, &[])
+    }
+
+    /// Like `rebuild_from_log`, also indexing the given payload fields.
+    pub fn rebuild_from_log_with_fields(
+        path: impl AsRef<Path>,
+        log: &RecordLog,
+        fields: &[String],
+    ) -> Result<Self> {
         let index = Self::new(path)?;
+        for field in fields {
+            index.add_field(field);
+        }
+        index.index_log(log)?;
+        Ok(index)
+    }
+
+    /// Index every record in `log`, skipping expired ones.
+    ///
+    /// Used on an empty (or just cleared) index.
+    pub(crate) fn index_log(&self, log: &RecordLog) -> Result<()> {
+        let now = Timestamp::now();
 
         // Iterate through all records in the log
         for result in log.iter() {
             let (offset, record) = result?;
+            if record.is_expired(now) {
+                continue;
+            }
 
             // Add to all indexes
-            index.add(
+            self.add(
                 record.id,
                 record.branch,
                 record.sequence,
@@ -69,9 +106,20 @@ impl RecordIndex {
                 &record.caused_by,
                 &record.linked_to,
             );
+            if let Some(expires_at) = record.expires_at {
+                self.set_expiry(record.id, expires_at);
+            }
+            if let Some(key) = &record.idempotency_key {
+                self.set_idempotency_key(key, record.id);
+            }
+            self.index_fields(&record);
+
+            if record.squash {
+                self.apply_squash(log, &record)?;
+            }
         }
 
-        Ok(index)
+        Ok(())
     }
 
     /// Load index - for backwards compatibility, just creates empty index.
@@ -82,6 +130,7 @@ impl RecordIndex {
     }
 
     /// Add an entry to the index.
+    #[allow(clippy::too_many_arguments)]
     pub fn add(
         &self,
         id: RecordId,
@@ -118,6 +167,239 @@ impl RecordIndex {
         }
     }
 
+    /// Start indexing a payload field. Returns false if it already was.
+    ///
+    /// Only records passed to `index_fields` afterwards are indexed under it.
+    pub fn add_field(&self, field: &str) -> bool {
+        let mut field_index = self.field_index.write();
+        if field_index.contains_key(field) {
+            return false;
+        }
+        field_index.insert(field.to_string(), HashMap::new());
+        true
+    }
+
+    /// Whether a payload field is indexed.
+    pub fn has_field(&self, field: &str) -> bool {
+        self.field_index.read().contains_key(field)
+    }
+
+    /// Whether any payload field is indexed.
+    pub fn has_fields(&self) -> bool {
+        !self.field_index.read().is_empty()
+    }
+
+    /// Indexed payload fields, sorted.
+    pub fn fields(&self) -> Vec<String> {
+        let mut fields: Vec<_> = self.field_index.read().keys().cloned().collect();
+        fields.sort();
+        fields
+    }
+
+    /// Index a record under each registered field its payload has.
+    ///
+    /// Only top-level string and integer values are indexed. Payloads that
+    /// aren't JSON or MessagePack objects are skipped.
+    pub fn index_fields(&self, record: &Record) {
+        let mut field_index = self.field_index.write();
+        if field_index.is_empty() {
+            return;
+        }
+        let fields: Vec<String> = field_index.keys().cloned().collect();
+        for (field, value) in Self::field_values(&fields, record) {
+            if let Some(values) = field_index.get_mut(&field) {
+                values.entry(value).or_default().push(record.id);
+            }
+        }
+    }
+
+    /// Index a record under one registered field, for backfilling it.
+    pub fn index_field(&self, field: &str, record: &Record) {
+        let fields = [field.to_string()];
+        for (field, value) in Self::field_values(&fields, record) {
+            if let Some(values) = self.field_index.write().get_mut(&field) {
+                values.entry(value).or_default().push(record.id);
+            }
+        }
+    }
+
+    /// Get records whose payload has `field` set to `value`.
+    pub fn get_by_field(&self, field: &str, value: &FieldValue) -> Vec<RecordId> {
+        self.field_index
+            .read()
+            .get(field)
+            .and_then(|values| values.get(value))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn unindex_fields(&self, record: &Record) {
+        let mut field_index = self.field_index.write();
+        if field_index.is_empty() {
+            return;
+        }
+        let fields: Vec<String> = field_index.keys().cloned().collect();
+        for (field, value) in Self::field_values(&fields, record) {
+            if let Some(values) = field_index.get_mut(&field) {
+                if let Some(ids) = values.get_mut(&value) {
+                    ids.retain(|&other| other != record.id);
+                    if ids.is_empty() {
+                        values.remove(&value);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Values of `fields` in a record's payload.
+    fn field_values(fields: &[String], record: &Record) -> Vec<(String, FieldValue)> {
+        let payload = match record.decode::<serde_json::Value>() {
+            Ok(serde_json::Value::Object(payload)) => payload,
+            _ => return Vec::new(),
+        };
+        fields
+            .iter()
+            .filter_map(|field| {
+                let value = FieldValue::from_json(payload.get(field)?)?;
+                Some((field.clone(), value))
+            })
+            .collect()
+    }
+
+    /// Record when a record expires.
+    pub fn set_expiry(&self, id: RecordId, expires_at: Timestamp) {
+        self.expiry.write().insert(id, expires_at);
+    }
+
+    /// Record the key a record was appended under. The first record with
+    /// a key keeps it.
+    pub fn set_idempotency_key(&self, key: &str, id: RecordId) {
+        self.idempotency_keys.write().entry(key.to_string()).or_insert(id);
+    }
+
+    /// Get the record appended under an idempotency key.
+    pub fn get_by_idempotency_key(&self, key: &str) -> Option<RecordId> {
+        self.idempotency_keys.read().get(key).copied()
+    }
+
+    /// Whether a record's TTL has passed at `now`.
+    pub fn is_expired(&self, id: RecordId, now: Timestamp) -> bool {
+        self.expiry
+            .read()
+            .get(&id)
+            .is_some_and(|&expires_at| expires_at <= now)
+    }
+
+    /// Get all records whose TTL has passed at `now`.
+    pub fn expired(&self, now: Timestamp) -> Vec<RecordId> {
+        self.expiry
+            .read()
+            .iter()
+            .filter(|(_, &expires_at)| expires_at <= now)
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    /// Remove a record from every index.
+    ///
+    /// Entries pointing at the record from other records' causation lists
+    /// are left alone; lookups of the removed ID simply miss.
+    pub fn remove(&self, record: &Record) {
+        let offset = self.id_to_offset.write().remove(&record.id);
+
+        let mut entries = self.entries.write();
+        let key = (record.branch, record.sequence);
+        if offset.is_some() && entries.get(&key) == offset.as_ref() {
+            entries.remove(&key);
+        }
+        drop(entries);
+
+        let mut type_index = self.type_index.write();
+        if let Some(ids) = type_index.get_mut(&record.record_type) {
+            ids.retain(|&other| other != record.id);
+            if ids.is_empty() {
+                type_index.remove(&record.record_type);
+            }
+        }
+        drop(type_index);
+        self.unindex_fields(record);
+
+        for (index, targets) in [
+            (&self.caused_by_index, &record.caused_by),
+            (&self.linked_to_index, &record.linked_to),
+        ] {
+            let mut index = index.write();
+            for target in targets {
+                if let Some(ids) = index.get_mut(target) {
+                    ids.retain(|&other| other != record.id);
+                    if ids.is_empty() {
+                        index.remove(target);
+                    }
+                }
+            }
+        }
+
+        self.expiry.write().remove(&record.id);
+
+        if let Some(key) = &record.idempotency_key {
+            let mut keys = self.idempotency_keys.write();
+            if keys.get(key) == Some(&record.id) {
+                keys.remove(key);
+            }
+        }
+    }
+
+    /// Hide the records replaced by a squash summary.
+    ///
+    /// They are dropped from sequence and type lookups but stay reachable by
+    /// ID (and through causation links), since they remain in the log.
+    pub fn apply_squash(&self, log: &RecordLog, summary: &Record) -> Result<()> {
+        for &id in &summary.linked_to {
+            let offset = match self.get_offset_by_id(id) {
+                Some(offset) => offset,
+                None => continue,
+            };
+            let record = log.read_at(offset)?;
+
+            let mut entries = self.entries.write();
+            if entries.get(&(record.branch, record.sequence)) == Some(&offset) {
+                entries.remove(&(record.branch, record.sequence));
+            }
+            drop(entries);
+
+            let mut type_index = self.type_index.write();
+            if let Some(ids) = type_index.get_mut(&record.record_type) {
+                ids.retain(|&other| other != id);
+                if ids.is_empty() {
+                    type_index.remove(&record.record_type);
+                }
+            }
+            drop(type_index);
+            self.unindex_fields(&record);
+        }
+        Ok(())
+    }
+
+    /// Remove all entries.
+    pub fn clear(&self) {
+        self.entries.write().clear();
+        self.id_to_offset.write().clear();
+        self.type_index.write().clear();
+        self.caused_by_index.write().clear();
+        self.linked_to_index.write().clear();
+        self.expiry.write().clear();
+        self.idempotency_keys.write().clear();
+        // Fields stay registered; only their entries go
+        for values in self.field_index.write().values_mut() {
+            values.clear();
+        }
+    }
+
+    /// Get the path the index was created with.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     /// Get offset for a sequence on a branch.
     pub fn get_offset(&self, branch: BranchId, sequence: Sequence) -> Option<u64> {
         self.entries.read().get(&(branch, sequence)).copied()
@@ -128,6 +410,16 @@ impl RecordIndex {
         self.id_to_offset.read().get(&id).copied()
     }
 
+    /// Get every (branch, sequence) -> offset entry, in order.
+    pub fn sequence_offsets(&self) -> Vec<((BranchId, Sequence), u64)> {
+        self.entries.read().iter().map(|(&key, &offset)| (key, offset)).collect()
+    }
+
+    /// Get every record ID -> offset entry.
+    pub fn id_offsets(&self) -> Vec<(RecordId, u64)> {
+        self.id_to_offset.read().iter().map(|(&id, &offset)| (id, offset)).collect()
+    }
+
     /// Get all record IDs of a given type.
     pub fn get_by_type(&self, record_type: &str) -> Vec<RecordId> {
         self.type_index
@@ -137,6 +429,35 @@ impl RecordIndex {
             .unwrap_or_default()
     }
 
+    /// Count records of a given type that are not expired at `now`.
+    pub fn count_by_type(&self, record_type: &str, now: Timestamp) -> usize {
+        let type_index = self.type_index.read();
+        let Some(ids) = type_index.get(record_type) else {
+            return 0;
+        };
+        let expiry = self.expiry.read();
+        if expiry.is_empty() {
+            return ids.len();
+        }
+        ids.iter()
+            .filter(|id| expiry.get(id).is_none_or(|&expires_at| expires_at > now))
+            .count()
+    }
+
+    /// Get every record type with its count of records not expired at `now`,
+    /// sorted by type name. Types with no live records are omitted.
+    pub fn type_counts(&self, now: Timestamp) -> Vec<(String, usize)> {
+        let mut counts: Vec<_> = self
+            .type_index
+            .read()
+            .keys()
+            .map(|record_type| (record_type.clone(), self.count_by_type(record_type, now)))
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        counts.sort();
+        counts
+    }
+
     /// Get records that have `id` in their caused_by.
     pub fn get_caused_by(&self, id: RecordId) -> Vec<RecordId> {
         self.caused_by_index
@@ -229,6 +550,39 @@ impl RecordIndex {
         self.id_to_offset.read().len()
     }
 
+    /// Estimate the in-memory footprint of the index in bytes.
+    ///
+    /// Counts keys and values only; allocator and map overhead is ignored.
+    pub fn memory_size(&self) -> u64 {
+        let id_size = std::mem::size_of::<RecordId>();
+        let offset_size = std::mem::size_of::<u64>();
+        let entry_size = std::mem::size_of::<(BranchId, Sequence)>() + offset_size;
+
+        let mut total = self.entries.read().len() * entry_size;
+        total += self.id_to_offset.read().len() * (id_size + offset_size);
+        total += self
+            .type_index
+            .read()
+            .iter()
+            .map(|(record_type, ids)| record_type.len() + ids.len() * id_size)
+            .sum::<usize>();
+        for causation in [&self.caused_by_index, &self.linked_to_index] {
+            total += causation
+                .read()
+                .values()
+                .map(|ids| id_size + ids.len() * id_size)
+                .sum::<usize>();
+        }
+        total += self
+            .idempotency_keys
+            .read()
+            .keys()
+            .map(|key| key.len() + id_size)
+            .sum::<usize>();
+
+        total as u64
+    }
+
     /// Save index to file - NO-OP.
     ///
     /// Index is no longer persisted; it's rebuilt from the log on startup.
@@ -364,6 +718,7 @@ mod tests {
         let index = RecordIndex::rebuild_from_log(&index_path, &log).unwrap();
 
         assert_eq!(index.count(), 0);
+        assert_eq!(index.memory_size(), 0);
     }
 
     #[test]
@@ -1,11 +1,15 @@
 //! Append-only record log.
 
 use crate::error::{Result, StoreError};
-use crate::types::{BranchId, PayloadEncoding, Record, RecordId, RecordInput, Sequence, Timestamp};
+use crate::types::{
+    BranchId, Hash, HashAlgorithm, PayloadEncoding, Record, RecordId, RecordInput, Sequence, Timestamp,
+};
 use parking_lot::RwLock;
-use std::fs::{File, OpenOptions};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
 /// Magic bytes for record log.
 const LOG_MAGIC: &[u8; 4] = b"REC\0";
@@ -13,8 +17,147 @@ const LOG_MAGIC: &[u8; 4] = b"REC\0";
 /// Current log format version.
 const LOG_VERSION: u8 = 1;
 
-/// Record header size (fixed part).
-const RECORD_HEADER_SIZE: usize = 4 + 1 + 1 + 8 + 8 + 8 + 8; // magic + version + flags + id + seq + branch + timestamp
+/// Record flag: squash summary (see `Record::squash`).
+const FLAG_SQUASH: u8 = 0x01;
+
+/// Record flag: an expiry timestamp follows the creation timestamp.
+const FLAG_EXPIRES: u8 = 0x02;
+
+/// Record flag: a Lamport timestamp follows the (optional) expiry.
+const FLAG_LAMPORT: u8 = 0x04;
+
+/// Record flag: a length-prefixed idempotency key follows the Lamport timestamp.
+const FLAG_IDEMPOTENCY_KEY: u8 = 0x08;
+
+/// Record flag: a schema hash (algorithm byte, then digest) follows the
+/// idempotency key.
+const FLAG_SCHEMA: u8 = 0x10;
+
+/// When the record log fsyncs appended records.
+///
+/// Records that were written but not yet synced live only in the OS page
+/// cache and are lost if the machine crashes (a process crash alone does not
+/// lose them). Syncing more often shrinks that window at the cost of one
+/// fsync per sync point, which dominates append latency on most disks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Sync after every append. Nothing acknowledged is ever lost.
+    EveryWrite,
+    /// Sync after every N appends. Up to N - 1 records can be lost.
+    EveryN(u64),
+    /// Sync at least once per interval. Bounds the loss window by time
+    /// rather than record count. A `Store` runs a background thread that
+    /// also saves its metadata each interval, so idle stretches are covered;
+    /// a bare `RecordLog` syncs on the first append after the interval.
+    Interval(Duration),
+}
+
+impl Default for SyncPolicy {
+    fn default() -> Self {
+        SyncPolicy::EveryN(100)
+    }
+}
+
+/// Check that variable-length fields fit their on-disk length prefixes.
+fn validate_lengths(fields: &RecordFields<'_>) -> Result<()> {
+    let max = u16::MAX as usize;
+    if fields.record_type.len() > max {
+        return Err(StoreError::InvalidOperation(format!(
+            "Record type is {} bytes, maximum is {}",
+            fields.record_type.len(),
+            max
+        )));
+    }
+    if fields.caused_by.len() > max {
+        return Err(StoreError::InvalidOperation(format!(
+            "Record has {} caused_by entries, maximum is {}",
+            fields.caused_by.len(),
+            max
+        )));
+    }
+    if fields.idempotency_key.is_some_and(|key| key.len() > max) {
+        return Err(StoreError::InvalidOperation(format!(
+            "Idempotency key is longer than {} bytes",
+            max
+        )));
+    }
+    if fields.linked_to.len() > max {
+        return Err(StoreError::InvalidOperation(format!(
+            "Record has {} linked_to entries, maximum is {}",
+            fields.linked_to.len(),
+            max
+        )));
+    }
+    if fields.payload.len() > u32::MAX as usize {
+        return Err(StoreError::InvalidOperation(format!(
+            "Record payload is {} bytes, maximum is {}",
+            fields.payload.len(),
+            u32::MAX
+        )));
+    }
+    Ok(())
+}
+
+/// A record's fields as written to the log, borrowed from wherever they
+/// live, so a record can be written without building a `Record`.
+pub(crate) struct RecordFields<'a> {
+    pub id: RecordId,
+    pub sequence: Sequence,
+    pub branch: BranchId,
+    pub timestamp: Timestamp,
+    pub record_type: &'a str,
+    pub payload: &'a [u8],
+    pub encoding: PayloadEncoding,
+    pub caused_by: &'a [RecordId],
+    pub linked_to: &'a [RecordId],
+    pub squash: bool,
+    pub expires_at: Option<Timestamp>,
+    pub lamport: Option<u64>,
+    pub idempotency_key: Option<&'a str>,
+    pub schema: Option<Hash>,
+}
+
+impl<'a> RecordFields<'a> {
+    fn of(record: &'a Record) -> Self {
+        Self {
+            id: record.id,
+            sequence: record.sequence,
+            branch: record.branch,
+            timestamp: record.timestamp,
+            record_type: &record.record_type,
+            payload: &record.payload,
+            encoding: record.encoding,
+            caused_by: &record.caused_by,
+            linked_to: &record.linked_to,
+            squash: record.squash,
+            expires_at: record.expires_at,
+            lamport: record.lamport,
+            idempotency_key: record.idempotency_key.as_deref(),
+            schema: record.schema,
+        }
+    }
+
+    /// The fields of a record for `input`, to be appended at `sequence` on
+    /// `branch`. The ID and timestamp are assigned by `append_fields`.
+    fn from_input(input: &'a RecordInput, branch: BranchId, sequence: Sequence, squash: bool) -> Self {
+        Self {
+            id: RecordId(0), // Will be assigned
+            sequence,
+            branch,
+            timestamp: Timestamp(0),
+            record_type: &input.record_type,
+            payload: &input.payload,
+            encoding: input.encoding,
+            caused_by: &input.caused_by,
+            linked_to: &input.linked_to,
+            squash,
+            expires_at: input.expires_at,
+            lamport: input.lamport,
+            idempotency_key: input.idempotency_key.as_deref(),
+            schema: input.schema,
+        }
+    }
+}
 
 /// Append-only record log.
 pub struct RecordLog {
@@ -27,23 +170,29 @@ pub struct RecordLog {
     /// Next record ID to assign.
     next_id: RwLock<u64>,
 
+    /// Highest Lamport timestamp written so far (the log's clock).
+    max_lamport: RwLock<u64>,
+
     /// Current file size (for appending).
     file_size: RwLock<u64>,
 
     /// Number of writes since last sync.
     writes_since_sync: RwLock<u64>,
 
-    /// Sync every N writes (0 = sync every write, critical for durability vs performance)
-    sync_interval: u64,
+    /// File size as of the last sync.
+    synced_size: RwLock<u64>,
+
+    /// Time of the last sync.
+    last_sync: RwLock<Instant>,
+
+    /// When appends are synced (critical for durability vs performance).
+    sync_policy: SyncPolicy,
 }
 
 impl RecordLog {
-    /// Default sync interval - sync every 100 writes for balance of durability and performance.
-    const DEFAULT_SYNC_INTERVAL: u64 = 100;
-
-    /// Open or create a record log with default sync interval.
+    /// Open or create a record log with the default sync policy.
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
-        Self::open_with_sync_interval(path, Self::DEFAULT_SYNC_INTERVAL)
+        Self::open_with_sync_policy(path, SyncPolicy::default())
     }
 
     /// Open or create a record log with custom sync interval.
@@ -52,31 +201,70 @@ impl RecordLog {
     /// - sync_interval = 100: sync every 100 writes (good balance)
     /// - sync_interval = 1000: sync every 1000 writes (fastest, least durable)
     pub fn open_with_sync_interval(path: impl AsRef<Path>, sync_interval: u64) -> Result<Self> {
+        Self::open_with_sync_policy(path, SyncPolicy::EveryN(sync_interval.max(1)))
+    }
+
+    /// Open or create a record log with the given sync policy.
+    pub fn open_with_sync_policy(path: impl AsRef<Path>, sync_policy: SyncPolicy) -> Result<Self> {
         let path = path.as_ref().to_path_buf();
 
         let file = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
+            .truncate(false)
             .open(&path)?;
 
         let metadata = file.metadata()?;
         let file_size = metadata.len();
 
-        // Determine next ID by scanning if file exists
-        let next_id = if file_size > 0 {
-            Self::find_max_id(&file)? + 1
+        // Determine next ID and the clock by scanning if file exists
+        let (max_id, max_lamport) = if file_size > 0 {
+            Self::find_max_id(&file)?
+        } else {
+            (0, 0)
+        };
+
+        Ok(Self {
+            path,
+            file: RwLock::new(file),
+            next_id: RwLock::new(max_id + 1),
+            max_lamport: RwLock::new(max_lamport),
+            file_size: RwLock::new(file_size),
+            writes_since_sync: RwLock::new(0),
+            synced_size: RwLock::new(file_size),
+            last_sync: RwLock::new(Instant::now()),
+            sync_policy,
+        })
+    }
+
+    /// Open an existing record log for reading only.
+    ///
+    /// The file is opened without write access; `append` will fail with an
+    /// IO error. Records written by another process become visible once
+    /// they reach the file, but the end offset is fixed at open time.
+    pub fn open_read_only(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+
+        let file = OpenOptions::new().read(true).open(&path)?;
+        let file_size = file.metadata()?.len();
+
+        let (max_id, max_lamport) = if file_size > 0 {
+            Self::find_max_id(&file)?
         } else {
-            1
+            (0, 0)
         };
 
         Ok(Self {
             path,
             file: RwLock::new(file),
-            next_id: RwLock::new(next_id),
+            next_id: RwLock::new(max_id + 1),
+            max_lamport: RwLock::new(max_lamport),
             file_size: RwLock::new(file_size),
             writes_since_sync: RwLock::new(0),
-            sync_interval: if sync_interval == 0 { 1 } else { sync_interval },
+            synced_size: RwLock::new(file_size),
+            last_sync: RwLock::new(Instant::now()),
+            sync_policy: SyncPolicy::default(),
         })
     }
 
@@ -89,13 +277,29 @@ impl RecordLog {
         branch: BranchId,
         sequence: Sequence,
     ) -> Result<(Record, u64)> {
-        let mut file = self.file.write();
+        self.append_record(input, branch, sequence, false)
+    }
 
-        // Assign ID
-        let id = RecordId(*self.next_id.read());
-        *self.next_id.write() += 1;
+    /// Append a squash summary record (see `Store::squash_records`).
+    pub fn append_squash(
+        &self,
+        input: RecordInput,
+        branch: BranchId,
+        sequence: Sequence,
+    ) -> Result<(Record, u64)> {
+        self.append_record(input, branch, sequence, true)
+    }
 
-        let timestamp = Timestamp::now();
+    fn append_record(
+        &self,
+        input: RecordInput,
+        branch: BranchId,
+        sequence: Sequence,
+        squash: bool,
+    ) -> Result<(Record, u64)> {
+        let mut fields = RecordFields::from_input(&input, branch, sequence, squash);
+        let offset = self.append_fields(&mut fields)?;
+        let (id, timestamp, lamport) = (fields.id, fields.timestamp, fields.lamport);
 
         // Build record
         let record = Record {
@@ -108,50 +312,132 @@ impl RecordLog {
             encoding: input.encoding,
             caused_by: input.caused_by,
             linked_to: input.linked_to,
+            squash,
+            expires_at: input.expires_at,
+            lamport,
+            idempotency_key: input.idempotency_key,
+            schema: input.schema,
+        };
+        Ok((record, offset))
+    }
+
+    /// Append a record straight from borrowed fields, without building a
+    /// `Record`. Assigns the ID, timestamp and Lamport stamp into `fields`
+    /// and returns the offset where it was written.
+    pub(crate) fn append_fields(&self, fields: &mut RecordFields<'_>) -> Result<u64> {
+        validate_lengths(fields)?;
+
+        let mut file = self.file.write();
+
+        // Assign ID
+        fields.id = RecordId(*self.next_id.read());
+        *self.next_id.write() += 1;
+
+        // Tick the clock, or catch up with a replicated record's stamp
+        let lamport = {
+            let mut clock = self.max_lamport.write();
+            let lamport = fields.lamport.unwrap_or(*clock + 1);
+            *clock = (*clock).max(lamport);
+            lamport
         };
+        fields.lamport = Some(lamport);
+        fields.timestamp = Timestamp::now();
 
         // Serialize and write
         let offset = *self.file_size.read();
         file.seek(SeekFrom::Start(offset))?;
 
-        self.write_record(&mut *file, &record)?;
+        self.write_record(&mut file, fields)?;
 
         let new_size = file.stream_position()?;
         *self.file_size.write() = new_size;
 
-        // Sync periodically based on sync_interval
-        let mut writes = self.writes_since_sync.write();
-        *writes += 1;
-        if *writes >= self.sync_interval {
-            file.sync_all()?;
-            *writes = 0;
+        // Sync according to the sync policy
+        let writes = {
+            let mut writes = self.writes_since_sync.write();
+            *writes += 1;
+            *writes
+        };
+        let due = match self.sync_policy {
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::EveryN(n) => writes >= n,
+            SyncPolicy::Interval(interval) => self.last_sync.read().elapsed() >= interval,
+        };
+        if due {
+            self.sync_file(&file, new_size)?;
         }
 
-        Ok((record, offset))
+        Ok(offset)
     }
 
     /// Force sync all pending writes to disk.
     pub fn sync(&self) -> Result<()> {
-        let mut file = self.file.write();
+        let file = self.file.write();
+        self.sync_file(&file, *self.file_size.read())
+    }
+
+    /// Fsync the (locked) file and reset the sync bookkeeping.
+    fn sync_file(&self, file: &File, size: u64) -> Result<()> {
         file.sync_all()?;
         *self.writes_since_sync.write() = 0;
+        *self.synced_size.write() = size;
+        *self.last_sync.write() = Instant::now();
         Ok(())
     }
 
+    /// Get the sync policy.
+    pub fn sync_policy(&self) -> SyncPolicy {
+        self.sync_policy
+    }
+
+    /// Number of appends not yet synced to disk.
+    pub fn unsynced_writes(&self) -> u64 {
+        *self.writes_since_sync.read()
+    }
+
+    /// Length of the log prefix known to be on disk.
+    ///
+    /// Everything past this offset could be lost if the machine crashed now.
+    pub fn synced_size(&self) -> u64 {
+        *self.synced_size.read()
+    }
+
     /// Read a record at a given offset.
     pub fn read_at(&self, offset: u64) -> Result<Record> {
+        self.read_with_end(offset).map(|(record, _)| record)
+    }
+
+    /// Read a record at a given offset, also returning the offset just past it.
+    pub fn read_with_end(&self, offset: u64) -> Result<(Record, u64)> {
         let mut file = self.file.write();
         file.seek(SeekFrom::Start(offset))?;
-        self.read_record(&mut *file)
+        self.read_record(&mut file)
+    }
+
+    /// Read a record at a given offset, also returning where the next record starts.
+    ///
+    /// Unlike `read_at`, a payload checksum mismatch still reports the next
+    /// offset so a scan can step over the damaged record. The next offset is
+    /// None when the record's framing itself can't be read.
+    pub fn read_with_next(&self, offset: u64) -> (Result<Record>, Option<u64>) {
+        let mut file = self.file.write();
+        if let Err(e) = file.seek(SeekFrom::Start(offset)) {
+            return (Err(e.into()), None);
+        }
+        match self.read_record(&mut file) {
+            Ok((record, end)) => (Ok(record), Some(end)),
+            Err(e @ StoreError::ChecksumMismatch { .. }) => (Err(e), file.stream_position().ok()),
+            Err(e) => (Err(e), None),
+        }
     }
 
     /// Iterate all records from the beginning.
-    pub fn iter(&self) -> RecordIterator {
+    pub fn iter(&self) -> RecordIterator<'_> {
         self.iter_from(0)
     }
 
     /// Iterate all records from a given offset.
-    pub fn iter_from(&self, offset: u64) -> RecordIterator {
+    pub fn iter_from(&self, offset: u64) -> RecordIterator<'_> {
         RecordIterator {
             log: self,
             offset,
@@ -159,21 +445,185 @@ impl RecordLog {
         }
     }
 
+    /// The bytes from `offset` to the end of the file, as `truncate` would
+    /// discard them.
+    pub fn read_tail(&self, offset: u64) -> Result<Vec<u8>> {
+        let mut file = self.file.write();
+        let mut tail = Vec::new();
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_to_end(&mut tail)?;
+        Ok(tail)
+    }
+
+    /// Cut the log back to `len` bytes, discarding everything after it.
+    ///
+    /// Returns the discarded bytes (whatever of them is still on disk) so
+    /// callers can keep a copy. The next record ID is recomputed from the
+    /// records that remain.
+    pub fn truncate(&self, len: u64) -> Result<Vec<u8>> {
+        let mut file = self.file.write();
+
+        let mut removed = Vec::new();
+        file.seek(SeekFrom::Start(len))?;
+        file.read_to_end(&mut removed)?;
+
+        file.set_len(len)?;
+        self.sync_file(&file, len)?;
+
+        *self.file_size.write() = len;
+        let (max_id, max_lamport) = if len > 0 {
+            Self::find_max_id(&file)?
+        } else {
+            (0, 0)
+        };
+        *self.next_id.write() = max_id + 1;
+        *self.max_lamport.write() = max_lamport;
+
+        Ok(removed)
+    }
+
+    /// Rewrite the log, keeping only some records.
+    ///
+    /// `keep` is called with each record, its offset, and the old -> new
+    /// offsets of the records kept so far; it returns the record to write
+    /// (possibly changed) or None to drop it. The new log is written next to
+    /// the old one, synced and renamed over it, so a crash leaves one or the
+    /// other. Reads and appends wait until it's done. Returns the old -> new
+    /// offset map. Fails without changing anything on an unreadable record.
+    pub(crate) fn rewrite(
+        &self,
+        keep: impl FnMut(u64, Record, &HashMap<u64, u64>) -> Result<Option<Record>>,
+    ) -> Result<HashMap<u64, u64>> {
+        let mut file = self.file.write();
+        let tmp_path = self.path.with_extension("log.compact");
+        let (offsets, new_size) = self.write_filtered_locked(&mut file, &tmp_path, keep)?;
+
+        fs::rename(&tmp_path, &self.path)?;
+        *file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        self.sync_file(&file, new_size)?;
+        *self.file_size.write() = new_size;
+
+        Ok(offsets)
+    }
+
+    /// Write the records `keep` accepts to a new log at `dest`, leaving this
+    /// one as it is. `keep` works as for `rewrite`. The new file is synced;
+    /// returns the old -> new offset map. `dest` is removed on failure.
+    pub(crate) fn write_filtered(
+        &self,
+        dest: &Path,
+        keep: impl FnMut(u64, Record, &HashMap<u64, u64>) -> Result<Option<Record>>,
+    ) -> Result<HashMap<u64, u64>> {
+        let mut file = self.file.write();
+        let (offsets, _) = self.write_filtered_locked(&mut file, dest, keep)?;
+        Ok(offsets)
+    }
+
+    /// `write_filtered` for a caller holding the file lock. Also returns the
+    /// size of the new log.
+    fn write_filtered_locked(
+        &self,
+        file: &mut File,
+        dest: &Path,
+        mut keep: impl FnMut(u64, Record, &HashMap<u64, u64>) -> Result<Option<Record>>,
+    ) -> Result<(HashMap<u64, u64>, u64)> {
+        let end = *self.file_size.read();
+        let written = (|| {
+            let mut out = File::create(dest)?;
+            let mut offsets = HashMap::new();
+            let mut offset = 0;
+            let mut new_offset = 0;
+            while offset < end {
+                file.seek(SeekFrom::Start(offset))?;
+                let (record, next) = self.read_record(file)?;
+                if let Some(record) = keep(offset, record, &offsets)? {
+                    self.write_record(&mut out, &RecordFields::of(&record))?;
+                    offsets.insert(offset, new_offset);
+                    new_offset = out.stream_position()?;
+                }
+                offset = next;
+            }
+            out.sync_all()?;
+            Ok((offsets, new_offset))
+        })();
+        if written.is_err() {
+            let _ = fs::remove_file(dest);
+        }
+        written
+    }
+
+    /// Reopen the log file after something else replaced it on disk. The
+    /// next record ID and the clock never go back.
+    pub(crate) fn reopen(&self) -> Result<()> {
+        let mut file = self.file.write();
+        *file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        let size = file.metadata()?.len();
+        let (max_id, max_lamport) = if size > 0 {
+            Self::find_max_id(&file)?
+        } else {
+            (0, 0)
+        };
+
+        let mut next_id = self.next_id.write();
+        *next_id = (*next_id).max(max_id + 1);
+        let mut lamport = self.max_lamport.write();
+        *lamport = (*lamport).max(max_lamport);
+        *self.file_size.write() = size;
+        *self.synced_size.write() = size;
+        *self.writes_since_sync.write() = 0;
+        Ok(())
+    }
+
+    /// Carry on from `other`'s record IDs and clock, so records appended
+    /// here never reuse an ID or timestamp it handed out.
+    pub(crate) fn continue_from(&self, other: &RecordLog) {
+        let mut next_id = self.next_id.write();
+        *next_id = (*next_id).max(*other.next_id.read());
+        let mut lamport = self.max_lamport.write();
+        *lamport = (*lamport).max(other.max_lamport());
+    }
+
+    /// Highest Lamport timestamp in the log (0 if none).
+    pub fn max_lamport(&self) -> u64 {
+        *self.max_lamport.read()
+    }
+
     /// Get current file size.
     pub fn size(&self) -> u64 {
         *self.file_size.read()
     }
 
+    /// Get the path of the log file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
     /// Write a record to the file.
-    fn write_record(&self, file: &mut File, record: &Record) -> Result<()> {
+    fn write_record(&self, file: &mut File, record: &RecordFields<'_>) -> Result<()> {
         // Magic
         file.write_all(LOG_MAGIC)?;
 
         // Version
         file.write_all(&[LOG_VERSION])?;
 
-        // Flags (reserved)
-        file.write_all(&[0u8])?;
+        // Flags
+        let mut flags = 0u8;
+        if record.squash {
+            flags |= FLAG_SQUASH;
+        }
+        if record.expires_at.is_some() {
+            flags |= FLAG_EXPIRES;
+        }
+        if record.lamport.is_some() {
+            flags |= FLAG_LAMPORT;
+        }
+        if record.idempotency_key.is_some() {
+            flags |= FLAG_IDEMPOTENCY_KEY;
+        }
+        if record.schema.is_some() {
+            flags |= FLAG_SCHEMA;
+        }
+        file.write_all(&[flags])?;
 
         // Record ID
         file.write_all(&record.id.0.to_le_bytes())?;
@@ -187,6 +637,32 @@ impl RecordLog {
         // Timestamp
         file.write_all(&record.timestamp.0.to_le_bytes())?;
 
+        // Expiry (only present with FLAG_EXPIRES)
+        if let Some(expires_at) = record.expires_at {
+            file.write_all(&expires_at.0.to_le_bytes())?;
+        }
+
+        // Lamport timestamp (only present with FLAG_LAMPORT)
+        if let Some(lamport) = record.lamport {
+            file.write_all(&lamport.to_le_bytes())?;
+        }
+
+        // Idempotency key (only present with FLAG_IDEMPOTENCY_KEY)
+        if let Some(key) = record.idempotency_key {
+            file.write_all(&(key.len() as u16).to_le_bytes())?;
+            file.write_all(key.as_bytes())?;
+        }
+
+        // Schema (only present with FLAG_SCHEMA)
+        if let Some(schema) = record.schema {
+            let algorithm_byte = match schema.1 {
+                HashAlgorithm::Sha256 => 0u8,
+                HashAlgorithm::Blake3 => 1u8,
+            };
+            file.write_all(&[algorithm_byte])?;
+            file.write_all(&schema.0)?;
+        }
+
         // Type
         let type_bytes = record.record_type.as_bytes();
         file.write_all(&(type_bytes.len() as u16).to_le_bytes())?;
@@ -202,30 +678,35 @@ impl RecordLog {
 
         // Payload
         file.write_all(&(record.payload.len() as u32).to_le_bytes())?;
-        file.write_all(&record.payload)?;
+        file.write_all(record.payload)?;
 
         // Caused by
         file.write_all(&(record.caused_by.len() as u16).to_le_bytes())?;
-        for id in &record.caused_by {
+        for id in record.caused_by {
             file.write_all(&id.0.to_le_bytes())?;
         }
 
         // Linked to
         file.write_all(&(record.linked_to.len() as u16).to_le_bytes())?;
-        for id in &record.linked_to {
+        for id in record.linked_to {
             file.write_all(&id.0.to_le_bytes())?;
         }
 
         // Checksum of entire record (excluding checksum itself)
         // For simplicity, we'll compute checksum of payload only
-        let checksum = crc32fast::hash(&record.payload);
+        let checksum = crc32fast::hash(record.payload);
         file.write_all(&checksum.to_le_bytes())?;
 
         Ok(())
     }
 
     /// Read a record from the file at current position.
-    fn read_record(&self, file: &mut File) -> Result<Record> {
+    ///
+    /// Returns the record and the offset just past it, where the next record starts.
+    fn read_record(&self, file: &mut File) -> Result<(Record, u64)> {
+        #[cfg(test)]
+        tests::RECORDS_READ.with(|count| count.set(count.get() + 1));
+
         // Magic
         let mut magic = [0u8; 4];
         file.read_exact(&mut magic)?;
@@ -244,8 +725,8 @@ impl RecordLog {
         }
 
         // Flags
-        let mut _flags = [0u8; 1];
-        file.read_exact(&mut _flags)?;
+        let mut flags = [0u8; 1];
+        file.read_exact(&mut flags)?;
 
         // Record ID
         let mut id_bytes = [0u8; 8];
@@ -267,6 +748,56 @@ impl RecordLog {
         file.read_exact(&mut ts_bytes)?;
         let timestamp = Timestamp(i64::from_le_bytes(ts_bytes));
 
+        // Expiry
+        let expires_at = if flags[0] & FLAG_EXPIRES != 0 {
+            let mut expiry_bytes = [0u8; 8];
+            file.read_exact(&mut expiry_bytes)?;
+            Some(Timestamp(i64::from_le_bytes(expiry_bytes)))
+        } else {
+            None
+        };
+
+        // Lamport timestamp
+        let lamport = if flags[0] & FLAG_LAMPORT != 0 {
+            let mut lamport_bytes = [0u8; 8];
+            file.read_exact(&mut lamport_bytes)?;
+            Some(u64::from_le_bytes(lamport_bytes))
+        } else {
+            None
+        };
+
+        // Idempotency key
+        let idempotency_key = if flags[0] & FLAG_IDEMPOTENCY_KEY != 0 {
+            let mut key_len_bytes = [0u8; 2];
+            file.read_exact(&mut key_len_bytes)?;
+            let mut key_bytes = vec![0u8; u16::from_le_bytes(key_len_bytes) as usize];
+            file.read_exact(&mut key_bytes)?;
+            Some(String::from_utf8_lossy(&key_bytes).into_owned())
+        } else {
+            None
+        };
+
+        // Schema
+        let schema = if flags[0] & FLAG_SCHEMA != 0 {
+            let mut algorithm_byte = [0u8; 1];
+            file.read_exact(&mut algorithm_byte)?;
+            let algorithm = match algorithm_byte[0] {
+                0 => HashAlgorithm::Sha256,
+                1 => HashAlgorithm::Blake3,
+                other => {
+                    return Err(StoreError::InvalidFormat(format!(
+                        "Unknown schema hash algorithm: {}",
+                        other
+                    )))
+                }
+            };
+            let mut digest = [0u8; 32];
+            file.read_exact(&mut digest)?;
+            Some(Hash(digest, algorithm))
+        } else {
+            None
+        };
+
         // Type
         let mut type_len_bytes = [0u8; 2];
         file.read_exact(&mut type_len_bytes)?;
@@ -327,7 +858,7 @@ impl RecordLog {
             });
         }
 
-        Ok(Record {
+        let record = Record {
             id,
             sequence,
             branch,
@@ -337,15 +868,22 @@ impl RecordLog {
             encoding,
             caused_by,
             linked_to,
-        })
+            squash: flags[0] & FLAG_SQUASH != 0,
+            expires_at,
+            lamport,
+            idempotency_key,
+            schema,
+        };
+        Ok((record, file.stream_position()?))
     }
 
-    /// Find the maximum record ID in the log.
-    fn find_max_id(file: &File) -> Result<u64> {
+    /// Find the maximum record ID and Lamport timestamp in the log.
+    fn find_max_id(file: &File) -> Result<(u64, u64)> {
         let mut file = file.try_clone()?;
         file.seek(SeekFrom::Start(0))?;
 
         let mut max_id = 0u64;
+        let mut max_lamport = 0u64;
         let file_size = file.metadata()?.len();
 
         while file.stream_position()? < file_size {
@@ -359,8 +897,10 @@ impl RecordLog {
                 break;
             }
 
-            // Skip version and flags
-            file.seek(SeekFrom::Current(2))?;
+            // Skip version, read flags
+            file.seek(SeekFrom::Current(1))?;
+            let mut flags = [0u8; 1];
+            file.read_exact(&mut flags)?;
 
             // Read ID
             let mut id_bytes = [0u8; 8];
@@ -369,8 +909,26 @@ impl RecordLog {
             max_id = max_id.max(id);
 
             // Skip to next record - we need to read lengths to know how far to skip
-            // Skip: sequence(8) + branch(8) + timestamp(8)
-            file.seek(SeekFrom::Current(24))?;
+            // Skip: sequence(8) + branch(8) + timestamp(8) [+ expiry(8)]
+            let fixed = if flags[0] & FLAG_EXPIRES != 0 { 32 } else { 24 };
+            file.seek(SeekFrom::Current(fixed))?;
+
+            if flags[0] & FLAG_LAMPORT != 0 {
+                let mut lamport_bytes = [0u8; 8];
+                file.read_exact(&mut lamport_bytes)?;
+                max_lamport = max_lamport.max(u64::from_le_bytes(lamport_bytes));
+            }
+
+            if flags[0] & FLAG_IDEMPOTENCY_KEY != 0 {
+                let mut key_len_bytes = [0u8; 2];
+                file.read_exact(&mut key_len_bytes)?;
+                file.seek(SeekFrom::Current(u16::from_le_bytes(key_len_bytes) as i64))?;
+            }
+
+            // Skip schema: algorithm(1) + digest(32)
+            if flags[0] & FLAG_SCHEMA != 0 {
+                file.seek(SeekFrom::Current(33))?;
+            }
 
             // Read type length and skip type
             let mut type_len_bytes = [0u8; 2];
@@ -403,7 +961,7 @@ impl RecordLog {
             file.seek(SeekFrom::Current(4))?;
         }
 
-        Ok(max_id)
+        Ok((max_id, max_lamport))
     }
 }
 
@@ -423,22 +981,9 @@ impl<'a> Iterator for RecordIterator<'a> {
         }
 
         let current_offset = self.offset;
-        match self.log.read_at(current_offset) {
-            Ok(record) => {
-                // Calculate next offset by re-reading position
-                // This is a bit inefficient; we could track size during read
-                let mut file = self.log.file.write();
-                if let Ok(_) = file.seek(SeekFrom::Start(current_offset)) {
-                    // Skip to end of record
-                    if let Ok(rec) = self.log.read_record(&mut *file) {
-                        drop(rec);
-                        self.offset = file.stream_position().unwrap_or(self.end);
-                    } else {
-                        self.offset = self.end;
-                    }
-                } else {
-                    self.offset = self.end;
-                }
+        match self.log.read_with_end(current_offset) {
+            Ok((record, next)) => {
+                self.offset = next;
                 Some(Ok((current_offset, record)))
             }
             Err(e) => {
@@ -452,8 +997,14 @@ impl<'a> Iterator for RecordIterator<'a> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::cell::Cell;
     use tempfile::TempDir;
 
+    thread_local! {
+        /// Records decoded by `read_record` on this thread.
+        pub(super) static RECORDS_READ: Cell<u64> = const { Cell::new(0) };
+    }
+
     #[test]
     fn test_append_and_read() {
         let dir = TempDir::new().unwrap();
@@ -484,6 +1035,31 @@ mod tests {
         assert_eq!(records.len(), 10);
     }
 
+    #[test]
+    fn test_iter_reads_each_record_once() {
+        let dir = TempDir::new().unwrap();
+        let log = RecordLog::open(dir.path().join("log.bin")).unwrap();
+
+        let mut offsets = Vec::new();
+        for i in 1..=10_000 {
+            let input = RecordInput::raw("test", format!("record {}", i).into_bytes());
+            let (_, offset) = log.append(input, BranchId(1), Sequence(i)).unwrap();
+            offsets.push(offset);
+        }
+
+        RECORDS_READ.with(|count| count.set(0));
+        let scanned: Vec<(u64, Record)> = log.iter().map(|r| r.unwrap()).collect();
+        assert_eq!(RECORDS_READ.with(Cell::get), 10_000);
+
+        // Same records, at the same offsets, as reading each one directly
+        assert_eq!(scanned.len(), offsets.len());
+        for ((offset, record), &expected_offset) in scanned.iter().zip(&offsets) {
+            assert_eq!(*offset, expected_offset);
+            let direct = log.read_at(expected_offset).unwrap();
+            assert_eq!(format!("{:?}", record), format!("{:?}", direct));
+        }
+    }
+
     #[test]
     fn test_persistence() {
         let dir = TempDir::new().unwrap();
@@ -510,4 +1086,55 @@ mod tests {
             assert_eq!(record.id.0, 6); // Should continue from max ID
         }
     }
+
+    #[test]
+    fn test_truncate() {
+        let dir = TempDir::new().unwrap();
+        let log = RecordLog::open(dir.path().join("log.bin")).unwrap();
+
+        let mut offsets = Vec::new();
+        for i in 1..=3 {
+            let input = RecordInput::raw("test", format!("record {}", i).into_bytes());
+            offsets.push(log.append(input, BranchId(1), Sequence(i)).unwrap().1);
+        }
+        let size = log.size();
+
+        let removed = log.truncate(offsets[2]).unwrap();
+        assert_eq!(removed.len() as u64, size - offsets[2]);
+        assert_eq!(log.iter().count(), 2);
+
+        // IDs continue from the records that remain
+        let input = RecordInput::raw("test", b"again".to_vec());
+        let (record, offset) = log.append(input, BranchId(1), Sequence(3)).unwrap();
+        assert_eq!(record.id.0, 3);
+        assert_eq!(offset, offsets[2]);
+    }
+
+    #[test]
+    fn test_msgpack_roundtrip() {
+        #[derive(Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+        struct Point {
+            x: i32,
+            y: i32,
+            label: String,
+        }
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("log.bin");
+        let log = RecordLog::open(&path).unwrap();
+
+        let point = Point { x: 3, y: -4, label: "p".into() };
+        let input = RecordInput::msgpack("point", &point).unwrap();
+        let (_, offset) = log.append(input, BranchId(1), Sequence(1)).unwrap();
+        log.sync().unwrap();
+
+        // Encoding byte follows the fixed header, the Lamport stamp and the type string
+        let bytes = std::fs::read(&path).unwrap();
+        let encoding_at = offset as usize + 38 + 8 + 2 + "point".len();
+        assert_eq!(bytes[encoding_at], 1);
+
+        let record = log.read_at(offset).unwrap();
+        assert_eq!(record.encoding, PayloadEncoding::MessagePack);
+        assert_eq!(record.decode::<Point>().unwrap(), point);
+    }
 }
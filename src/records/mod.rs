@@ -5,6 +5,8 @@
 
 mod log;
 mod index;
+pub(crate) mod schema;
 
-pub use log::RecordLog;
+pub use log::{RecordLog, SyncPolicy};
+pub(crate) use log::RecordFields;
 pub use index::RecordIndex;
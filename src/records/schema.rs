@@ -0,0 +1,196 @@
+//! Payload validation against JSON schemas registered per record type.
+//!
+//! Supports the structural subset of JSON Schema that record payloads
+//! need: `type`, `enum`, `const`, `required`, `properties`,
+//! `additionalProperties`, `items`, `minItems`/`maxItems`,
+//! `minLength`/`maxLength` and `minimum`/`maximum`. Other keywords are
+//! ignored, so richer schemas still load but only these parts are checked.
+
+use crate::types::Hash;
+use serde_json::Value;
+
+/// A schema registered for a record type, and the blob it is stored in.
+pub(crate) struct RecordSchema {
+    pub(crate) hash: Hash,
+    pub(crate) schema: Value,
+}
+
+/// Check `value` against `schema`.
+///
+/// Returns a description of the first violation, naming where in the
+/// value it was found (`$` is the root).
+pub(crate) fn validate(schema: &Value, value: &Value) -> Result<(), String> {
+    validate_at(schema, value, "$")
+}
+
+/// Whether `schema` can be used as a schema at all (an object, or a
+/// boolean accepting or rejecting everything).
+pub(crate) fn is_schema(schema: &Value) -> bool {
+    schema.is_object() || schema.is_boolean()
+}
+
+fn validate_at(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let schema = match schema {
+        Value::Bool(true) => return Ok(()),
+        Value::Bool(false) => return Err(format!("{}: no value is allowed here", path)),
+        Value::Object(schema) => schema,
+        _ => return Ok(()),
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let matches = match expected {
+            Value::String(name) => has_type(value, name),
+            Value::Array(names) => names.iter().filter_map(Value::as_str).any(|name| has_type(value, name)),
+            _ => true,
+        };
+        if !matches {
+            return Err(format!("{}: expected type {}, got {}", path, expected, type_name(value)));
+        }
+    }
+    if let Some(Value::Array(allowed)) = schema.get("enum") {
+        if !allowed.contains(value) {
+            return Err(format!("{}: {} is not one of {}", path, value, Value::Array(allowed.clone())));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            return Err(format!("{}: expected {}, got {}", path, expected, value));
+        }
+    }
+
+    match value {
+        Value::Object(fields) => {
+            if let Some(Value::Array(required)) = schema.get("required") {
+                if let Some(missing) = required
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .find(|name| !fields.contains_key(*name))
+                {
+                    return Err(format!("{}: missing required field {:?}", path, missing));
+                }
+            }
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for (name, field) in fields {
+                let field_path = format!("{}.{}", path, name);
+                match properties.and_then(|properties| properties.get(name)) {
+                    Some(field_schema) => validate_at(field_schema, field, &field_path)?,
+                    None => {
+                        if let Some(additional) = schema.get("additionalProperties") {
+                            validate_at(additional, field, &field_path)?;
+                        }
+                    }
+                }
+            }
+        }
+        Value::Array(items) => {
+            check_bound(schema, "minItems", items.len(), path, |min, len| len >= min, "items")?;
+            check_bound(schema, "maxItems", items.len(), path, |max, len| len <= max, "items")?;
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate_at(item_schema, item, &format!("{}[{}]", path, i))?;
+                }
+            }
+        }
+        Value::String(s) => {
+            let len = s.chars().count();
+            check_bound(schema, "minLength", len, path, |min, len| len >= min, "characters")?;
+            check_bound(schema, "maxLength", len, path, |max, len| len <= max, "characters")?;
+        }
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or(f64::NAN);
+            if let Some(min) = schema.get("minimum").and_then(Value::as_f64) {
+                if n < min {
+                    return Err(format!("{}: {} is less than the minimum {}", path, n, min));
+                }
+            }
+            if let Some(max) = schema.get("maximum").and_then(Value::as_f64) {
+                if n > max {
+                    return Err(format!("{}: {} is greater than the maximum {}", path, n, max));
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Check a length against an integer keyword such as `minItems`.
+fn check_bound(
+    schema: &serde_json::Map<String, Value>,
+    keyword: &str,
+    len: usize,
+    path: &str,
+    ok: impl Fn(usize, usize) -> bool,
+    unit: &str,
+) -> Result<(), String> {
+    match schema.get(keyword).and_then(Value::as_u64) {
+        Some(bound) if !ok(bound as usize, len) => {
+            Err(format!("{}: has {} {}, {} is {}", path, len, unit, keyword, bound))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn has_type(value: &Value, name: &str) -> bool {
+    match name {
+        "integer" => value.as_i64().is_some() || value.as_u64().is_some(),
+        "number" => value.is_number(),
+        name => type_name(value) == name,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_validate_object() {
+        let schema = json!({
+            "type": "object",
+            "required": ["role", "text"],
+            "properties": {
+                "role": {"enum": ["user", "assistant"]},
+                "text": {"type": "string", "minLength": 1},
+                "tokens": {"type": "integer", "minimum": 0},
+                "tags": {"type": "array", "items": {"type": "string"}, "maxItems": 2}
+            },
+            "additionalProperties": false
+        });
+
+        assert!(validate(&schema, &json!({"role": "user", "text": "hi", "tokens": 3})).is_ok());
+        assert!(validate(&schema, &json!({"role": "user", "text": "hi", "tags": ["a"]})).is_ok());
+
+        let err = validate(&schema, &json!({"role": "user"})).unwrap_err();
+        assert!(err.contains("\"text\""), "{}", err);
+        let err = validate(&schema, &json!({"role": "system", "text": "hi"})).unwrap_err();
+        assert!(err.starts_with("$.role"), "{}", err);
+        let err = validate(&schema, &json!({"role": "user", "text": "hi", "tokens": 1.5})).unwrap_err();
+        assert!(err.starts_with("$.tokens"), "{}", err);
+        let err = validate(&schema, &json!({"role": "user", "text": "hi", "tags": ["a", 1]})).unwrap_err();
+        assert!(err.starts_with("$.tags[1]"), "{}", err);
+        assert!(validate(&schema, &json!({"role": "user", "text": "hi", "extra": 1})).is_err());
+        assert!(validate(&schema, &json!({"role": "user", "text": ""})).is_err());
+        assert!(validate(&schema, &json!(["not", "an", "object"])).is_err());
+    }
+
+    #[test]
+    fn test_boolean_and_unknown_keywords() {
+        assert!(validate(&json!(true), &json!({"anything": 1})).is_ok());
+        assert!(validate(&json!(false), &json!(null)).is_err());
+        assert!(validate(&json!({"format": "email", "type": ["string", "null"]}), &json!(null)).is_ok());
+        assert!(is_schema(&json!({})));
+        assert!(!is_schema(&json!("object")));
+    }
+}
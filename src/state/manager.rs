@@ -6,16 +6,17 @@
 
 use crate::error::{Result, StoreError};
 use crate::records::RecordLog;
-use crate::state::operations::apply_operation;
-use crate::types::{BranchId, StateOperation, StateRegistration, StateStrategy, StateUpdateRecord};
+use crate::state::operations::apply_operation_with_capacity;
+use crate::types::{BranchId, Record, StateOperation, StateRegistration, StateStrategy, StateUpdateRecord};
 use lru::LruCache;
-use parking_lot::RwLock;
+use parking_lot::{RwLock, RwLockWriteGuard};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Write};
 use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 /// Magic bytes for state index file.
@@ -36,6 +37,35 @@ pub enum SnapshotNeeded {
     Full,
 }
 
+/// Per-branch override of when a state snapshots.
+///
+/// Set with `Store::set_snapshot_policy`. Thresholds left as `None` fall
+/// back to the state's registered strategy.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotPolicy {
+    /// Whether updates create snapshots automatically. When off, snapshots
+    /// are only written by `create_snapshot_if_needed` or compaction.
+    pub auto_snapshot: bool,
+
+    /// Operations between snapshots: `snapshot_every` for `Delta`,
+    /// `delta_snapshot_every` for `AppendLog`, and the fixed threshold of
+    /// 100 for `Struct`.
+    pub delta_snapshot_every: Option<u64>,
+
+    /// Delta snapshots between full snapshots (`AppendLog` only).
+    pub full_snapshot_every: Option<u64>,
+}
+
+impl Default for SnapshotPolicy {
+    fn default() -> Self {
+        Self {
+            auto_snapshot: true,
+            delta_snapshot_every: None,
+            full_snapshot_every: None,
+        }
+    }
+}
+
 /// Statistics about compaction potential for a state.
 #[derive(Clone, Debug)]
 pub struct CompactionStats {
@@ -82,18 +112,110 @@ pub struct StateChainHead {
     /// File offset of most recent full snapshot.
     pub last_full_snapshot_offset: Option<u64>,
 
-    /// Whether there are non-Append operations (Edit, Redact) since the last snapshot.
+    /// Whether there are non-Append operations (Edit, Redact, Prepend) since the last snapshot.
     /// When true, a full snapshot should be created instead of a delta snapshot,
     /// because delta snapshots can only track Append operations.
     #[serde(default)]
     pub has_non_append_since_snapshot: bool,
 
     /// Current number of items in the state (for O(1) length queries).
-    /// Updated on each Append (+1), Redact (-(end-start)), Snapshot (=snapshot.len).
+    /// Updated on each Append or Prepend (+1), Redact (-(end-start)), Snapshot (=snapshot.len),
+    /// and capped at a `RingBuffer`'s capacity.
     #[serde(default)]
     pub item_count: usize,
 }
 
+impl StateChainHead {
+    /// A head for a chain whose first update is at `offset`.
+    fn new(offset: u64) -> Self {
+        Self {
+            head_offset: offset,
+            ops_since_delta_snapshot: 0,
+            delta_snapshots_since_full: 0,
+            last_delta_snapshot_offset: None,
+            last_full_snapshot_offset: None,
+            has_non_append_since_snapshot: false,
+            item_count: 0,
+        }
+    }
+
+    /// Move the head to an update at `offset`, adjusting snapshot and item accounting.
+    ///
+    /// `capacity` is the state's `StateStrategy::capacity`.
+    fn advance(&mut self, offset: u64, operation: &StateOperation, capacity: Option<usize>) {
+        self.head_offset = offset;
+
+        match operation {
+            StateOperation::Snapshot(data) => {
+                // Full snapshot resets everything
+                self.ops_since_delta_snapshot = 0;
+                self.delta_snapshots_since_full = 0;
+                self.last_full_snapshot_offset = Some(offset);
+                self.last_delta_snapshot_offset = None; // Full snapshot supersedes deltas
+                self.has_non_append_since_snapshot = false; // Reset the flag
+                // Update item count from snapshot
+                if let Ok(arr) = serde_json::from_slice::<Vec<serde_json::Value>>(data) {
+                    self.item_count = arr.len();
+                }
+            }
+            StateOperation::Clear => {
+                // A clear is a full snapshot of nothing
+                self.ops_since_delta_snapshot = 0;
+                self.delta_snapshots_since_full = 0;
+                self.last_full_snapshot_offset = Some(offset);
+                self.last_delta_snapshot_offset = None;
+                self.has_non_append_since_snapshot = false;
+                self.item_count = 0;
+            }
+            StateOperation::DeltaSnapshot(_) => {
+                // Delta snapshot resets op counter, increments delta counter
+                // Note: Delta doesn't change item_count - it consolidates existing items
+                self.ops_since_delta_snapshot = 0;
+                self.delta_snapshots_since_full += 1;
+                self.last_delta_snapshot_offset = Some(offset);
+            }
+            StateOperation::Append(_) => {
+                self.ops_since_delta_snapshot += 1;
+                self.item_count += 1;
+            }
+            StateOperation::AppendMany(items) => {
+                // One op in the chain, but every item counts
+                self.ops_since_delta_snapshot += 1;
+                self.item_count += items.len();
+            }
+            StateOperation::Prepend(_) => {
+                // Shifts every item, which a delta snapshot can't express
+                self.ops_since_delta_snapshot += 1;
+                self.has_non_append_since_snapshot = true;
+                self.item_count += 1;
+            }
+            StateOperation::Redact { start, end } => {
+                self.ops_since_delta_snapshot += 1;
+                self.has_non_append_since_snapshot = true;
+                // Redact removes items (clamped to valid range, as when applied)
+                let remove_count = (*end).min(self.item_count).saturating_sub(*start);
+                self.item_count -= remove_count;
+            }
+            StateOperation::Edit { .. } => {
+                self.ops_since_delta_snapshot += 1;
+                self.has_non_append_since_snapshot = true;
+                // Edit doesn't change count
+            }
+            StateOperation::Set(_) | StateOperation::SetIfSequence { .. } => {
+                self.ops_since_delta_snapshot += 1;
+                // Set replaces entire state - can't track count without parsing
+            }
+            StateOperation::Delta { .. } | StateOperation::Field { .. } => {
+                self.ops_since_delta_snapshot += 1;
+                // Delta/Field operations for Struct type - don't change count
+            }
+        }
+        if let Some(capacity) = capacity {
+            self.item_count = self.item_count.min(capacity);
+        }
+    }
+}
+
 /// In-memory state index (small - just heads and strategies).
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct StateIndex {
@@ -102,6 +224,77 @@ pub struct StateIndex {
 
     /// Registered state strategies.
     pub strategies: HashMap<String, StateStrategy>,
+
+    /// Snapshot policy overrides per (branch_id, state_id).
+    #[serde(default)]
+    pub policies: HashMap<(BranchId, String), SnapshotPolicy>,
+
+    /// Offsets of the full snapshots each state's history was pruned at.
+    #[serde(default)]
+    pub pruned: HashMap<String, Vec<u64>>,
+}
+
+/// Rebuilds state chain heads by replaying state update records in log order.
+///
+/// Used by `Store::repair`. Each update continues the head it points back to
+/// via `prev_update_offset`, so chains inherited across branches keep their
+/// accounting.
+#[derive(Default)]
+pub(crate) struct HeadRebuilder {
+    /// State ID and head as of each update offset.
+    at_offset: HashMap<u64, (String, StateChainHead)>,
+
+    /// Latest head built from each branch's own updates.
+    own: HashMap<(BranchId, String), StateChainHead>,
+}
+
+impl HeadRebuilder {
+    /// Replay one state update found at `offset` on `branch`.
+    ///
+    /// `capacity` is the state's `StateStrategy::capacity`.
+    pub fn observe(
+        &mut self,
+        branch: BranchId,
+        offset: u64,
+        update: &StateUpdateRecord,
+        capacity: Option<usize>,
+    ) {
+        let mut head = match update.prev_update_offset.and_then(|p| self.at_offset.get(&p)) {
+            Some((state_id, head)) if *state_id == update.state_id => head.clone(),
+            _ => StateChainHead::new(offset),
+        };
+        head.advance(offset, &update.operation, capacity);
+
+        self.at_offset
+            .insert(offset, (update.state_id.clone(), head.clone()));
+        self.own.insert((branch, update.state_id.clone()), head);
+    }
+
+    /// Whether `offset` holds a surviving update for `state_id`.
+    fn is_valid(&self, state_id: &str, offset: u64) -> bool {
+        matches!(self.at_offset.get(&offset), Some((id, _)) if id == state_id)
+    }
+}
+
+/// Fail with `StateTooLarge` if a state value of `bytes` is over `limit`.
+pub(crate) fn check_reconstruct_size(bytes: usize, limit: Option<u64>) -> Result<()> {
+    match limit {
+        Some(limit) if bytes as u64 > limit => Err(StoreError::StateTooLarge {
+            bytes: bytes as u64,
+            limit,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Decode the state update in `record`, read at `offset` while walking
+/// `state_id`'s chain.
+pub(crate) fn decode_update(record: &Record, state_id: &str, offset: u64) -> Result<StateUpdateRecord> {
+    serde_json::from_slice(&record.payload).map_err(|source| StoreError::CorruptStateRecord {
+        state_id: state_id.to_string(),
+        offset,
+        source,
+    })
 }
 
 /// Cached state value.
@@ -127,6 +320,9 @@ pub struct StateManager {
 
     /// Reference to record log for disk-based chain traversal.
     log: Option<Arc<RecordLog>>,
+
+    /// Whether the index changed since it was last saved or loaded.
+    dirty: AtomicBool,
 }
 
 impl StateManager {
@@ -145,6 +341,7 @@ impl StateManager {
             index: RwLock::new(StateIndex::default()),
             cache: RwLock::new(LruCache::new(cache_size)),
             log: None,
+            dirty: AtomicBool::new(true),
         })
     }
 
@@ -168,6 +365,7 @@ impl StateManager {
             index: RwLock::new(StateIndex::default()),
             cache: RwLock::new(LruCache::new(cache_size)),
             log: None,
+            dirty: AtomicBool::new(!path.exists()),
         };
 
         if path.exists() {
@@ -177,19 +375,32 @@ impl StateManager {
         Ok(manager)
     }
 
-    /// Register a new state with its strategy.
-    pub fn register_state(&self, registration: StateRegistration) -> Result<()> {
-        let mut index = self.index.write();
-
-        if index.strategies.contains_key(&registration.id) {
-            return Err(StoreError::StateExists(registration.id));
+    /// Register a state with its strategy.
+    ///
+    /// Registering an id again with the same strategy changes nothing and
+    /// returns false. A different strategy replaces the old one only while
+    /// the state has no updates on any branch; otherwise it's rejected with
+    /// `InvalidOperation`. Returns true if the registration was stored.
+    pub fn register_state(&self, registration: StateRegistration) -> Result<bool> {
+        let mut index = self.index_mut();
+
+        if let Some(existing) = index.strategies.get(&registration.id) {
+            if *existing == registration.strategy {
+                return Ok(false);
+            }
+            if index.heads.keys().any(|(_, id)| *id == registration.id) {
+                return Err(StoreError::InvalidOperation(format!(
+                    "State {} already has data under a different strategy",
+                    registration.id
+                )));
+            }
         }
 
         index
             .strategies
             .insert(registration.id.clone(), registration.strategy);
 
-        Ok(())
+        Ok(true)
     }
 
     /// Record a state update (called when a state update record is appended).
@@ -202,67 +413,29 @@ impl StateManager {
         offset: u64,
         operation: &StateOperation,
     ) -> Result<()> {
-        let mut index = self.index.write();
-
-        let key = (branch_id, state_id.to_string());
-        let head = index.heads.entry(key.clone()).or_insert_with(|| {
-            StateChainHead {
-                head_offset: offset,
-                ops_since_delta_snapshot: 0,
-                delta_snapshots_since_full: 0,
-                last_delta_snapshot_offset: None,
-                last_full_snapshot_offset: None,
-                has_non_append_since_snapshot: false,
-                item_count: 0,
-            }
-        });
+        self.record_updates(branch_id, state_id, &[(offset, operation)])
+    }
 
-        head.head_offset = offset;
+    /// Record consecutive state updates (oldest first) under one lock.
+    pub fn record_updates(
+        &self,
+        branch_id: BranchId,
+        state_id: &str,
+        updates: &[(u64, &StateOperation)],
+    ) -> Result<()> {
+        let Some(&(first_offset, _)) = updates.first() else {
+            return Ok(());
+        };
+        let mut index = self.index_mut();
 
-        match operation {
-            StateOperation::Snapshot(data) => {
-                // Full snapshot resets everything
-                head.ops_since_delta_snapshot = 0;
-                head.delta_snapshots_since_full = 0;
-                head.last_full_snapshot_offset = Some(offset);
-                head.last_delta_snapshot_offset = None; // Full snapshot supersedes deltas
-                head.has_non_append_since_snapshot = false; // Reset the flag
-                // Update item count from snapshot
-                if let Ok(arr) = serde_json::from_slice::<Vec<serde_json::Value>>(data) {
-                    head.item_count = arr.len();
-                }
-            }
-            StateOperation::DeltaSnapshot(_) => {
-                // Delta snapshot resets op counter, increments delta counter
-                // Note: Delta doesn't change item_count - it consolidates existing items
-                head.ops_since_delta_snapshot = 0;
-                head.delta_snapshots_since_full += 1;
-                head.last_delta_snapshot_offset = Some(offset);
-            }
-            StateOperation::Append(_) => {
-                head.ops_since_delta_snapshot += 1;
-                head.item_count += 1;
-            }
-            StateOperation::Redact { start, end } => {
-                head.ops_since_delta_snapshot += 1;
-                head.has_non_append_since_snapshot = true;
-                // Redact removes items (clamped to valid range)
-                let remove_count = end.saturating_sub(*start).min(head.item_count);
-                head.item_count = head.item_count.saturating_sub(remove_count);
-            }
-            StateOperation::Edit { .. } => {
-                head.ops_since_delta_snapshot += 1;
-                head.has_non_append_since_snapshot = true;
-                // Edit doesn't change count
-            }
-            StateOperation::Set(_) => {
-                head.ops_since_delta_snapshot += 1;
-                // Set replaces entire state - can't track count without parsing
-            }
-            StateOperation::Delta { .. } | StateOperation::Field { .. } => {
-                head.ops_since_delta_snapshot += 1;
-                // Delta/Field operations for Struct type - don't change count
-            }
+        let capacity = index.strategies.get(state_id).and_then(StateStrategy::capacity);
+        let key = (branch_id, state_id.to_string());
+        let head = index
+            .heads
+            .entry(key)
+            .or_insert_with(|| StateChainHead::new(first_offset));
+        for &(offset, operation) in updates {
+            head.advance(offset, operation, capacity);
         }
 
         // Invalidate cache for this state (need to invalidate for all branches)
@@ -278,6 +451,17 @@ impl StateManager {
     /// Uses LRU cache for fast repeated access. On cache miss,
     /// reconstructs state by traversing the chain from disk.
     pub fn get_state(&self, branch_id: BranchId, state_id: &str) -> Result<Option<Vec<u8>>> {
+        self.get_state_limited(branch_id, state_id, None)
+    }
+
+    /// `get_state`, failing with `StateTooLarge` if the value is larger than
+    /// `limit` bytes. A reconstruction stops as soon as it grows past it.
+    pub fn get_state_limited(
+        &self,
+        branch_id: BranchId,
+        state_id: &str,
+        limit: Option<u64>,
+    ) -> Result<Option<Vec<u8>>> {
         let index = self.index.read();
 
         let key = (branch_id, state_id.to_string());
@@ -285,6 +469,9 @@ impl StateManager {
             Some(h) => h.clone(),
             None => return Ok(None),
         };
+        let strategy = index.strategies.get(state_id);
+        let capacity = strategy.and_then(StateStrategy::capacity);
+        let is_snapshot = matches!(strategy, Some(StateStrategy::Snapshot));
         drop(index);
 
         let cache_key = format!("{}:{}", branch_id.0, state_id);
@@ -294,6 +481,7 @@ impl StateManager {
             let mut cache = self.cache.write();
             if let Some(cached) = cache.get(&cache_key) {
                 if cached.head_offset == head.head_offset {
+                    check_reconstruct_size(cached.value.len(), limit)?;
                     return Ok(Some(cached.value.clone()));
                 }
                 // Stale cache entry, will reconstruct
@@ -304,9 +492,20 @@ impl StateManager {
         let log = self
             .log
             .as_ref()
-            .ok_or_else(|| StoreError::NotInitialized)?;
+            .ok_or_else(|| StoreError::NotInitialized(self.path.clone()))?;
 
-        let value = self.reconstruct_from_disk(log, head.head_offset)?;
+        let head_value = if is_snapshot {
+            Self::read_full_value(log, state_id, head.head_offset)?
+        } else {
+            None
+        };
+        let value = match head_value {
+            Some(value) => {
+                check_reconstruct_size(value.len(), limit)?;
+                value
+            }
+            None => self.reconstruct_from_disk(log, state_id, head.head_offset, capacity, limit)?,
+        };
 
         // Cache the result
         {
@@ -323,6 +522,21 @@ impl StateManager {
         Ok(Some(value))
     }
 
+    /// The value written by the update at `offset`, if it replaces the whole state.
+    ///
+    /// For `Snapshot` states a `Set` or `Snapshot` supersedes everything
+    /// before it, so the chain behind it needn't be read. Returns None for
+    /// partial updates (`Field`), which need the chain walked.
+    fn read_full_value(log: &RecordLog, state_id: &str, offset: u64) -> Result<Option<Vec<u8>>> {
+        let record = log.read_at(offset)?;
+        match decode_update(&record, state_id, offset)?.operation {
+            StateOperation::Set(value)
+            | StateOperation::SetIfSequence { value, .. }
+            | StateOperation::Snapshot(value) => Ok(Some(value)),
+            _ => Ok(None),
+        }
+    }
+
     /// Reconstruct state by traversing chain from disk.
     ///
     /// For AppendLog with incremental snapshots:
@@ -330,8 +544,17 @@ impl StateManager {
     /// - DeltaSnapshot: Consolidates ops before it; continue to find more deltas/full snapshot
     /// - Regular ops: Collect only if before any snapshot
     ///
-    /// Reconstruction: base_snapshot + delta_snapshots + recent_ops
-    fn reconstruct_from_disk(&self, log: &RecordLog, head_offset: u64) -> Result<Vec<u8>> {
+    /// Reconstruction: base_snapshot + delta_snapshots + recent_ops, trimmed
+    /// to `capacity` as each is applied, and abandoned as soon as the value
+    /// grows past `limit` bytes.
+    fn reconstruct_from_disk(
+        &self,
+        log: &RecordLog,
+        state_id: &str,
+        head_offset: u64,
+        capacity: Option<usize>,
+        limit: Option<u64>,
+    ) -> Result<Vec<u8>> {
         let mut operations = Vec::new();
         let mut current_offset = Some(head_offset);
         let mut hit_snapshot = false; // Once we hit any snapshot, stop collecting regular ops
@@ -341,11 +564,10 @@ impl StateManager {
             let record = log.read_at(offset)?;
 
             // Parse the state update from the record payload
-            let update: StateUpdateRecord = serde_json::from_slice(&record.payload)
-                .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+            let update = decode_update(&record, state_id, offset)?;
 
             match &update.operation {
-                StateOperation::Snapshot(_) => {
+                StateOperation::Snapshot(_) | StateOperation::Clear => {
                     // Full snapshot - add it and stop completely
                     operations.push(update.operation.clone());
                     break;
@@ -372,7 +594,8 @@ impl StateManager {
 
         let mut state = Vec::new();
         for op in operations {
-            state = apply_operation(state, op)?;
+            state = apply_operation_with_capacity(state, op, capacity)?;
+            check_reconstruct_size(state.len(), limit)?;
         }
 
         Ok(state)
@@ -396,20 +619,17 @@ impl StateManager {
         let index = self.index.read();
 
         let key = (branch_id, state_id.to_string());
-        let head = match index.heads.get(&key) {
-            Some(h) => h,
-            None => return None,
-        };
-
-        let strategy = match index.strategies.get(state_id) {
-            Some(s) => s,
-            None => return None,
+        let head = index.heads.get(&key)?;
+        let strategy = index.strategies.get(state_id)?;
+        let policy = index.policies.get(&key);
+        let ops_threshold = |registered: u64| {
+            policy.and_then(|p| p.delta_snapshot_every).unwrap_or(registered)
         };
 
         match strategy {
             StateStrategy::Snapshot => None, // Set strategy always stores full value
             StateStrategy::Delta { snapshot_every } => {
-                if head.ops_since_delta_snapshot >= *snapshot_every {
+                if head.ops_since_delta_snapshot >= ops_threshold(*snapshot_every) {
                     Some(SnapshotNeeded::Full)
                 } else {
                     None
@@ -418,11 +638,19 @@ impl StateManager {
             StateStrategy::AppendLog {
                 delta_snapshot_every,
                 full_snapshot_every,
+            }
+            | StateStrategy::RingBuffer {
+                delta_snapshot_every,
+                full_snapshot_every,
+                ..
             } => {
+                let full_snapshot_every = policy
+                    .and_then(|p| p.full_snapshot_every)
+                    .unwrap_or(*full_snapshot_every);
                 // Check if full snapshot is needed first
-                if head.delta_snapshots_since_full >= *full_snapshot_every {
+                if head.delta_snapshots_since_full >= full_snapshot_every {
                     Some(SnapshotNeeded::Full)
-                } else if head.ops_since_delta_snapshot >= *delta_snapshot_every {
+                } else if head.ops_since_delta_snapshot >= ops_threshold(*delta_snapshot_every) {
                     // If there are non-Append operations (Edit, Redact) since the last snapshot,
                     // we need a full snapshot instead of a delta, because delta snapshots
                     // only track Append operations.
@@ -437,7 +665,7 @@ impl StateManager {
             }
             StateStrategy::Struct { .. } => {
                 // For struct, snapshot when ops exceed threshold
-                if head.ops_since_delta_snapshot >= 100 {
+                if head.ops_since_delta_snapshot >= ops_threshold(100) {
                     Some(SnapshotNeeded::Full)
                 } else {
                     None
@@ -451,9 +679,117 @@ impl StateManager {
         self.snapshot_needed(branch_id, state_id).is_some()
     }
 
-    /// Copy state chain heads from one branch to another (for branching).
+    /// Snapshot policy for a state on a branch (the default if none is set).
+    pub fn snapshot_policy(&self, branch_id: BranchId, state_id: &str) -> SnapshotPolicy {
+        self.index
+            .read()
+            .policies
+            .get(&(branch_id, state_id.to_string()))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Replace the strategy of a registered state, whatever its data.
+    ///
+    /// For `Store::migrate_state_strategy`, which re-seeds the chains first.
+    pub fn set_strategy(&self, state_id: &str, strategy: StateStrategy) -> Result<()> {
+        let mut index = self.index_mut();
+        match index.strategies.get_mut(state_id) {
+            Some(existing) => {
+                *existing = strategy;
+                Ok(())
+            }
+            None => Err(StoreError::StateNotRegistered(state_id.to_string())),
+        }
+    }
+
+    /// Set the snapshot policy for a state on a branch.
+    ///
+    /// Setting the default policy removes the override.
+    pub fn set_snapshot_policy(
+        &self,
+        branch_id: BranchId,
+        state_id: &str,
+        policy: SnapshotPolicy,
+    ) -> Result<()> {
+        let mut index = self.index_mut();
+        if !index.strategies.contains_key(state_id) {
+            return Err(StoreError::StateNotRegistered(state_id.to_string()));
+        }
+
+        let key = (branch_id, state_id.to_string());
+        if policy == SnapshotPolicy::default() {
+            index.policies.remove(&key);
+        } else {
+            index.policies.insert(key, policy);
+        }
+        Ok(())
+    }
+
+    /// Record that `state_id`'s history behind the full snapshot at
+    /// `snapshot_offset` was pruned (see `Store::prune_history`).
+    pub fn mark_pruned(&self, state_id: &str, snapshot_offset: u64) {
+        let mut index = self.index_mut();
+        let offsets = index.pruned.entry(state_id.to_string()).or_default();
+        if !offsets.contains(&snapshot_offset) {
+            offsets.push(snapshot_offset);
+        }
+    }
+
+    /// Offsets of the snapshots a state's history was pruned at.
+    pub fn pruned_at(&self, state_id: &str) -> Vec<u64> {
+        self.index.read().pruned.get(state_id).cloned().unwrap_or_default()
+    }
+
+    /// Chain heads for a state on every branch that has one.
+    pub fn heads_for_state(&self, state_id: &str) -> Vec<(BranchId, StateChainHead)> {
+        self.index
+            .read()
+            .heads
+            .iter()
+            .filter(|((_, id), _)| id == state_id)
+            .map(|((branch_id, _), head)| (*branch_id, head.clone()))
+            .collect()
+    }
+
+    /// Point chain heads at their records' new offsets after the log was
+    /// rewritten, dropping heads and policies of branches not in `live`.
+    /// Heads whose record is gone are dropped too.
+    pub(crate) fn remap_offsets(&self, offsets: &HashMap<u64, u64>, live: &HashSet<BranchId>) {
+        let mut index = self.index_mut();
+        let remap = |offset: Option<u64>| offset.and_then(|o| offsets.get(&o).copied());
+
+        index.heads.retain(|(branch_id, _), head| {
+            let Some(head_offset) = remap(Some(head.head_offset)) else {
+                return false;
+            };
+            head.head_offset = head_offset;
+            head.last_delta_snapshot_offset = remap(head.last_delta_snapshot_offset);
+            head.last_full_snapshot_offset = remap(head.last_full_snapshot_offset);
+            live.contains(branch_id)
+        });
+        index.policies.retain(|(branch_id, _), _| live.contains(branch_id));
+        for pruned in index.pruned.values_mut() {
+            *pruned = pruned.iter().filter_map(|&o| remap(Some(o))).collect();
+        }
+        index.pruned.retain(|_, pruned| !pruned.is_empty());
+        drop(index);
+
+        self.cache.write().clear();
+    }
+
+    /// Copy state chain heads and snapshot policies from one branch to
+    /// another (for branching).
     pub fn copy_heads_for_branch(&self, from_branch: BranchId, to_branch: BranchId) {
-        let mut index = self.index.write();
+        let mut index = self.index_mut();
+
+        let policies_to_copy: Vec<_> = index.policies.iter()
+            .filter(|((branch_id, _), _)| *branch_id == from_branch)
+            .map(|((_, state_id), policy)| (state_id.clone(), policy.clone()))
+            .collect();
+        for (state_id, policy) in policies_to_copy {
+            index.policies.insert((to_branch, state_id), policy);
+        }
 
         // Find all heads for the source branch
         let heads_to_copy: Vec<_> = index.heads.iter()
@@ -478,7 +814,7 @@ impl StateManager {
         head_offset: u64,
         item_count: usize,
     ) {
-        let mut index = self.index.write();
+        let mut index = self.index_mut();
 
         let head = StateChainHead {
             head_offset,
@@ -494,6 +830,58 @@ impl StateManager {
         index.heads.insert((branch_id, state_id.to_string()), head);
     }
 
+    /// Forget a branch's chain head for a state, as if it had never been
+    /// updated there.
+    pub fn remove_head(&self, branch_id: BranchId, state_id: &str) {
+        self.index_mut().heads.remove(&(branch_id, state_id.to_string()));
+        self.cache.write().clear();
+    }
+
+    /// Replace chain heads that no longer point at a surviving update.
+    ///
+    /// Heads that are still valid are kept (they may be inherited from a
+    /// parent branch), unless the branch's own updates have moved past them.
+    /// Heads with nothing to fall back on are removed. Returns the number of
+    /// heads changed.
+    pub(crate) fn repair_heads(&self, rebuilt: HeadRebuilder) -> usize {
+        let mut index = self.index_mut();
+
+        let mut keys: Vec<(BranchId, String)> = index.heads.keys().cloned().collect();
+        keys.extend(
+            rebuilt
+                .own
+                .keys()
+                .filter(|key| !index.heads.contains_key(*key))
+                .cloned(),
+        );
+
+        let mut repaired = 0;
+        for key in keys {
+            let existing = index
+                .heads
+                .get(&key)
+                .filter(|head| rebuilt.is_valid(&key.1, head.head_offset));
+            let replacement = match (existing, rebuilt.own.get(&key)) {
+                (Some(current), Some(own)) if own.head_offset > current.head_offset => {
+                    Some(own.clone())
+                }
+                (Some(_), _) => continue,
+                (None, own) => own.cloned(),
+            };
+
+            repaired += 1;
+            match replacement {
+                Some(head) => index.heads.insert(key, head),
+                None => index.heads.remove(&key),
+            };
+        }
+
+        if repaired > 0 {
+            self.cache.write().clear();
+        }
+        repaired
+    }
+
     /// Get all registered state IDs.
     pub fn state_ids(&self) -> Vec<String> {
         self.index.read().strategies.keys().cloned().collect()
@@ -535,7 +923,8 @@ impl StateManager {
     /// Count operations in the chain for a state.
     ///
     /// This traverses the chain to count how many records would be eliminated
-    /// by creating a full snapshot at the current head.
+    /// by creating a full snapshot at the current head. Pruned history is not
+    /// counted: the walk stops at the snapshot it was pruned at.
     pub fn count_chain_operations(&self, branch_id: BranchId, state_id: &str) -> Result<Option<ChainStats>> {
         let index = self.index.read();
         let key = (branch_id, state_id.to_string());
@@ -543,12 +932,13 @@ impl StateManager {
             Some(h) => h.clone(),
             None => return Ok(None),
         };
+        let pruned = index.pruned.get(state_id).cloned().unwrap_or_default();
         drop(index);
 
         let log = self
             .log
             .as_ref()
-            .ok_or_else(|| StoreError::NotInitialized)?;
+            .ok_or_else(|| StoreError::NotInitialized(self.path.clone()))?;
 
         let mut total_ops = 0u64;
         let mut ops_before_snapshot = 0u64;
@@ -561,8 +951,7 @@ impl StateManager {
             let record = log.read_at(offset)?;
             let record_size = record.payload.len() as u64;
 
-            let update: StateUpdateRecord = serde_json::from_slice(&record.payload)
-                .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+            let update = decode_update(&record, state_id, offset)?;
 
             total_ops += 1;
             total_bytes += record_size;
@@ -572,9 +961,12 @@ impl StateManager {
                 bytes_before_snapshot += record_size;
             }
 
-            if matches!(update.operation, StateOperation::Snapshot(_)) {
+            if matches!(update.operation, StateOperation::Snapshot(_) | StateOperation::Clear) {
                 found_full_snapshot = true;
             }
+            if pruned.contains(&offset) {
+                break;
+            }
 
             current_offset = update.prev_update_offset;
         }
@@ -589,12 +981,45 @@ impl StateManager {
     }
 
     /// Save state index to file.
+    ///
+    /// Skipped if nothing changed since the last save.
     pub fn save(&self) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        self.save_to(&self.path).inspect_err(|_| self.dirty.store(true, Ordering::Release))
+    }
+
+    /// Whether the index has changes `save` would write.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.load(Ordering::Acquire)
+    }
+
+    /// Lock the index for a change, marking it dirty. The flag is set with
+    /// the lock held, so a concurrent `save` can't clear it and then write
+    /// the index from before the change.
+    fn index_mut(&self) -> RwLockWriteGuard<'_, StateIndex> {
+        let index = self.index.write();
+        self.dirty.store(true, Ordering::Release);
+        index
+    }
+
+    /// Read the state index back from its file, dropping unsaved changes
+    /// and cached states.
+    pub fn reload(&self) -> Result<()> {
+        self.load_from_file()?;
+        self.cache.write().clear();
+        self.dirty.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    /// Save the state index to another file, leaving this one alone.
+    pub fn save_to(&self, path: &Path) -> Result<()> {
         let mut file = OpenOptions::new()
             .write(true)
             .create(true)
             .truncate(true)
-            .open(&self.path)?;
+            .open(path)?;
 
         // Write magic
         file.write_all(STATE_INDEX_MAGIC)?;
@@ -847,6 +1272,69 @@ mod tests {
         assert_eq!(head.ops_since_delta_snapshot, 1);
     }
 
+    #[test]
+    fn test_snapshot_strategy_reads_only_head() {
+        let (_dir, log, manager) = setup_test();
+
+        manager
+            .register_state(StateRegistration {
+                id: "config".to_string(),
+                strategy: StateStrategy::Snapshot,
+                initial_value: None,
+            })
+            .unwrap();
+
+        // The first update links to an offset past the end of the log, so
+        // walking the chain to it would fail
+        let mut prev = Some(u64::MAX / 2);
+        for i in 1..=100u64 {
+            let op = StateOperation::Set(format!("{{\"version\":{}}}", i).into_bytes());
+            let offset = append_state_update(&log, "config", prev, op.clone(), i);
+            manager.record_update(TEST_BRANCH, "config", offset, &op).unwrap();
+            prev = Some(offset);
+        }
+
+        let state = manager.get_state(TEST_BRANCH, "config").unwrap().unwrap();
+        assert_eq!(state, b"{\"version\":100}");
+
+        // A partial update at the head needs the chain behind it
+        let op = StateOperation::Field {
+            name: "name".to_string(),
+            operation: Box::new(StateOperation::Set(b"\"main\"".to_vec())),
+        };
+        let offset = append_state_update(&log, "config", prev, op.clone(), 101);
+        manager.record_update(TEST_BRANCH, "config", offset, &op).unwrap();
+        assert!(manager.get_state(TEST_BRANCH, "config").is_err());
+    }
+
+    #[test]
+    fn test_snapshot_strategy_field_walks_chain() {
+        let (_dir, log, manager) = setup_test();
+
+        manager
+            .register_state(StateRegistration {
+                id: "config".to_string(),
+                strategy: StateStrategy::Snapshot,
+                initial_value: None,
+            })
+            .unwrap();
+
+        let set = StateOperation::Set(b"{\"version\":1}".to_vec());
+        let offset1 = append_state_update(&log, "config", None, set.clone(), 1);
+        manager.record_update(TEST_BRANCH, "config", offset1, &set).unwrap();
+
+        let field = StateOperation::Field {
+            name: "name".to_string(),
+            operation: Box::new(StateOperation::Set(b"\"main\"".to_vec())),
+        };
+        let offset2 = append_state_update(&log, "config", Some(offset1), field.clone(), 2);
+        manager.record_update(TEST_BRANCH, "config", offset2, &field).unwrap();
+
+        let state = manager.get_state(TEST_BRANCH, "config").unwrap().unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&state).unwrap();
+        assert_eq!(value, serde_json::json!({"version": 1, "name": "main"}));
+    }
+
     #[test]
     fn test_cache_hit() {
         let (_dir, log, manager) = setup_test();
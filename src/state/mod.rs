@@ -8,6 +8,8 @@ mod manager;
 mod operations;
 
 pub use manager::{
-    ChainStats, CompactionStats, SnapshotNeeded, StateChainHead, StateIndex, StateManager,
+    ChainStats, CompactionStats, SnapshotNeeded, SnapshotPolicy, StateChainHead, StateIndex,
+    StateManager,
 };
-pub use operations::apply_operation;
+pub(crate) use manager::{check_reconstruct_size, decode_update, HeadRebuilder};
+pub use operations::{apply_operation, apply_operation_with_capacity, validate_operation};
@@ -1,17 +1,137 @@
 //! State operation application.
 
 use crate::error::{Result, StoreError};
-use crate::types::StateOperation;
+use crate::types::{StateOperation, StateStrategy};
+
+/// Check that an operation is allowed for a state's registered strategy.
+///
+/// `Snapshot` and `DeltaSnapshot` are written internally by compaction and
+/// are accepted for every strategy. `Field` operations on a `Struct` state
+/// are checked against the field's own strategy when one is declared.
+pub fn validate_operation(strategy: &StateStrategy, operation: &StateOperation) -> Result<()> {
+    let allowed = match (strategy, operation) {
+        (_, StateOperation::Snapshot(_)) | (_, StateOperation::DeltaSnapshot(_)) => true,
+        (StateStrategy::Snapshot, StateOperation::Set(_))
+        | (StateStrategy::Snapshot, StateOperation::SetIfSequence { .. })
+        | (StateStrategy::Snapshot, StateOperation::Field { .. }) => true,
+        (StateStrategy::Delta { .. }, StateOperation::Set(_))
+        | (StateStrategy::Delta { .. }, StateOperation::Delta { .. }) => true,
+        (StateStrategy::AppendLog { .. }, StateOperation::Append(_))
+        | (StateStrategy::AppendLog { .. }, StateOperation::AppendMany(_))
+        | (StateStrategy::AppendLog { .. }, StateOperation::Prepend(_))
+        | (StateStrategy::AppendLog { .. }, StateOperation::Redact { .. })
+        | (StateStrategy::AppendLog { .. }, StateOperation::Edit { .. })
+        | (StateStrategy::AppendLog { .. }, StateOperation::Clear) => true,
+        (StateStrategy::RingBuffer { .. }, StateOperation::Append(_))
+        | (StateStrategy::RingBuffer { .. }, StateOperation::AppendMany(_))
+        | (StateStrategy::RingBuffer { .. }, StateOperation::Prepend(_))
+        | (StateStrategy::RingBuffer { .. }, StateOperation::Redact { .. })
+        | (StateStrategy::RingBuffer { .. }, StateOperation::Edit { .. })
+        | (StateStrategy::RingBuffer { .. }, StateOperation::Clear) => true,
+        (StateStrategy::Struct { .. }, StateOperation::Set(_)) => true,
+        (StateStrategy::Struct { fields }, StateOperation::Field { name, operation }) => {
+            if let Some(field_strategy) = fields.get(name) {
+                return validate_operation(field_strategy, operation);
+            }
+            true
+        }
+        _ => false,
+    };
+
+    if allowed {
+        Ok(())
+    } else {
+        Err(StoreError::InvalidOperation(format!(
+            "{} operation is not supported by the {} strategy",
+            operation_name(operation),
+            strategy_name(strategy)
+        )))
+    }
+}
+
+/// Human-readable name of an operation, for error messages.
+fn operation_name(operation: &StateOperation) -> &'static str {
+    match operation {
+        StateOperation::Set(_) => "Set",
+        StateOperation::SetIfSequence { .. } => "SetIfSequence",
+        StateOperation::Delta { .. } => "Delta",
+        StateOperation::Append(_) => "Append",
+        StateOperation::AppendMany(_) => "AppendMany",
+        StateOperation::Prepend(_) => "Prepend",
+        StateOperation::Redact { .. } => "Redact",
+        StateOperation::Edit { .. } => "Edit",
+        StateOperation::Snapshot(_) => "Snapshot",
+        StateOperation::DeltaSnapshot(_) => "DeltaSnapshot",
+        StateOperation::Clear => "Clear",
+        StateOperation::Field { .. } => "Field",
+    }
+}
+
+/// Human-readable name of a strategy, for error messages.
+fn strategy_name(strategy: &StateStrategy) -> &'static str {
+    match strategy {
+        StateStrategy::Snapshot => "Snapshot",
+        StateStrategy::Delta { .. } => "Delta",
+        StateStrategy::AppendLog { .. } => "AppendLog",
+        StateStrategy::RingBuffer { .. } => "RingBuffer",
+        StateStrategy::Struct { .. } => "Struct",
+    }
+}
+
+/// Apply a state operation to a value, keeping at most `capacity` items.
+///
+/// With a capacity (see `StateStrategy::capacity`), operations that add
+/// items drop the oldest ones once the array grows past it: items at the
+/// front for appends, items at the back for a prepend. Without one this is
+/// `apply_operation`.
+pub fn apply_operation_with_capacity(
+    state: Vec<u8>,
+    operation: StateOperation,
+    capacity: Option<usize>,
+) -> Result<Vec<u8>> {
+    let grows = matches!(
+        operation,
+        StateOperation::Append(_)
+            | StateOperation::AppendMany(_)
+            | StateOperation::Prepend(_)
+            | StateOperation::Snapshot(_)
+            | StateOperation::DeltaSnapshot(_)
+    );
+    let from_back = matches!(operation, StateOperation::Prepend(_));
+    let state = apply_operation(state, operation)?;
+    match capacity {
+        Some(capacity) if grows => trim_to_capacity(state, capacity, from_back),
+        _ => Ok(state),
+    }
+}
+
+/// Drop items from the front of a JSON array (or its back, with
+/// `from_back`) until at most `capacity` remain.
+fn trim_to_capacity(state: Vec<u8>, capacity: usize, from_back: bool) -> Result<Vec<u8>> {
+    let mut arr: Vec<serde_json::Value> = serde_json::from_slice(&state)
+        .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+    if arr.len() <= capacity {
+        return Ok(state);
+    }
+    if from_back {
+        arr.truncate(capacity);
+    } else {
+        arr.drain(..arr.len() - capacity);
+    }
+    serde_json::to_vec(&arr).map_err(|e| StoreError::Serialization(e.to_string()))
+}
 
 /// Apply a state operation to a value.
 ///
 /// The value is expected to be JSON-encoded for structured operations.
 pub fn apply_operation(state: Vec<u8>, operation: StateOperation) -> Result<Vec<u8>> {
     match operation {
-        StateOperation::Set(value) => Ok(value),
+        StateOperation::Set(value) | StateOperation::SetIfSequence { value, .. } => Ok(value),
 
         StateOperation::Snapshot(value) => Ok(value),
 
+        StateOperation::Clear => Ok(b"[]".to_vec()),
+
         StateOperation::DeltaSnapshot(delta_items) => {
             // Delta snapshot contains items added since last delta/full snapshot.
             // Concatenate with current state (both are JSON arrays).
@@ -53,6 +173,41 @@ pub fn apply_operation(state: Vec<u8>, operation: StateOperation) -> Result<Vec<
             serde_json::to_vec(&arr).map_err(|e| StoreError::Serialization(e.to_string()))
         }
 
+        StateOperation::AppendMany(items) => {
+            // Parse state as JSON array, append each item in order
+            let mut arr: Vec<serde_json::Value> = if state.is_empty() {
+                Vec::new()
+            } else {
+                serde_json::from_slice(&state)
+                    .map_err(|e| StoreError::Deserialization(e.to_string()))?
+            };
+
+            for item in items {
+                let item_value: serde_json::Value = serde_json::from_slice(&item)
+                    .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+                arr.push(item_value);
+            }
+
+            serde_json::to_vec(&arr).map_err(|e| StoreError::Serialization(e.to_string()))
+        }
+
+        StateOperation::Prepend(item) => {
+            // Parse state as JSON array, insert item at the front
+            let mut arr: Vec<serde_json::Value> = if state.is_empty() {
+                Vec::new()
+            } else {
+                serde_json::from_slice(&state)
+                    .map_err(|e| StoreError::Deserialization(e.to_string()))?
+            };
+
+            let item_value: serde_json::Value = serde_json::from_slice(&item)
+                .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+
+            arr.insert(0, item_value);
+
+            serde_json::to_vec(&arr).map_err(|e| StoreError::Serialization(e.to_string()))
+        }
+
         StateOperation::Redact { start, end } => {
             // Parse state as JSON array, remove range
             let mut arr: Vec<serde_json::Value> = if state.is_empty() {
@@ -158,6 +313,46 @@ mod tests {
         assert_eq!(arr, vec![1, 2]);
     }
 
+    #[test]
+    fn test_append_with_capacity() {
+        let mut state = vec![];
+        for i in 1..=5 {
+            let op = StateOperation::Append(i.to_string().into_bytes());
+            state = apply_operation_with_capacity(state, op, Some(3)).unwrap();
+        }
+        let arr: Vec<i32> = serde_json::from_slice(&state).unwrap();
+        assert_eq!(arr, vec![3, 4, 5]);
+
+        let op = StateOperation::AppendMany(vec![b"6".to_vec(), b"7".to_vec()]);
+        let state = apply_operation_with_capacity(state, op, Some(3)).unwrap();
+        let arr: Vec<i32> = serde_json::from_slice(&state).unwrap();
+        assert_eq!(arr, vec![5, 6, 7]);
+
+        // Edits address the items still held
+        let op = StateOperation::Edit { index: 0, new_value: b"50".to_vec() };
+        let state = apply_operation_with_capacity(state, op, Some(3)).unwrap();
+        let arr: Vec<i32> = serde_json::from_slice(&state).unwrap();
+        assert_eq!(arr, vec![50, 6, 7]);
+    }
+
+    #[test]
+    fn test_prepend_with_capacity() {
+        let state = serde_json::to_vec(&json!([2, 3])).unwrap();
+        let state = apply_operation(state, StateOperation::Prepend(b"1".to_vec())).unwrap();
+        let arr: Vec<i32> = serde_json::from_slice(&state).unwrap();
+        assert_eq!(arr, vec![1, 2, 3]);
+
+        // A full buffer keeps the prepended item and drops the last one
+        let state = apply_operation_with_capacity(state, StateOperation::Prepend(b"0".to_vec()), Some(3)).unwrap();
+        let arr: Vec<i32> = serde_json::from_slice(&state).unwrap();
+        assert_eq!(arr, vec![0, 1, 2]);
+
+        // Appends still drop from the front
+        let state = apply_operation_with_capacity(state, StateOperation::Append(b"9".to_vec()), Some(3)).unwrap();
+        let arr: Vec<i32> = serde_json::from_slice(&state).unwrap();
+        assert_eq!(arr, vec![1, 2, 9]);
+    }
+
     #[test]
     fn test_redact() {
         let state = serde_json::to_vec(&json!([1, 2, 3, 4, 5])).unwrap();
@@ -212,6 +407,30 @@ mod tests {
         assert_eq!(obj["items"], json!([1, 2, 3]));
     }
 
+    #[test]
+    fn test_validate_operation() {
+        let append_log = StateStrategy::AppendLog {
+            delta_snapshot_every: 10,
+            full_snapshot_every: 5,
+        };
+
+        assert!(validate_operation(&append_log, &StateOperation::Append(b"1".to_vec())).is_ok());
+        assert!(validate_operation(&append_log, &StateOperation::Snapshot(b"[]".to_vec())).is_ok());
+        assert!(validate_operation(&append_log, &StateOperation::Set(b"[]".to_vec())).is_err());
+
+        assert!(validate_operation(&StateStrategy::Snapshot, &StateOperation::Set(b"1".to_vec())).is_ok());
+        assert!(validate_operation(
+            &StateStrategy::Snapshot,
+            &StateOperation::DeltaSnapshot(b"[]".to_vec())
+        )
+        .is_ok());
+        assert!(validate_operation(
+            &StateStrategy::Snapshot,
+            &StateOperation::Edit { index: 0, new_value: b"1".to_vec() }
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_delta_snapshot() {
         // Start with base array
@@ -1,20 +1,72 @@
 //! Main Store struct tying all components together.
 
-use crate::blobs::BlobStorage;
-use crate::branches::BranchManager;
+use crate::blobs::{
+    canonical_json, normalize_content_type, BlobCacheConfig, BlobCacheStats, BlobStorage,
+};
+use crate::branches::{BranchGcOptions, BranchGcResult, BranchGraph, BranchManager, MAIN_BRANCH};
 use crate::error::{Result, StoreError};
-use crate::records::{RecordIndex, RecordLog};
-use crate::state::StateManager;
-use crate::subscriptions::{SubscriptionConfig, SubscriptionHandle, SubscriptionId, SubscriptionManager};
+use crate::observer::{NoopObserver, StoreObserver};
+use crate::records::schema::RecordSchema;
+use crate::records::{RecordFields, RecordIndex, RecordLog, SyncPolicy};
+use crate::state::{
+    check_reconstruct_size, decode_update, validate_operation, HeadRebuilder, SnapshotNeeded, SnapshotPolicy, StateChainHead,
+    StateManager,
+};
+use crate::subscriptions::{
+    BlockingEventIter, StateWatcher, StoreEvent, SubscriptionConfig, SubscriptionFilter,
+    SubscriptionHandle, SubscriptionId, SubscriptionManager,
+};
 use crate::types::{
-    Blob, Branch, Hash, Record, RecordId, RecordInput, Sequence,
-    StateOperation, StateRegistration, StateUpdateRecord, StoreStats, Timestamp,
+    Blob, BlobInfo, Branch, BranchId, Change, ChangeCursor, ChangeKey, FieldValue, Hash, HashAlgorithm,
+    PayloadEncoding, Record, RecordId, RecordInput, Sequence, StateOperation, StateRegistration, StateSizeInfo,
+    StateStrategy, StateUpdateRecord, StoreStats, Timestamp,
 };
+use crate::wal::{JournaledRecord, WalEntryStatus, WalOperation, WriteAheadLog};
 use fs2::FileExt;
-use parking_lot::Mutex;
+use parking_lot::{Mutex, RwLock, RwLockReadGuard};
+use serde::de::DeserializeOwned;
+use serde_json::value::RawValue;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
+use std::io::{BufRead, Write};
 use std::path::{Path, PathBuf};
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Journal file inside the store directory.
+const WAL_FILE: &str = "store.wal";
+
+/// Journaled changes left unpersisted before a write runs `sync` itself,
+/// which drops their entries from the WAL.
+const WAL_CHECKPOINT_ENTRIES: usize = 1000;
+
+/// Payload fields registered with `Store::add_field_index` (JSON list).
+const FIELD_INDEX_FILE: &str = "fields.json";
+
+/// Schema blob of each record type registered with
+/// `Store::register_record_schema` (JSON map of type to hash hex).
+const RECORD_SCHEMA_FILE: &str = "record_schemas.json";
+
+/// Node ID and per-source replication watermarks (JSON, see `ReplicationState`).
+const REPLICATION_FILE: &str = "replication.json";
+
+/// Directory `Store::compact` builds its result in before installing it.
+const COMPACT_DIR: &str = "compact";
+
+/// Marker in `COMPACT_DIR` saying the compaction is complete and must be
+/// installed, on the next open if not before.
+const COMPACT_COMMIT_FILE: &str = "COMMIT";
+
+/// Blobs a committed compaction deletes (hash hex, one per line), in
+/// `COMPACT_DIR`.
+const COMPACT_BLOBS_FILE: &str = "blobs.delete";
+
+/// Store files a compaction replaces, as named in `COMPACT_DIR` and in the
+/// store directory.
+const COMPACT_FILES: [&str; 3] = ["records.log", "state.bin", "branches.bin"];
 
 /// Store configuration.
 #[derive(Clone, Debug)]
@@ -25,8 +77,184 @@ pub struct StoreConfig {
     /// Blob cache size (number of blobs).
     pub blob_cache_size: usize,
 
+    /// Blob cache limits and eviction policy. When set, replaces
+    /// `blob_cache_size`; `None` keeps an LRU cache of `blob_cache_size`
+    /// blobs with no byte limit.
+    pub blob_cache: Option<BlobCacheConfig>,
+
     /// Whether to create the store if it doesn't exist.
     pub create_if_missing: bool,
+
+    /// Open without taking the exclusive lock and reject all writes.
+    ///
+    /// A read-only handle sees whatever the writer has flushed to disk at
+    /// open time. Mutating methods return `StoreError::ReadOnly`.
+    pub read_only: bool,
+
+    /// How long a writer waits for read-only handles to close before
+    /// giving up with `StoreError::Locked`. `None` fails immediately.
+    pub lock_timeout: Option<Duration>,
+
+    /// When appended records are fsynced to the log.
+    ///
+    /// Stricter policies lose less on a machine crash but pay an fsync per
+    /// sync point. See `SyncPolicy` for the tradeoffs. Writes are also
+    /// journaled to the write-ahead log, which is fsynced by the same
+    /// policy: a process crash loses no completed write, a machine crash
+    /// what the policy allows. The journal holds a copy of each write until
+    /// the next `sync`, which runs by itself every 1000 journaled writes
+    /// (and each interval under `Interval`).
+    pub sync_policy: SyncPolicy,
+
+    /// Hash bytes used as nested blob shard directories: 1 (256 dirs) or
+    /// 2 (65,536 dirs, for stores with tens of millions of blobs).
+    ///
+    /// Recorded in the manifest at creation; `None` creates with depth 1
+    /// and opens with whatever the manifest says. Opening with a different
+    /// depth set explicitly fails with `InvalidOperation`; existing blobs
+    /// are never re-sharded.
+    pub blob_shard_depth: Option<u8>,
+
+    /// Hash function blobs are addressed by. BLAKE3 hashes large blobs
+    /// considerably faster than the default SHA-256.
+    ///
+    /// Recorded in the manifest at creation. Opening with a different
+    /// algorithm fails with `InvalidOperation`, as do blob reads with a hash
+    /// of another algorithm.
+    pub blob_hash: HashAlgorithm,
+
+    /// Metrics hooks called as operations complete. `None` uses a no-op.
+    pub observer: Option<Arc<dyn StoreObserver>>,
+
+    /// Upgrade a store written in an older format when opening it.
+    ///
+    /// Runs the steps in `migrations::MIGRATIONS` up to the current format
+    /// and rewrites the manifest. Older formats that can still be read open
+    /// unchanged when this is off, so older builds can keep using them.
+    /// Ignored for read-only handles.
+    pub auto_migrate: bool,
+
+    /// Longest record type accepted by `append`, in bytes. At most 65,535,
+    /// the most the log's length prefix can hold.
+    pub max_record_type_len: usize,
+
+    /// Check blob content types and store them normalized (see
+    /// `normalize_content_type`). Invalid types are rejected with
+    /// `InvalidOperation`, and `list_blobs_by_type` normalizes its query.
+    pub validate_content_type: bool,
+
+    /// Reject appends whose `caused_by` or `linked_to` name a record that
+    /// doesn't exist (or has expired) with `RecordNotFound`. Replicated and
+    /// imported records are not checked.
+    pub validate_links: bool,
+
+    /// Defer building the record index until the first call that needs it,
+    /// instead of scanning the whole log in `open`. Blob and state reads
+    /// never need it, so handles that only do those skip the scan entirely.
+    /// A log that can't be read fails that first call rather than `open`.
+    pub lazy_index: bool,
+
+    /// Largest record payload `append` accepts, in bytes. Larger payloads
+    /// fail with `PayloadTooLarge`. `None` means no limit.
+    pub max_record_payload_bytes: Option<u64>,
+
+    /// Largest blob the `store_blob` family accepts, in bytes. Larger blobs
+    /// fail with `PayloadTooLarge`. `None` means no limit.
+    pub max_blob_bytes: Option<u64>,
+
+    /// Largest value `get_state` and its historical variants rebuild, in
+    /// bytes. Rebuilding stops with `StateTooLarge` as soon as the value
+    /// grows past it, before it can exhaust memory; read such states with
+    /// `iter_state_items` or `get_state_tail` instead.
+    /// Internal rebuilds (snapshots, migrations) aren't limited. `None`
+    /// means no limit.
+    pub max_reconstruct_bytes: Option<u64>,
+}
+
+impl StoreConfig {
+    /// Check the config for values that can't work.
+    ///
+    /// Called by `Store::open` and `Store::create`; returns `InvalidConfig`
+    /// naming the offending field.
+    pub fn validate(&self) -> Result<()> {
+        if self.path.as_os_str().is_empty() {
+            return Err(StoreError::InvalidConfig("path is empty".into()));
+        }
+        if self.blob_cache_size == 0 {
+            return Err(StoreError::InvalidConfig(
+                "blob_cache_size must be at least 1".into(),
+            ));
+        }
+        if let Some(cache) = &self.blob_cache {
+            if cache.max_entries == 0 {
+                return Err(StoreError::InvalidConfig(
+                    "blob_cache max_entries must be at least 1".into(),
+                ));
+            }
+            if cache.max_bytes == Some(0) {
+                return Err(StoreError::InvalidConfig(
+                    "blob_cache max_bytes must be at least 1".into(),
+                ));
+            }
+        }
+        if let Some(depth) = self.blob_shard_depth.filter(|depth| !(1..=2).contains(depth)) {
+            return Err(StoreError::InvalidConfig(format!(
+                "blob_shard_depth must be 1 or 2, got {}",
+                depth
+            )));
+        }
+        match self.sync_policy {
+            SyncPolicy::EveryN(0) => {
+                return Err(StoreError::InvalidConfig(
+                    "sync_policy EveryN needs a count of at least 1".into(),
+                ));
+            }
+            SyncPolicy::Interval(interval) if interval.is_zero() => {
+                return Err(StoreError::InvalidConfig(
+                    "sync_policy Interval must be non-zero".into(),
+                ));
+            }
+            _ => {}
+        }
+        if !(1..=u16::MAX as usize).contains(&self.max_record_type_len) {
+            return Err(StoreError::InvalidConfig(format!(
+                "max_record_type_len must be between 1 and {}, got {}",
+                u16::MAX,
+                self.max_record_type_len
+            )));
+        }
+
+        // A writer needs to be able to create files under the path
+        if !self.read_only {
+            let existing = self.path.ancestors().find(|p| p.exists());
+            if let Some(dir) = existing {
+                let metadata = fs::metadata(dir)?;
+                if !metadata.is_dir() {
+                    return Err(StoreError::InvalidConfig(format!(
+                        "{} is not a directory",
+                        dir.display()
+                    )));
+                }
+                if metadata.permissions().readonly() {
+                    return Err(StoreError::InvalidConfig(format!(
+                        "{} is not writable",
+                        dir.display()
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The blob cache settings in effect: `blob_cache`, or an LRU cache of
+    /// `blob_cache_size` blobs.
+    fn blob_cache_config(&self) -> BlobCacheConfig {
+        self.blob_cache.clone().unwrap_or(BlobCacheConfig {
+            max_entries: self.blob_cache_size,
+            ..Default::default()
+        })
+    }
 }
 
 impl Default for StoreConfig {
@@ -34,7 +262,22 @@ impl Default for StoreConfig {
         Self {
             path: PathBuf::from("./store"),
             blob_cache_size: 1000,
+            blob_cache: None,
             create_if_missing: true,
+            read_only: false,
+            lock_timeout: None,
+            sync_policy: SyncPolicy::default(),
+            blob_shard_depth: None,
+            blob_hash: HashAlgorithm::default(),
+            observer: None,
+            auto_migrate: false,
+            max_record_type_len: 256,
+            max_record_payload_bytes: None,
+            max_blob_bytes: None,
+            max_reconstruct_bytes: None,
+            validate_content_type: false,
+            validate_links: false,
+            lazy_index: false,
         }
     }
 }
@@ -54,11 +297,300 @@ pub struct CompactionSummary {
     pub states_needing_compaction: usize,
 }
 
+/// Result of `Store::prune_history`.
+#[derive(Clone, Debug, Default)]
+pub struct PruneReport {
+    /// States whose history was pruned.
+    pub states_pruned: usize,
+    /// Full snapshots written so each state had one at its head.
+    pub snapshots_created: usize,
+    /// Update records dropped from the index.
+    pub records_pruned: usize,
+    /// Pre-snapshot updates kept because another branch's chain still uses them.
+    pub records_kept: usize,
+}
+
+/// Result of `Store::export_branch`.
+#[derive(Clone, Debug, Default)]
+pub struct ExportManifest {
+    /// The branch that was exported.
+    pub branch: String,
+    /// Records written, state updates not included.
+    pub records: u64,
+    /// State updates written.
+    pub state_updates: u64,
+    /// States registered in the stream.
+    pub states: u64,
+    /// Blobs written.
+    pub blobs: u64,
+}
+
+/// Result of `Store::get_causation_tree`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CausationTree {
+    /// The record the traversal started from.
+    pub root: RecordId,
+    /// The root and its effects, breadth-first.
+    pub nodes: Vec<CausationNode>,
+    /// Whether effects past `max_depth` were left out.
+    pub truncated: bool,
+}
+
+impl CausationTree {
+    /// The node for a record, if it's in the tree.
+    pub fn get(&self, id: RecordId) -> Option<&CausationNode> {
+        self.nodes.iter().find(|node| node.id == id)
+    }
+}
+
+/// A record in a `CausationTree`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CausationNode {
+    pub id: RecordId,
+    /// Links from the root to here on the shortest path (0 for the root).
+    pub depth: usize,
+    /// Records in the tree listed in this one's `caused_by`.
+    pub causes: Vec<RecordId>,
+}
+
+/// Result of `Store::branch_size`: what a branch holds beyond its ancestors.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BranchSizeInfo {
+    /// Records appended on the branch after its branch point.
+    pub records: u64,
+    /// Log bytes those records take up.
+    pub bytes: u64,
+    /// How many of them are state updates.
+    pub state_operations: u64,
+}
+
+/// Result of `Store::compact_log_by_branch`.
+#[derive(Clone, Debug, Default)]
+pub struct VacuumStats {
+    /// Records read from the log.
+    pub records_scanned: u64,
+    /// Records removed from the log.
+    pub records_removed: u64,
+    /// Bytes the log shrank by.
+    pub bytes_reclaimed: u64,
+}
+
+/// Options for `Store::compact`.
+#[derive(Clone, Debug, Default)]
+pub struct CompactOptions {
+    /// Write a full snapshot of each state on the current branch first.
+    pub snapshot_states: bool,
+    /// Delete branches matching these criteria before the log is rewritten.
+    pub branch_gc: Option<BranchGcOptions>,
+    /// Delete blobs no record mentions. Off by default: which blobs are
+    /// referenced is guessed from record payloads, and a reference written
+    /// in a form `Store::compact` doesn't recognize gets its blob deleted.
+    pub gc_blobs: bool,
+}
+
+/// Result of `Store::compact`.
+#[derive(Clone, Debug, Default)]
+pub struct CompactReport {
+    /// States given a full snapshot.
+    pub states_snapshotted: usize,
+    /// Branch GC outcome (empty if `branch_gc` was None).
+    pub branches: BranchGcResult,
+    /// Log records removed and bytes the log shrank by.
+    pub log: VacuumStats,
+    /// Blob files deleted, chunks included.
+    pub blobs_deleted: u64,
+    /// Bytes those blob files took up.
+    pub blob_bytes_reclaimed: u64,
+}
+
+impl CompactReport {
+    /// Bytes reclaimed on disk, log and blobs together.
+    pub fn bytes_reclaimed(&self) -> u64 {
+        self.log.bytes_reclaimed + self.blob_bytes_reclaimed
+    }
+}
+
+/// A snapshot written automatically, reported to the hook set with
+/// `Store::set_snapshot_hook`.
+#[derive(Clone, Debug)]
+pub struct SnapshotEvent {
+    /// The state snapshotted.
+    pub state_id: String,
+    /// Full or delta snapshot.
+    pub kind: SnapshotNeeded,
+    /// Sequence of the snapshot record.
+    pub sequence: Sequence,
+    /// Size of the snapshot data in bytes.
+    pub size: usize,
+}
+
+/// Callback set with `Store::set_snapshot_hook`.
+type SnapshotHook = Box<dyn FnMut(SnapshotEvent) + Send>;
+
+/// Progress report from `Store::compact_all_states_truncating`, sent after
+/// each state.
+#[derive(Clone, Debug)]
+pub struct CompactionProgress {
+    /// Position of this state in the run (0-based).
+    pub state_index: usize,
+    /// Number of states in the run.
+    pub total_states: usize,
+    /// The state just processed.
+    pub state_id: String,
+    /// Bytes removed from the log, summed over the run so far.
+    pub bytes_reclaimed: u64,
+}
+
+/// Options for `Store::verify`.
+#[derive(Clone, Debug, Default)]
+pub struct VerifyOptions {
+    /// Return as soon as the first problem is found.
+    pub stop_on_first_error: bool,
+}
+
+/// Where a verification problem was found.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerifyLocation {
+    /// Record in the log at a byte offset.
+    Record { offset: u64 },
+    /// Index entry for a record ID.
+    IndexId(RecordId),
+    /// Index entry for a sequence on a branch.
+    IndexSequence { branch: BranchId, sequence: Sequence },
+    /// Blob file.
+    Blob(Hash),
+    /// Branch metadata.
+    Branch(String),
+}
+
+/// A single problem found by `Store::verify`.
+#[derive(Clone, Debug)]
+pub struct VerifyIssue {
+    pub location: VerifyLocation,
+    pub message: String,
+}
+
+/// Result of `Store::verify`.
+#[derive(Clone, Debug, Default)]
+pub struct VerifyReport {
+    /// Records read from the log.
+    pub records_checked: u64,
+    /// Blobs re-read and re-hashed.
+    pub blobs_checked: u64,
+    /// Branches whose heads were checked.
+    pub branches_checked: u64,
+    /// Problems found, in the order they were encountered.
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl VerifyReport {
+    /// Whether no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn push(&mut self, location: VerifyLocation, message: impl Into<String>) {
+        self.issues.push(VerifyIssue {
+            location,
+            message: message.into(),
+        });
+    }
+}
+
+/// Result of `Store::check_consistency`.
+#[derive(Clone, Debug, Default)]
+pub struct ConsistencyReport {
+    /// Branches whose heads were compared with the index.
+    pub branches_checked: u64,
+    /// Disagreements found between branch heads, the index and the log.
+    pub issues: Vec<VerifyIssue>,
+}
+
+impl ConsistencyReport {
+    /// Whether no problems were found.
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    fn push(&mut self, location: VerifyLocation, message: impl Into<String>) {
+        self.issues.push(VerifyIssue {
+            location,
+            message: message.into(),
+        });
+    }
+}
+
+/// Options for `Store::repair`.
+#[derive(Clone, Debug, Default)]
+pub struct RepairOptions {
+    /// Copy an unreadable log tail into `quarantine/` before truncating it,
+    /// instead of discarding it.
+    pub quarantine: bool,
+}
+
+/// Result of `Store::repair`.
+#[derive(Clone, Debug, Default)]
+pub struct RepairReport {
+    /// Records added to the rebuilt index.
+    pub records_indexed: u64,
+    /// Offsets of records left out of the index because their payload
+    /// checksum failed. They stay in the log.
+    pub corrupt_records: Vec<u64>,
+    /// Bytes cut from the end of the log.
+    pub truncated_bytes: u64,
+    /// Where the truncated bytes were saved, if quarantining.
+    pub quarantine_path: Option<PathBuf>,
+    /// State chain heads moved or removed.
+    pub state_heads_repaired: u64,
+    /// Branch heads moved.
+    pub branch_heads_repaired: u64,
+}
+
+impl RepairReport {
+    /// Whether the repair changed nothing.
+    ///
+    /// Corrupt records are reported on every run and don't count as changes.
+    pub fn is_noop(&self) -> bool {
+        self.truncated_bytes == 0
+            && self.state_heads_repaired == 0
+            && self.branch_heads_repaired == 0
+    }
+}
+
+/// How a state changed between two sequences (`Store::get_state_diff_between`).
+#[derive(Clone, Debug)]
+pub struct StateDiff {
+    /// The state that was diffed.
+    pub state_id: String,
+    /// Exclusive lower bound of the window.
+    pub from: Sequence,
+    /// Inclusive upper bound of the window.
+    pub to: Sequence,
+    /// Operations applied in the window, oldest first.
+    pub operations: Vec<(Sequence, StateOperation)>,
+    /// Whether a full snapshot in the window replaced the value. The item
+    /// counts then cover only the operations after the last such snapshot.
+    pub baseline_reset: bool,
+    /// AppendLog items appended.
+    pub items_added: usize,
+    /// AppendLog edit operations.
+    pub items_edited: usize,
+    /// AppendLog items removed by redactions (clamped to the length at the time).
+    pub items_redacted: usize,
+}
+
 /// Magic bytes for store manifest.
 const STORE_MAGIC: &[u8; 4] = b"RST\0";
 
 /// Current store format version.
-const STORE_VERSION: u8 = 1;
+///
+/// Version 2 adds a blob shard depth byte to the manifest, version 3 a blob
+/// hash algorithm byte. Stores are written in the oldest version that can
+/// describe them so older builds can open them.
+const STORE_VERSION: u8 = 3;
+
+/// How often a waiting writer re-checks the reader lock.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(10);
 
 /// The main record store.
 ///
@@ -67,33 +599,95 @@ const STORE_VERSION: u8 = 1;
 /// - Storing and retrieving blobs
 /// - Managing per-state chains
 /// - Creating and switching branches
+///
+/// # Locking
+///
+/// Two advisory lock files coordinate processes sharing a store directory:
+///
+/// - `LOCK` is held exclusively by the single writer for its whole lifetime.
+/// - `READERS` is held shared by every read-only handle. A writer probes it
+///   with an exclusive lock when opening, so it fails with
+///   `StoreError::Locked` (or waits up to `lock_timeout`) while any reader
+///   is open. Readers may open while a writer is already running.
+///
+/// Locks are `flock`-style on Unix and `LockFileEx` on Windows, released
+/// automatically when the handle is dropped or the process exits. They are
+/// advisory: processes that don't use this crate can still modify the files,
+/// and network filesystems (NFS, SMB) may not honor them reliably.
 pub struct Store {
     /// Store configuration.
     config: StoreConfig,
 
-    /// Lock file for exclusive access.
+    /// Held lock file: `LOCK` (exclusive) for writers, `READERS` (shared)
+    /// for read-only handles.
     _lock_file: File,
 
     /// Record log (shared with StateManager for disk-based traversal).
     log: Arc<RecordLog>,
 
-    /// Record index.
+    /// Record index. Use `index()`, which builds it on first use when
+    /// `lazy_index` is set.
     pub(crate) index: RecordIndex,
 
-    /// Blob storage.
-    blobs: BlobStorage,
+    /// Whether `index` has been built from the log.
+    index_built: AtomicBool,
+
+    /// Held while building `index`, so only one thread does it.
+    index_build_lock: Mutex<()>,
+
+    /// Blob storage (shared with the sync worker).
+    blobs: Arc<BlobStorage>,
 
-    /// State manager.
-    pub(crate) state: StateManager,
+    /// State manager (shared with the sync worker).
+    pub(crate) state: Arc<StateManager>,
 
-    /// Branch manager.
-    branches: BranchManager,
+    /// Branch manager (shared with the sync worker).
+    branches: Arc<BranchManager>,
 
     /// Subscription manager for live updates.
     subscriptions: SubscriptionManager,
 
-    /// Lock for write operations to ensure atomicity.
+    /// Lock for write operations to ensure atomicity. State updates hold it
+    /// only around the log append; see `ChainLocks`.
     write_lock: Mutex<()>,
+
+    /// Per-state locks ordering updates to each state's chain.
+    chain_locks: ChainLocks,
+
+    /// Held shared by every journaled change and exclusively by `compact`
+    /// while it replaces the store's files. Taken after the chain and
+    /// write locks.
+    maintenance_lock: RwLock<()>,
+
+    /// Background syncer for `SyncPolicy::Interval`.
+    sync_worker: Option<SyncWorker>,
+
+    /// Metrics hooks from the config, or a no-op.
+    observer: Arc<dyn StoreObserver>,
+
+    /// Journal for blob stores and branch changes (writers only, shared
+    /// with the sync worker).
+    wal: Option<Arc<WriteAheadLog>>,
+
+    /// Journaled entries applied in memory but not yet persisted by `sync`
+    /// (shared with the sync worker).
+    wal_unsynced: Arc<Mutex<Vec<u64>>>,
+
+    /// Whether updates may snapshot automatically (see `with_auto_snapshot`).
+    auto_snapshot: AtomicBool,
+
+    /// Called after each snapshot `create_snapshot_if_needed` writes.
+    snapshot_hook: Mutex<Option<SnapshotHook>>,
+
+    /// This store's node ID and the changes applied from other stores.
+    replication: Mutex<ReplicationState>,
+
+    /// Last record appended to each branch through this handle, for
+    /// `append_following`. Not persisted.
+    last_appended: Mutex<HashMap<BranchId, RecordId>>,
+
+    /// Payload schemas by record type (see `register_record_schema`).
+    record_schemas: Mutex<HashMap<String, RecordSchema>>,
 }
 
 impl Store {
@@ -104,25 +698,39 @@ impl Store {
         } else if config.create_if_missing {
             Self::create(config)
         } else {
-            Err(StoreError::NotInitialized)
+            Err(StoreError::NotInitialized(config.path))
         }
     }
 
     /// Create a new store.
     pub fn create(config: StoreConfig) -> Result<Self> {
+        if config.read_only {
+            return Err(StoreError::ReadOnly);
+        }
+        config.validate()?;
+
         // Create directory structure
         fs::create_dir_all(&config.path)?;
         fs::create_dir_all(config.path.join("blobs"))?;
 
         // Write manifest
-        Self::write_manifest(&config.path)?;
+        let shard_depth = config.blob_shard_depth.unwrap_or(1);
+        Self::write_manifest(&config.path, shard_depth, config.blob_hash)?;
 
         // Acquire lock
-        let lock_file = Self::acquire_lock(&config.path)?;
+        let lock_file = Self::acquire_lock(&config.path, config.lock_timeout)?;
 
         // Initialize components
-        let log = Arc::new(RecordLog::open(config.path.join("records.log"))?);
-        let blobs = BlobStorage::new(config.path.join("blobs"), config.blob_cache_size)?;
+        let log = Arc::new(RecordLog::open_with_sync_policy(
+            config.path.join("records.log"),
+            config.sync_policy,
+        )?);
+        let blobs = BlobStorage::with_cache_config(
+            config.path.join("blobs"),
+            config.blob_cache_config(),
+            shard_depth,
+            config.blob_hash,
+        )?;
         let mut state = StateManager::new(config.path.join("state.bin"))?;
         let branches = BranchManager::new(config.path.join("branches.bin"))?;
 
@@ -132,65 +740,449 @@ impl Store {
         // Connect state manager to log for disk-based traversal
         state.set_log(Arc::clone(&log));
 
+        let blobs = Arc::new(blobs);
+        let state = Arc::new(state);
+        let branches = Arc::new(branches);
+        let wal = Arc::new(WriteAheadLog::open_with_sync_policy(
+            config.path.join(WAL_FILE),
+            config.sync_policy,
+        )?);
+        let wal_unsynced = Arc::new(Mutex::new(Vec::new()));
+        let sync_worker = match config.sync_policy {
+            SyncPolicy::Interval(interval) if !config.read_only => Some(SyncWorker::spawn(
+                interval,
+                Arc::clone(&log),
+                Arc::clone(&state),
+                Arc::clone(&branches),
+                Arc::clone(&blobs),
+                Arc::clone(&wal),
+                Arc::clone(&wal_unsynced),
+            )?),
+            _ => None,
+        };
+
+        let observer = config
+            .observer
+            .clone()
+            .unwrap_or_else(|| Arc::new(NoopObserver));
+
+        let replication = ReplicationState::load(&config.path, true)?;
+
         Ok(Self {
             config,
             _lock_file: lock_file,
             log,
             index,
+            index_built: AtomicBool::new(true),
+            index_build_lock: Mutex::new(()),
             blobs,
             state,
             branches,
-            subscriptions: SubscriptionManager::new(),
+            subscriptions: SubscriptionManager::with_observer(Arc::clone(&observer)),
             write_lock: Mutex::new(()),
+            chain_locks: ChainLocks::default(),
+            maintenance_lock: RwLock::new(()),
+            sync_worker,
+            observer,
+            wal: Some(wal),
+            wal_unsynced,
+            auto_snapshot: AtomicBool::new(true),
+            replication: Mutex::new(replication),
+            last_appended: Mutex::new(HashMap::new()),
+            snapshot_hook: Mutex::new(None),
+            record_schemas: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Open an existing store for reading only.
+    ///
+    /// Convenience for `open` with `read_only` set.
+    pub fn open_read_only(config: StoreConfig) -> Result<Self> {
+        Self::open(StoreConfig {
+            read_only: true,
+            ..config
         })
     }
 
     /// Open an existing store.
     pub fn open(config: StoreConfig) -> Result<Self> {
+        config.validate()?;
+        if !config.path.join("MANIFEST").exists() {
+            return Err(StoreError::NotInitialized(config.path));
+        }
+
         // Verify manifest
-        Self::verify_manifest(&config.path)?;
+        let (version, _, _) = Self::verify_manifest(&config.path)?;
 
-        // Acquire lock
-        let lock_file = Self::acquire_lock(&config.path)?;
+        // Acquire lock (read-only handles don't contend with the writer)
+        let lock_file = if config.read_only {
+            Self::acquire_reader_lock(&config.path)?
+        } else {
+            Self::acquire_lock(&config.path, config.lock_timeout)?
+        };
+
+        // Upgrade older formats under the writer lock
+        if config.auto_migrate && !config.read_only && version < STORE_VERSION {
+            crate::migrations::run(
+                &config.path,
+                version,
+                STORE_VERSION,
+                crate::migrations::MIGRATIONS,
+            )?;
+        }
+        let (_, shard_depth, blob_hash) = Self::verify_manifest(&config.path)?;
+        if let Some(asked) = config.blob_shard_depth.filter(|&asked| asked != shard_depth) {
+            return Err(StoreError::InvalidOperation(format!(
+                "Store uses blob shard depth {}, config asks for {}; re-sharding is not supported",
+                shard_depth, asked
+            )));
+        }
+        if blob_hash != config.blob_hash {
+            return Err(StoreError::InvalidOperation(format!(
+                "Store addresses blobs by {:?}, config asks for {:?}; mixing algorithms is not supported",
+                blob_hash, config.blob_hash
+            )));
+        }
+
+        // Finish a compaction that committed before the last process died,
+        // or drop one that didn't
+        let compacted_blobs = if config.read_only {
+            Vec::new()
+        } else {
+            Self::install_compaction(&config.path)?
+        };
 
         // Open components
-        let log = Arc::new(RecordLog::open(config.path.join("records.log"))?);
-        let blobs = BlobStorage::new(config.path.join("blobs"), config.blob_cache_size)?;
+        let log_path = config.path.join("records.log");
+        let log = Arc::new(if config.read_only {
+            RecordLog::open_read_only(log_path)?
+        } else {
+            RecordLog::open_with_sync_policy(log_path, config.sync_policy)?
+        });
+        let blobs = BlobStorage::with_cache_config(
+            config.path.join("blobs"),
+            config.blob_cache_config(),
+            shard_depth,
+            blob_hash,
+        )?;
+        if !config.read_only {
+            Self::finish_compaction(&config.path, &blobs, &compacted_blobs)?;
+        }
         let mut state = StateManager::load(config.path.join("state.bin"))?;
         let branches = BranchManager::load(config.path.join("branches.bin"))?;
 
-        // Rebuild index from log (O(N) startup, but O(1) sync)
-        let index = RecordIndex::rebuild_from_log(config.path.join("records.idx"), &log)?;
+        // Rebuild index from log (O(N) startup, but O(1) sync), unless
+        // that's deferred to first use
+        let fields = Self::load_field_index_names(&config.path)?;
+        let index_built = !config.lazy_index;
+        let index = if index_built {
+            RecordIndex::rebuild_from_log_with_fields(config.path.join("records.idx"), &log, &fields)?
+        } else {
+            let index = RecordIndex::new(config.path.join("records.idx"))?;
+            for field in &fields {
+                index.add_field(field);
+            }
+            index
+        };
 
         // Connect state manager to log for disk-based traversal
         state.set_log(Arc::clone(&log));
 
-        Ok(Self {
+        let blobs = Arc::new(blobs);
+        let state = Arc::new(state);
+        let branches = Arc::new(branches);
+        let wal = if config.read_only {
+            None
+        } else {
+            Some(Arc::new(WriteAheadLog::open_with_sync_policy(
+                config.path.join(WAL_FILE),
+                config.sync_policy,
+            )?))
+        };
+        let wal_unsynced = Arc::new(Mutex::new(Vec::new()));
+        let sync_worker = match (config.sync_policy, &wal) {
+            (SyncPolicy::Interval(interval), Some(wal)) => Some(SyncWorker::spawn(
+                interval,
+                Arc::clone(&log),
+                Arc::clone(&state),
+                Arc::clone(&branches),
+                Arc::clone(&blobs),
+                Arc::clone(wal),
+                Arc::clone(&wal_unsynced),
+            )?),
+            _ => None,
+        };
+
+        let observer = config
+            .observer
+            .clone()
+            .unwrap_or_else(|| Arc::new(NoopObserver));
+
+        let replication = ReplicationState::load(&config.path, !config.read_only)?;
+
+        let store = Self {
             config,
             _lock_file: lock_file,
             log,
             index,
+            index_built: AtomicBool::new(index_built),
+            index_build_lock: Mutex::new(()),
             blobs,
             state,
             branches,
-            subscriptions: SubscriptionManager::new(),
+            subscriptions: SubscriptionManager::with_observer(Arc::clone(&observer)),
             write_lock: Mutex::new(()),
-        })
+            chain_locks: ChainLocks::default(),
+            maintenance_lock: RwLock::new(()),
+            sync_worker,
+            observer,
+            wal,
+            wal_unsynced,
+            auto_snapshot: AtomicBool::new(true),
+            replication: Mutex::new(replication),
+            last_appended: Mutex::new(HashMap::new()),
+            snapshot_hook: Mutex::new(None),
+            record_schemas: Mutex::new(HashMap::new()),
+        };
+        if index_built {
+            store.hide_pruned_history()?;
+        }
+        store.recover_from_wal()?;
+        // Schema blobs may only have been restored by the journal replay
+        store.load_record_schemas()?;
+        Ok(store)
     }
 
     // --- Record Operations ---
 
     /// Append a record to the current branch.
+    ///
+    /// If `input` has an idempotency key that a record in the store was
+    /// already appended with, nothing is written and that record is
+    /// returned (see `RecordInput::with_idempotency_key`).
     pub fn append(&self, input: RecordInput) -> Result<Record> {
+        self.append_with_offset(input).map(|(record, _)| record)
+    }
+
+    /// Append a record to the current branch, returning only its ID and
+    /// sequence.
+    ///
+    /// Writes what `append` writes, for write loops that have no use for
+    /// the record. No `Record` is built: the journal entry takes the
+    /// input's data instead of a copy and the log record is written
+    /// straight from it. While a subscriber, an observer or an indexed
+    /// field needs the record, this appends as `append` does.
+    pub fn append_fast(&self, mut input: RecordInput) -> Result<(RecordId, Sequence)> {
+        let _maintenance = self.ensure_writable()?;
+        let _lock = self.write_lock.lock();
+
+        if let Some((existing, _)) = self.find_by_idempotency_key(&input)? {
+            return Ok((existing.id, existing.sequence));
+        }
+        self.check_links(&input)?;
+        self.check_schema(&mut input)?;
+        let branch = self.branches.current_branch();
+        if self.config.observer.is_some()
+            || self.subscriptions.subscription_count() > 0
+            || self.index()?.has_fields()
+        {
+            return self
+                .append_to_branch(&branch, input, false)
+                .map(|record| (record.id, record.sequence));
+        }
+
+        self.check_appendable(&branch, &input)?;
+        let operation = Self::append_journal(&branch, input, false);
+        let WalOperation::AppendRecord { record_type, payload, record: Some(journaled) } = &operation else {
+            unreachable!("append journal entries carry the record");
+        };
+        let mut fields = RecordFields {
+            id: RecordId(0), // Will be assigned
+            sequence: branch.head.next(),
+            branch: branch.id,
+            timestamp: Timestamp(0),
+            record_type,
+            payload,
+            encoding: journaled.encoding,
+            caused_by: &journaled.caused_by,
+            linked_to: &journaled.linked_to,
+            squash: false,
+            expires_at: journaled.expires_at,
+            lamport: journaled.lamport,
+            idempotency_key: journaled.idempotency_key.as_deref(),
+            schema: journaled.schema,
+        };
+        self.journaled_ref(&operation, || self.write_record_fields(&branch, &mut fields))?;
+        Ok((fields.id, fields.sequence))
+    }
+
+    /// Append a record to the current branch, also returning its byte offset in the log.
+    ///
+    /// For building external indexes over the log. An offset stays valid for
+    /// the life of the log file, but log compaction (`compact_log_by_branch`,
+    /// `truncate_branch`) rewrites the file and invalidates every offset
+    /// handed out before it.
+    pub fn append_with_offset(&self, mut input: RecordInput) -> Result<(Record, u64)> {
+        let _maintenance = self.ensure_writable()?;
+        let _lock = self.write_lock.lock();
+
+        if let Some(existing) = self.find_by_idempotency_key(&input)? {
+            return Ok(existing);
+        }
+        self.check_links(&input)?;
+        self.check_schema(&mut input)?;
+        let branch = self.branches.current_branch();
+        self.append_to_branch_with_offset(&branch, input, false)
+    }
+
+    /// Append a record to the current branch, caused by `causes` in
+    /// addition to any `caused_by` already on `input`.
+    pub fn append_caused_by(&self, mut input: RecordInput, causes: &[RecordId]) -> Result<Record> {
+        input.caused_by.extend_from_slice(causes);
+        self.append(input)
+    }
+
+    /// Append a record to the current branch, caused by the record appended
+    /// to that branch before it.
+    ///
+    /// Chains a sequence of appends without passing IDs around. Only records
+    /// appended through this handle count, so the first call on a branch
+    /// (and the first after reopening the store or truncating the branch)
+    /// adds no cause; neither do state updates. Each branch keeps its own
+    /// chain.
+    pub fn append_following(&self, mut input: RecordInput) -> Result<Record> {
+        let _maintenance = self.ensure_writable()?;
         let _lock = self.write_lock.lock();
 
+        if let Some((existing, _)) = self.find_by_idempotency_key(&input)? {
+            return Ok(existing);
+        }
         let branch = self.branches.current_branch();
+        if let Some(&previous) = self.last_appended.lock().get(&branch.id) {
+            if !input.caused_by.contains(&previous) {
+                input.caused_by.push(previous);
+            }
+        }
+        self.check_links(&input)?;
+        self.check_schema(&mut input)?;
+        self.append_to_branch(&branch, input, false)
+    }
+
+    /// Check whether a record exists, without reading it from the log.
+    ///
+    /// Expired records don't count, matching `get_record`.
+    pub fn record_exists(&self, id: RecordId) -> bool {
+        self.index_or_empty().get_offset_by_id(id).is_some() && !self.index_or_empty().is_expired(id, Timestamp::now())
+    }
+
+    /// Read the record starting at a byte offset in the log.
+    ///
+    /// The counterpart to `append_with_offset`. Returns `InvalidFormat` if
+    /// the offset is past the end of the log or doesn't fall on a record
+    /// boundary, rather than decoding whatever bytes happen to be there.
+    pub fn read_record_at(&self, offset: u64) -> Result<Record> {
+        let not_a_record = || StoreError::InvalidFormat(format!("No record starts at offset {}", offset));
+        if offset >= self.log.size() {
+            return Err(not_a_record());
+        }
+        let record = match self.log.read_at(offset) {
+            Ok(record) => record,
+            Err(StoreError::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                return Err(not_a_record())
+            }
+            Err(e) => return Err(e),
+        };
+        // Payload bytes can mimic a record header; the index knows where the
+        // record with this ID really lives.
+        match self.index()?.get_offset_by_id(record.id) {
+            Some(indexed) if indexed != offset => Err(not_a_record()),
+            _ => Ok(record),
+        }
+    }
+
+    /// The live record already appended under `input`'s idempotency key,
+    /// with its offset. Caller holds the write lock.
+    fn find_by_idempotency_key(&self, input: &RecordInput) -> Result<Option<(Record, u64)>> {
+        let Some(key) = &input.idempotency_key else {
+            return Ok(None);
+        };
+        let index = self.index()?;
+        let offset = index
+            .get_by_idempotency_key(key)
+            .filter(|&id| !index.is_expired(id, Timestamp::now()))
+            .and_then(|id| index.get_offset_by_id(id));
+        match offset {
+            Some(offset) => Ok(Some((self.log.read_at(offset)?, offset))),
+            None => Ok(None),
+        }
+    }
+
+    /// Append a record at the head of `branch`. Caller holds the write lock.
+    fn append_to_branch(&self, branch: &Branch, input: RecordInput, squash: bool) -> Result<Record> {
+        self.append_to_branch_with_offset(branch, input, squash).map(|(record, _)| record)
+    }
+
+    /// `append_to_branch`, also returning the record's log offset.
+    fn append_to_branch_with_offset(
+        &self,
+        branch: &Branch,
+        input: RecordInput,
+        squash: bool,
+    ) -> Result<(Record, u64)> {
+        let started = Instant::now();
+        self.check_appendable(branch, &input)?;
+        let operation = Self::append_journal(branch, input.clone(), squash);
+        self.journaled(operation, || self.write_record(branch, input, squash, started))
+    }
+
+    /// Check `input` can go at the head of `branch`: a valid type, a
+    /// payload within the size limit, and a head in step with the index.
+    fn check_appendable(&self, branch: &Branch, input: &RecordInput) -> Result<()> {
+        self.validate_record_type(&input.record_type)?;
+        Self::check_size(input.payload.len() as u64, self.config.max_record_payload_bytes)?;
+        self.ensure_head_matches_index(branch)
+    }
+
+    /// The journal entry for appending `input` at the head of `branch`.
+    fn append_journal(branch: &Branch, input: RecordInput, squash: bool) -> WalOperation {
+        WalOperation::AppendRecord {
+            record_type: input.record_type,
+            payload: input.payload,
+            record: Some(JournaledRecord {
+                branch: branch.name.clone(),
+                sequence: branch.head.next().0,
+                encoding: input.encoding,
+                caused_by: input.caused_by,
+                linked_to: input.linked_to,
+                expires_at: input.expires_at,
+                lamport: input.lamport,
+                idempotency_key: input.idempotency_key,
+                schema: input.schema,
+                squash,
+            }),
+        }
+    }
+
+    /// Write a validated record at the head of `branch` and index it.
+    /// Caller holds the write lock and has checked the head against the
+    /// index.
+    fn write_record(
+        &self,
+        branch: &Branch,
+        input: RecordInput,
+        squash: bool,
+        started: Instant,
+    ) -> Result<(Record, u64)> {
         let next_seq = branch.head.next();
 
-        let (record, offset) = self.log.append(input, branch.id, next_seq)?;
+        let (record, offset) = if squash {
+            self.log.append_squash(input, branch.id, next_seq)?
+        } else {
+            self.log.append(input, branch.id, next_seq)?
+        };
 
         // Update indices
-        self.index.add(
+        self.index()?.add(
             record.id,
             branch.id,
             next_seq,
@@ -199,40 +1191,389 @@ impl Store {
             &record.caused_by,
             &record.linked_to,
         );
+        if let Some(expires_at) = record.expires_at {
+            self.index()?.set_expiry(record.id, expires_at);
+        }
+        if let Some(key) = &record.idempotency_key {
+            self.index()?.set_idempotency_key(key, record.id);
+        }
+        self.index()?.index_fields(&record);
+        self.last_appended.lock().insert(branch.id, record.id);
 
         // Update branch head
         self.branches.update_head(branch.id, next_seq)?;
 
         // Broadcast to subscribers
-        self.subscriptions.broadcast_record(&record);
+        self.subscriptions.broadcast_record(&record, &branch.name);
         self.subscriptions.broadcast_branch_head(&branch.name, next_seq);
 
-        Ok(record)
+        self.observer.on_append(&record, started.elapsed());
+        Ok((record, offset))
     }
 
-    /// Get a record by ID.
-    pub fn get_record(&self, id: RecordId) -> Result<Option<Record>> {
-        if let Some(offset) = self.index.get_offset_by_id(id) {
-            Ok(Some(self.log.read_at(offset)?))
-        } else {
-            Ok(None)
+    /// `write_record` from borrowed fields, for `append_fast`. Nothing is
+    /// broadcast or observed and no payload fields are indexed, so the
+    /// caller checks nothing needs that.
+    fn write_record_fields(&self, branch: &Branch, fields: &mut RecordFields<'_>) -> Result<()> {
+        let offset = self.log.append_fields(fields)?;
+
+        let index = self.index()?;
+        index.add(
+            fields.id,
+            branch.id,
+            fields.sequence,
+            offset,
+            fields.record_type,
+            fields.caused_by,
+            fields.linked_to,
+        );
+        if let Some(expires_at) = fields.expires_at {
+            index.set_expiry(fields.id, expires_at);
         }
-    }
+        if let Some(key) = fields.idempotency_key {
+            index.set_idempotency_key(key, fields.id);
+        }
+        self.last_appended.lock().insert(branch.id, fields.id);
 
-    /// Get records by type.
-    pub fn get_records_by_type(&self, record_type: &str) -> Vec<RecordId> {
-        self.index.get_by_type(record_type)
+        self.branches.update_head(branch.id, fields.sequence)?;
+        self.subscriptions.broadcast_branch_head(&branch.name, fields.sequence);
+        Ok(())
     }
 
-    /// Iterate records from a sequence.
-    pub fn iter_from(&self, seq: Sequence) -> impl Iterator<Item = Result<(u64, Record)>> + '_ {
-        let branch = self.branches.current_branch();
-        let offset = self
-            .index
-            .get_offset(branch.id, seq)
-            .unwrap_or(0);
-        self.log.iter_from(offset)
-    }
+    /// Replace a contiguous range of records on a branch with one summary.
+    ///
+    /// The summary is appended at the head of `branch` with `linked_to` set
+    /// to the squashed records, which then disappear from sequence-ordered
+    /// and type lookups (`query_range`, `get_records_by_type`). They are not
+    /// vacuumed: they stay in the log and remain readable by ID, so links to
+    /// them keep resolving. The hiding is recorded on the summary itself and
+    /// survives a reopen.
+    ///
+    /// Refuses with `InvalidOperation` if the range contains state updates
+    /// (their chains would break), if any record outside the range lists one
+    /// inside it in `caused_by`, or if `summary` already has `linked_to` set.
+    pub fn squash_records(
+        &self,
+        branch: &str,
+        from: Sequence,
+        to: Sequence,
+        summary: RecordInput,
+    ) -> Result<Record> {
+        let _maintenance = self.ensure_writable()?;
+        let _lock = self.write_lock.lock();
+
+        let branch = self
+            .branches
+            .get_branch(branch)
+            .ok_or_else(|| StoreError::BranchNotFound(branch.to_string()))?;
+
+        if from > to {
+            return Err(StoreError::InvalidOperation(format!(
+                "Squash range is empty: {:?} > {:?}",
+                from, to
+            )));
+        }
+        if to > branch.head {
+            return Err(StoreError::InvalidSequence(to, branch.head));
+        }
+        if !summary.linked_to.is_empty() {
+            return Err(StoreError::InvalidOperation(
+                "Squash summary links are set from the squashed range".into(),
+            ));
+        }
+
+        let offsets = self.index()?.query_range(branch.id, Some(from), Some(to), usize::MAX, false);
+        if offsets.is_empty() {
+            return Err(StoreError::InvalidOperation(format!(
+                "No records to squash between {:?} and {:?}",
+                from, to
+            )));
+        }
+
+        let mut squashed = Vec::with_capacity(offsets.len());
+        for (_seq, offset) in offsets {
+            let record = self.log.read_at(offset)?;
+            if record.record_type == "state_update" {
+                return Err(StoreError::InvalidOperation(format!(
+                    "Cannot squash state update record {}",
+                    record.id
+                )));
+            }
+            squashed.push(record.id);
+        }
+
+        // Causation chains must stay within the range
+        for &id in &squashed {
+            if let Some(effect) = self
+                .index()?
+                .get_caused_by(id)
+                .into_iter()
+                .find(|effect| !squashed.contains(effect))
+            {
+                return Err(StoreError::InvalidOperation(format!(
+                    "Record {} is caused by squashed record {}",
+                    effect, id
+                )));
+            }
+        }
+
+        let record = self.append_to_branch(&branch, summary.with_linked_to(squashed), true)?;
+        self.index()?.apply_squash(&self.log, &record)?;
+        self.branches.invalidate_record_counts();
+
+        Ok(record)
+    }
+
+    /// The store's Lamport clock: the highest timestamp stamped so far.
+    ///
+    /// Every append (including state updates) is stamped with the next
+    /// tick. A record appended with `RecordInput::with_lamport` keeps its
+    /// timestamp and moves the clock up to it if it's ahead, so ordering
+    /// records by `(lamport, replica)` gives a total order consistent with
+    /// causality across replicas. Persisted in the log and recovered on open.
+    pub fn current_lamport(&self) -> u64 {
+        self.log.max_lamport()
+    }
+
+    /// Get a record by ID.
+    ///
+    /// Records whose TTL has passed are not returned, even before
+    /// `expire_records` sweeps them.
+    pub fn get_record(&self, id: RecordId) -> Result<Option<Record>> {
+        let record = match self.index()?.get_offset_by_id(id) {
+            Some(offset) => Some(self.log.read_at(offset)?)
+                .filter(|record| !record.is_expired(Timestamp::now())),
+            None => None,
+        };
+        self.observer.on_record_read(id, record.is_some());
+        Ok(record)
+    }
+
+    /// Get records by type, excluding expired ones.
+    pub fn get_records_by_type(&self, record_type: &str) -> Vec<RecordId> {
+        let now = Timestamp::now();
+        let index = self.index_or_empty();
+        index
+            .get_by_type(record_type)
+            .into_iter()
+            .filter(|&id| !index.is_expired(id, now))
+            .collect()
+    }
+
+    /// Get the records of a type visible from `branch`, in sequence order,
+    /// excluding expired ones.
+    ///
+    /// Unlike `get_records_by_type`, which spans every branch, this keeps
+    /// only the ancestors' records up to each branch point and the branch's
+    /// own (see `iter_records`).
+    pub fn get_records_by_type_on_branch(&self, record_type: &str, branch: &str) -> Result<Vec<RecordId>> {
+        let branch = self
+            .branches
+            .get_branch(branch)
+            .ok_or_else(|| StoreError::BranchNotFound(branch.to_string()))?;
+        let index = self.index()?;
+        let by_offset: HashMap<u64, RecordId> = self
+            .get_records_by_type(record_type)
+            .into_iter()
+            .filter_map(|id| index.get_offset_by_id(id).map(|offset| (offset, id)))
+            .collect();
+        if by_offset.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut ids = Vec::new();
+        for (branch_id, first, last) in self.branches.visible_ranges(branch.id)? {
+            for (_, offset) in self.index()?.query_range(branch_id, Some(first), Some(last), usize::MAX, false) {
+                if let Some(&id) = by_offset.get(&offset) {
+                    ids.push(id);
+                }
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Index records by a top-level payload field.
+    ///
+    /// Existing records are indexed straight away (one pass over the index),
+    /// and new ones as they are appended. Only string and integer values are
+    /// indexed; records lacking the field, or whose payload isn't a JSON or
+    /// MessagePack object, are skipped. The field list is saved in the store
+    /// and the index is rebuilt from the log on open, like the type index.
+    /// Adding an already indexed field is a no-op.
+    pub fn add_field_index(&self, field: &str) -> Result<()> {
+        let _maintenance = self.ensure_writable()?;
+        let _lock = self.write_lock.lock();
+        if !self.index()?.add_field(field) {
+            return Ok(());
+        }
+
+        // Backfill visible records; squashed-away ones stay out, as with types
+        for (_, offset) in self.index()?.sequence_offsets() {
+            let record = self.log.read_at(offset)?;
+            self.index()?.index_field(field, &record);
+        }
+
+        let fields = serde_json::to_vec(&self.index()?.fields())?;
+        fs::write(self.config.path.join(FIELD_INDEX_FILE), fields)?;
+        Ok(())
+    }
+
+    /// Get records whose payload has `field` set to `value`, excluding
+    /// expired ones.
+    ///
+    /// Returns `InvalidOperation` if `field` isn't indexed.
+    pub fn get_records_by_field(
+        &self,
+        field: &str,
+        value: impl Into<FieldValue>,
+    ) -> Result<Vec<RecordId>> {
+        if !self.index()?.has_field(field) {
+            return Err(StoreError::InvalidOperation(format!(
+                "Field {} is not indexed",
+                field
+            )));
+        }
+        let now = Timestamp::now();
+        let index = self.index()?;
+        Ok(index
+            .get_by_field(field, &value.into())
+            .into_iter()
+            .filter(|&id| !index.is_expired(id, now))
+            .collect())
+    }
+
+    /// Validate the payloads of `record_type` records against a JSON schema.
+    ///
+    /// From now on `append` (and the other appends to the current branch)
+    /// rejects records of that type whose payload doesn't match, with
+    /// `SchemaViolation`, and stamps the schema's hash on the ones that do
+    /// (see `RecordInput::with_schema`). Raw payloads can't be checked and
+    /// are rejected too; use `RecordInput::without_schema_validation` for
+    /// trusted bulk loads. Records already in the store aren't checked.
+    ///
+    /// The schema is stored as a blob and the registration survives a
+    /// reopen. Registering again replaces the type's schema. Only a subset
+    /// of JSON Schema is checked (see `records::schema`). Returns the
+    /// schema blob's hash.
+    pub fn register_record_schema(&self, record_type: &str, schema: &serde_json::Value) -> Result<Hash> {
+        let _maintenance = self.ensure_writable()?;
+        self.validate_record_type(record_type)?;
+        if !crate::records::schema::is_schema(schema) {
+            return Err(StoreError::InvalidOperation(format!(
+                "Schema for {} must be a JSON object or boolean",
+                record_type
+            )));
+        }
+        let hash = self.store_json_blob(schema, "application/schema+json")?;
+
+        let _lock = self.write_lock.lock();
+        let mut schemas = self.record_schemas.lock();
+        schemas.insert(
+            record_type.to_string(),
+            RecordSchema {
+                hash,
+                schema: schema.clone(),
+            },
+        );
+        Self::save_record_schemas(&self.config.path, &schemas)?;
+        Ok(hash)
+    }
+
+    /// Stop validating `record_type` payloads. Returns false if no schema
+    /// was registered for it. The schema blob is kept.
+    pub fn unregister_record_schema(&self, record_type: &str) -> Result<bool> {
+        let _maintenance = self.ensure_writable()?;
+        let _lock = self.write_lock.lock();
+        let mut schemas = self.record_schemas.lock();
+        if schemas.remove(record_type).is_none() {
+            return Ok(false);
+        }
+        Self::save_record_schemas(&self.config.path, &schemas)?;
+        Ok(true)
+    }
+
+    /// Hash of the schema blob registered for `record_type`, if any.
+    pub fn record_schema(&self, record_type: &str) -> Option<Hash> {
+        self.record_schemas.lock().get(record_type).map(|registered| registered.hash)
+    }
+
+    /// Count records of a given type without collecting their IDs.
+    pub fn count_records_by_type(&self, record_type: &str) -> usize {
+        self.index_or_empty().count_by_type(record_type, Timestamp::now())
+    }
+
+    /// List every record type with its record count, sorted by type name.
+    pub fn list_record_types(&self) -> Vec<(String, usize)> {
+        self.index_or_empty().type_counts(Timestamp::now())
+    }
+
+    /// Remove records whose TTL has passed at `now`.
+    ///
+    /// Returns the number of records removed. If there are any, the log is
+    /// rewritten without them as `compact_log_by_branch` does it, so their
+    /// space is reclaimed, and offsets obtained before are invalid after.
+    pub fn expire_records(&self, now: Timestamp) -> Result<usize> {
+        let _maintenance = self.ensure_writable()?;
+        let _lock = self.write_lock.lock();
+
+        let expired: HashSet<RecordId> = self.index()?.expired(now).into_iter().collect();
+        if expired.is_empty() {
+            return Ok(0);
+        }
+        let live: HashSet<BranchId> = self.branches.list_branches().iter().map(|branch| branch.id).collect();
+        self.rewrite_log(|_, record| !expired.contains(&record.id), &live)?;
+
+        Ok(expired.len())
+    }
+
+    /// Iterate log records from a sequence on the current branch onwards.
+    ///
+    /// Starts at the branch's first record at or after `seq` (the next one
+    /// if `seq` itself was expired, say) and yields nothing if `seq` is past
+    /// the head. Records are yielded in log order from there, whatever
+    /// branch they belong to.
+    pub fn iter_from(&self, seq: Sequence) -> impl Iterator<Item = Result<(u64, Record)>> + '_ {
+        self.iter_branch_from(self.branches.current_branch().id, seq)
+    }
+
+    /// Iterate log records from the first record at or after `seq` on `branch_id`.
+    fn iter_branch_from(
+        &self,
+        branch_id: BranchId,
+        seq: Sequence,
+    ) -> impl Iterator<Item = Result<(u64, Record)>> + '_ {
+        let offset = self
+            .index_or_empty()
+            .query_range(branch_id, Some(seq), None, 1, false)
+            .first()
+            .map_or(self.log.size(), |&(_, offset)| offset);
+        self.log.iter_from(offset)
+    }
+
+    /// Iterate over the records visible from the current branch, in sequence order.
+    ///
+    /// Yields the ancestors' records up to each branch point followed by the
+    /// branch's own, and nothing from sibling branches. Expired records are
+    /// skipped. Records are read from the log lazily.
+    pub fn iter_records(&self) -> impl Iterator<Item = Result<Record>> + '_ {
+        let ranges = self
+            .branches
+            .visible_ranges(self.branches.current_branch().id)
+            .unwrap_or_default();
+        let now = Timestamp::now();
+        let index = self.index_or_empty();
+
+        ranges
+            .into_iter()
+            .flat_map(move |(branch, first, last)| {
+                index
+                    .query_range(branch, Some(first), Some(last), usize::MAX, false)
+            })
+            .filter_map(move |(_, offset)| match self.log.read_at(offset) {
+                Ok(record) if record.is_expired(now) => None,
+                result => Some(result),
+            })
+    }
 
     /// Query records in a sequence range with efficient O(log n + k) lookup.
     ///
@@ -263,7 +1604,7 @@ impl Store {
             limit
         };
 
-        let offsets = self.index.query_range(
+        let offsets = self.index()?.query_range(
             branch.id,
             from,
             to,
@@ -272,6 +1613,7 @@ impl Store {
         );
 
         // Read records and filter
+        let now = Timestamp::now();
         let mut records = Vec::with_capacity(limit);
         for (_seq, offset) in offsets {
             if records.len() >= limit {
@@ -279,6 +1621,9 @@ impl Store {
             }
 
             let record = self.log.read_at(offset)?;
+            if record.is_expired(now) {
+                continue;
+            }
 
             // Apply type filter if specified
             if let Some(types) = types {
@@ -297,24 +1642,258 @@ impl Store {
 
     /// Store a blob.
     pub fn store_blob(&self, content: &[u8], content_type: &str) -> Result<Hash> {
-        self.blobs.store(content, content_type)
+        let _maintenance = self.ensure_writable()?;
+        Self::check_size(content.len() as u64, self.config.max_blob_bytes)?;
+        let content_type = &self.content_type(content_type)?;
+        let operation = WalOperation::StoreBlob {
+            content: content.to_vec(),
+            content_type: content_type.to_string(),
+            chunked: false,
+        };
+        let hash = self.journaled(operation, || self.blobs.store(content, content_type))?;
+        self.observer.on_blob_store(&hash, content.len());
+        Ok(hash)
+    }
+
+    /// Store a blob in content-defined chunks.
+    ///
+    /// Chunks shared with previously stored blobs are not written again, so
+    /// successive versions of a large file only cost their changed regions.
+    /// The blob is retrieved with `get_blob` like any other.
+    pub fn store_blob_chunked(&self, content: &[u8], content_type: &str) -> Result<Hash> {
+        let _maintenance = self.ensure_writable()?;
+        Self::check_size(content.len() as u64, self.config.max_blob_bytes)?;
+        let content_type = &self.content_type(content_type)?;
+        let operation = WalOperation::StoreBlob {
+            content: content.to_vec(),
+            content_type: content_type.to_string(),
+            chunked: true,
+        };
+        let hash = self.journaled(operation, || self.blobs.store_chunked(content, content_type))?;
+        self.observer.on_blob_store(&hash, content.len());
+        Ok(hash)
+    }
+
+    /// Store a blob read to the end from `reader`.
+    ///
+    /// Reading stops as soon as the content passes `max_blob_bytes`, failing
+    /// with `PayloadTooLarge` before anything is written; the reported size
+    /// is how much had been read by then.
+    pub fn store_blob_from_reader(&self, reader: impl std::io::Read, content_type: &str) -> Result<Hash> {
+        use std::io::Read;
+
+        let _maintenance = self.ensure_writable()?;
+        let mut content = Vec::new();
+        match self.config.max_blob_bytes {
+            Some(limit) => {
+                reader.take(limit.saturating_add(1)).read_to_end(&mut content)?;
+                Self::check_size(content.len() as u64, Some(limit))?;
+            }
+            None => {
+                let mut reader = reader;
+                reader.read_to_end(&mut content)?;
+            }
+        }
+        self.store_blob(&content, content_type)
+    }
+
+    /// Store a value as a JSON blob, encoded canonically (see `canonical_json`).
+    ///
+    /// Values that are equal as JSON get the same hash regardless of key
+    /// order or how their numbers were written, so they are stored once.
+    /// `store_blob` hashes the bytes it is given, so the same values passed
+    /// to it as raw JSON text can land on different blobs.
+    pub fn store_json_blob<T: serde::Serialize + ?Sized>(&self, value: &T, content_type: &str) -> Result<Hash> {
+        self.store_blob(&canonical_json(value)?, content_type)
     }
 
     /// Get a blob by hash.
     pub fn get_blob(&self, hash: &Hash) -> Result<Option<Blob>> {
-        self.blobs.get(hash)
+        let (blob, cache_hit) = self.blobs.get_tracked(hash)?;
+        if blob.is_some() {
+            self.observer.on_blob_read(hash, cache_hit);
+        }
+        Ok(blob)
+    }
+
+    /// Read `len` bytes of a blob starting at `start`, e.g. to serve an HTTP
+    /// range request, without loading the whole blob.
+    ///
+    /// Returns None if the blob doesn't exist. A range running past the end
+    /// is cut short there; a `start` past the end fails with
+    /// `InvalidOperation`. Unlike `get_blob`, the checksum isn't verified.
+    pub fn get_blob_range(&self, hash: &Hash, start: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        self.blobs.get_range(hash, start, len)
+    }
+
+    /// Get a blob's size, content type and creation time without reading its content.
+    pub fn blob_info(&self, hash: &Hash) -> Result<Option<BlobInfo>> {
+        self.blobs.info(hash)
+    }
+
+    /// List blobs stored with a content type.
+    ///
+    /// When identical content is stored under several content types, the
+    /// blob is listed under the type it was first stored with.
+    pub fn list_blobs_by_type(&self, content_type: &str) -> Result<Vec<Hash>> {
+        Ok(self.blobs.list_by_type(&self.content_type(content_type)?))
     }
 
-    /// Check if a blob exists.
+    /// Check if a blob exists on disk.
+    ///
+    /// Stats the blob file directly, so the answer doesn't depend on (or
+    /// change) what the blob cache holds.
     pub fn blob_exists(&self, hash: &Hash) -> bool {
-        self.blobs.exists(hash)
+        self.blobs.exists_on_disk(hash)
+    }
+
+    /// Blob cache hits, misses and evictions since open, and what it holds now.
+    ///
+    /// Stored blobs are cached too, so a read straight after `store_blob`
+    /// is a hit.
+    pub fn blob_cache_stats(&self) -> BlobCacheStats {
+        self.blobs.cache_stats()
+    }
+
+    /// Re-read a blob from disk and check its content still hashes to `hash`.
+    ///
+    /// Returns false if the blob is damaged: its content, checksum or
+    /// format no longer match, or a chunk of it is missing. Fails with
+    /// `BlobNotFound` if there is no such blob. Reads bypass the blob cache
+    /// and change nothing.
+    pub fn verify_blob(&self, hash: &Hash) -> Result<bool> {
+        if !self.blobs.exists_on_disk(hash) {
+            return Err(StoreError::BlobNotFound(*hash));
+        }
+        Ok(self.blob_damage(hash)?.is_none())
+    }
+
+    /// Check every blob as `verify_blob` does, returning the damaged ones.
+    pub fn verify_all_blobs(&self) -> Result<Vec<Hash>> {
+        let mut damaged = Vec::new();
+        for hash in self.blobs.list()? {
+            if self.blob_damage(&hash)?.is_some() {
+                damaged.push(hash);
+            }
+        }
+        Ok(damaged)
     }
 
     // --- State Operations ---
 
     /// Register a new state.
+    ///
+    /// If the registration has an `initial_value`, it is written as the
+    /// state's first snapshot on the current branch (see `set_initial_value`).
+    ///
+    /// Registering an existing id with the same strategy is a no-op, so
+    /// registrations can be repeated on every open; the initial value is
+    /// not written again. A different strategy is accepted only while the
+    /// state has no updates on any branch, and returns `InvalidOperation`
+    /// once it does; use `migrate_state_strategy` to convert its data.
     pub fn register_state(&self, registration: StateRegistration) -> Result<()> {
-        self.state.register_state(registration)
+        let _maintenance = self.ensure_writable()?;
+        if let Some(value) = &registration.initial_value {
+            Self::validate_initial_value(&registration.strategy, value)?;
+        }
+        let id = registration.id.clone();
+        let initial_value = registration.initial_value.clone();
+        let registered = self.state.register_state(registration)?;
+
+        if let (true, Some(value)) = (registered, initial_value) {
+            self.set_initial_value(&id, value)?;
+        }
+        Ok(())
+    }
+
+    /// Whether a state with this id has been registered.
+    pub fn is_state_registered(&self, state_id: &str) -> bool {
+        self.state.get_strategy(state_id).is_some()
+    }
+
+    /// Change the strategy of a registered state, keeping its value.
+    ///
+    /// Every branch with history for the state gets a full `Snapshot` of
+    /// its current value at its head, then the registration switches to
+    /// `strategy`. A `Snapshot` state holding a JSON array becomes an
+    /// `AppendLog` of its elements, and an `AppendLog` becomes a `Snapshot`
+    /// of the whole array. A `RingBuffer` keeps only its newest `capacity`
+    /// items.
+    ///
+    /// Returns `InvalidOperation`, without writing anything, if a branch's
+    /// value can't be held by the new strategy: `AppendLog` and
+    /// `RingBuffer` need a JSON array, `Struct` a JSON object. Older
+    /// updates stay in the history, so `get_state_at` still reads them.
+    pub fn migrate_state_strategy(&self, state_id: &str, strategy: StateStrategy) -> Result<()> {
+        let _maintenance = self.ensure_writable()?;
+        let current = self
+            .state
+            .get_strategy(state_id)
+            .ok_or_else(|| StoreError::StateNotRegistered(state_id.to_string()))?;
+        if current == strategy {
+            return Ok(());
+        }
+
+        let chain = self.chain_locks.get(state_id);
+        let _chain = chain.lock();
+
+        // Check every branch before writing to any
+        let mut seeds = Vec::new();
+        for (branch_id, _) in self.state.heads_for_state(state_id) {
+            let Some(branch) = self.branches.get_branch_by_id(branch_id) else {
+                continue;
+            };
+            let value = self.state.get_state(branch_id, state_id)?.unwrap_or_default();
+            Self::check_migrated_value(state_id, &branch.name, &strategy, &value)?;
+            let value = crate::state::apply_operation_with_capacity(
+                Vec::new(),
+                StateOperation::Snapshot(value),
+                strategy.capacity(),
+            )?;
+            seeds.push((branch.id, value));
+        }
+
+        for (branch_id, value) in seeds {
+            let operation = StateOperation::Snapshot(value);
+            let encoded = serde_json::value::to_raw_value(&operation)?;
+            let _lock = self.write_lock.lock();
+            let branch = self
+                .branches
+                .get_branch_by_id(branch_id)
+                .ok_or_else(|| StoreError::BranchNotFound(branch_id.0.to_string()))?;
+            self.append_state_update(&branch, state_id, operation, &encoded, None)?;
+        }
+
+        self.state.set_strategy(state_id, strategy)?;
+        self.state.save()
+    }
+
+    /// Seed a registered state with a value on the current branch.
+    ///
+    /// The value is written as a full `Snapshot`, so `get_state` returns it
+    /// straight away and, for `AppendLog` states (which need a JSON array),
+    /// `get_state_len` counts its items. Only allowed while the state has
+    /// no history on this branch; other branches are unaffected unless they
+    /// are created from this one afterwards.
+    pub fn set_initial_value(&self, state_id: &str, value: Vec<u8>) -> Result<Record> {
+        let _maintenance = self.ensure_writable()?;
+        let strategy = self
+            .state
+            .get_strategy(state_id)
+            .ok_or_else(|| StoreError::StateNotRegistered(state_id.to_string()))?;
+        Self::validate_initial_value(&strategy, &value)?;
+
+        let chain = self.chain_locks.get(state_id);
+        let _chain = chain.lock();
+        let branch_id = self.branches.current_branch().id;
+        if self.state.get_head(branch_id, state_id).is_some() {
+            return Err(StoreError::InvalidOperation(format!(
+                "State {} already has history on this branch",
+                state_id
+            )));
+        }
+
+        self.update_state_locked(state_id, StateOperation::Snapshot(value), true)
     }
 
     /// Update a state and record it.
@@ -323,33 +1902,60 @@ impl Store {
     /// recording. This ensures only valid operations are written to the log.
     ///
     /// Snapshots are automatically created when thresholds are reached.
+    ///
+    /// Safe to call from several threads: updates to one state apply in
+    /// order, and updates to different states only wait on each other for
+    /// the log append itself.
     pub fn update_state(
         &self,
         state_id: &str,
         operation: StateOperation,
     ) -> Result<Record> {
-        self.update_state_internal(state_id, operation, false)
+        let _maintenance = self.ensure_writable()?;
+        let chain = self.chain_locks.get(state_id);
+        let _chain = chain.lock();
+        self.update_state_locked(state_id, operation, false)
     }
 
-    /// Internal update_state with option to skip auto-snapshot.
-    fn update_state_internal(
+    /// Validate and write one state update. Caller holds the state's chain
+    /// lock, which stays held through the auto-snapshot so nothing else
+    /// can update the state between the snapshot being computed and written.
+    fn update_state_locked(
         &self,
         state_id: &str,
         operation: StateOperation,
         skip_auto_snapshot: bool,
     ) -> Result<Record> {
-        let _lock = self.write_lock.lock();
+        let started = Instant::now();
 
-        let branch = self.branches.current_branch();
+        // Reject operations the registered strategy can't represent
+        if let Some(strategy) = self.state.get_strategy(state_id) {
+            validate_operation(&strategy, &operation)?;
+        }
+
+        // The head check is made under the write lock; the update is
+        // recorded as a plain Set
+        let (operation, expected_head) = match operation {
+            StateOperation::SetIfSequence { expected_head, value } => {
+                (StateOperation::Set(value), Some(expected_head))
+            }
+            operation => (operation, None),
+        };
 
-        // Validate operation WITHOUT loading full state (critical for 50M+ operations)
-        // - Append: Always succeeds, no validation needed
-        // - Edit: Just check index < len
-        // - Redact: Check start <= end <= len
-        // - Set/Snapshot/DeltaSnapshot: No validation needed
-        match &operation {
-            StateOperation::Edit { index, .. } => {
-                let len = self.get_state_len(state_id)?.unwrap_or(0);
+        // Encoding the operation is the costly part of the payload, so it
+        // happens before taking the write lock
+        let encoded = serde_json::value::to_raw_value(&operation)?;
+        let record = {
+            let _lock = self.write_lock.lock();
+            let branch = self.branches.current_branch();
+            // Validate operation WITHOUT loading full state (critical for 50M+ operations),
+            // against the branch it's written to
+            // - Edit: Just check index < len
+            // - Redact: Out-of-bounds or start > end redacts are allowed and are
+            //   no-ops or clamped during apply_operation
+            // - Append, Set, Snapshot, DeltaSnapshot: No validation needed
+            if let StateOperation::Edit { index, .. } = &operation {
+                let len = self.state_len_on(branch.id, state_id)?.unwrap_or(0);
                 if *index >= len {
                     return Err(StoreError::InvalidOperation(format!(
                         "Edit index {} out of bounds (len={})",
@@ -357,87 +1963,331 @@ impl Store {
                     )));
                 }
             }
-            StateOperation::Redact { start, end } => {
-                // Note: Out-of-bounds or start > end redacts are allowed and will be
-                // no-ops or clamped during apply_operation. This is intentional for
-                // flexibility - the actual validation happens at reconstruction time.
-                let _ = (start, end); // Suppress unused warnings
+            if let Some(expected) = expected_head {
+                let actual = self.head_sequence_on(branch.id, state_id)?.unwrap_or_default();
+                if actual != expected {
+                    return Err(StoreError::ConcurrentModification { expected, actual });
+                }
             }
-            // Append, Set, Snapshot, DeltaSnapshot - no validation needed
-            _ => {}
-        }
+            self.append_state_update(&branch, state_id, operation, &encoded, None)?
+        };
+        self.observer.on_state_update(state_id, 1, started.elapsed());
 
-        // Get current head offset for this state (for chaining)
-        let prev_update_offset = self.state.get_head(branch.id, state_id).map(|h| h.head_offset);
+        // Auto-snapshot if needed (based on strategy thresholds)
+        // Done after indices/branch update so the snapshot sees consistent state
+        if !skip_auto_snapshot {
+            self.auto_snapshot_if_needed(state_id)?;
+        }
 
-        let next_seq = branch.head.next();
+        Ok(record)
+    }
 
-        // Create the state update record payload
-        let update = StateUpdateRecord {
-            record_id: RecordId(0), // Will be assigned
-            global_sequence: next_seq,
+    /// Write one state update at the head of `branch`, chained to the
+    /// state's previous update. `encoded` is `operation` as JSON. Caller
+    /// holds the write lock and has validated the operation.
+    fn append_state_update(
+        &self,
+        branch: &Branch,
+        state_id: &str,
+        operation: StateOperation,
+        encoded: &RawValue,
+        lamport: Option<u64>,
+    ) -> Result<Record> {
+        self.ensure_head_matches_index(branch)?;
+        let journal = WalOperation::UpdateState {
             state_id: state_id.to_string(),
-            prev_update_offset,
-            operation: operation.clone(),
-            timestamp: Timestamp::now(),
+            operation_data: encoded.get().as_bytes().to_vec(),
+            branch: branch.name.clone(),
+            sequence: branch.head.next().0,
+            lamport,
         };
+        self.journaled(journal, || {
+            self.write_state_update(branch, state_id, operation, encoded, lamport)
+        })
+    }
 
-        // Serialize and append
-        let payload = serde_json::to_vec(&update)?;
-        let input = RecordInput::raw("state_update", payload);
+    /// `append_state_update` without the journal entry. Caller has also
+    /// checked the head against the index.
+    fn write_state_update(
+        &self,
+        branch: &Branch,
+        state_id: &str,
+        operation: StateOperation,
+        encoded: &RawValue,
+        lamport: Option<u64>,
+    ) -> Result<Record> {
+        // Get current head offset for this state (for chaining)
+        let prev_update_offset = self.state.get_head(branch.id, state_id).map(|h| h.head_offset);
+        let next_seq = branch.head.next();
 
-        let (record, offset) = self.log.append(input, branch.id, next_seq)?;
+        let (record, offset) =
+            self.write_state_record(branch.id, state_id, next_seq, prev_update_offset, encoded, lamport)?;
 
         // Update the state manager with the offset
         self.state.record_update(branch.id, state_id, offset, &operation)?;
 
-        // Update indices
-        self.index.add(
+        // Update branch head
+        self.branches.update_head(branch.id, next_seq)?;
+
+        // Broadcast state delta to subscribers
+        self.subscriptions
+            .broadcast_state_delta(state_id, &branch.name, operation, next_seq);
+        self.subscriptions.broadcast_branch_head(&branch.name, next_seq);
+
+        Ok(record)
+    }
+
+    /// Append and index the `state_update` record for `encoded` at
+    /// `sequence`. Returns the record and its offset; the caller advances
+    /// the state and branch heads.
+    fn write_state_record(
+        &self,
+        branch_id: BranchId,
+        state_id: &str,
+        sequence: Sequence,
+        prev_update_offset: Option<u64>,
+        encoded: &RawValue,
+        lamport: Option<u64>,
+    ) -> Result<(Record, u64)> {
+        let input = Self::state_update_input(state_id, sequence, prev_update_offset, encoded, lamport)?;
+        let (record, offset) = self.log.append(input, branch_id, sequence)?;
+        self.index()?.add(
             record.id,
-            branch.id,
-            next_seq,
+            branch_id,
+            sequence,
             offset,
             &record.record_type,
             &record.caused_by,
             &record.linked_to,
         );
+        Ok((record, offset))
+    }
 
-        // Update branch head
-        self.branches.update_head(branch.id, next_seq)?;
-
-        // Broadcast state delta to subscribers
-        self.subscriptions.broadcast_state_delta(state_id, operation, next_seq);
-        self.subscriptions.broadcast_branch_head(&branch.name, next_seq);
-
-        // Auto-snapshot if needed (based on strategy thresholds)
-        // Done after indices/branch update so the snapshot sees consistent state
-        if !skip_auto_snapshot {
-            // Drop the lock before calling auto_snapshot to avoid deadlock
-            drop(_lock);
-            self.auto_snapshot_if_needed(state_id)?;
+    /// Undo a partly written batch: unindex `written` and cut the log back
+    /// to `start`, its size before the batch.
+    fn unwind_state_records(&self, start: u64, written: &[(Record, u64)]) -> Result<()> {
+        let index = self.index()?;
+        for (record, _) in written {
+            index.remove(record);
         }
-
-        Ok(record)
+        self.log.truncate(start)?;
+        Ok(())
     }
 
-    /// Get the current value of a state.
-    pub fn get_state(&self, state_id: &str) -> Result<Option<Vec<u8>>> {
-        let branch_id = self.branches.current_branch().id;
-        self.state.get_state(branch_id, state_id)
+    /// The `state_update` record for an operation on `state_id` at
+    /// `sequence`, chained to the update at `prev_update_offset`.
+    fn state_update_input(
+        state_id: &str,
+        sequence: Sequence,
+        prev_update_offset: Option<u64>,
+        encoded: &RawValue,
+        lamport: Option<u64>,
+    ) -> Result<RecordInput> {
+        let update = EncodedStateUpdate {
+            record_id: RecordId(0), // Will be assigned
+            global_sequence: sequence,
+            state_id,
+            prev_update_offset,
+            operation: encoded,
+            timestamp: Timestamp::now(),
+        };
+        let mut input = RecordInput::raw("state_update", serde_json::to_vec(&update)?);
+        input.lamport = lamport;
+        Ok(input)
     }
 
-    /// Get the value of a state at a specific sequence number (historical access).
+    /// Apply several operations to one state atomically.
     ///
-    /// This reconstructs the state as it was at the given sequence by:
+    /// Every operation is validated first; if any is rejected, nothing is
+    /// written. The operations are then written under one lock as consecutive
+    /// chained records, and the auto-snapshot check runs once at the end.
+    /// Returns the records in order.
+    pub fn update_state_batch(
+        &self,
+        state_id: &str,
+        ops: Vec<StateOperation>,
+    ) -> Result<Vec<Record>> {
+        let _maintenance = self.ensure_writable()?;
+        if ops.is_empty() {
+            return Ok(Vec::new());
+        }
+        let started = Instant::now();
+        let chain = self.chain_locks.get(state_id);
+        let _chain = chain.lock();
+
+        // Validate against the strategy and the length each op will see
+        let strategy = self.state.get_strategy(state_id);
+        let capacity = strategy.as_ref().and_then(StateStrategy::capacity);
+        // Only array strategies accept edits, so the tracked count is enough
+        let mut len = self
+            .state
+            .get_head(self.branches.current_branch().id, state_id)
+            .map_or(0, |head| head.item_count);
+        for operation in &ops {
+            if let Some(strategy) = &strategy {
+                validate_operation(strategy, operation)?;
+            }
+            match operation {
+                StateOperation::SetIfSequence { .. } => {
+                    return Err(StoreError::InvalidOperation(
+                        "SetIfSequence is only supported by update_state".into(),
+                    ));
+                }
+                StateOperation::Edit { index, .. } if *index >= len => {
+                    return Err(StoreError::InvalidOperation(format!(
+                        "Edit index {} out of bounds (len={})",
+                        index, len
+                    )));
+                }
+                StateOperation::Append(_) | StateOperation::Prepend(_) => len += 1,
+                StateOperation::AppendMany(items) => len += items.len(),
+                StateOperation::Redact { start, end } => {
+                    len -= (*end).min(len).saturating_sub(*start);
+                }
+                StateOperation::Set(data) | StateOperation::Snapshot(data) => {
+                    len = serde_json::from_slice::<Vec<serde_json::Value>>(data)
+                        .map(|arr| arr.len())
+                        .unwrap_or(0);
+                }
+                StateOperation::Clear => len = 0,
+                _ => {}
+            }
+            len = len.min(capacity.unwrap_or(usize::MAX));
+        }
+        let encoded = ops
+            .iter()
+            .map(serde_json::value::to_raw_value)
+            .collect::<serde_json::Result<Vec<_>>>()?;
+
+        let lock = self.write_lock.lock();
+        let branch = self.branches.current_branch();
+        self.ensure_head_matches_index(&branch)?;
+        let journal = WalOperation::UpdateStateBatch {
+            state_id: state_id.to_string(),
+            operations: encoded.iter().map(|operation| operation.get().as_bytes().to_vec()).collect(),
+            branch: branch.name.clone(),
+            sequence: branch.head.next().0,
+        };
+        let records = self.journaled(journal, || self.write_state_batch(&branch, state_id, ops, &encoded))?;
+        self.observer
+            .on_state_update(state_id, records.len(), started.elapsed());
+
+        // Drop the write lock before auto-snapshotting to avoid deadlock;
+        // the chain lock stays held
+        drop(lock);
+        self.auto_snapshot_if_needed(state_id)?;
+
+        Ok(records)
+    }
+
+    /// Write a validated batch at the head of `branch` as consecutive
+    /// chained updates, all or none. `encoded` is `ops` as JSON. Caller
+    /// holds the write lock and has checked the head against the index.
+    fn write_state_batch(
+        &self,
+        branch: &Branch,
+        state_id: &str,
+        ops: Vec<StateOperation>,
+        encoded: &[Box<RawValue>],
+    ) -> Result<Vec<Record>> {
+        let start = self.log.size();
+        let mut prev_update_offset = self.state.get_head(branch.id, state_id).map(|h| h.head_offset);
+        let mut seq = branch.head;
+        let mut written = Vec::with_capacity(ops.len());
+
+        // Write every record and advance the branch head, or undo the
+        // records already written so no partial batch stays in the log
+        let result = encoded.iter().try_for_each(|operation| {
+            seq = seq.next();
+            let (record, offset) =
+                self.write_state_record(branch.id, state_id, seq, prev_update_offset, operation, None)?;
+            prev_update_offset = Some(offset);
+            written.push((record, offset));
+            Ok(())
+        });
+        if let Err(e) = result.and_then(|()| self.branches.update_head(branch.id, seq)) {
+            self.unwind_state_records(start, &written)?;
+            return Err(e);
+        }
+
+        // Advance the chain head once for the whole batch
+        let updates: Vec<_> = written
+            .iter()
+            .zip(&ops)
+            .map(|((_, offset), operation)| (*offset, operation))
+            .collect();
+        self.state.record_updates(branch.id, state_id, &updates)?;
+
+        let records: Vec<Record> = written.into_iter().map(|(record, _)| record).collect();
+        for (record, operation) in records.iter().zip(ops) {
+            self.subscriptions
+                .broadcast_state_delta(state_id, &branch.name, operation, record.sequence);
+        }
+        self.subscriptions.broadcast_branch_head(&branch.name, seq);
+        Ok(records)
+    }
+
+    /// Get the current value of a state.
+    ///
+    /// Fails with `StateTooLarge` if the value is larger than
+    /// `max_reconstruct_bytes`.
+    pub fn get_state(&self, state_id: &str) -> Result<Option<Vec<u8>>> {
+        let branch_id = self.branches.current_branch().id;
+        self.state
+            .get_state_limited(branch_id, state_id, self.config.max_reconstruct_bytes)
+    }
+
+    /// The current value of a state without the `max_reconstruct_bytes`
+    /// limit, for snapshots and compaction that need the whole value.
+    fn current_state(&self, state_id: &str) -> Result<Option<Vec<u8>>> {
+        let branch_id = self.branches.current_branch().id;
+        self.state.get_state(branch_id, state_id)
+    }
+
+    /// Get the current value of a state, deserialized from JSON.
+    ///
+    /// Returns `Deserialization` naming the state if the value doesn't fit `T`.
+    pub fn get_state_as<T: DeserializeOwned>(&self, state_id: &str) -> Result<Option<T>> {
+        Self::decode_state(state_id, self.get_state(state_id)?)
+    }
+
+    /// Get the value of a state at a specific sequence number (historical access).
+    ///
+    /// This reconstructs the state as it was at the given sequence by:
     /// 1. Walking the state chain backwards from the head
     /// 2. Skipping operations with sequence > at_sequence
     /// 3. Collecting operations with sequence <= at_sequence
     /// 4. Applying them in forward order
     ///
-    /// Returns None if the state didn't exist at that sequence.
+    /// Returns None if the state didn't exist at that sequence. Limited by
+    /// `max_reconstruct_bytes` like `get_state`.
     pub fn get_state_at(&self, state_id: &str, at_sequence: Sequence) -> Result<Option<Vec<u8>>> {
         let branch_id = self.branches.current_branch().id;
-        self.get_state_at_for_branch(branch_id, state_id, at_sequence)
+        self.reconstruct_state_where(
+            branch_id,
+            state_id,
+            |record| record.sequence <= at_sequence,
+            self.config.max_reconstruct_bytes,
+        )
+    }
+
+    /// Get the value of a state as of a point in time (historical access).
+    ///
+    /// Like `get_state_at`, but keeps the updates whose record timestamp is
+    /// at or before `at`. Each update is judged by its own stored timestamp,
+    /// so clocks that stepped backwards don't cut the chain short: a later
+    /// update stamped earlier than `at` still counts, and a snapshot stamped
+    /// after `at` is skipped in favour of the updates behind it.
+    ///
+    /// Returns None if the state had no updates by then.
+    pub fn get_state_at_time(&self, state_id: &str, at: Timestamp) -> Result<Option<Vec<u8>>> {
+        let branch_id = self.branches.current_branch().id;
+        self.reconstruct_state_where(
+            branch_id,
+            state_id,
+            |record| record.timestamp <= at,
+            self.config.max_reconstruct_bytes,
+        )
     }
 
     /// Get the value of a state at a specific sequence on a specific branch.
@@ -449,13 +2299,29 @@ impl Store {
         branch_id: crate::types::BranchId,
         state_id: &str,
         at_sequence: Sequence,
+    ) -> Result<Option<Vec<u8>>> {
+        self.reconstruct_state_where(branch_id, state_id, |record| record.sequence <= at_sequence, None)
+    }
+
+    /// Reconstruct a state on a branch from the updates `include` accepts.
+    ///
+    /// Walks the chain backwards from the head, skipping rejected updates,
+    /// until an accepted full snapshot. Returns None if nothing was accepted.
+    /// Fails with `StateTooLarge` once the value grows past `limit` bytes.
+    fn reconstruct_state_where(
+        &self,
+        branch_id: BranchId,
+        state_id: &str,
+        include: impl Fn(&Record) -> bool,
+        limit: Option<u64>,
     ) -> Result<Option<Vec<u8>>> {
         let head = match self.state.get_head(branch_id, state_id) {
             Some(h) => h,
             None => return Ok(None),
         };
+        let pruned = self.state.pruned_at(state_id);
 
-        // Walk chain backwards, collecting operations at or before target sequence
+        // Walk chain backwards, collecting the accepted operations
         let mut operations = Vec::new();
         let mut current_offset = Some(head.head_offset);
         let mut hit_snapshot = false;
@@ -464,22 +2330,21 @@ impl Store {
         while let Some(offset) = current_offset {
             let record = self.log.read_at(offset)?;
 
-            // Skip if this record is after the target sequence
-            if record.sequence > at_sequence {
+            // Skip updates outside the target
+            if !include(&record) {
+                Self::ensure_not_pruned(&pruned, offset, state_id, &record)?;
                 // Parse just to get prev_update_offset
-                let update: StateUpdateRecord = serde_json::from_slice(&record.payload)
-                    .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+                let update = decode_update(&record, state_id, offset)?;
                 current_offset = update.prev_update_offset;
                 continue;
             }
 
             found_any = true;
 
-            let update: StateUpdateRecord = serde_json::from_slice(&record.payload)
-                .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+            let update = decode_update(&record, state_id, offset)?;
 
             match &update.operation {
-                StateOperation::Snapshot(_) => {
+                StateOperation::Snapshot(_) | StateOperation::Clear => {
                     operations.push(update.operation.clone());
                     break;
                 }
@@ -498,15 +2363,17 @@ impl Store {
         }
 
         if !found_any {
-            // State didn't exist at that sequence
+            // State didn't exist at the target
             return Ok(None);
         }
 
         // Apply operations in forward order
         operations.reverse();
+        let capacity = self.state_capacity(state_id);
         let mut state = Vec::new();
         for op in operations {
-            state = crate::state::apply_operation(state, op)?;
+            state = crate::state::apply_operation_with_capacity(state, op, capacity)?;
+            check_reconstruct_size(state.len(), limit)?;
         }
 
         Ok(Some(state))
@@ -529,6 +2396,7 @@ impl Store {
         };
 
         // Walk chain to find the last update at or before target sequence
+        let pruned = self.state.pruned_at(state_id);
         let mut current_offset = Some(head.head_offset);
         let mut found_offset: Option<u64> = None;
         let mut operations = Vec::new();
@@ -537,11 +2405,11 @@ impl Store {
         while let Some(offset) = current_offset {
             let record = self.log.read_at(offset)?;
 
-            let update: StateUpdateRecord = serde_json::from_slice(&record.payload)
-                .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+            let update = decode_update(&record, state_id, offset)?;
 
             // Skip if this record is after the target sequence
             if record.sequence > at_sequence {
+                Self::ensure_not_pruned(&pruned, offset, state_id, &record)?;
                 current_offset = update.prev_update_offset;
                 continue;
             }
@@ -553,7 +2421,7 @@ impl Store {
 
             // Collect operations to compute item_count
             match &update.operation {
-                StateOperation::Snapshot(_) => {
+                StateOperation::Snapshot(_) | StateOperation::Clear => {
                     operations.push(update.operation.clone());
                     break;
                 }
@@ -578,9 +2446,10 @@ impl Store {
 
         // Reconstruct state to count items
         operations.reverse();
+        let capacity = self.state_capacity(state_id);
         let mut state = Vec::new();
         for op in operations {
-            state = crate::state::apply_operation(state, op)?;
+            state = crate::state::apply_operation_with_capacity(state, op, capacity)?;
         }
 
         // Count items in the resulting state (assumes JSON array for AppendLog)
@@ -595,18 +2464,185 @@ impl Store {
         Ok(Some((head_offset, item_count)))
     }
 
-    /// Get the length of an AppendLog state without loading all items.
+    /// Report how a state changed in the sequence window `(from, to]` on the
+    /// current branch.
     ///
-    /// This is O(1) - the count is tracked in the state chain head.
-    /// Returns None if state doesn't exist, Some(0) for empty state.
-    pub fn get_state_len(&self, state_id: &str) -> Result<Option<usize>> {
+    /// Walks the chain from the head collecting the updates in the window.
+    /// Delta snapshots only consolidate earlier appends, so they are listed
+    /// but don't affect the item counts; a full snapshot resets them (see
+    /// `StateDiff::baseline_reset`).
+    pub fn get_state_diff_between(
+        &self,
+        state_id: &str,
+        from: Sequence,
+        to: Sequence,
+    ) -> Result<StateDiff> {
+        if from > to {
+            return Err(StoreError::InvalidOperation(format!(
+                "Diff window start {:?} is after its end {:?}",
+                from, to
+            )));
+        }
         let branch_id = self.branches.current_branch().id;
+        let head = self
+            .state
+            .get_head(branch_id, state_id)
+            .ok_or_else(|| StoreError::StateNotRegistered(state_id.to_string()))?;
+
+        // Walk back to the start of the window
+        let mut operations = Vec::new();
+        let mut current_offset = Some(head.head_offset);
+        while let Some(offset) = current_offset {
+            let record = self.log.read_at(offset)?;
+            if record.sequence <= from {
+                break;
+            }
+            let update = decode_update(&record, state_id, offset)?;
+            if record.sequence <= to {
+                operations.push((record.sequence, update.operation));
+            }
+            current_offset = update.prev_update_offset;
+        }
+        operations.reverse();
+
+        // Net item changes, tracking the length so redactions clamp like apply_operation
+        let capacity = self.state_capacity(state_id);
+        let mut len = self
+            .find_chain_info_at(branch_id, state_id, from)?
+            .map(|(_, count)| count)
+            .unwrap_or(0);
+        let mut diff = StateDiff {
+            state_id: state_id.to_string(),
+            from,
+            to,
+            operations: Vec::new(),
+            baseline_reset: false,
+            items_added: 0,
+            items_edited: 0,
+            items_redacted: 0,
+        };
+        for (_, operation) in &operations {
+            match operation {
+                StateOperation::Append(_) | StateOperation::Prepend(_) => {
+                    diff.items_added += 1;
+                    len += 1;
+                }
+                StateOperation::AppendMany(items) => {
+                    diff.items_added += items.len();
+                    len += items.len();
+                }
+                StateOperation::Edit { .. } => diff.items_edited += 1,
+                StateOperation::Redact { start, end } => {
+                    let removed = (*end).min(len).saturating_sub(*start);
+                    diff.items_redacted += removed;
+                    len -= removed;
+                }
+                StateOperation::Snapshot(data) => {
+                    diff.baseline_reset = true;
+                    diff.items_added = 0;
+                    diff.items_edited = 0;
+                    diff.items_redacted = 0;
+                    len = serde_json::from_slice::<Vec<serde_json::Value>>(data)
+                        .map(|arr| arr.len())
+                        .unwrap_or(0);
+                }
+                StateOperation::Clear => {
+                    diff.baseline_reset = true;
+                    diff.items_added = 0;
+                    diff.items_edited = 0;
+                    diff.items_redacted = 0;
+                    len = 0;
+                }
+                _ => {}
+            }
+            len = len.min(capacity.unwrap_or(usize::MAX));
+        }
+        diff.operations = operations;
+
+        Ok(diff)
+    }
+
+    /// Roll a state back to its value at `seq` on the current branch.
+    ///
+    /// Appends a full `Snapshot` of that value rather than rewriting
+    /// anything, so later reads see the rolled-back value while the undone
+    /// operations stay in the history (and `get_state_at` still finds them).
+    /// Returns `InvalidOperation` if the state didn't exist at `seq`.
+    pub fn rollback_state_to(&self, state_id: &str, seq: Sequence) -> Result<Record> {
+        let _maintenance = self.ensure_writable()?;
+        let chain = self.chain_locks.get(state_id);
+        let _chain = chain.lock();
+        let value = self.get_state_at(state_id, seq)?.ok_or_else(|| {
+            StoreError::InvalidOperation(format!(
+                "State {} did not exist at sequence {}",
+                state_id, seq.0
+            ))
+        })?;
+        self.update_state_locked(state_id, StateOperation::Snapshot(value), false)
+    }
+
+    /// Sequence of the latest update to a state on the current branch, to
+    /// pass as `expected_head` in `StateOperation::SetIfSequence`.
+    ///
+    /// Returns None if the state has no updates on this branch.
+    pub fn state_head_sequence(&self, state_id: &str) -> Result<Option<Sequence>> {
+        self.head_sequence_on(self.branches.current_branch().id, state_id)
+    }
+
+    fn head_sequence_on(&self, branch_id: BranchId, state_id: &str) -> Result<Option<Sequence>> {
         match self.state.get_head(branch_id, state_id) {
-            Some(head) => Ok(Some(head.item_count)),
+            Some(head) => Ok(Some(self.log.read_at(head.head_offset)?.sequence)),
             None => Ok(None),
         }
     }
 
+    /// Get the number of items in an array state without loading all items.
+    ///
+    /// For AppendLog and RingBuffer states this is O(1) - the count is
+    /// tracked in the state chain head. Snapshot and Delta states hold their
+    /// whole value in the head update, so only that update is read; their
+    /// value must be a JSON array, otherwise (and for Struct states) this
+    /// fails with `InvalidOperation`.
+    /// Returns None if state doesn't exist, Some(0) for empty state.
+    pub fn get_state_len(&self, state_id: &str) -> Result<Option<usize>> {
+        self.state_len_on(self.branches.current_branch().id, state_id)
+    }
+
+    /// `get_state_len` on `branch_id`.
+    fn state_len_on(&self, branch_id: BranchId, state_id: &str) -> Result<Option<usize>> {
+        let Some(head) = self.state.get_head(branch_id, state_id) else {
+            return Ok(None);
+        };
+        let value = match self.state.get_strategy(state_id) {
+            Some(StateStrategy::Snapshot) | Some(StateStrategy::Delta { .. }) => {
+                let record = self.log.read_at(head.head_offset)?;
+                match decode_update(&record, state_id, head.head_offset)?.operation {
+                    StateOperation::Set(value)
+                    | StateOperation::Snapshot(value)
+                    | StateOperation::Delta { new_value: value, .. } => value,
+                    _ => self
+                        .state
+                        .get_state_limited(branch_id, state_id, self.config.max_reconstruct_bytes)?
+                        .unwrap_or_default(),
+                }
+            }
+            Some(StateStrategy::Struct { .. }) => {
+                return Err(StoreError::InvalidOperation(format!(
+                    "State {} is a struct, not an array",
+                    state_id
+                )));
+            }
+            _ => return Ok(Some(head.item_count)),
+        };
+        match serde_json::from_slice::<serde_json::Value>(&value) {
+            Ok(serde_json::Value::Array(items)) => Ok(Some(items.len())),
+            _ => Err(StoreError::InvalidOperation(format!(
+                "State {} does not hold a JSON array",
+                state_id
+            ))),
+        }
+    }
+
     /// Get a slice of an AppendLog state.
     ///
     /// This is efficient for accessing recent items (near the end) because
@@ -647,6 +2683,10 @@ impl Store {
     ///
     /// This is O(count + recent_ops) - only traverses as far back as needed.
     /// For states with many items but few recent changes, this is very fast.
+    /// Edits and redactions are applied to the collected items; only a
+    /// redaction whose extent can't be recovered walking back (one clamped
+    /// at the end of the state), any edit or redaction on a RingBuffer, or a
+    /// prepend falls back to full reconstruction.
     pub fn get_state_tail(&self, state_id: &str, count: usize) -> Result<Option<Vec<u8>>> {
         let branch_id = self.branches.current_branch().id;
         let head = match self.state.get_head(branch_id, state_id) {
@@ -663,57 +2703,120 @@ impl Store {
             return self.get_state(state_id);
         }
 
-        // Collect items walking backwards until we have enough
-        // We need to handle: Append (add 1), DeltaSnapshot (add N), Snapshot (stop), Redact/Edit (complex)
-        let mut items_collected: Vec<serde_json::Value> = Vec::new();
+        match self.tail_from_recent_ops(state_id, &head, count)? {
+            Some(tail) => Ok(Some(serde_json::to_vec(&tail)?)),
+            None => {
+                // Fall back to full reconstruction and slice
+                let state = self.get_state(state_id)?.unwrap_or_default();
+                if state.is_empty() {
+                    return Ok(Some(serde_json::to_vec(&Vec::<serde_json::Value>::new())?));
+                }
+                let arr: Vec<serde_json::Value> = serde_json::from_slice(&state)
+                    .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+                let start = arr.len().saturating_sub(count);
+                Ok(Some(serde_json::to_vec(&arr[start..])?))
+            }
+        }
+    }
+
+    /// The last `count` items of a state, from only as much of its chain as
+    /// they depend on. None if that can't be worked out and the caller
+    /// should reconstruct the whole state.
+    ///
+    /// Walks back from the head tracking the state's length and how many of
+    /// its last items the tail depends on: appends supply them, edits before
+    /// that window are irrelevant, and redactions before it widen it. The
+    /// collected operations are then replayed onto the known suffix.
+    fn tail_from_recent_ops(
+        &self,
+        state_id: &str,
+        head: &StateChainHead,
+        count: usize,
+    ) -> Result<Option<Vec<serde_json::Value>>> {
+        let parse = |data: &[u8]| -> Result<serde_json::Value> {
+            serde_json::from_slice(data).map_err(|e| StoreError::Deserialization(e.to_string()))
+        };
+        let capacity = self.state_capacity(state_id);
+        let mut ops = Vec::new();
+        let mut len = head.item_count;
+        let mut needed = count;
+        let mut suffix: Vec<serde_json::Value> = Vec::new();
+        // Anything older than a delta snapshot other than earlier snapshots is folded into it
+        let mut hit_snapshot = false;
         let mut current_offset = Some(head.head_offset);
-        let mut need_full_reconstruct = false;
 
         while let Some(offset) = current_offset {
-            if items_collected.len() >= count {
+            if needed == 0 {
                 break;
             }
-
             let record = self.log.read_at(offset)?;
-            let update: StateUpdateRecord = serde_json::from_slice(&record.payload)
-                .map_err(|e| StoreError::Deserialization(e.to_string()))?;
-
-            match &update.operation {
+            let update = decode_update(&record, state_id, offset)?;
+
+            match update.operation {
+                StateOperation::Append(_)
+                | StateOperation::AppendMany(_)
+                | StateOperation::Prepend(_)
+                | StateOperation::Edit { .. }
+                | StateOperation::Redact { .. }
+                    if hit_snapshot => {}
+                // Shifts every item, and a full ring buffer loses its last one
+                StateOperation::Prepend(_) => return Ok(None),
+                // A ring buffer's length before a trimming append can't be
+                // recovered walking back, so positions can't be tracked
+                StateOperation::Edit { .. } | StateOperation::Redact { .. } if capacity.is_some() => {
+                    return Ok(None)
+                }
                 StateOperation::Append(item) => {
-                    let value: serde_json::Value = serde_json::from_slice(item)
-                        .map_err(|e| StoreError::Deserialization(e.to_string()))?;
-                    items_collected.push(value);
+                    needed = needed.saturating_sub(1);
+                    len = len.saturating_sub(1);
+                    ops.push(TailOp::Append(vec![parse(&item)?]));
+                }
+                StateOperation::AppendMany(items) => {
+                    let items = items.iter().map(|item| parse(item)).collect::<Result<Vec<_>>>()?;
+                    needed = needed.saturating_sub(items.len());
+                    len = len.saturating_sub(items.len());
+                    ops.push(TailOp::Append(items));
                 }
                 StateOperation::DeltaSnapshot(data) => {
-                    let arr: Vec<serde_json::Value> = serde_json::from_slice(data)
+                    let items: Vec<serde_json::Value> = serde_json::from_slice(&data)
                         .map_err(|e| StoreError::Deserialization(e.to_string()))?;
-                    // Take from end of delta to fill our need
-                    let need = count - items_collected.len();
-                    let start = arr.len().saturating_sub(need);
-                    for item in arr[start..].iter().rev() {
-                        items_collected.push(item.clone());
-                        if items_collected.len() >= count {
-                            break;
-                        }
+                    needed = needed.saturating_sub(items.len());
+                    len = len.saturating_sub(items.len());
+                    ops.push(TailOp::Append(items));
+                    hit_snapshot = true;
+                }
+                // Edits before the window don't affect it
+                StateOperation::Edit { index, new_value } if index >= len - needed.min(len) => {
+                    ops.push(TailOp::Edit { index, value: parse(&new_value)? });
+                }
+                StateOperation::Redact { start, end } => {
+                    let removed = if start > len || end <= start {
+                        0
+                    } else if start < len {
+                        end - start
+                    } else {
+                        // Clamped at the end: how much it removed is unknown
+                        return Ok(None);
+                    };
+                    // Items before `start` kept their place; the window widens
+                    // if it reached back past `start`
+                    if len - needed.min(len) < start {
+                        needed += removed;
                     }
+                    len += removed;
+                    ops.push(TailOp::Redact { start, end });
                 }
                 StateOperation::Snapshot(data) => {
-                    let arr: Vec<serde_json::Value> = serde_json::from_slice(data)
+                    suffix = serde_json::from_slice(&data)
                         .map_err(|e| StoreError::Deserialization(e.to_string()))?;
-                    // Take from end of snapshot
-                    let need = count - items_collected.len();
-                    let start = arr.len().saturating_sub(need);
-                    for item in arr[start..].iter().rev() {
-                        items_collected.push(item.clone());
-                        if items_collected.len() >= count {
-                            break;
-                        }
-                    }
-                    break; // Full snapshot - we're done
+                    len = suffix.len();
+                    current_offset = None;
+                    needed = 0;
+                    continue;
                 }
-                StateOperation::Edit { .. } | StateOperation::Redact { .. } => {
-                    // Edit/Redact make tail optimization complex - fall back to full reconstruct
-                    need_full_reconstruct = true;
+                StateOperation::Clear => {
+                    len = 0;
+                    needed = 0;
                     break;
                 }
                 _ => {}
@@ -721,22 +2824,71 @@ impl Store {
 
             current_offset = update.prev_update_offset;
         }
+        if needed > 0 && len > 0 && capacity.is_none() {
+            // Ran out of chain before accounting for every item
+            return Ok(None);
+        }
 
-        if need_full_reconstruct {
-            // Fall back to full reconstruction and slice
-            let state = self.get_state(state_id)?.unwrap_or_default();
-            if state.is_empty() {
-                return Ok(Some(serde_json::to_vec(&Vec::<serde_json::Value>::new())?));
+        for op in ops.into_iter().rev() {
+            match op {
+                TailOp::Append(items) => {
+                    len += items.len();
+                    suffix.extend(items);
+                    if let Some(capacity) = capacity {
+                        len = len.min(capacity);
+                        let excess = suffix.len().saturating_sub(capacity);
+                        suffix.drain(..excess);
+                    }
+                }
+                TailOp::Edit { index, value } => {
+                    let known = len - suffix.len();
+                    if index >= known && index < len {
+                        suffix[index - known] = value;
+                    }
+                }
+                TailOp::Redact { start, end } => {
+                    let (start, end) = (start.min(len), end.min(len));
+                    if start < end {
+                        let known = len - suffix.len();
+                        suffix.drain(start.max(known) - known..end.max(known) - known);
+                        len -= end - start;
+                    }
+                }
             }
-            let arr: Vec<serde_json::Value> = serde_json::from_slice(&state)
-                .map_err(|e| StoreError::Deserialization(e.to_string()))?;
-            let start = arr.len().saturating_sub(count);
-            return Ok(Some(serde_json::to_vec(&arr[start..])?));
         }
 
-        // Reverse since we collected in reverse order
-        items_collected.reverse();
-        Ok(Some(serde_json::to_vec(&items_collected)?))
+        if suffix.len() < count.min(len) {
+            return Ok(None);
+        }
+        let start = suffix.len().saturating_sub(count);
+        Ok(Some(suffix.split_off(start)))
+    }
+
+    /// Get a slice of an AppendLog state, deserialized into its items.
+    pub fn get_state_slice_as<T: DeserializeOwned>(
+        &self,
+        state_id: &str,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Option<Vec<T>>> {
+        Self::decode_state(state_id, self.get_state_slice(state_id, offset, limit)?)
+    }
+
+    /// Get the last N items of an AppendLog state, deserialized into its items.
+    pub fn get_state_tail_as<T: DeserializeOwned>(
+        &self,
+        state_id: &str,
+        count: usize,
+    ) -> Result<Option<Vec<T>>> {
+        Self::decode_state(state_id, self.get_state_tail(state_id, count)?)
+    }
+
+    fn decode_state<T: DeserializeOwned>(state_id: &str, data: Option<Vec<u8>>) -> Result<Option<T>> {
+        data.map(|data| {
+            serde_json::from_slice(&data)
+                .map_err(|e| StoreError::Deserialization(format!("state {}: {}", state_id, e)))
+        })
+        .transpose()
     }
 
     /// Legacy implementation for reference - walks entire chain
@@ -762,11 +2914,10 @@ impl Store {
 
         while let Some(offset) = current_offset {
             let record = self.log.read_at(offset)?;
-            let update: StateUpdateRecord = serde_json::from_slice(&record.payload)
-                .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+            let update = decode_update(&record, state_id, offset)?;
 
             match &update.operation {
-                StateOperation::Snapshot(_) => {
+                StateOperation::Snapshot(_) | StateOperation::Clear => {
                     ops.push(update.operation.clone());
                     break; // Full snapshot has everything
                 }
@@ -793,11 +2944,24 @@ impl Store {
                         .map_err(|e| StoreError::Deserialization(e.to_string()))?;
                     all_items.push(value);
                 }
+                StateOperation::AppendMany(items) => {
+                    for item in items {
+                        let value: serde_json::Value = serde_json::from_slice(&item)
+                            .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+                        all_items.push(value);
+                    }
+                }
+                StateOperation::Prepend(item) => {
+                    let value: serde_json::Value = serde_json::from_slice(&item)
+                        .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+                    all_items.insert(0, value);
+                }
                 StateOperation::Snapshot(data) | StateOperation::DeltaSnapshot(data) => {
                     let arr: Vec<serde_json::Value> = serde_json::from_slice(&data)
                         .map_err(|e| StoreError::Deserialization(e.to_string()))?;
                     all_items.extend(arr);
                 }
+                StateOperation::Clear => all_items.clear(),
                 StateOperation::Redact { start, end } => {
                     let start = start.min(all_items.len());
                     let end = end.min(all_items.len());
@@ -805,12 +2969,10 @@ impl Store {
                         all_items.drain(start..end);
                     }
                 }
-                StateOperation::Edit { index, new_value } => {
-                    if index < all_items.len() {
-                        let value: serde_json::Value = serde_json::from_slice(&new_value)
-                            .map_err(|e| StoreError::Deserialization(e.to_string()))?;
-                        all_items[index] = value;
-                    }
+                StateOperation::Edit { index, new_value } if index < all_items.len() => {
+                    let value: serde_json::Value = serde_json::from_slice(&new_value)
+                        .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+                    all_items[index] = value;
                 }
                 _ => {}
             }
@@ -837,7 +2999,9 @@ impl Store {
 
         Ok(Some(StateItemIterator::new(
             self.log.clone(),
+            state_id,
             head.head_offset,
+            self.state_capacity(state_id),
         )))
     }
 
@@ -880,13 +3044,18 @@ impl Store {
     /// For maximum compaction benefit, this creates a full snapshot regardless
     /// of the configured snapshot strategy.
     pub fn compact_state(&self, state_id: &str) -> Result<Option<Record>> {
-        let current = match self.get_state(state_id)? {
+        let _maintenance = self.ensure_writable()?;
+        let chain = self.chain_locks.get(state_id);
+        let _chain = chain.lock();
+        let current = match self.current_state(state_id)? {
             Some(s) => s,
             None => return Ok(None),
         };
 
         // Create a full snapshot with the current state
-        let record = self.update_state(state_id, StateOperation::Snapshot(current))?;
+        let size = current.len();
+        let record = self.update_state_locked(state_id, StateOperation::Snapshot(current), false)?;
+        self.observer.on_snapshot(state_id, SnapshotNeeded::Full, size);
         Ok(Some(record))
     }
 
@@ -906,6 +3075,134 @@ impl Store {
         Ok(compacted)
     }
 
+    /// Compact all states and remove the history they no longer need from
+    /// the log, reporting progress after each state.
+    ///
+    /// States are processed in ID order. Each one is pruned on the current
+    /// branch as `prune_history` does it, then the log is rewritten without
+    /// the updates the pruning hid, as `compact_log_by_branch` rewrites it.
+    /// A state's rewrite is done before `progress` hears about it, so
+    /// returning `ControlFlow::Break` stops with what was reclaimed so far
+    /// kept, and calling this again picks up the rest.
+    ///
+    /// The returned summary covers the states processed: `total_*` is their
+    /// chain size before compaction, `compactable_*` the updates and bytes
+    /// removed from the log, and `states_needing_compaction` how many states
+    /// had any removed. Iterators and offsets obtained before the call are
+    /// invalid after it; if the process dies between a rewrite and saving
+    /// the state index, run `repair`.
+    pub fn compact_all_states_truncating(
+        &self,
+        mut progress: impl FnMut(CompactionProgress) -> ControlFlow<()>,
+    ) -> Result<CompactionSummary> {
+        let _maintenance = self.ensure_writable()?;
+        let mut state_ids = self.state.state_ids();
+        state_ids.sort();
+        let total_states = state_ids.len();
+
+        let mut summary = CompactionSummary {
+            total_operations: 0,
+            compactable_operations: 0,
+            total_bytes: 0,
+            compactable_bytes: 0,
+            states_needing_compaction: 0,
+        };
+
+        for (state_index, state_id) in state_ids.into_iter().enumerate() {
+            if let Some(before) = self.get_chain_stats(&state_id)? {
+                summary.total_operations += before.total_operations;
+                summary.total_bytes += before.total_bytes;
+
+                self.prune_history(&[&state_id])?;
+                let (removed, bytes) = self.truncate_pruned(&state_id)?;
+                if removed > 0 {
+                    summary.compactable_operations += removed as u64;
+                    summary.compactable_bytes += bytes;
+                    summary.states_needing_compaction += 1;
+                }
+            }
+
+            let flow = progress(CompactionProgress {
+                state_index,
+                total_states,
+                state_id,
+                bytes_reclaimed: summary.compactable_bytes,
+            });
+            if flow.is_break() {
+                break;
+            }
+        }
+
+        Ok(summary)
+    }
+
+    /// Rewrite the log without the updates `prune_history` hid for
+    /// `state_id`. Returns the records removed and the bytes reclaimed.
+    fn truncate_pruned(&self, state_id: &str) -> Result<(usize, u64)> {
+        let _lock = self.write_lock.lock();
+        let mut removed = HashSet::new();
+        for snapshot_offset in self.state.pruned_at(state_id) {
+            removed.extend(self.superseded_updates(state_id, snapshot_offset)?.0);
+        }
+        if removed.is_empty() {
+            return Ok((0, 0));
+        }
+
+        let live: HashSet<BranchId> = self.branches.list_branches().iter().map(|branch| branch.id).collect();
+        let bytes = self.rewrite_log(|offset, _| !removed.contains(&offset), &live)?;
+        Ok((removed.len(), bytes))
+    }
+
+    /// Discard the history of states before their latest full snapshot on
+    /// the current branch.
+    ///
+    /// Each state gets a full snapshot at its head (unless its head already
+    /// is one), and every update behind it in the chain is dropped from the
+    /// index, so those records no longer turn up by ID, type or sequence.
+    /// Historical reads that would need them (`get_state_at`,
+    /// `create_branch_at`, `get_state_diff_between`) fail with
+    /// `HistoryPruned` instead. Updates that another branch's chain still
+    /// runs through are kept. The log is append-only, so the bytes stay on
+    /// disk; the pruning is saved with the state index and reapplied when
+    /// the store is reopened.
+    ///
+    /// States with no history on this branch are skipped.
+    pub fn prune_history(&self, state_ids: &[&str]) -> Result<PruneReport> {
+        let _maintenance = self.ensure_writable()?;
+        let branch_id = self.branches.current_branch().id;
+        let mut report = PruneReport::default();
+
+        for &state_id in state_ids {
+            if self.state.get_strategy(state_id).is_none() {
+                return Err(StoreError::StateNotRegistered(state_id.to_string()));
+            }
+            let head = match self.state.get_head(branch_id, state_id) {
+                Some(head) => head,
+                None => continue,
+            };
+
+            let snapshot_offset = if head.last_full_snapshot_offset == Some(head.head_offset) {
+                head.head_offset
+            } else {
+                self.compact_state(state_id)?;
+                report.snapshots_created += 1;
+                self.state
+                    .get_head(branch_id, state_id)
+                    .map(|head| head.head_offset)
+                    .ok_or_else(|| StoreError::StateNotRegistered(state_id.to_string()))?
+            };
+
+            self.state.mark_pruned(state_id, snapshot_offset);
+            let (pruned, kept) = self.unindex_pruned(state_id, snapshot_offset)?;
+            report.records_pruned += pruned;
+            report.records_kept += kept;
+            report.states_pruned += 1;
+        }
+
+        self.state.save()?;
+        Ok(report)
+    }
+
     /// Get compaction summary for all states.
     ///
     /// Returns total operations and bytes that could be skipped after compaction.
@@ -951,54 +3248,135 @@ impl Store {
     /// Note: This is now called automatically by `update_state()`. You only need
     /// to call this manually if you want to force a snapshot check at a specific time.
     pub fn create_snapshot_if_needed(&self, state_id: &str) -> Result<Option<Record>> {
+        let chain = self.chain_locks.get(state_id);
+        let _chain = chain.lock();
         self.create_snapshot_if_needed_internal(state_id, false)
     }
 
     /// Internal snapshot creation that can skip auto-snapshot to avoid recursion.
+    /// Caller holds the state's chain lock.
     fn create_snapshot_if_needed_internal(
         &self,
         state_id: &str,
         skip_auto: bool,
     ) -> Result<Option<Record>> {
-        use crate::state::SnapshotNeeded;
-
-        match self.state.snapshot_needed(self.branches.current_branch().id, state_id) {
-            None => Ok(None),
-            Some(SnapshotNeeded::Full) => {
-                let current = self.get_state(state_id)?.unwrap_or_default();
-                let record = self.update_state_internal(
-                    state_id,
-                    StateOperation::Snapshot(current),
-                    skip_auto,
-                )?;
-                Ok(Some(record))
-            }
-            Some(SnapshotNeeded::Delta) => {
-                let delta_items = self.compute_delta_items(state_id)?;
-                let record = self.update_state_internal(
-                    state_id,
-                    StateOperation::DeltaSnapshot(delta_items),
-                    skip_auto,
-                )?;
-                Ok(Some(record))
+        let mut kind = match self.state.snapshot_needed(self.branches.current_branch().id, state_id) {
+            Some(kind) => kind,
+            None => return Ok(None),
+        };
+        let mut delta = None;
+        if kind == SnapshotNeeded::Delta {
+            // A delta can't carry edits or redactions; take a full snapshot instead
+            delta = self.compute_delta_items(state_id)?;
+            if delta.is_none() {
+                kind = SnapshotNeeded::Full;
             }
         }
+        let operation = match delta {
+            Some(items) => StateOperation::DeltaSnapshot(items),
+            None => StateOperation::Snapshot(self.current_state(state_id)?.unwrap_or_default()),
+        };
+        let size = match &operation {
+            StateOperation::Snapshot(data) | StateOperation::DeltaSnapshot(data) => data.len(),
+            _ => 0,
+        };
+        let record = self.update_state_locked(state_id, operation, skip_auto)?;
+        self.observer.on_snapshot(state_id, kind, size);
+
+        let mut hook = self.snapshot_hook.lock();
+        if let Some(hook) = hook.as_mut() {
+            // Hooks may ship the snapshot elsewhere, so it must survive a crash first
+            self.log.sync()?;
+            hook(SnapshotEvent {
+                state_id: state_id.to_string(),
+                kind,
+                sequence: record.sequence,
+                size,
+            });
+        }
+        Ok(Some(record))
+    }
+
+    /// Call `hook` after every snapshot that `update_state` (or
+    /// `create_snapshot_if_needed`) writes, replacing any earlier hook.
+    ///
+    /// The log is synced before the hook runs, so the snapshot record is on
+    /// disk by then. The hook runs on the writing thread while the state is
+    /// locked: it must not update that state or set another hook, and slow
+    /// work is best handed off to another thread. Snapshots from
+    /// `compact_state` are not reported; `StoreObserver::on_snapshot` sees
+    /// those too.
+    pub fn set_snapshot_hook(&self, hook: impl FnMut(SnapshotEvent) + Send + 'static) {
+        *self.snapshot_hook.lock() = Some(Box::new(hook));
+    }
+
+    /// Remove the hook set with `set_snapshot_hook`.
+    pub fn clear_snapshot_hook(&self) {
+        self.snapshot_hook.lock().take();
     }
 
     /// Auto-snapshot helper called by update_state. Skips auto-snapshot on the
     /// snapshot operation itself to avoid infinite recursion.
+    ///
+    /// Does nothing while auto-snapshots are suspended store-wide or by the
+    /// state's policy on the current branch. Caller holds the state's chain
+    /// lock.
     fn auto_snapshot_if_needed(&self, state_id: &str) -> Result<()> {
-        self.create_snapshot_if_needed_internal(state_id, true)?;
-        Ok(())
-    }
-
+        if !self.auto_snapshot.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        let branch_id = self.branches.current_branch().id;
+        if !self.state.snapshot_policy(branch_id, state_id).auto_snapshot {
+            return Ok(());
+        }
+        self.create_snapshot_if_needed_internal(state_id, true)?;
+        Ok(())
+    }
+
+    /// Get a state's snapshot policy on the current branch.
+    pub fn snapshot_policy(&self, state_id: &str) -> Result<SnapshotPolicy> {
+        if self.state.get_strategy(state_id).is_none() {
+            return Err(StoreError::StateNotRegistered(state_id.to_string()));
+        }
+        Ok(self.state.snapshot_policy(self.branches.current_branch().id, state_id))
+    }
+
+    /// Change when a state snapshots on the current branch.
+    ///
+    /// Overrides the thresholds from registration without re-registering,
+    /// and can turn automatic snapshots off (e.g. for a bulk import) and
+    /// back on. Branches created afterwards inherit the policy. Saved to
+    /// the state index straight away, so it survives a reopen.
+    pub fn set_snapshot_policy(&self, state_id: &str, policy: SnapshotPolicy) -> Result<()> {
+        let _maintenance = self.ensure_writable()?;
+        let branch_id = self.branches.current_branch().id;
+        self.state.set_snapshot_policy(branch_id, state_id, policy)?;
+        self.state.save()
+    }
+
+    /// Turn automatic snapshots on or off for every state until the
+    /// returned guard is dropped.
+    ///
+    /// Unlike `set_snapshot_policy` this is not persisted. Updates made
+    /// while suspended still count towards the thresholds, so the next
+    /// update afterwards (or `create_snapshot_if_needed`) catches up.
+    pub fn with_auto_snapshot(&self, enabled: bool) -> AutoSnapshotGuard<'_> {
+        let previous = self.auto_snapshot.swap(enabled, Ordering::AcqRel);
+        AutoSnapshotGuard {
+            flag: &self.auto_snapshot,
+            previous,
+        }
+    }
+
     /// Compute items added since the last delta or full snapshot.
     ///
     /// This walks the chain collecting Append operations until hitting a snapshot.
-    fn compute_delta_items(&self, state_id: &str) -> Result<Vec<u8>> {
+    /// Returns None if an Edit, Redact or Prepend came since then: a delta only
+    /// adds items to the end, so it can't express them and a full snapshot is needed.
+    fn compute_delta_items(&self, state_id: &str) -> Result<Option<Vec<u8>>> {
         let head = match self.state.get_head(self.branches.current_branch().id, state_id) {
             Some(h) => h,
-            None => return Ok(serde_json::to_vec(&Vec::<serde_json::Value>::new())?),
+            None => return Ok(Some(serde_json::to_vec(&Vec::<serde_json::Value>::new())?)),
         };
 
         // Walk chain backwards, collecting append operations until snapshot
@@ -1007,8 +3385,7 @@ impl Store {
 
         while let Some(offset) = current_offset {
             let record = self.log.read_at(offset)?;
-            let update: StateUpdateRecord = serde_json::from_slice(&record.payload)
-                .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+            let update = decode_update(&record, state_id, offset)?;
 
             match &update.operation {
                 StateOperation::Append(item) => {
@@ -1016,29 +3393,45 @@ impl Store {
                         .map_err(|e| StoreError::Deserialization(e.to_string()))?;
                     appended_items.push(value);
                 }
-                StateOperation::Snapshot(_) | StateOperation::DeltaSnapshot(_) => {
+                StateOperation::AppendMany(items) => {
+                    // Reversed here, restored by the final reverse
+                    for item in items.iter().rev() {
+                        let value: serde_json::Value = serde_json::from_slice(item)
+                            .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+                        appended_items.push(value);
+                    }
+                }
+                StateOperation::Snapshot(_) | StateOperation::DeltaSnapshot(_) | StateOperation::Clear => {
                     // Hit a snapshot, stop collecting
                     break;
                 }
-                _ => {
-                    // Other operations (Edit, Redact) - for now, fall back to full state
-                    // This is a simplification; proper delta handling would track edits too
+                StateOperation::Edit { .. } | StateOperation::Redact { .. } | StateOperation::Prepend(_) => {
+                    return Ok(None)
                 }
+                _ => {}
             }
 
             current_offset = update.prev_update_offset;
         }
 
+        // A ring buffer only ever holds its newest items
+        if let Some(capacity) = self.state_capacity(state_id) {
+            appended_items.truncate(capacity);
+        }
+
         // Reverse since we collected in reverse order
         appended_items.reverse();
 
-        serde_json::to_vec(&appended_items).map_err(|e| StoreError::Serialization(e.to_string()))
+        serde_json::to_vec(&appended_items)
+            .map(Some)
+            .map_err(|e| StoreError::Serialization(e.to_string()))
     }
 
     // --- Branch Operations ---
 
     /// Create a new branch from the current branch head.
     pub fn create_branch(&self, name: &str, from: Option<&str>) -> Result<Branch> {
+        let _maintenance = self.ensure_writable()?;
         let parent = if let Some(from_name) = from {
             self.branches.get_branch(from_name).ok_or_else(|| {
                 StoreError::BranchNotFound(from_name.to_string())
@@ -1047,21 +3440,41 @@ impl Store {
             self.branches.current_branch()
         };
 
-        let parent_name = parent.name.clone();
-        let new_branch = self.branches.create_branch(name, Some(&parent_name))?;
+        // Journaled with the branch point so a replay forks from the same place
+        let operation = WalOperation::CreateBranch {
+            name: name.to_string(),
+            from: Some(parent.name.clone()),
+            at: Some(parent.head.0),
+            empty: false,
+        };
+        self.journaled(operation, || {
+            let parent_name = parent.name.clone();
+            let new_branch = self.branches.create_branch(name, Some(&parent_name))?;
 
-        // Copy state chain heads from parent to child
-        self.state.copy_heads_for_branch(parent.id, new_branch.id);
+            // Copy state chain heads from parent to child
+            self.state.copy_heads_for_branch(parent.id, new_branch.id);
 
-        // Broadcast branch created
-        self.subscriptions.broadcast_branch_created(&new_branch, Some(parent_name));
+            // Broadcast branch created
+            self.subscriptions.broadcast_branch_created(&new_branch, Some(parent_name));
 
-        Ok(new_branch)
+            Ok(new_branch)
+        })
     }
 
     /// Create a branch without copying state from parent.
     /// This is useful for creating branches with custom state (e.g., time-travel branching).
     pub fn create_empty_branch(&self, name: &str, from: Option<&str>) -> Result<Branch> {
+        let _maintenance = self.ensure_writable()?;
+        let operation = WalOperation::CreateBranch {
+            name: name.to_string(),
+            from: from.map(str::to_string),
+            at: None,
+            empty: true,
+        };
+        self.journaled(operation, || self.create_empty_branch_inner(name, from))
+    }
+
+    fn create_empty_branch_inner(&self, name: &str, from: Option<&str>) -> Result<Branch> {
         let parent_name = from.map(|n| n.to_string()).or_else(|| {
             Some(self.branches.current_branch().name.clone())
         });
@@ -1086,6 +3499,17 @@ impl Store {
     /// * `from` - Parent branch name to branch from
     /// * `at` - Sequence number on parent to branch at (must be <= parent's head)
     pub fn create_branch_at(&self, name: &str, from: &str, at: Sequence) -> Result<Branch> {
+        let _maintenance = self.ensure_writable()?;
+        let operation = WalOperation::CreateBranch {
+            name: name.to_string(),
+            from: Some(from.to_string()),
+            at: Some(at.0),
+            empty: false,
+        };
+        self.journaled(operation, || self.create_branch_at_inner(name, from, at))
+    }
+
+    fn create_branch_at_inner(&self, name: &str, from: &str, at: Sequence) -> Result<Branch> {
         let parent = self
             .branches
             .get_branch(from)
@@ -1110,9 +3534,92 @@ impl Store {
         Ok(new_branch)
     }
 
+    /// Create `new_name` as an independent copy of `source`.
+    ///
+    /// Unlike `create_branch`, which shares the parent's records and state
+    /// chains up to the branch point, this writes a fresh copy of every
+    /// record visible from `source` (its ancestors' included) onto a new
+    /// branch forked from main at sequence 0. State updates are rebuilt as
+    /// new chains, so pruning, truncating or deleting the source later
+    /// leaves the copy untouched. `caused_by` and `linked_to` references
+    /// between copied records point at the copies. Expired and hidden
+    /// records aren't copied, squash summaries become plain records, and
+    /// sequences are renumbered without gaps.
+    ///
+    /// Costs one append per visible record: O(history), not O(1) like
+    /// `create_branch`.
+    pub fn duplicate_branch(&self, source: &str, new_name: &str) -> Result<Branch> {
+        let _maintenance = self.ensure_writable()?;
+        let source_branch = self
+            .branches
+            .get_branch(source)
+            .ok_or_else(|| StoreError::BranchNotFound(source.to_string()))?;
+        let ranges = self.branches.visible_ranges(source_branch.id)?;
+
+        let copy = self.create_branch_at(new_name, MAIN_BRANCH, Sequence(0))?;
+        let _lock = self.write_lock.lock();
+        let now = Timestamp::now();
+        let mut copied: HashMap<RecordId, RecordId> = HashMap::new();
+        let remap = |ids: &[RecordId], copied: &HashMap<RecordId, RecordId>| -> Vec<RecordId> {
+            ids.iter().map(|id| copied.get(id).copied().unwrap_or(*id)).collect()
+        };
+
+        for (branch_id, first, last) in ranges {
+            for (_, offset) in self.index()?.query_range(branch_id, Some(first), Some(last), usize::MAX, false) {
+                let record = self.log.read_at(offset)?;
+                if record.is_expired(now) {
+                    continue;
+                }
+                let target = self
+                    .branches
+                    .get_branch_by_id(copy.id)
+                    .ok_or_else(|| StoreError::BranchNotFound(new_name.to_string()))?;
+
+                let new_record = if record.record_type == "state_update" {
+                    let update: StateUpdateRecord = serde_json::from_slice(&record.payload)
+                        .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+                    let encoded = serde_json::value::to_raw_value(&update.operation)?;
+                    self.append_state_update(&target, &update.state_id, update.operation, &encoded, None)?
+                } else {
+                    let input = RecordInput {
+                        record_type: record.record_type,
+                        payload: record.payload,
+                        encoding: record.encoding,
+                        caused_by: remap(&record.caused_by, &copied),
+                        linked_to: remap(&record.linked_to, &copied),
+                        expires_at: record.expires_at,
+                        lamport: None,
+                        // The original keeps the key
+                        idempotency_key: None,
+                        schema: record.schema,
+                        skip_schema_validation: false,
+                    };
+                    self.append_to_branch(&target, input, false)?
+                };
+                copied.insert(record.id, new_record.id);
+            }
+        }
+
+        self.state.save()?;
+        self.branches
+            .get_branch(new_name)
+            .ok_or_else(|| StoreError::BranchNotFound(new_name.to_string()))
+    }
+
     /// Switch to a different branch.
+    ///
+    /// Allowed on read-only handles: the switch only changes which branch
+    /// this handle reads from and is never persisted.
     pub fn switch_branch(&self, name: &str) -> Result<Branch> {
-        self.branches.switch_branch(name)
+        // `compact` reloads the current branch along with the rest
+        let _maintenance = self.maintenance_lock.read_recursive();
+        let operation = WalOperation::SwitchBranch { name: name.to_string() };
+        self.journaled(operation, || {
+            let from = self.branches.current_branch();
+            let branch = self.branches.switch_branch(name)?;
+            self.subscriptions.broadcast_branch_switched(&from.name, &branch.name);
+            Ok(branch)
+        })
     }
 
     /// Get the current branch.
@@ -1120,1033 +3627,5806 @@ impl Store {
         self.branches.current_branch()
     }
 
+    /// Get the head sequence of a branch.
+    pub fn branch_head(&self, name: &str) -> Result<Sequence> {
+        self.branches
+            .get_branch(name)
+            .map(|branch| branch.head)
+            .ok_or_else(|| StoreError::BranchNotFound(name.to_string()))
+    }
+
     /// List all branches.
     pub fn list_branches(&self) -> Vec<Branch> {
         self.branches.list_branches()
     }
 
-    /// Delete a branch.
-    pub fn delete_branch(&self, name: &str) -> Result<()> {
-        self.branches.delete_branch(name)?;
-
-        // Broadcast branch deleted
-        self.subscriptions.broadcast_branch_deleted(name);
+    /// Names of the branches that see the record at `seq` on the current
+    /// branch, sorted.
+    ///
+    /// The sequence is resolved in the current branch's history, so one at
+    /// or before the branch point refers to the ancestor's record and is
+    /// also seen by the ancestor and its other descendants that forked
+    /// after it. Check this before `truncate_branch` or
+    /// `compact_log_by_branch` to see what else still relies on a record.
+    /// Empty if the current branch has nothing at `seq`.
+    pub fn branches_containing_sequence(&self, seq: Sequence) -> Vec<String> {
+        let current = self.branches.current_branch();
+        let owner = self.branches.visible_ranges(current.id).ok().and_then(|ranges| {
+            ranges
+                .into_iter()
+                .find(|&(_, first, last)| first <= seq && seq <= last)
+                .map(|(id, _, _)| id)
+        });
+        match owner {
+            Some(owner) => self.branches.branches_containing(owner, seq),
+            None => Vec::new(),
+        }
+    }
 
-        Ok(())
+    /// Count the records visible from each branch, by branch name.
+    ///
+    /// A branch sees its own records plus those its ancestors held at each
+    /// branch point (see `BranchManager::visible_ranges`), so a child
+    /// counts everything it inherited. Expired records count until swept by
+    /// `expire_records`; squashed and pruned ones don't count.
+    ///
+    /// Counts are kept current as records are appended, so this is cheap to
+    /// call repeatedly. Anything that hides records or changes branch
+    /// ancestry (expiry sweeps, squashing, pruning, compaction, deleting a
+    /// branch) triggers one recount over the index on the next call.
+    pub fn branch_record_counts(&self) -> Result<HashMap<String, u64>> {
+        let counts = match self.branches.record_counts() {
+            Some(counts) => counts,
+            None => {
+                // Appends update the counts under the write lock; keep them out
+                // while counting
+                let _lock = self.write_lock.lock();
+                let counts = self.count_branch_records()?;
+                self.branches.set_record_counts(counts.clone());
+                counts
+            }
+        };
+        Ok(self
+            .branches
+            .list_branches()
+            .into_iter()
+            .map(|branch| (branch.name, counts.get(&branch.id).copied().unwrap_or(0)))
+            .collect())
     }
 
-    // --- Store Operations ---
+    /// Count the records visible from each branch from the index. Caller
+    /// holds the write lock.
+    fn count_branch_records(&self) -> Result<HashMap<BranchId, u64>> {
+        // Each branch's indexed sequences, in order
+        let mut sequences: HashMap<BranchId, Vec<Sequence>> = HashMap::new();
+        for ((branch, sequence), _) in self.index()?.sequence_offsets() {
+            sequences.entry(branch).or_default().push(sequence);
+        }
 
-    /// Get store statistics.
-    pub fn stats(&self) -> Result<StoreStats> {
-        Ok(StoreStats {
-            record_count: self.index.count() as u64,
-            blob_count: self.blobs.list()?.len() as u64,
-            branch_count: self.branches.branch_count() as u64,
-            state_slot_count: self.state.state_count() as u64,
-            total_size_bytes: self.log.size() + self.blobs.total_size()?,
-            blob_size_bytes: self.blobs.total_size()?,
-            index_size_bytes: 0, // Would need to track this
-        })
+        let mut counts = HashMap::new();
+        for branch in self.branches.list_branches() {
+            let mut count = 0;
+            for (id, first, last) in self.branches.visible_ranges(branch.id)? {
+                if let Some(sequences) = sequences.get(&id) {
+                    let start = sequences.partition_point(|&sequence| sequence < first);
+                    let end = sequences.partition_point(|&sequence| sequence <= last);
+                    count += (end - start) as u64;
+                }
+            }
+            counts.insert(branch.id, count);
+        }
+        Ok(counts)
     }
 
-    /// Sync all data to disk.
+    /// Count the records a branch holds that no ancestor shares.
     ///
-    /// This is O(1) - only syncs the log file and small metadata files.
-    /// The record index is not persisted; it's rebuilt from the log on startup.
-    pub fn sync(&self) -> Result<()> {
-        // Sync the append-only log (O(1) - just fsync)
-        self.log.sync()?;
-        // Sync small metadata files (O(states) and O(branches), typically tiny)
-        self.state.save()?;
-        self.branches.save()?;
-        Ok(())
+    /// Only records appended on the branch after its branch point are
+    /// counted; everything it inherits belongs to its ancestors. For `main`
+    /// that is every record on it. Useful for judging what deleting or
+    /// archiving a branch would free.
+    pub fn branch_size(&self, name: &str) -> Result<BranchSizeInfo> {
+        let branch = self
+            .branches
+            .get_branch(name)
+            .ok_or_else(|| StoreError::BranchNotFound(name.to_string()))?;
+        let first = branch.branch_point.map_or(Sequence(1), |point| point.next());
+
+        let mut size = BranchSizeInfo::default();
+        if first > branch.head {
+            return Ok(size);
+        }
+        for (_, offset) in self
+            .index()?
+            .query_range(branch.id, Some(first), Some(branch.head), usize::MAX, false)
+        {
+            let (record, end) = self.log.read_with_end(offset)?;
+            size.records += 1;
+            size.bytes += end - offset;
+            if record.record_type == "state_update" {
+                size.state_operations += 1;
+            }
+        }
+        Ok(size)
     }
 
-    /// Get the store path.
-    pub fn path(&self) -> &Path {
-        &self.config.path
+    /// Get all branches and their parent links, for rendering the branch tree.
+    pub fn branch_graph(&self) -> BranchGraph {
+        self.branches.graph()
     }
 
-    /// Get records that were caused by a given record (reverse lookup).
+    /// Remove records of deleted branches from the log.
     ///
-    /// Returns record IDs that have `record_id` in their `caused_by` field.
-    pub fn get_effects(&self, record_id: RecordId) -> Vec<RecordId> {
-        self.index.get_caused_by(record_id)
+    /// `delete_branch` only forgets the branch; its records stay in the log.
+    /// This rewrites the log without records whose branch no longer exists,
+    /// except those a live branch still sees: a child of a deleted branch
+    /// keeps its parent's records up to its branch point, and records on a
+    /// live branch's state chain are kept. Records of live branches are
+    /// always kept. State chain links and heads are moved to the new
+    /// offsets and the index is rebuilt.
+    ///
+    /// The new log replaces the old one atomically, but the state index is
+    /// saved right after; if the process dies in between, run `repair`.
+    /// Iterators and offsets obtained before compaction are invalid after
+    /// it. Fails without changing anything if a record can't be read (run
+    /// `repair` first).
+    pub fn compact_log_by_branch(&self) -> Result<VacuumStats> {
+        let _maintenance = self.ensure_writable()?;
+        let _lock = self.write_lock.lock();
+        self.compact_log_by_branch_locked()
     }
 
-    /// Get records that link to a given record (reverse lookup).
-    ///
-    /// Returns record IDs that have `record_id` in their `linked_to` field.
-    pub fn get_links_to(&self, record_id: RecordId) -> Vec<RecordId> {
-        self.index.get_linked_to(record_id)
+    /// `compact_log_by_branch` for a caller holding the write lock.
+    fn compact_log_by_branch_locked(&self) -> Result<VacuumStats> {
+        let (keep, live) = self.vacuum_filter(&self.branches, &self.state)?;
+        let mut stats = self.vacuum_stats(&keep)?;
+        if stats.records_removed == 0 {
+            return Ok(stats);
+        }
+
+        stats.bytes_reclaimed = self.rewrite_log(keep, &live)?;
+        Ok(stats)
     }
 
-    // --- Subscription Operations ---
+    /// Which records `compact_log_by_branch` keeps given these branches and
+    /// state heads, and the IDs of the live branches.
+    fn vacuum_filter(
+        &self,
+        branches: &BranchManager,
+        state: &StateManager,
+    ) -> Result<(impl Fn(u64, &Record) -> bool, HashSet<BranchId>)> {
+        let live_branches = branches.list_branches();
+        let live: HashSet<BranchId> = live_branches.iter().map(|branch| branch.id).collect();
+
+        // Deleted parents' records that orphaned children still see
+        let mut retained: HashMap<BranchId, Sequence> = HashMap::new();
+        for branch in &live_branches {
+            if let (Some(parent), Some(point)) = (branch.parent, branch.branch_point) {
+                if !live.contains(&parent) {
+                    let last = retained.entry(parent).or_default();
+                    *last = (*last).max(point);
+                }
+            }
+        }
 
-    /// Subscribe to store events.
-    ///
-    /// Returns a handle for receiving events. Call `catch_up_subscription` to
-    /// replay historical data before going live.
-    ///
-    /// # Example
-    ///
-    /// ```ignore
-    /// let handle = store.subscribe(SubscriptionConfig {
-    ///     filter: SubscriptionFilter::records(),
-    ///     from_sequence: Some(Sequence(100)),
-    ///     ..Default::default()
-    /// });
+        // Updates that live state chains run through
+        let mut reachable = HashSet::new();
+        for state_id in state.state_ids() {
+            let pruned = state.pruned_at(&state_id);
+            for (branch_id, head) in state.heads_for_state(&state_id) {
+                if live.contains(&branch_id) {
+                    reachable.extend(self.chain_offsets(&state_id, Some(head.head_offset), &pruned)?);
+                }
+            }
+        }
+
+        let kept = live.clone();
+        let keep = move |offset: u64, record: &Record| {
+            kept.contains(&record.branch)
+                || retained.get(&record.branch).is_some_and(|&last| record.sequence <= last)
+                || reachable.contains(&offset)
+        };
+        Ok((keep, live))
+    }
+
+    /// Count the records in the log and those `keep` rejects.
+    fn vacuum_stats(&self, keep: &impl Fn(u64, &Record) -> bool) -> Result<VacuumStats> {
+        let mut stats = VacuumStats::default();
+        for result in self.log.iter() {
+            let (offset, record) = result?;
+            stats.records_scanned += 1;
+            if !keep(offset, &record) {
+                stats.records_removed += 1;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Discard the records appended to a branch after `keep_through`.
     ///
-    /// // Replay historical data
-    /// store.catch_up_subscription(handle.id)?;
+    /// Resets the branch head to `keep_through`, rewinds each state's chain
+    /// head on the branch to its last update at or before it, and rewrites
+    /// the log without the branch's later records. Records elsewhere that
+    /// link to them stop resolving, as after `compact_log_by_branch`.
     ///
-    /// // Now receive live events
-    /// while let Ok(event) = handle.recv() {
-    ///     match event {
-    ///         StoreEvent::Record { record } => { /* ... */ }
-    ///         StoreEvent::CaughtUp => { /* now live */ }
-    ///         _ => {}
-    ///     }
-    /// }
-    /// ```
-    pub fn subscribe(&self, config: SubscriptionConfig) -> SubscriptionHandle {
-        self.subscriptions.subscribe(config)
+    /// Fails with `InvalidSequence` if `keep_through` is past the head, and
+    /// with `InvalidOperation` if it's before the branch point (those
+    /// records belong to the parent) or another branch forked after it.
+    /// Rewinding a state past the snapshot its history was pruned at fails
+    /// with `HistoryPruned`; nothing is changed in either case. The log is
+    /// replaced atomically but the state index and branch heads are saved
+    /// after it; if the process dies in between, the next `open` brings
+    /// them in line from the journal.
+    pub fn truncate_branch(&self, name: &str, keep_through: Sequence) -> Result<()> {
+        let _maintenance = self.ensure_writable()?;
+        let _lock = self.write_lock.lock();
+
+        let branch = self.truncatable_branch(name, keep_through)?;
+        if keep_through == branch.head {
+            return Ok(());
+        }
+        let first_removed = match self.index()?.query_range(branch.id, Some(keep_through.next()), None, 1, false)[..] {
+            [(_, offset)] => Some(self.log.read_at(offset)?.id),
+            _ => None,
+        };
+        let operation = WalOperation::TruncateBranch {
+            name: name.to_string(),
+            keep_through: keep_through.0,
+            first_removed,
+        };
+        self.journaled(operation, || self.truncate_branch_inner(&branch, keep_through))
     }
 
-    /// Unsubscribe and clean up.
-    pub fn unsubscribe(&self, id: SubscriptionId) {
-        self.subscriptions.unsubscribe(id)
+    /// The branch `truncate_branch` would cut back to `keep_through`, or
+    /// the error it fails with before changing anything.
+    fn truncatable_branch(&self, name: &str, keep_through: Sequence) -> Result<Branch> {
+        let branch = self
+            .branches
+            .get_branch(name)
+            .ok_or_else(|| StoreError::BranchNotFound(name.to_string()))?;
+        if keep_through > branch.head {
+            return Err(StoreError::InvalidSequence(keep_through, branch.head));
+        }
+        if let Some(point) = branch.branch_point.filter(|&point| keep_through < point) {
+            return Err(StoreError::InvalidOperation(format!(
+                "cannot truncate '{}' to {} before its branch point {}",
+                name, keep_through.0, point.0
+            )));
+        }
+        let branches = self.branches.list_branches();
+        if let Some(child) = branches.iter().find(|child| {
+            child.parent == Some(branch.id) && child.branch_point.is_some_and(|point| point > keep_through)
+        }) {
+            return Err(StoreError::InvalidOperation(format!(
+                "branch '{}' forked from '{}' after sequence {}",
+                child.name, name, keep_through.0
+            )));
+        }
+        Ok(branch)
     }
 
-    /// Get the number of active subscriptions.
-    pub fn subscription_count(&self) -> usize {
-        self.subscriptions.subscription_count()
+    /// `truncate_branch` without the checks or the journal entry. Caller
+    /// holds the write lock.
+    fn truncate_branch_inner(&self, branch: &Branch, keep_through: Sequence) -> Result<()> {
+        // Find every rewound head before changing anything
+        let mut rewound = Vec::new();
+        for state_id in self.state.state_ids() {
+            if self.state.get_head(branch.id, &state_id).is_some() {
+                let info = self.find_chain_info_at(branch.id, &state_id, keep_through)?;
+                rewound.push((state_id, info));
+            }
+        }
+
+        let live: HashSet<BranchId> = self.branches.list_branches().iter().map(|branch| branch.id).collect();
+        let keep = |_: u64, record: &Record| record.branch != branch.id || record.sequence <= keep_through;
+        let offsets = self.rewrite_log_records(keep)?;
+
+        // The rewound heads hold old offsets, so they go in before the remap
+        for (state_id, info) in rewound {
+            match info {
+                Some((head_offset, item_count)) => {
+                    self.state.set_head_for_branch(branch.id, &state_id, head_offset, item_count)
+                }
+                None => self.state.remove_head(branch.id, &state_id),
+            }
+        }
+        self.remap_after_rewrite(&offsets, &live)?;
+        self.last_appended.lock().remove(&branch.id);
+
+        self.branches.update_head(branch.id, keep_through)?;
+        self.branches.save()?;
+        self.subscriptions.broadcast_branch_head(&branch.name, keep_through);
+        Ok(())
     }
 
-    /// Mark a subscription as caught up (for manual catch-up handling).
-    pub fn mark_subscription_caught_up(&self, id: SubscriptionId) -> Result<()> {
-        self.subscriptions.mark_caught_up(id)
+    /// Delete a branch.
+    pub fn delete_branch(&self, name: &str) -> Result<()> {
+        let _maintenance = self.ensure_writable()?;
+        let operation = WalOperation::DeleteBranch { name: name.to_string() };
+        self.journaled(operation, || self.branches.delete_branch(name))?;
+
+        // Broadcast branch deleted
+        self.subscriptions.broadcast_branch_deleted(name);
+
+        Ok(())
     }
 
-    /// Perform catch-up for a subscription.
-    ///
-    /// If `from_sequence` is set in the config, this replays:
-    /// 1. State snapshots for subscribed states at the starting sequence
-    /// 2. Historical records matching the filter from that sequence
+    // --- Replication Operations ---
+
+    /// Collect the mutations made after `cursor`, for replaying on another
+    /// store with `apply_changes`.
     ///
-    /// After catch-up completes, the subscription is marked as caught up
-    /// and will receive live events.
-    pub fn catch_up_subscription(&self, id: SubscriptionId) -> Result<()> {
-        let config = self
-            .subscriptions
-            .get_config(id)
-            .ok_or(StoreError::SubscriptionDropped)?;
+    /// Returns the changes in an order that can be applied as-is (blobs,
+    /// then records and state updates in log order with each branch created
+    /// ahead of its first record) and a cursor to pass to the next call.
+    /// Records on deleted branches are left out, as are branches whose
+    /// parent was deleted. Branches are replayed as forks of their parent's
+    /// state at the branch point, including ones made with
+    /// `create_empty_branch`, and states are only sent once they have
+    /// been updated. Snapshot policies and field indices are not sent.
+    pub fn changes_since(&self, cursor: ChangeCursor) -> Result<(Vec<Change>, ChangeCursor)> {
+        // Hold off writers so the branch list matches the log
+        let _lock = self.write_lock.lock();
+        let node_id = self.node_id();
+        let mut changes = Vec::new();
+        let mut next = cursor;
+
+        // Blobs first: records may refer to them by hash
+        for info in self.blobs.list_since(cursor.blob_created)? {
+            let blob = self
+                .blobs
+                .get(&info.hash)?
+                .ok_or(StoreError::BlobNotFound(info.hash))?;
+            next.blob_created = next.blob_created.max(info.created);
+            changes.push(Change::StoreBlob {
+                content: blob.content,
+                content_type: blob.content_type,
+            });
+        }
 
-        // If no from_sequence, just mark as caught up immediately
-        let from_seq = match config.from_sequence {
-            Some(seq) => seq,
-            None => {
-                return self.subscriptions.mark_caught_up(id);
+        let branches: HashMap<BranchId, Branch> = self
+            .branches
+            .list_branches()
+            .into_iter()
+            .map(|branch| (branch.id, branch))
+            .collect();
+        let main = self.branches.get_branch(MAIN_BRANCH).map_or(0, |branch| branch.id.0);
+        let known = cursor.branch_id.max(main);
+        let mut sent: HashSet<BranchId> =
+            branches.keys().copied().filter(|id| id.0 <= known).collect();
+        let mut registered = HashSet::new();
+
+        let start = self
+            .index()?
+            .get_offset_by_id(RecordId(cursor.record_id))
+            .unwrap_or(0);
+        for item in self.log.iter_from(start) {
+            let (_, record) = item?;
+            if record.id.0 <= cursor.record_id {
+                continue;
             }
-        };
-
-        // Send state snapshots for subscribed states
-        if config.filter.include_state_changes {
-            let state_ids = match &config.filter.state_ids {
-                Some(ids) => ids.clone(),
-                None => self.state.state_ids(),
+            next.record_id = record.id.0;
+            if !Self::push_branch_change(record.branch, &branches, &mut sent, &mut changes) {
+                continue;
+            }
+            let branch = branches[&record.branch].name.clone();
+            let key = ChangeKey {
+                source: node_id.clone(),
+                seq: record.id.0,
             };
 
-            for state_id in state_ids {
-                // Get state at the starting sequence, or current state if it didn't exist then
-                let (state_data, snapshot_seq) = match self.get_state_at(&state_id, from_seq)? {
-                    Some(data) => (data, from_seq),
-                    None => {
-                        // State didn't exist at from_seq, try current state
-                        match self.get_state(&state_id)? {
-                            Some(data) => (data, self.current_branch().head),
-                            None => continue, // State doesn't exist at all
-                        }
-                    }
-                };
+            if record.record_type != "state_update" {
+                changes.push(Change::Append { key, branch, record });
+                continue;
+            }
+            let update: StateUpdateRecord = serde_json::from_slice(&record.payload)
+                .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+            if registered.insert(update.state_id.clone()) {
+                if let Some(strategy) = self.state.get_strategy(&update.state_id) {
+                    changes.push(Change::RegisterState {
+                        registration: StateRegistration {
+                            id: update.state_id.clone(),
+                            strategy,
+                            initial_value: None,
+                        },
+                    });
+                }
+            }
+            changes.push(Change::UpdateState {
+                key,
+                branch,
+                sequence: record.sequence,
+                state_id: update.state_id,
+                operation: update.operation,
+                lamport: record.lamport,
+            });
+        }
 
-                // Check size limits and truncate if needed
-                let total_bytes = state_data.len();
-                let (data, truncated, from_index, total_length) =
-                    if total_bytes > config.max_snapshot_bytes {
-                        // Try to parse as JSON array to provide partial data
-                        if let Ok(serde_json::Value::Array(arr)) =
-                            serde_json::from_slice::<serde_json::Value>(&state_data)
-                        {
-                            let total_len = arr.len();
-                            // Send tail of the array that fits
-                            let mut size = 0;
-                            let mut start_idx = total_len;
-                            for (i, item) in arr.iter().rev().enumerate() {
-                                let item_size = serde_json::to_string(item)
-                                    .map(|s| s.len())
-                                    .unwrap_or(0);
-                                if size + item_size > config.max_snapshot_bytes {
-                                    break;
-                                }
-                                size += item_size;
-                                start_idx = total_len - 1 - i;
-                            }
-                            let truncated_arr: Vec<_> = arr[start_idx..].to_vec();
-                            (
-                                serde_json::Value::Array(truncated_arr),
-                                true,
-                                Some(start_idx),
-                                Some(total_len),
-                            )
-                        } else {
-                            // Non-array, just send null and mark truncated
-                            (serde_json::Value::Null, true, None, None)
-                        }
-                    } else {
-                        let json_data: serde_json::Value =
-                            serde_json::from_slice(&state_data).unwrap_or(serde_json::Value::Null);
-                        (json_data, false, None, None)
-                    };
+        // Branches with nothing on them yet, parents first
+        let mut ids: Vec<BranchId> = branches.keys().copied().collect();
+        ids.sort();
+        for id in ids {
+            Self::push_branch_change(id, &branches, &mut sent, &mut changes);
+            next.branch_id = next.branch_id.max(id.0);
+        }
 
-                let event = crate::subscriptions::StoreEvent::StateSnapshot {
-                    state_id,
-                    data,
-                    // Use the sequence where we got the snapshot from
-                    sequence: snapshot_seq,
-                    truncated,
-                    total_bytes,
-                    from_index,
-                    total_length,
-                };
+        Ok((changes, next))
+    }
 
-                if !self.subscriptions.send_to(id, event) {
-                    return Err(StoreError::SubscriptionDropped);
+    /// Replay changes from another store's `changes_since`, in order.
+    ///
+    /// Replaying is idempotent, so retries and overlapping pulls are safe.
+    /// Branches, states and blobs that already exist are left alone.
+    /// Records and state updates are skipped if their `ChangeKey` is at or
+    /// below the highest one applied from that source (see
+    /// `applied_watermark`); otherwise one already at their branch and
+    /// sequence must match them, and is then skipped too. A different one
+    /// there fails with `ReplicationConflict`, and a change that would leave
+    /// a gap in a branch's sequence fails with `InvalidSequence`. Changes
+    /// before a failing one stay applied; the failing one isn't recorded
+    /// as applied, so a retry writes it.
+    ///
+    /// Record IDs match the source's as long as this store only receives
+    /// changes, so links between records carry over.
+    pub fn apply_changes(&self, changes: Vec<Change>) -> Result<()> {
+        let _maintenance = self.ensure_writable()?;
+        let mut applied = self.replication.lock().applied.clone();
+        let result = self.apply_changes_inner(changes, &mut applied);
+
+        // Keep the watermarks of whatever got applied, even on failure
+        let mut replication = self.replication.lock();
+        replication.applied = applied;
+        replication.save(&self.config.path)?;
+        result
+    }
+
+    /// This store's replication node ID, sent as `ChangeKey::source` with
+    /// its changes. Generated when the store is created.
+    pub fn node_id(&self) -> String {
+        self.replication.lock().node_id.clone()
+    }
+
+    /// The highest `ChangeKey::seq` applied from `source`, if any.
+    pub fn applied_watermark(&self, source: &str) -> Option<u64> {
+        self.replication.lock().applied.get(source).copied()
+    }
+
+    /// Write the records visible from one branch to `writer`, for loading
+    /// into a new store with `import_branch`.
+    ///
+    /// The stream is JSON lines of `Change`s: the blobs the records mention
+    /// (by hash, as `compact` finds them), then every record and state
+    /// update visible from the branch, inherited ones included, oldest
+    /// first. Everything goes on `main`, so the imported store's `main`
+    /// holds what this branch does. Records are renumbered from 1 without
+    /// gaps, and `caused_by` and `linked_to` keep only links to exported
+    /// records. Like `duplicate_branch`, expired and hidden records are
+    /// left out and squash summaries become plain records.
+    pub fn export_branch<W: Write>(&self, branch: &str, mut writer: W) -> Result<ExportManifest> {
+        let source = self
+            .branches
+            .get_branch(branch)
+            .ok_or_else(|| StoreError::BranchNotFound(branch.to_string()))?;
+        // Hold off writers so the records and states agree
+        let _lock = self.write_lock.lock();
+        let now = Timestamp::now();
+        let mut manifest = ExportManifest {
+            branch: source.name.clone(),
+            ..Default::default()
+        };
+
+        let mut offsets = Vec::new();
+        for (branch_id, first, last) in self.branches.visible_ranges(source.id)? {
+            for (_, offset) in self.index()?.query_range(branch_id, Some(first), Some(last), usize::MAX, false) {
+                offsets.push(offset);
+            }
+        }
+
+        // Blobs first: records may refer to them by hash
+        let mut mentioned = HashSet::new();
+        for &offset in &offsets {
+            let record = self.log.read_at(offset)?;
+            if record.record_type != "state_update" && !record.is_expired(now) {
+                mentioned.extend(Self::referenced_blobs(&record, self.config.blob_hash));
+            }
+        }
+        let mut mentioned: Vec<Hash> = mentioned.into_iter().collect();
+        mentioned.sort_by_key(|hash| hash.0);
+        for hash in mentioned {
+            if let Some(blob) = self.blobs.get(&hash)? {
+                Self::write_change(
+                    &mut writer,
+                    &Change::StoreBlob {
+                        content: blob.content,
+                        content_type: blob.content_type,
+                    },
+                )?;
+                manifest.blobs += 1;
+            }
+        }
+
+        let node_id = self.node_id();
+        let mut renumbered: HashMap<RecordId, RecordId> = HashMap::new();
+        let mut registered = HashSet::new();
+        for offset in offsets {
+            let mut record = self.log.read_at(offset)?;
+            if record.is_expired(now) {
+                continue;
+            }
+            let position = renumbered.len() as u64 + 1;
+            renumbered.insert(record.id, RecordId(position));
+            let key = ChangeKey {
+                source: node_id.clone(),
+                seq: position,
+            };
+
+            if record.record_type == "state_update" {
+                let update: StateUpdateRecord = serde_json::from_slice(&record.payload)
+                    .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+                if registered.insert(update.state_id.clone()) {
+                    if let Some(strategy) = self.state.get_strategy(&update.state_id) {
+                        let registration = StateRegistration {
+                            id: update.state_id.clone(),
+                            strategy,
+                            initial_value: None,
+                        };
+                        Self::write_change(&mut writer, &Change::RegisterState { registration })?;
+                        manifest.states += 1;
+                    }
                 }
+                let change = Change::UpdateState {
+                    key,
+                    branch: MAIN_BRANCH.to_string(),
+                    sequence: Sequence(position),
+                    state_id: update.state_id,
+                    operation: update.operation,
+                    lamport: record.lamport,
+                };
+                Self::write_change(&mut writer, &change)?;
+                manifest.state_updates += 1;
+                continue;
             }
+
+            let exported = |ids: &[RecordId]| -> Vec<RecordId> {
+                ids.iter().filter_map(|id| renumbered.get(id).copied()).collect()
+            };
+            record.caused_by = exported(&record.caused_by);
+            record.linked_to = exported(&record.linked_to);
+            record.id = RecordId(position);
+            record.sequence = Sequence(position);
+            record.squash = false;
+            let change = Change::Append {
+                key,
+                branch: MAIN_BRANCH.to_string(),
+                record,
+            };
+            Self::write_change(&mut writer, &change)?;
+            manifest.records += 1;
         }
 
-        // Replay historical records
-        if config.filter.include_records {
-            let current_branch = self.branches.current_branch();
-            let payload_threshold = 4096; // Same as manager default
+        writer.flush()?;
+        Ok(manifest)
+    }
 
-            for result in self.iter_from(from_seq) {
-                let (_offset, record) = result?;
+    /// Load a stream written by `export_branch` onto `main`.
+    ///
+    /// Meant for a new store: record IDs and sequences in the stream are
+    /// only right for an empty `main`, so anything else fails with
+    /// `InvalidOperation` before reading. The changes are replayed with
+    /// `apply_changes`.
+    pub fn import_branch<R: BufRead>(&self, reader: R) -> Result<()> {
+        let _maintenance = self.ensure_writable()?;
+        let main = self
+            .branches
+            .get_branch(MAIN_BRANCH)
+            .ok_or_else(|| StoreError::BranchNotFound(MAIN_BRANCH.to_string()))?;
+        if main.head != Sequence(0) || self.record_count() > 0 {
+            return Err(StoreError::InvalidOperation(
+                "Branch exports can only be imported into an empty store".to_string(),
+            ));
+        }
 
-                // Skip records not on current branch
-                if record.branch != current_branch.id {
+        let mut changes = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let change: Change =
+                serde_json::from_str(&line).map_err(|e| StoreError::Deserialization(e.to_string()))?;
+            changes.push(change);
+        }
+        self.apply_changes(changes)
+    }
+
+    /// Write one change of an export as a line of JSON.
+    fn write_change<W: Write>(writer: &mut W, change: &Change) -> Result<()> {
+        serde_json::to_writer(&mut *writer, change)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn apply_changes_inner(
+        &self,
+        changes: Vec<Change>,
+        applied: &mut HashMap<String, u64>,
+    ) -> Result<()> {
+        for change in changes {
+            if let Some(key) = change.key() {
+                if applied.get(&key.source).is_some_and(|&seq| key.seq <= seq) {
                     continue;
                 }
-
-                // Apply record type filter
-                if let Some(ref types) = config.filter.record_types {
-                    if !types.contains(&record.record_type) {
+            }
+            match change {
+                Change::StoreBlob { content, content_type } => {
+                    self.store_blob(&content, &content_type)?;
+                }
+                Change::CreateBranch { name, parent, at } => {
+                    if self.branches.get_branch(&name).is_none() {
+                        self.create_branch_at(&name, &parent, at)?;
+                    }
+                }
+                Change::RegisterState { registration } => {
+                    if self.state.get_strategy(&registration.id).is_none() {
+                        self.register_state(registration)?;
+                    }
+                }
+                Change::Append { key, branch, record } => {
+                    let _lock = self.write_lock.lock();
+                    let target = self.replay_target(&branch, record.sequence, &key, |existing| {
+                        existing.record_type == record.record_type
+                            && existing.encoding == record.encoding
+                            && existing.payload == record.payload
+                    })?;
+                    let Some(branch) = target else {
+                        applied.insert(key.source, key.seq);
                         continue;
+                    };
+                    let input = RecordInput {
+                        record_type: record.record_type,
+                        payload: record.payload,
+                        encoding: record.encoding,
+                        caused_by: record.caused_by,
+                        linked_to: record.linked_to,
+                        expires_at: record.expires_at,
+                        lamport: record.lamport,
+                        idempotency_key: record.idempotency_key,
+                        schema: record.schema,
+                        skip_schema_validation: false,
+                    };
+                    let appended = self.append_to_branch(&branch, input, record.squash)?;
+                    applied.insert(key.source, key.seq);
+                    if record.squash {
+                        self.index()?.apply_squash(&self.log, &appended)?;
+                        self.branches.invalidate_record_counts();
                     }
                 }
-
-                let summary =
-                    crate::subscriptions::RecordSummary::from_record(&record, payload_threshold);
-                let event = crate::subscriptions::StoreEvent::Record { record: summary };
-
-                if !self.subscriptions.send_to(id, event) {
-                    return Err(StoreError::SubscriptionDropped);
+                Change::UpdateState { key, branch, sequence, state_id, operation, lamport } => {
+                    let _lock = self.write_lock.lock();
+                    let encoded = serde_json::to_vec(&operation)?;
+                    let target = self.replay_target(&branch, sequence, &key, |existing| {
+                        serde_json::from_slice::<StateUpdateRecord>(&existing.payload).is_ok_and(
+                            |update| {
+                                existing.record_type == "state_update"
+                                    && update.state_id == state_id
+                                    && serde_json::to_vec(&update.operation).is_ok_and(|op| op == encoded)
+                            },
+                        )
+                    })?;
+                    let Some(branch) = target else {
+                        applied.insert(key.source, key.seq);
+                        continue;
+                    };
+                    let encoded = serde_json::value::to_raw_value(&operation)?;
+                    self.append_state_update(&branch, &state_id, operation, &encoded, lamport)?;
+                    applied.insert(key.source, key.seq);
                 }
             }
         }
-
-        // Mark as caught up
-        self.subscriptions.mark_caught_up(id)
+        Ok(())
     }
 
-    // --- Private Helpers ---
-
-    fn write_manifest(path: &Path) -> Result<()> {
-        use std::io::Write;
+    // --- Store Operations ---
 
-        let manifest_path = path.join("MANIFEST");
-        let mut file = File::create(manifest_path)?;
+    /// Get store statistics.
+    ///
+    /// The per-state breakdown walks every state chain on the current branch,
+    /// so this is O(state operations) rather than O(1).
+    pub fn stats(&self) -> Result<StoreStats> {
+        let branch_id = self.branches.current_branch().id;
+        let mut state_breakdown = Vec::new();
+        for state_id in self.state.state_ids() {
+            if let Some(chain) = self.state.count_chain_operations(branch_id, &state_id)? {
+                state_breakdown.push(StateSizeInfo {
+                    state_id,
+                    operation_count: chain.total_operations,
+                    bytes: chain.total_bytes,
+                });
+            }
+        }
 
-        file.write_all(STORE_MAGIC)?;
-        file.write_all(&[STORE_VERSION])?;
-        file.sync_all()?;
+        let blob_size_bytes = self.blobs.total_size()?;
 
-        Ok(())
+        Ok(StoreStats {
+            record_count: self.record_count(),
+            blob_count: self.blob_count()?,
+            branch_count: self.branches.branch_count() as u64,
+            state_slot_count: self.state.state_count() as u64,
+            total_size_bytes: self.log.size() + blob_size_bytes,
+            blob_size_bytes,
+            index_size_bytes: self.index()?.memory_size(),
+            state_breakdown,
+            blob_cache_hit_rate: self.blobs.cache_hit_rate(),
+        })
     }
 
-    fn verify_manifest(path: &Path) -> Result<()> {
-        use std::io::Read;
+    /// Number of indexed records, as in `stats().record_count`.
+    ///
+    /// Read from the in-memory index, so it's O(1) and doesn't touch disk.
+    pub fn record_count(&self) -> u64 {
+        self.index_or_empty().count() as u64
+    }
 
-        let manifest_path = path.join("MANIFEST");
-        let mut file = File::open(manifest_path)?;
+    /// Number of stored blobs, as in `stats().blob_count`.
+    ///
+    /// Lists the blob directory but, unlike `stats`, doesn't read each
+    /// blob's size or walk any state chains.
+    pub fn blob_count(&self) -> Result<u64> {
+        self.blobs.count()
+    }
 
-        let mut magic = [0u8; 4];
-        file.read_exact(&mut magic)?;
-        if &magic != STORE_MAGIC {
-            return Err(StoreError::InvalidFormat("Invalid store magic".into()));
+    /// Write a consistent copy of the store to `dest` while it stays open.
+    ///
+    /// Holds the write lock for the duration, so records, state and branch
+    /// changes wait until the copy is done; blob writes carry on and land in
+    /// the copy only if complete. The log is copied up to its size at the
+    /// time of the call and the state and branch indices are written from
+    /// memory, so a store opened at `dest` holds exactly what this one held
+    /// then. The record and blob type indices are rebuilt when it's opened.
+    ///
+    /// `dest` must not exist or be an empty directory. Fails with `ReadOnly`
+    /// on a read-only handle, which has nothing of its own to flush.
+    pub fn snapshot_to_dir(&self, dest: impl AsRef<Path>) -> Result<()> {
+        let dest = dest.as_ref();
+        let _maintenance = self.ensure_writable()?;
+        if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+            return Err(StoreError::InvalidOperation(format!(
+                "Snapshot destination {} is not empty",
+                dest.display()
+            )));
         }
 
-        let mut version = [0u8; 1];
-        file.read_exact(&mut version)?;
-        if version[0] != STORE_VERSION {
-            return Err(StoreError::InvalidFormat(format!(
-                "Unsupported store version: {}",
-                version[0]
-            )));
+        let _write_guard = self.write_lock.lock();
+        self.sync()?;
+        fs::create_dir_all(dest.join("blobs"))?;
+
+        let log_size = self.log.size();
+        Self::copy_file_prefix(self.log.path(), &dest.join("records.log"), log_size)?;
+        self.state.save_to(&dest.join("state.bin"))?;
+        self.branches.save_to(&dest.join("branches.bin"))?;
+        for name in [FIELD_INDEX_FILE, REPLICATION_FILE, RECORD_SCHEMA_FILE] {
+            let path = self.config.path.join(name);
+            if path.exists() {
+                let len = fs::metadata(&path)?.len();
+                Self::copy_file_prefix(&path, &dest.join(name), len)?;
+            }
         }
+        self.blobs.copy_to(&dest.join("blobs"))?;
 
+        // Last, so a snapshot cut short doesn't open as a store
+        let manifest = self.config.path.join("MANIFEST");
+        Self::copy_file_prefix(&manifest, &dest.join("MANIFEST"), fs::metadata(&manifest)?.len())?;
+        File::open(dest)?.sync_all()?;
         Ok(())
     }
 
-    fn acquire_lock(path: &Path) -> Result<File> {
-        let lock_path = path.join("LOCK");
-        let lock_file = File::create(lock_path)?;
-
-        lock_file
-            .try_lock_exclusive()
-            .map_err(|_| StoreError::Locked)?;
-
-        Ok(lock_file)
+    /// Sync all data to disk.
+    ///
+    /// This is O(1) - only syncs the log file and small metadata files.
+    /// The state, branch and blob type indices are only rewritten if they
+    /// changed since they were last saved. The record index is not
+    /// persisted; it's rebuilt from the log on startup. Journal entries
+    /// for the writes this persisted are then dropped from the WAL.
+    pub fn sync(&self) -> Result<()> {
+        let _maintenance = self.ensure_writable()?;
+        self.sync_files()
     }
-}
 
-impl Drop for Store {
-    fn drop(&mut self) {
-        // Best-effort sync on drop
-        let _ = self.sync();
+    /// `sync` for a caller that has checked the handle is writable.
+    fn sync_files(&self) -> Result<()> {
+        persist(
+            &self.log,
+            &self.state,
+            &self.branches,
+            &self.blobs,
+            self.wal.as_deref(),
+            &self.wal_unsynced,
+        )
     }
-}
-
-/// Iterator over items in an AppendLog state.
-///
-/// This reconstructs items lazily, yielding them one at a time.
-/// Items are yielded in order (oldest first).
-pub struct StateItemIterator {
-    log: Arc<RecordLog>,
-    /// Buffer of items to yield
-    items_buffer: Vec<serde_json::Value>,
-    /// Current index in items_buffer
-    current_index: usize,
-    /// Head offset to start from
-    head_offset: u64,
-    /// Whether we've finished preparing
-    prepared: bool,
-}
 
-impl StateItemIterator {
-    fn new(log: Arc<RecordLog>, head_offset: u64) -> Self {
-        Self {
-            log,
-            items_buffer: Vec::new(),
-            current_index: 0,
-            head_offset,
-            prepared: false,
+    /// Flush everything to disk and close the store.
+    ///
+    /// Stops the background syncer, syncs the log and saves the state,
+    /// branch and blob indices like `sync`, then releases the lock. Unlike
+    /// dropping the store, which syncs on a best-effort basis and ignores
+    /// failures, this returns the first error, so prefer it wherever a lost
+    /// final flush matters. The lock is released either way.
+    pub fn close(mut self) -> Result<()> {
+        self.sync_worker.take();
+        if self.config.read_only {
+            return Ok(());
         }
+        self.sync()
     }
 
-    /// Prepare by collecting and ordering all items.
-    fn prepare(&mut self) -> Result<()> {
-        if self.prepared {
-            return Ok(());
-        }
+    /// Check that branch heads, the index and the log agree.
+    ///
+    /// A much cheaper check than `verify`: for each branch, compares the head
+    /// with the highest indexed sequence (a head behind it means the next
+    /// append would reuse a sequence) and confirms that index entry points
+    /// at the record it claims to. A head past it is fine: the branch's
+    /// last records may have expired, been squashed or pruned. Records in
+    /// the log after the last indexed one are flagged when they sit past
+    /// their branch's head, as a partial write leaves them. Works on
+    /// read-only handles.
+    pub fn check_consistency(&self) -> Result<ConsistencyReport> {
+        let _lock = self.write_lock.lock();
+        let mut report = ConsistencyReport::default();
 
-        // Two-pass approach: collect operations, reverse, apply
-        let mut ops: Vec<StateOperation> = Vec::new();
-        let mut current_offset = Some(self.head_offset);
-        let mut hit_snapshot = false;
+        let branches = self.branches.list_branches();
+        for branch in &branches {
+            report.branches_checked += 1;
+            let Some(max) = self.index()?.max_sequence(branch.id) else {
+                continue;
+            };
 
-        // Pass 1: Collect operations in reverse order
-        while let Some(offset) = current_offset {
-            let record = self.log.read_at(offset)?;
-            let update: StateUpdateRecord = serde_json::from_slice(&record.payload)
-                .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+            if max > branch.head {
+                report.push(
+                    VerifyLocation::Branch(branch.name.clone()),
+                    format!("head {:?} is behind indexed sequence {:?}", branch.head, max),
+                );
+            }
 
-            match &update.operation {
-                StateOperation::Snapshot(_) => {
-                    ops.push(update.operation.clone());
-                    break; // Full snapshot has everything
-                }
-                StateOperation::DeltaSnapshot(_) => {
-                    ops.push(update.operation.clone());
-                    hit_snapshot = true;
-                }
-                _ => {
-                    if !hit_snapshot {
-                        ops.push(update.operation.clone());
-                    }
+            if let Some(offset) = self.index()?.get_offset(branch.id, max) {
+                let problem = match self.log.read_at(offset) {
+                    Ok(record) if record.branch == branch.id && record.sequence == max => None,
+                    Ok(record) => Some(format!(
+                        "offset {} holds {:?} on {:?}",
+                        offset, record.sequence, record.branch
+                    )),
+                    Err(e) => Some(e.to_string()),
+                };
+                if let Some(problem) = problem {
+                    let location = VerifyLocation::IndexSequence { branch: branch.id, sequence: max };
+                    report.push(location, problem);
                 }
             }
-
-            current_offset = update.prev_update_offset;
         }
 
-        // Pass 2: Apply operations in forward order
-        ops.reverse();
-        for op in ops {
-            match op {
-                StateOperation::Append(item) => {
-                    let value: serde_json::Value = serde_json::from_slice(&item)
-                        .map_err(|e| StoreError::Deserialization(e.to_string()))?;
-                    self.items_buffer.push(value);
+        // Log tail: anything after the last indexed record should be at or before its head
+        let heads: HashMap<BranchId, Sequence> = branches.iter().map(|b| (b.id, b.head)).collect();
+        let mut offset = match self.index()?.id_offsets().into_iter().map(|(_, offset)| offset).max() {
+            Some(last) => self.log.read_with_next(last).1.unwrap_or(self.log.size()),
+            None => 0,
+        };
+        while offset < self.log.size() {
+            let (result, next) = self.log.read_with_next(offset);
+            match result {
+                Ok(record) if heads.get(&record.branch).is_none_or(|&head| record.sequence > head) => {
+                    report.push(
+                        VerifyLocation::Record { offset },
+                        format!(
+                            "unindexed record {:?} on {:?} is past the branch head",
+                            record.sequence, record.branch
+                        ),
+                    );
                 }
-                StateOperation::Snapshot(data) | StateOperation::DeltaSnapshot(data) => {
-                    let arr: Vec<serde_json::Value> = serde_json::from_slice(&data)
-                        .map_err(|e| StoreError::Deserialization(e.to_string()))?;
-                    self.items_buffer.extend(arr);
+                Ok(_) => {}
+                Err(e) => report.push(VerifyLocation::Record { offset }, e.to_string()),
+            }
+            match next {
+                Some(next) => offset = next,
+                None => break,
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Check the store for silent corruption.
+    ///
+    /// Re-reads every record in the log verifying its checksum, confirms each
+    /// index entry points at the record it claims to, re-reads and re-hashes
+    /// every blob (bypassing the cache), and checks that no branch head is
+    /// beyond the last sequence written for it. Problems are collected into
+    /// the report rather than returned as errors; `Err` is reserved for
+    /// failures of the scan itself (e.g. an unreadable blob directory).
+    ///
+    /// Holds the write lock for the duration so the log doesn't move
+    /// underneath the scan. Works on read-only handles.
+    pub fn verify(&self, options: VerifyOptions) -> Result<VerifyReport> {
+        let _lock = self.write_lock.lock();
+        let stop = options.stop_on_first_error;
+        let mut report = VerifyReport::default();
+
+        // Log: walk every record, stepping over bad payloads where possible
+        let mut records: HashMap<u64, (RecordId, BranchId, Sequence)> = HashMap::new();
+        let mut max_sequence: HashMap<BranchId, Sequence> = HashMap::new();
+        let end = self.log.size();
+        let mut offset = 0;
+        while offset < end {
+            report.records_checked += 1;
+            let (result, next) = self.log.read_with_next(offset);
+            match result {
+                Ok(record) => {
+                    records.insert(offset, (record.id, record.branch, record.sequence));
+                    let max = max_sequence.entry(record.branch).or_default();
+                    *max = (*max).max(record.sequence);
                 }
-                StateOperation::Redact { start, end } => {
-                    let start = start.min(self.items_buffer.len());
-                    let end = end.min(self.items_buffer.len());
-                    if start < end {
-                        self.items_buffer.drain(start..end);
+                Err(e) => {
+                    report.push(VerifyLocation::Record { offset }, e.to_string());
+                    if stop {
+                        return Ok(report);
                     }
                 }
-                StateOperation::Edit { index, new_value } => {
-                    if index < self.items_buffer.len() {
-                        let value: serde_json::Value = serde_json::from_slice(&new_value)
-                            .map_err(|e| StoreError::Deserialization(e.to_string()))?;
-                        self.items_buffer[index] = value;
+            }
+            match next {
+                Some(next) => offset = next,
+                None => {
+                    report.push(
+                        VerifyLocation::Record { offset },
+                        format!("log unreadable from offset {} to {}", offset, end),
+                    );
+                    if stop {
+                        return Ok(report);
                     }
+                    break;
                 }
-                _ => {}
             }
         }
 
-        self.prepared = true;
+        // Index: every entry must point at a readable record with matching identity
+        for (id, offset) in self.index()?.id_offsets() {
+            let problem = match records.get(&offset) {
+                Some((found, _, _)) if *found == id => continue,
+                Some((found, _, _)) => format!("offset {} holds record {}", offset, found),
+                None => format!("offset {} is not a readable record", offset),
+            };
+            report.push(VerifyLocation::IndexId(id), problem);
+            if stop {
+                return Ok(report);
+            }
+        }
+        for ((branch, sequence), offset) in self.index()?.sequence_offsets() {
+            let problem = match records.get(&offset) {
+                Some((_, b, s)) if *b == branch && *s == sequence => continue,
+                Some((_, b, s)) => format!("offset {} holds {:?} on {:?}", offset, s, b),
+                None => format!("offset {} is not a readable record", offset),
+            };
+            report.push(VerifyLocation::IndexSequence { branch, sequence }, problem);
+            if stop {
+                return Ok(report);
+            }
+        }
+
+        // Blobs: re-hash content and compare with the file name
+        for hash in self.blobs.list()? {
+            report.blobs_checked += 1;
+            if let Some(e) = self.blob_damage(&hash)? {
+                report.push(VerifyLocation::Blob(hash), e.to_string());
+                if stop {
+                    return Ok(report);
+                }
+            }
+        }
+
+        // Branches: a head may sit at its branch point but not past its last record
+        for branch in self.branches.list_branches() {
+            report.branches_checked += 1;
+            let written = max_sequence.get(&branch.id).copied().unwrap_or_default();
+            let max_visible = written.max(branch.branch_point.unwrap_or_default());
+            if branch.head > max_visible {
+                report.push(
+                    VerifyLocation::Branch(branch.name.clone()),
+                    format!("head {:?} exceeds max sequence {:?}", branch.head, max_visible),
+                );
+                if stop {
+                    return Ok(report);
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Bring the store's derived data back in line with the log.
+    ///
+    /// Rebuilds the record and causation indexes from scratch, cuts off a
+    /// trailing record that can't be read (e.g. a torn write), rebuilds
+    /// state chain heads from the surviving state update records, and moves
+    /// each branch head to the last sequence visible on it. Records with a
+    /// bad payload checksum elsewhere in the log are left out of the index.
+    ///
+    /// Safe to run on a healthy store, and idempotent: a second run reports
+    /// no changes. Metadata is synced before returning.
+    pub fn repair(&self, options: RepairOptions) -> Result<RepairReport> {
+        let _maintenance = self.ensure_writable()?;
+        let _lock = self.write_lock.lock();
+        let _build = self.index_build_lock.lock();
+        let mut report = RepairReport::default();
+
+        // Rebuild the index while scanning (also building one `lazy_index`
+        // deferred); collect state updates and sequences
+        self.index.clear();
+        self.branches.invalidate_record_counts();
+        let now = Timestamp::now();
+        let mut rebuilder = HeadRebuilder::default();
+        let mut max_sequence: HashMap<BranchId, Sequence> = HashMap::new();
+        let end = self.log.size();
+        let mut offset = 0;
+        while offset < end {
+            let (result, next) = self.log.read_with_next(offset);
+            let next = match next {
+                Some(next) => next,
+                // Framing is broken: everything from here on is the bad tail
+                None => break,
+            };
+            match result {
+                Ok(record) => {
+                    let max = max_sequence.entry(record.branch).or_default();
+                    *max = (*max).max(record.sequence);
+
+                    // Expired records keep their sequence but aren't indexed
+                    if record.is_expired(now) {
+                        offset = next;
+                        continue;
+                    }
+
+                    self.index.add(
+                        record.id,
+                        record.branch,
+                        record.sequence,
+                        offset,
+                        &record.record_type,
+                        &record.caused_by,
+                        &record.linked_to,
+                    );
+                    if let Some(expires_at) = record.expires_at {
+                        self.index.set_expiry(record.id, expires_at);
+                    }
+                    if let Some(key) = &record.idempotency_key {
+                        self.index.set_idempotency_key(key, record.id);
+                    }
+                    self.index.index_fields(&record);
+                    report.records_indexed += 1;
+                    if record.squash {
+                        self.index.apply_squash(&self.log, &record)?;
+                    }
+
+                    if record.record_type == "state_update" {
+                        if let Ok(update) = serde_json::from_slice::<StateUpdateRecord>(&record.payload) {
+                            let capacity = self.state_capacity(&update.state_id);
+                            rebuilder.observe(record.branch, offset, &update, capacity);
+                        }
+                    }
+                }
+                Err(_) => report.corrupt_records.push(offset),
+            }
+            offset = next;
+        }
+
+        // Cut the unreadable tail, once a quarantined copy is on disk
+        if offset < end {
+            if options.quarantine {
+                let removed = self.log.read_tail(offset)?;
+                if !removed.is_empty() {
+                    let dir = self.config.path.join("quarantine");
+                    fs::create_dir_all(&dir)?;
+                    let path = dir.join(format!("records-{}.bin", offset));
+                    let mut file = File::create(&path)?;
+                    file.write_all(&removed)?;
+                    file.sync_all()?;
+                    File::open(&dir)?.sync_all()?;
+                    report.quarantine_path = Some(path);
+                }
+            }
+            self.log.truncate(offset)?;
+            report.truncated_bytes = end - offset;
+        }
+
+        report.state_heads_repaired = self.state.repair_heads(rebuilder) as u64;
+        self.hide_pruned_history()?;
+        self.index_built.store(true, Ordering::Release);
+
+        // A branch head sits at its last own record, or its branch point if it has none
+        for branch in self.branches.list_branches() {
+            let written = max_sequence.get(&branch.id).copied().unwrap_or_default();
+            let expected = written.max(branch.branch_point.unwrap_or_default());
+            if branch.head != expected {
+                self.branches.update_head(branch.id, expected)?;
+                report.branch_heads_repaired += 1;
+            }
+        }
+
+        self.log.sync()?;
+        self.state.save()?;
+        self.branches.save()?;
+
+        Ok(report)
+    }
+
+    /// Reclaim space across the whole store.
+    ///
+    /// Holding every state's chain lock and the write lock throughout, and
+    /// keeping out every other change, this works out, in order:
+    /// 1. a full snapshot of each state on the current branch whose head
+    ///    isn't one already (`snapshot_states`),
+    /// 2. branch GC as `BranchManager::gc` does it (`branch_gc`),
+    /// 3. the log without deleted branches' records, as
+    ///    `compact_log_by_branch` leaves it,
+    /// 4. the blobs to delete (`gc_blobs`).
+    ///
+    /// The result is built in a directory of its own and committed there
+    /// before any of the store's files change, so the call either completes
+    /// or leaves the store as it was. If the process dies after the commit,
+    /// the next `open` finishes installing it. Iterators and offsets
+    /// obtained before compaction are invalid after it.
+    ///
+    /// **Blob GC is a heuristic and can delete blobs still in use.**
+    /// Records don't reference blobs themselves, so a blob is kept only
+    /// while a record left in the log refers to it in one of these ways:
+    /// - as its schema (`RecordInput::schema`), or the schema registered
+    ///   for a record type,
+    /// - as exactly 64 hex digits (`Hash::to_hex`) anywhere in its payload,
+    ///   with no other hex digit either side,
+    /// - as a serialized `Hash`, or its 32 bytes as an array of numbers,
+    ///   in a JSON or MessagePack payload.
+    ///
+    /// Anything else isn't seen: a hash inside a longer run of hex digits,
+    /// raw hash bytes in a `Raw` payload or a MessagePack binary, a hash
+    /// encoded some other way (e.g. base64), or one only kept outside the
+    /// store. So is a blob stored for a record that hasn't been written
+    /// yet. Chunks are kept while a blob using them is. Leave `gc_blobs`
+    /// off unless every blob reference is written in one of those forms.
+    ///
+    /// Every record and every snapshotted state is read before anything
+    /// is committed, so a store that needs `repair` fails the call
+    /// untouched.
+    pub fn compact(&self, options: CompactOptions) -> Result<CompactReport> {
+        if self.config.read_only {
+            return Err(StoreError::ReadOnly);
+        }
+        let _maintenance = self.maintenance_lock.write();
+        let mut state_ids = self.state.state_ids();
+        state_ids.sort();
+        let chains: Vec<_> = state_ids.iter().map(|state_id| self.chain_locks.get(state_id)).collect();
+        let _chains: Vec<_> = chains.iter().map(|chain| chain.lock()).collect();
+        let _lock = self.write_lock.lock();
+
+        for result in self.log.iter() {
+            result?;
+        }
+        // Work from the files on disk, with nothing left in the journal to
+        // replay over the compacted ones
+        self.sync_files()?;
+
+        let staging = self.config.path.join(COMPACT_DIR);
+        let staged = self
+            .stage_compaction(&staging, &state_ids, &options)
+            .and_then(|staged| Self::commit_compaction(&staging).map(|()| staged));
+        let (report, snapshot_sizes) = match staged {
+            Ok(staged) => staged,
+            Err(e) => {
+                let _ = fs::remove_dir_all(&staging);
+                return Err(e);
+            }
+        };
+
+        // Committed: if anything below fails, the next open finishes it
+        let deleted_blobs = Self::install_compaction(&self.config.path)?;
+        self.log.reopen()?;
+        self.state.reload()?;
+        self.branches.reload()?;
+        self.reindex()?;
+        Self::finish_compaction(&self.config.path, &self.blobs, &deleted_blobs)?;
+
+        for name in &report.branches.deleted {
+            self.subscriptions.broadcast_branch_deleted(name);
+        }
+        for (state_id, size) in snapshot_sizes {
+            self.observer.on_snapshot(&state_id, SnapshotNeeded::Full, size);
+        }
+        Ok(report)
+    }
+
+    /// Build the result of `compact` in `staging`: the new log, state and
+    /// branch indices, and the list of blobs to delete. The store itself is
+    /// left alone. Returns the report and the size of each snapshot taken.
+    fn stage_compaction(
+        &self,
+        staging: &Path,
+        state_ids: &[String],
+        options: &CompactOptions,
+    ) -> Result<(CompactReport, Vec<(String, usize)>)> {
+        match fs::remove_dir_all(staging) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        fs::create_dir_all(staging)?;
+        let mut report = CompactReport::default();
+
+        // Copies of the indices as just synced, changed instead of the live ones
+        let state = StateManager::load(self.config.path.join("state.bin"))?;
+        let branches = BranchManager::load(self.config.path.join("branches.bin"))?;
+
+        let mut snapshots = Vec::new();
+        if options.snapshot_states {
+            for state_id in state_ids {
+                let Some(stats) = self.get_chain_stats(state_id)? else {
+                    continue;
+                };
+                // Only the head op after the last full snapshot means nothing to gain
+                let live_ops = stats.total_operations - stats.operations_before_snapshot;
+                if stats.has_full_snapshot && live_ops <= 1 {
+                    continue;
+                }
+                if let Some(current) = self.current_state(state_id)? {
+                    snapshots.push((state_id.clone(), current));
+                }
+            }
+        }
+
+        if let Some(branch_gc) = options.branch_gc.clone() {
+            report.branches = branches.gc(branch_gc)?;
+        }
+
+        let (keep, live) = self.vacuum_filter(&branches, &state)?;
+        report.log = self.vacuum_stats(&keep)?;
+        let log_path = staging.join("records.log");
+        let mut snapshot_sizes = Vec::new();
+        if report.log.records_removed > 0 || !snapshots.is_empty() {
+            let offsets = self.log.write_filtered(&log_path, |offset, record, offsets| {
+                if !keep(offset, &record) {
+                    return Ok(None);
+                }
+                Self::relink_state_update(record, offsets).map(Some)
+            })?;
+            state.remap_offsets(&offsets, &live);
+
+            let log = RecordLog::open(&log_path)?;
+            report.log.bytes_reclaimed = self.log.size().saturating_sub(log.size());
+            log.continue_from(&self.log);
+            let mut branch = branches.current_branch();
+            for (state_id, current) in snapshots {
+                snapshot_sizes.push((state_id.clone(), current.len()));
+                let operation = StateOperation::Snapshot(current);
+                let encoded = serde_json::value::to_raw_value(&operation)?;
+                let prev_update_offset = state.get_head(branch.id, &state_id).map(|head| head.head_offset);
+                branch.head = branch.head.next();
+                let input = Self::state_update_input(&state_id, branch.head, prev_update_offset, &encoded, None)?;
+                let (_, offset) = log.append(input, branch.id, branch.head)?;
+                state.record_update(branch.id, &state_id, offset, &operation)?;
+                report.states_snapshotted += 1;
+            }
+            branches.update_head(branch.id, branch.head)?;
+            log.sync()?;
+        }
+        state.save_to(&staging.join("state.bin"))?;
+        branches.save_to(&staging.join("branches.bin"))?;
+
+        if options.gc_blobs {
+            let staged_log;
+            let log = if log_path.exists() {
+                staged_log = RecordLog::open_read_only(&log_path)?;
+                &staged_log
+            } else {
+                &*self.log
+            };
+            let mut referenced = HashSet::new();
+            for result in log.iter() {
+                let (_, record) = result?;
+                referenced.extend(Self::referenced_blobs(&record, self.config.blob_hash));
+            }
+            referenced.extend(self.record_schemas.lock().values().map(|schema| schema.hash));
+
+            let mut list = String::new();
+            for (hash, size) in self.blobs.unreferenced(&referenced)? {
+                list.push_str(&hash.to_hex());
+                list.push('\n');
+                report.blobs_deleted += 1;
+                report.blob_bytes_reclaimed += size;
+            }
+            let path = staging.join(COMPACT_BLOBS_FILE);
+            fs::write(&path, list)?;
+            File::open(&path)?.sync_all()?;
+        }
+
+        File::open(staging)?.sync_all()?;
+        Ok((report, snapshot_sizes))
+    }
+
+    /// Mark the compaction staged in `staging` complete. Once the marker is
+    /// on disk the compaction is installed, by the next open if need be.
+    fn commit_compaction(staging: &Path) -> Result<()> {
+        let marker = staging.join(COMPACT_COMMIT_FILE);
+        let tmp = marker.with_extension("tmp");
+        File::create(&tmp)?.sync_all()?;
+        fs::rename(&tmp, &marker)?;
+        File::open(staging)?.sync_all()?;
         Ok(())
     }
-}
 
-impl Iterator for StateItemIterator {
-    type Item = Result<serde_json::Value>;
+    /// Move a committed compaction's files from `COMPACT_DIR` into the
+    /// store at `path` and return the blobs it deletes, leaving those and
+    /// the directory to `finish_compaction`. A compaction that never
+    /// committed is discarded. Safe to repeat after a crash part-way.
+    fn install_compaction(path: &Path) -> Result<Vec<Hash>> {
+        let staging = path.join(COMPACT_DIR);
+        if !staging.join(COMPACT_COMMIT_FILE).exists() {
+            if staging.exists() {
+                fs::remove_dir_all(&staging)?;
+            }
+            return Ok(Vec::new());
+        }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if !self.prepared {
-            if let Err(e) = self.prepare() {
-                return Some(Err(e));
+        for name in COMPACT_FILES {
+            let staged = staging.join(name);
+            if staged.exists() {
+                fs::rename(&staged, path.join(name))?;
             }
         }
+        File::open(path)?.sync_all()?;
 
-        if self.current_index >= self.items_buffer.len() {
-            None
-        } else {
-            let item = self.items_buffer[self.current_index].clone();
-            self.current_index += 1;
-            Some(Ok(item))
+        match fs::read_to_string(staging.join(COMPACT_BLOBS_FILE)) {
+            Ok(list) => list.lines().map(Hash::from_hex).collect(),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use serde_json::json;
-    use tempfile::TempDir;
+    /// Delete the blobs an installed compaction dropped, then its directory.
+    fn finish_compaction(path: &Path, blobs: &BlobStorage, deleted: &[Hash]) -> Result<()> {
+        for hash in deleted {
+            blobs.delete(hash)?;
+        }
+        if !deleted.is_empty() {
+            blobs.save()?;
+        }
+        match fs::remove_dir_all(path.join(COMPACT_DIR)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether this handle was opened read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.config.read_only
+    }
+
+    /// Get the store path.
+    pub fn path(&self) -> &Path {
+        &self.config.path
+    }
+
+    /// Get records that were caused by a given record (reverse lookup).
+    ///
+    /// Returns record IDs that have `record_id` in their `caused_by` field.
+    pub fn get_effects(&self, record_id: RecordId) -> Vec<RecordId> {
+        self.index_or_empty().get_caused_by(record_id)
+    }
+
+    /// Get every record `root` led to, directly or through other effects.
+    ///
+    /// Follows `caused_by` links breadth-first, so each record appears once,
+    /// at its shortest distance from the root. `max_depth` limits how far
+    /// (1 = direct effects only); `truncated` tells whether it cut anything
+    /// off. Links back into the tree, cycles included, are reported as
+    /// causes and not followed again.
+    pub fn get_causation_tree(&self, root: RecordId, max_depth: Option<usize>) -> CausationTree {
+        let mut tree = CausationTree {
+            root,
+            nodes: vec![CausationNode { id: root, depth: 0, causes: Vec::new() }],
+            truncated: false,
+        };
+        let mut positions = HashMap::from([(root, 0)]);
+
+        let mut next = 0;
+        while next < tree.nodes.len() {
+            let (id, depth) = (tree.nodes[next].id, tree.nodes[next].depth);
+            let at_limit = max_depth.is_some_and(|max| depth >= max);
+            for effect in self.index_or_empty().get_caused_by(id) {
+                let position = match positions.get(&effect) {
+                    Some(&position) => position,
+                    None if at_limit => {
+                        tree.truncated = true;
+                        continue;
+                    }
+                    None => {
+                        positions.insert(effect, tree.nodes.len());
+                        tree.nodes.push(CausationNode { id: effect, depth: depth + 1, causes: Vec::new() });
+                        tree.nodes.len() - 1
+                    }
+                };
+                tree.nodes[position].causes.push(id);
+            }
+            next += 1;
+        }
+
+        tree
+    }
+
+    /// Get records that link to a given record (reverse lookup).
+    ///
+    /// Returns record IDs that have `record_id` in their `linked_to` field.
+    pub fn get_links_to(&self, record_id: RecordId) -> Vec<RecordId> {
+        self.index_or_empty().get_linked_to(record_id)
+    }
+
+    // --- Subscription Operations ---
+
+    /// Subscribe to store events.
+    ///
+    /// Returns a handle for receiving events. Call `catch_up_subscription` to
+    /// replay historical data before going live.
+    ///
+    /// # Example
+    ///
+    /// ```ignore
+    /// let handle = store.subscribe(SubscriptionConfig {
+    ///     filter: SubscriptionFilter::records(),
+    ///     from_sequence: Some(Sequence(100)),
+    ///     ..Default::default()
+    /// });
+    ///
+    /// // Replay historical data
+    /// store.catch_up_subscription(handle.id)?;
+    ///
+    /// // Now receive live events
+    /// while let Ok(event) = handle.recv() {
+    ///     match event {
+    ///         StoreEvent::Record { record } => { /* ... */ }
+    ///         StoreEvent::CaughtUp => { /* now live */ }
+    ///         _ => {}
+    ///     }
+    /// }
+    /// ```
+    pub fn subscribe(&self, config: SubscriptionConfig) -> SubscriptionHandle {
+        self.subscriptions.subscribe(config)
+    }
+
+    /// Subscribe and return a blocking iterator over the events.
+    ///
+    /// Runs catch-up first, so with `from_sequence` set the iterator yields
+    /// the historical events, then `CaughtUp`, then live events. The
+    /// subscription is cleaned up once the iterator is dropped.
+    ///
+    /// ```ignore
+    /// for event in store.subscribe_blocking_iter(config)? {
+    ///     if let StoreEvent::Record { record } = event { /* ... */ }
+    /// }
+    /// ```
+    pub fn subscribe_blocking_iter(&self, config: SubscriptionConfig) -> Result<BlockingEventIter> {
+        let handle = self.subscribe(config);
+        self.catch_up_subscription(handle.id)?;
+        Ok(handle.into_iter())
+    }
+
+    /// Collect the historical events matching `config`, without live streaming.
+    ///
+    /// Subscribes, runs catch-up, drains events up to `CaughtUp` (not
+    /// included) and unsubscribes. A missing `from_sequence` means the start
+    /// of the branch. Catch-up doesn't take the write lock, so writers are
+    /// never blocked; their records arrive after `CaughtUp` and are not
+    /// returned. `buffer_size` must hold the whole history, otherwise this
+    /// fails with `SubscriptionDropped`.
+    pub fn snapshot_events(&self, config: SubscriptionConfig) -> Result<Vec<StoreEvent>> {
+        let config = SubscriptionConfig {
+            from_sequence: config.from_sequence.or(Some(Sequence(1))),
+            ..config
+        };
+        let handle = self.subscribe(config);
+        let result = self.catch_up_subscription(handle.id);
+        self.unsubscribe(handle.id);
+        result?;
+
+        let mut events = Vec::new();
+        for event in handle.receiver.try_iter() {
+            match event {
+                StoreEvent::CaughtUp => break,
+                event => events.push(event),
+            }
+        }
+        Ok(events)
+    }
+
+    /// Watch a state's value on the current branch.
+    ///
+    /// The returned watcher starts at the state's current value and yields
+    /// the whole new value after each change, applying deltas itself;
+    /// changes that pile up between reads are coalesced. Fails with
+    /// `StateNotRegistered` for unknown states.
+    pub fn watch_state(&self, state_id: &str) -> Result<StateWatcher> {
+        if !self.is_state_registered(state_id) {
+            return Err(StoreError::StateNotRegistered(state_id.to_string()));
+        }
+        let branch = self.branches.current_branch();
+        let handle = self.subscribe(SubscriptionConfig {
+            filter: SubscriptionFilter::states(vec![state_id.to_string()]),
+            branch: Some(branch.name.clone()),
+            ..Default::default()
+        });
+        self.catch_up_subscription(handle.id)?;
+
+        // Updates broadcast under the chain lock, so the value and head read
+        // here line up with the deltas the watcher skips
+        let chain = self.chain_locks.get(state_id);
+        let _chain = chain.lock();
+        let value = self.state.get_state(branch.id, state_id)?;
+        let head = self.head_sequence_on(branch.id, state_id)?.unwrap_or_default();
+        Ok(StateWatcher::new(handle, state_id, value, head, self.state_capacity(state_id)))
+    }
+
+    /// Unsubscribe and clean up.
+    pub fn unsubscribe(&self, id: SubscriptionId) {
+        self.subscriptions.unsubscribe(id)
+    }
+
+    /// Get the number of active subscriptions.
+    pub fn subscription_count(&self) -> usize {
+        self.subscriptions.subscription_count()
+    }
+
+    /// Mark a subscription as caught up (for manual catch-up handling).
+    pub fn mark_subscription_caught_up(&self, id: SubscriptionId) -> Result<()> {
+        self.subscriptions.mark_caught_up(id)
+    }
+
+    /// Perform catch-up for a subscription.
+    ///
+    /// Replays `config.branch` if set, otherwise the current branch. Fails
+    /// with `BranchNotFound` if the named branch doesn't exist.
+    ///
+    /// If `from_sequence` is set in the config, this replays:
+    /// 1. State snapshots for subscribed states at the starting sequence
+    ///    (or at the current head with `coalesce_catchup_state`)
+    /// 2. Historical records matching the filter from that sequence, and
+    ///    state deltas after each snapshot unless coalescing
+    ///
+    /// After catch-up completes, the subscription is marked as caught up
+    /// and will receive live events.
+    pub fn catch_up_subscription(&self, id: SubscriptionId) -> Result<()> {
+        let started = Instant::now();
+        let config = self
+            .subscriptions
+            .get_config(id)
+            .ok_or(StoreError::SubscriptionDropped)?;
+        let branch = match &config.branch {
+            Some(name) => self
+                .branches
+                .get_branch(name)
+                .ok_or_else(|| StoreError::BranchNotFound(name.clone()))?,
+            None => self.branches.current_branch(),
+        };
+
+        // If no from_sequence, just mark as caught up immediately
+        let from_seq = match config.from_sequence {
+            Some(seq) => seq,
+            None => {
+                return self.subscriptions.mark_caught_up(id);
+            }
+        };
+
+        // Send state snapshots for subscribed states
+        let mut snapshot_seqs: HashMap<String, Sequence> = HashMap::new();
+        if config.filter.include_state_changes {
+            let state_ids = match &config.filter.state_ids {
+                Some(ids) => ids.clone(),
+                None => self.state.state_ids(),
+            };
+
+            // Coalesced: one snapshot of the current value. Otherwise the
+            // snapshot is at `from_seq` and later deltas are replayed below.
+            let snapshot_at = if config.coalesce_catchup_state {
+                branch.head
+            } else {
+                from_seq
+            };
+
+            for state_id in state_ids {
+                // Get state at the snapshot sequence, or current state if it didn't exist then
+                let (state_data, snapshot_seq) =
+                    match self.get_state_at_for_branch(branch.id, &state_id, snapshot_at)? {
+                        Some(data) => (data, snapshot_at),
+                        None => {
+                            // State didn't exist at from_seq, try current state
+                            match self.state.get_state(branch.id, &state_id)? {
+                                Some(data) => (data, branch.head),
+                                None => continue, // State doesn't exist at all
+                            }
+                        }
+                    };
+
+                // Check size limits and truncate if needed
+                let total_bytes = state_data.len();
+                let (data, truncated, from_index, total_length) =
+                    if total_bytes > config.max_snapshot_bytes {
+                        // Try to parse as JSON array to provide partial data
+                        if let Ok(serde_json::Value::Array(arr)) =
+                            serde_json::from_slice::<serde_json::Value>(&state_data)
+                        {
+                            let total_len = arr.len();
+                            // Send tail of the array that fits
+                            let mut size = 0;
+                            let mut start_idx = total_len;
+                            for (i, item) in arr.iter().rev().enumerate() {
+                                let item_size = serde_json::to_string(item)
+                                    .map(|s| s.len())
+                                    .unwrap_or(0);
+                                if size + item_size > config.max_snapshot_bytes {
+                                    break;
+                                }
+                                size += item_size;
+                                start_idx = total_len - 1 - i;
+                            }
+                            let truncated_arr: Vec<_> = arr[start_idx..].to_vec();
+                            (
+                                serde_json::Value::Array(truncated_arr),
+                                true,
+                                Some(start_idx),
+                                Some(total_len),
+                            )
+                        } else {
+                            // Non-array, just send null and mark truncated
+                            (serde_json::Value::Null, true, None, None)
+                        }
+                    } else {
+                        let json_data: serde_json::Value =
+                            serde_json::from_slice(&state_data).unwrap_or(serde_json::Value::Null);
+                        (json_data, false, None, None)
+                    };
+
+                snapshot_seqs.insert(state_id.clone(), snapshot_seq);
+
+                let event = crate::subscriptions::StoreEvent::StateSnapshot {
+                    state_id,
+                    data,
+                    // Use the sequence where we got the snapshot from
+                    sequence: snapshot_seq,
+                    truncated,
+                    total_bytes,
+                    from_index,
+                    total_length,
+                };
+
+                if !self.subscriptions.send_to(id, event) {
+                    return Err(StoreError::SubscriptionDropped);
+                }
+            }
+        }
+
+        // Replay historical records and state deltas
+        let replay_deltas = config.filter.include_state_changes && !config.coalesce_catchup_state;
+        let payload_threshold = 4096; // Same as manager default
+        let related = config.filter.caused_by.is_some() || config.filter.linked_to.is_some();
+        if config.filter.include_records && related && !replay_deltas {
+            // Only records related to the given ones can match, and the
+            // causation indices list those without scanning the log
+            let index = self.index()?;
+            let mut candidates = HashSet::new();
+            if let Some(ids) = &config.filter.caused_by {
+                candidates.extend(ids.iter().flat_map(|&id| index.get_caused_by(id)));
+            } else if let Some(ids) = &config.filter.linked_to {
+                candidates.extend(ids.iter().flat_map(|&id| index.get_linked_to(id)));
+            }
+
+            let mut records = Vec::new();
+            for record_id in candidates {
+                let Some(offset) = self.index()?.get_offset_by_id(record_id) else {
+                    continue;
+                };
+                let record = self.log.read_at(offset)?;
+                if record.branch == branch.id
+                    && record.sequence >= from_seq
+                    && config.filter.matches_record(&record)
+                {
+                    records.push(record);
+                }
+            }
+            records.sort_by_key(|record| record.sequence);
+
+            for record in records {
+                let summary =
+                    crate::subscriptions::RecordSummary::from_record(&record, payload_threshold);
+                let event = crate::subscriptions::StoreEvent::Record { record: summary };
+                if !self.subscriptions.send_to(id, event) {
+                    return Err(StoreError::SubscriptionDropped);
+                }
+            }
+        } else if config.filter.include_records || replay_deltas {
+            for result in self.iter_branch_from(branch.id, from_seq) {
+                let (_offset, record) = result?;
+
+                // Skip records not on the followed branch
+                if record.branch != branch.id {
+                    continue;
+                }
+
+                // Deltas after each state's snapshot
+                if replay_deltas && record.record_type == "state_update" {
+                    if let Ok(update) = serde_json::from_slice::<StateUpdateRecord>(&record.payload) {
+                        if snapshot_seqs
+                            .get(&update.state_id)
+                            .is_some_and(|&seq| record.sequence > seq)
+                        {
+                            let event = crate::subscriptions::StoreEvent::StateDelta {
+                                state_id: update.state_id,
+                                operation: update.operation,
+                                sequence: record.sequence,
+                            };
+                            if !self.subscriptions.send_to(id, event) {
+                                return Err(StoreError::SubscriptionDropped);
+                            }
+                        }
+                    }
+                }
+
+                if !config.filter.matches_record(&record) {
+                    continue;
+                }
+
+                let summary =
+                    crate::subscriptions::RecordSummary::from_record(&record, payload_threshold);
+                let event = crate::subscriptions::StoreEvent::Record { record: summary };
+
+                if !self.subscriptions.send_to(id, event) {
+                    return Err(StoreError::SubscriptionDropped);
+                }
+            }
+        }
+
+        // Mark as caught up
+        self.subscriptions.mark_caught_up(id)?;
+        self.observer.on_catch_up(id, started.elapsed());
+        Ok(())
+    }
+
+    // --- Private Helpers ---
+
+    /// Rewrite the log without the records `keep` rejects, moving state
+    /// chain links and heads to the new offsets and rebuilding the index.
+    /// Heads of branches not in `live` are dropped. Caller holds the write
+    /// lock. Returns the bytes reclaimed.
+    fn rewrite_log(&self, keep: impl Fn(u64, &Record) -> bool, live: &HashSet<BranchId>) -> Result<u64> {
+        let size_before = self.log.size();
+        let offsets = self.rewrite_log_records(keep)?;
+        self.remap_after_rewrite(&offsets, live)?;
+        Ok(size_before - self.log.size())
+    }
+
+    /// The first half of `rewrite_log`: replace the log file and return the
+    /// old -> new offset map. Chain heads still hold old offsets until
+    /// `remap_after_rewrite`; on failure nothing has changed.
+    fn rewrite_log_records(&self, keep: impl Fn(u64, &Record) -> bool) -> Result<HashMap<u64, u64>> {
+        // Journaled appends name sequences the rewrite may drop; a replay
+        // must not bring them back
+        self.sync()?;
+        self.log.rewrite(|offset, record, offsets| {
+            if !keep(offset, &record) {
+                return Ok(None);
+            }
+            Self::relink_state_update(record, offsets).map(Some)
+        })
+    }
+
+    /// The second half of `rewrite_log`: move chain heads to the new
+    /// offsets and rebuild the index.
+    fn remap_after_rewrite(&self, offsets: &HashMap<u64, u64>, live: &HashSet<BranchId>) -> Result<()> {
+        self.state.remap_offsets(offsets, live);
+        self.reindex()?;
+        self.state.save()
+    }
+
+    /// Move a state update's link to the previous update to its new offset
+    /// after a log rewrite. Links into removed history are cut.
+    fn relink_state_update(mut record: Record, offsets: &HashMap<u64, u64>) -> Result<Record> {
+        if record.record_type == "state_update" {
+            if let Ok(mut update) = serde_json::from_slice::<StateUpdateRecord>(&record.payload) {
+                let prev = update.prev_update_offset.and_then(|p| offsets.get(&p).copied());
+                if prev != update.prev_update_offset {
+                    update.prev_update_offset = prev;
+                    record.payload = serde_json::to_vec(&update)?;
+                }
+            }
+        }
+        Ok(record)
+    }
+
+    /// Rebuild the index from the log after the log was replaced.
+    fn reindex(&self) -> Result<()> {
+        let _build = self.index_build_lock.lock();
+        self.index.clear();
+        self.index.index_log(&self.log)?;
+        self.branches.invalidate_record_counts();
+        self.hide_pruned_history()?;
+        self.index_built.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// What's wrong with a blob on disk, if anything. I/O failures other
+    /// than a truncated file are returned as errors, not damage.
+    fn blob_damage(&self, hash: &Hash) -> Result<Option<StoreError>> {
+        match self.blobs.verify(hash) {
+            Ok(()) => Ok(None),
+            Err(StoreError::Io(e)) if e.kind() != std::io::ErrorKind::UnexpectedEof => {
+                Err(StoreError::Io(e))
+            }
+            Err(e @ StoreError::InvalidOperation(_)) => Err(e),
+            Err(e) => Ok(Some(e)),
+        }
+    }
+
+    /// Hashes written out as exactly 64 hex digits in `payload`, taken to
+    /// be of `algorithm`.
+    fn mentioned_hashes(payload: &[u8], algorithm: HashAlgorithm) -> impl Iterator<Item = Hash> + '_ {
+        payload
+            .split(|byte| !byte.is_ascii_hexdigit())
+            .filter(|run| run.len() == 64)
+            .filter_map(|run| Hash::from_hex(std::str::from_utf8(run).ok()?).ok())
+            .map(move |hash| Hash(hash.0, algorithm))
+    }
+
+    /// Blobs `record` refers to, as `compact` finds them: its schema,
+    /// `mentioned_hashes` in the payload, and `Hash` values serialized into
+    /// a JSON or MessagePack payload. Bare hashes are taken to be of
+    /// `algorithm`.
+    fn referenced_blobs(record: &Record, algorithm: HashAlgorithm) -> HashSet<Hash> {
+        let mut hashes: HashSet<Hash> = Self::mentioned_hashes(&record.payload, algorithm).collect();
+        hashes.extend(record.schema);
+        let value = match record.encoding {
+            PayloadEncoding::Json => serde_json::from_slice::<serde_json::Value>(&record.payload).ok(),
+            PayloadEncoding::MessagePack => rmp_serde::from_slice::<serde_json::Value>(&record.payload).ok(),
+            PayloadEncoding::Raw => None,
+        };
+        if let Some(value) = value {
+            Self::serialized_hashes(&value, algorithm, &mut hashes);
+        }
+        hashes
+    }
+
+    /// Collect the arrays of 32 byte values in `value` into `hashes`. One
+    /// paired with an algorithm name, as a whole `Hash` serializes, is
+    /// taken to be of that algorithm, the rest of `algorithm`.
+    fn serialized_hashes(value: &serde_json::Value, algorithm: HashAlgorithm, hashes: &mut HashSet<Hash>) {
+        let bytes = |value: &serde_json::Value| -> Option<[u8; 32]> {
+            let items = value.as_array().filter(|items| items.len() == 32)?;
+            let mut bytes = [0; 32];
+            for (byte, item) in bytes.iter_mut().zip(items) {
+                *byte = u8::try_from(item.as_u64()?).ok()?;
+            }
+            Some(bytes)
+        };
+        match value {
+            serde_json::Value::Array(items) => {
+                let whole = match items.as_slice() {
+                    [bytes_item, name] => bytes(bytes_item)
+                        .zip(serde_json::from_value::<HashAlgorithm>(name.clone()).ok()),
+                    _ => None,
+                };
+                if let Some(hash) = bytes(value) {
+                    hashes.insert(Hash(hash, algorithm));
+                } else if let Some((hash, named)) = whole {
+                    hashes.insert(Hash(hash, named));
+                } else {
+                    for item in items {
+                        Self::serialized_hashes(item, algorithm, hashes);
+                    }
+                }
+            }
+            serde_json::Value::Object(fields) => {
+                for item in fields.values() {
+                    Self::serialized_hashes(item, algorithm, hashes);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Fail with `HistoryPruned` when a backwards chain walk would have to
+    /// continue past the snapshot a state's history was pruned at.
+    fn ensure_not_pruned(pruned: &[u64], offset: u64, state_id: &str, record: &Record) -> Result<()> {
+        if pruned.contains(&offset) {
+            return Err(StoreError::HistoryPruned {
+                state_id: state_id.to_string(),
+                before: record.sequence,
+            });
+        }
+        Ok(())
+    }
+
+    /// Offsets along a state chain from `start` backwards, stopping after
+    /// any snapshot in `pruned`.
+    fn chain_offsets(&self, state_id: &str, start: Option<u64>, pruned: &[u64]) -> Result<Vec<u64>> {
+        let mut offsets = Vec::new();
+        let mut current_offset = start;
+        while let Some(offset) = current_offset {
+            offsets.push(offset);
+            if pruned.contains(&offset) {
+                break;
+            }
+            let record = self.log.read_at(offset)?;
+            let update = decode_update(&record, state_id, offset)?;
+            current_offset = update.prev_update_offset;
+        }
+        Ok(offsets)
+    }
+
+    /// Drop the updates behind a pruned snapshot from the index, keeping any
+    /// that a branch's chain still runs through. Returns (dropped, kept).
+    fn unindex_pruned(&self, state_id: &str, snapshot_offset: u64) -> Result<(usize, usize)> {
+        let (superseded, kept) = self.superseded_updates(state_id, snapshot_offset)?;
+        for &offset in &superseded {
+            let record = self.log.read_at(offset)?;
+            self.index.remove(&record);
+        }
+        if !superseded.is_empty() {
+            self.branches.invalidate_record_counts();
+        }
+        Ok((superseded.len(), kept))
+    }
+
+    /// Offsets of the updates behind a pruned snapshot that no branch's
+    /// chain still runs through, and the number of those kept because one
+    /// does.
+    fn superseded_updates(&self, state_id: &str, snapshot_offset: u64) -> Result<(Vec<u64>, usize)> {
+        let pruned = self.state.pruned_at(state_id);
+        let snapshot = self.log.read_at(snapshot_offset)?;
+        let update = decode_update(&snapshot, state_id, snapshot_offset)?;
+        let behind = self.chain_offsets(state_id, update.prev_update_offset, &pruned)?;
+
+        let mut reachable = HashSet::new();
+        for (_, head) in self.state.heads_for_state(state_id) {
+            reachable.extend(self.chain_offsets(state_id, Some(head.head_offset), &pruned)?);
+        }
+
+        let (superseded, kept): (Vec<u64>, Vec<u64>) =
+            behind.into_iter().partition(|offset| !reachable.contains(offset));
+        Ok((superseded, kept.len()))
+    }
+
+    /// The record index, built from the log first if `lazy_index` deferred
+    /// that. Only one caller builds it; others wait for it.
+    fn index(&self) -> Result<&RecordIndex> {
+        if !self.index_built.load(Ordering::Acquire) {
+            let _build = self.index_build_lock.lock();
+            if !self.index_built.load(Ordering::Acquire) {
+                let built = self.index.index_log(&self.log).and_then(|()| self.hide_pruned_history());
+                if let Err(e) = built {
+                    // Left empty so the next call starts over
+                    self.index.clear();
+                    return Err(e);
+                }
+                self.index_built.store(true, Ordering::Release);
+            }
+        }
+        Ok(&self.index)
+    }
+
+    /// `index()` for methods that can't return an error. If the build
+    /// fails the index is left empty, and the error surfaces from the next
+    /// call that can return it.
+    fn index_or_empty(&self) -> &RecordIndex {
+        self.index().unwrap_or(&self.index)
+    }
+
+    /// Reapply `prune_history` after the index is rebuilt from the log.
+    fn hide_pruned_history(&self) -> Result<()> {
+        for state_id in self.state.state_ids() {
+            for snapshot_offset in self.state.pruned_at(&state_id) {
+                self.unindex_pruned(&state_id, snapshot_offset)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn load_field_index_names(path: &Path) -> Result<Vec<String>> {
+        match fs::read(path.join(FIELD_INDEX_FILE)) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| StoreError::Deserialization(e.to_string())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Refuse to write at `branch.head + 1` if the index already has a record
+    /// past the head there: the new record would reuse its sequence.
+    ///
+    /// Returns `InvalidSequence` so the caller can `repair` and retry.
+    fn ensure_head_matches_index(&self, branch: &Branch) -> Result<()> {
+        if let Some(max) = self.index()?.max_sequence(branch.id) {
+            if max > branch.head {
+                return Err(StoreError::InvalidSequence(max, branch.head));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the index holds records past `branch`'s head, which
+    /// `ensure_head_matches_index` refuses to write over.
+    fn index_ahead_of(&self, branch: &Branch) -> Result<bool> {
+        Ok(self.index()?.max_sequence(branch.id).is_some_and(|max| max > branch.head))
+    }
+
+    /// Journal `operation`, then run `apply`.
+    ///
+    /// On success the entry is committed and kept until the next `sync`
+    /// persists what it changed; on failure it is rolled back immediately.
+    /// Every `WAL_CHECKPOINT_ENTRIES` committed entries trigger that sync,
+    /// so the WAL stays bounded without one.
+    fn journaled<T>(&self, operation: WalOperation, apply: impl FnOnce() -> Result<T>) -> Result<T> {
+        self.journaled_ref(&operation, apply)
+    }
+
+    /// `journaled` for an operation `apply` reads its data from.
+    fn journaled_ref<T>(&self, operation: &WalOperation, apply: impl FnOnce() -> Result<T>) -> Result<T> {
+        let Some(wal) = &self.wal else {
+            return apply();
+        };
+        let seq = wal.log_ref(operation)?;
+        match apply() {
+            Ok(value) => {
+                wal.commit(seq)?;
+                let unsynced = {
+                    let mut unsynced = self.wal_unsynced.lock();
+                    unsynced.push(seq);
+                    unsynced.len()
+                };
+                if unsynced >= WAL_CHECKPOINT_ENTRIES {
+                    // The write itself is done; if this fails, the next sync
+                    // retries it
+                    let _ = self.sync_files();
+                }
+                Ok(value)
+            }
+            Err(e) => {
+                wal.rollback(seq)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Replay the journal entries a crash left behind.
+    ///
+    /// Committed entries whose effect didn't reach disk are applied again;
+    /// entries that can no longer apply (e.g. the parent branch is gone)
+    /// are rolled back. Pending entries were interrupted mid-way and are
+    /// discarded, except that records and state updates of either kind
+    /// that did reach the log bring the branch and state heads up to them.
+    ///
+    /// Any other error (e.g. I/O) fails the open and leaves the entry in
+    /// the WAL, so the next open retries it.
+    fn recover_from_wal(&self) -> Result<()> {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+        let entries = wal.get_unfinished_entries()?;
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let mut applied = Vec::new();
+        for entry in entries {
+            let committed = entry.status == WalEntryStatus::Committed;
+            match self.replay_wal_operation(&entry.operation, committed) {
+                Ok(true) => applied.push(entry.seq),
+                Ok(false) => wal.rollback(entry.seq)?,
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.log.sync()?;
+        self.state.save()?;
+        self.branches.save()?;
+        self.blobs.save()?;
+        wal.checkpoint(&applied)
+    }
+
+    /// Add a `CreateBranch` change for `id` (and any parents not sent yet).
+    ///
+    /// Returns false if the branch or one of its ancestors is gone.
+    fn push_branch_change(
+        id: BranchId,
+        branches: &HashMap<BranchId, Branch>,
+        sent: &mut HashSet<BranchId>,
+        changes: &mut Vec<Change>,
+    ) -> bool {
+        if sent.contains(&id) {
+            return true;
+        }
+        let Some(branch) = branches.get(&id) else {
+            return false;
+        };
+        let (Some(parent), Some(at)) = (branch.parent, branch.branch_point) else {
+            return false;
+        };
+        if !Self::push_branch_change(parent, branches, sent, changes) {
+            return false;
+        }
+        changes.push(Change::CreateBranch {
+            name: branch.name.clone(),
+            parent: branches[&parent].name.clone(),
+            at,
+        });
+        sent.insert(id);
+        true
+    }
+
+    /// The branch a replicated change at `sequence` goes on, or None if the
+    /// branch already has a record there that `matches` it. Caller holds
+    /// the write lock.
+    fn replay_target(
+        &self,
+        name: &str,
+        sequence: Sequence,
+        key: &ChangeKey,
+        matches: impl FnOnce(&Record) -> bool,
+    ) -> Result<Option<Branch>> {
+        let branch = self
+            .branches
+            .get_branch(name)
+            .ok_or_else(|| StoreError::BranchNotFound(name.to_string()))?;
+        if sequence <= branch.head {
+            // Records dropped from the index (expired or pruned) can't be
+            // compared, so they count as applied
+            let Some(offset) = self.index()?.get_offset(branch.id, sequence) else {
+                return Ok(None);
+            };
+            let existing = self.log.read_at(offset)?;
+            if !matches(&existing) {
+                return Err(StoreError::ReplicationConflict {
+                    branch: branch.name,
+                    sequence,
+                    details: format!(
+                        "record {} differs from change {} of {}",
+                        existing.id, key.seq, key.source
+                    ),
+                });
+            }
+            return Ok(None);
+        }
+        if sequence != branch.head.next() {
+            return Err(StoreError::InvalidSequence(sequence, branch.head));
+        }
+        Ok(Some(branch))
+    }
+
+    /// Bring the store up to one journal entry. Pending entries are only
+    /// reconciled with what reached the log, never applied. Returns whether
+    /// the entry's effect is now in the store; false also means it can
+    /// never apply. Errors are failures a later retry may get past.
+    fn replay_wal_operation(&self, operation: &WalOperation, committed: bool) -> Result<bool> {
+        match operation {
+            WalOperation::StoreBlob { content, content_type, chunked } => {
+                // A crash mid-write can leave a torn blob file behind
+                let hash = self.config.blob_hash.digest(content);
+                if self.blobs.exists_on_disk(&hash) && self.blobs.verify(&hash).is_err() {
+                    self.blobs.delete(&hash)?;
+                }
+                if !committed {
+                    return Ok(false);
+                }
+                if *chunked {
+                    self.blobs.store_chunked(content, content_type)?;
+                } else {
+                    self.blobs.store(content, content_type)?;
+                }
+            }
+            WalOperation::CreateBranch { .. } | WalOperation::SwitchBranch { .. } | WalOperation::DeleteBranch { .. }
+                if !committed =>
+            {
+                return Ok(false);
+            }
+            WalOperation::CreateBranch { name, from, at, empty } => {
+                if self.branches.get_branch(name).is_some() {
+                    return Ok(true);
+                }
+                if *empty {
+                    if from.as_deref().is_some_and(|from| self.branches.get_branch(from).is_none()) {
+                        return Ok(false);
+                    }
+                    self.create_empty_branch_inner(name, from.as_deref())?;
+                } else {
+                    let parent = from.as_deref().unwrap_or(MAIN_BRANCH);
+                    let Some(parent_branch) = self.branches.get_branch(parent) else {
+                        return Ok(false);
+                    };
+                    // Entries without a branch point fork from the parent's head
+                    let at = at.map_or(parent_branch.head, Sequence);
+                    if at > parent_branch.head {
+                        return Ok(false);
+                    }
+                    self.create_branch_at_inner(name, parent, at)?;
+                }
+            }
+            WalOperation::SwitchBranch { name } => {
+                if self.branches.get_branch(name).is_none() {
+                    return Ok(false);
+                }
+                self.branches.switch_branch(name)?;
+            }
+            WalOperation::DeleteBranch { name } => {
+                if name == MAIN_BRANCH || self.branches.current_branch().name == *name {
+                    return Ok(false);
+                }
+                if self.branches.get_branch(name).is_some() {
+                    self.branches.delete_branch(name)?;
+                }
+            }
+            WalOperation::AppendRecord { record_type, payload, record } => {
+                let Some(record) = record else {
+                    return Ok(false);
+                };
+                let Some(branch) = self.branches.get_branch(&record.branch) else {
+                    return Ok(false);
+                };
+                let sequence = Sequence(record.sequence);
+                if self.index()?.get_offset(branch.id, sequence).is_some() {
+                    // Reached the log; the branch head may not have been saved
+                    if branch.head < sequence {
+                        self.branches.update_head(branch.id, sequence)?;
+                    }
+                    return Ok(true);
+                }
+                if !committed || sequence != branch.head.next() || self.index_ahead_of(&branch)? {
+                    return Ok(false);
+                }
+                let input = RecordInput {
+                    record_type: record_type.clone(),
+                    payload: payload.clone(),
+                    encoding: record.encoding,
+                    caused_by: record.caused_by.clone(),
+                    linked_to: record.linked_to.clone(),
+                    expires_at: record.expires_at,
+                    lamport: record.lamport,
+                    idempotency_key: record.idempotency_key.clone(),
+                    schema: record.schema,
+                    skip_schema_validation: true,
+                };
+                let (appended, _) = self.write_record(&branch, input, record.squash, Instant::now())?;
+                if record.squash {
+                    self.index()?.apply_squash(&self.log, &appended)?;
+                    self.branches.invalidate_record_counts();
+                }
+            }
+            WalOperation::UpdateState { state_id, operation_data, branch, sequence, lamport } => {
+                let Some(branch) = self.branches.get_branch(branch) else {
+                    return Ok(false);
+                };
+                let sequence = Sequence(*sequence);
+                let Ok(operation) = serde_json::from_slice::<StateOperation>(operation_data) else {
+                    return Ok(false);
+                };
+                if let Some(offset) = self.index()?.get_offset(branch.id, sequence) {
+                    // Reached the log; the branch and state heads may not
+                    // have been saved
+                    if branch.head < sequence {
+                        self.branches.update_head(branch.id, sequence)?;
+                    }
+                    let stale = self
+                        .state
+                        .get_head(branch.id, state_id)
+                        .is_none_or(|head| head.head_offset < offset);
+                    if stale {
+                        self.state.record_update(branch.id, state_id, offset, &operation)?;
+                    }
+                    return Ok(true);
+                }
+                if !committed || sequence != branch.head.next() || self.index_ahead_of(&branch)? {
+                    return Ok(false);
+                }
+                let encoded = serde_json::value::to_raw_value(&operation)?;
+                self.write_state_update(&branch, state_id, operation, &encoded, *lamport)?;
+            }
+            WalOperation::UpdateStateBatch { state_id, operations, branch, sequence } => {
+                let Some(branch) = self.branches.get_branch(branch) else {
+                    return Ok(false);
+                };
+                let Ok(ops) = operations
+                    .iter()
+                    .map(|data| serde_json::from_slice::<StateOperation>(data))
+                    .collect::<serde_json::Result<Vec<_>>>()
+                else {
+                    return Ok(false);
+                };
+                let offsets: Vec<Option<u64>> = {
+                    let index = self.index()?;
+                    (0..ops.len() as u64)
+                        .map(|i| index.get_offset(branch.id, Sequence(sequence + i)))
+                        .collect()
+                };
+                match offsets[..] {
+                    [] => return Ok(true),
+                    [Some(first), ..] if offsets.iter().all(Option::is_some) => {
+                        // Reached the log; the branch and state heads may
+                        // not have been saved
+                        let last = Sequence(sequence + ops.len() as u64 - 1);
+                        if branch.head < last {
+                            self.branches.update_head(branch.id, last)?;
+                        }
+                        let head = self.state.get_head(branch.id, state_id).map(|head| head.head_offset);
+                        if head.is_none_or(|head| head < first) {
+                            let updates: Vec<_> = offsets.iter().flatten().copied().zip(&ops).collect();
+                            self.state.record_updates(branch.id, state_id, &updates)?;
+                        }
+                        return Ok(true);
+                    }
+                    [Some(first), ..] => {
+                        // Only part of the batch reached the log; it goes
+                        // in whole or not at all
+                        let mut written = Vec::new();
+                        for &offset in offsets.iter().flatten() {
+                            written.push((self.log.read_at(offset)?, offset));
+                        }
+                        self.unwind_state_records(first, &written)?;
+                    }
+                    _ => {}
+                }
+                if !committed || Sequence(*sequence) != branch.head.next() || self.index_ahead_of(&branch)? {
+                    return Ok(false);
+                }
+                let encoded = ops
+                    .iter()
+                    .map(serde_json::value::to_raw_value)
+                    .collect::<serde_json::Result<Vec<_>>>()?;
+                self.write_state_batch(&branch, state_id, ops, &encoded)?;
+            }
+            WalOperation::TruncateBranch { name, keep_through, first_removed } => {
+                let keep_through = Sequence(*keep_through);
+                let pending = match first_removed {
+                    Some(id) => self.index()?.get_offset_by_id(*id).is_some(),
+                    None => false,
+                };
+                if !pending {
+                    // The log was rewritten; the state and branch heads
+                    // saved before it may point into the old one
+                    self.repair(RepairOptions::default())?;
+                    return Ok(true);
+                }
+                if !committed {
+                    return Ok(false);
+                }
+                let Ok(branch) = self.truncatable_branch(name, keep_through) else {
+                    return Ok(false);
+                };
+                match self.truncate_branch_inner(&branch, keep_through) {
+                    Err(StoreError::HistoryPruned { .. }) => return Ok(false),
+                    result => result?,
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// With `validate_links` set, reject inputs whose causes or links don't
+    /// exist.
+    fn check_links(&self, input: &RecordInput) -> Result<()> {
+        if !self.config.validate_links {
+            return Ok(());
+        }
+        match input.caused_by.iter().chain(&input.linked_to).find(|&&id| !self.record_exists(id)) {
+            Some(&id) => Err(StoreError::RecordNotFound(id)),
+            None => Ok(()),
+        }
+    }
+
+    /// Check `input`'s payload against the schema registered for its type,
+    /// and reference the schema from the record. Caller holds the write lock.
+    fn check_schema(&self, input: &mut RecordInput) -> Result<()> {
+        if input.skip_schema_validation {
+            return Ok(());
+        }
+        let schemas = self.record_schemas.lock();
+        let Some(registered) = schemas.get(&input.record_type) else {
+            return Ok(());
+        };
+        let violation = |reason: String| StoreError::SchemaViolation {
+            record_type: input.record_type.clone(),
+            reason,
+        };
+        let payload: serde_json::Value = match input.encoding {
+            PayloadEncoding::Json => serde_json::from_slice(&input.payload)
+                .map_err(|e| violation(format!("payload is not valid JSON: {}", e)))?,
+            PayloadEncoding::MessagePack => rmp_serde::from_slice(&input.payload)
+                .map_err(|e| violation(format!("payload is not valid MessagePack: {}", e)))?,
+            PayloadEncoding::Raw => return Err(violation("raw payloads can't be checked".into())),
+        };
+        crate::records::schema::validate(&registered.schema, &payload).map_err(violation)?;
+        input.schema.get_or_insert(registered.hash);
+        Ok(())
+    }
+
+    /// Read the registered record schemas back from their blobs.
+    fn load_record_schemas(&self) -> Result<()> {
+        let hashes: HashMap<String, String> = match fs::read(self.config.path.join(RECORD_SCHEMA_FILE)) {
+            Ok(data) => serde_json::from_slice(&data)
+                .map_err(|e| StoreError::Deserialization(e.to_string()))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut schemas = self.record_schemas.lock();
+        for (record_type, hex) in hashes {
+            let hash = Hash::from_hex(&hex)?;
+            let blob = self.blobs.get(&hash)?.ok_or(StoreError::BlobNotFound(hash))?;
+            let schema = serde_json::from_slice(&blob.content)
+                .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+            schemas.insert(record_type, RecordSchema { hash, schema });
+        }
+        Ok(())
+    }
+
+    fn save_record_schemas(path: &Path, schemas: &HashMap<String, RecordSchema>) -> Result<()> {
+        let hashes: HashMap<&str, String> = schemas
+            .iter()
+            .map(|(record_type, registered)| (record_type.as_str(), registered.hash.to_hex()))
+            .collect();
+        fs::write(path.join(RECORD_SCHEMA_FILE), serde_json::to_vec(&hashes)?)?;
+        Ok(())
+    }
+
+    /// Reject record types that are empty, longer than
+    /// `max_record_type_len`, or contain NUL bytes (which C strings on the
+    /// other side of the bindings would cut short).
+    fn validate_record_type(&self, record_type: &str) -> Result<()> {
+        if record_type.is_empty() {
+            return Err(StoreError::InvalidOperation("Record type is empty".into()));
+        }
+        if record_type.len() > self.config.max_record_type_len {
+            return Err(StoreError::InvalidOperation(format!(
+                "Record type is {} bytes, longer than the maximum of {}",
+                record_type.len(),
+                self.config.max_record_type_len
+            )));
+        }
+        if record_type.contains('\0') {
+            return Err(StoreError::InvalidOperation(format!(
+                "Record type {:?} contains a NUL byte",
+                record_type
+            )));
+        }
+        Ok(())
+    }
+
+    /// Copy the first `len` bytes of `from` to a new file `to` and sync it.
+    fn copy_file_prefix(from: &Path, to: &Path, len: u64) -> Result<()> {
+        use std::io::Read;
+
+        let mut out = File::create(to)?;
+        let copied = std::io::copy(&mut File::open(from)?.take(len), &mut out)?;
+        if copied != len {
+            return Err(StoreError::Io(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("{} is shorter than {} bytes", from.display(), len),
+            )));
+        }
+        out.sync_all()?;
+        Ok(())
+    }
+
+    /// Fail with `PayloadTooLarge` if `size` is over `limit`.
+    fn check_size(size: u64, limit: Option<u64>) -> Result<()> {
+        match limit {
+            Some(limit) if size > limit => Err(StoreError::PayloadTooLarge { size, limit }),
+            _ => Ok(()),
+        }
+    }
+
+    /// The content type to store or look up: normalized when
+    /// `validate_content_type` is set, as given otherwise.
+    fn content_type<'a>(&self, content_type: &'a str) -> Result<Cow<'a, str>> {
+        if self.config.validate_content_type {
+            Ok(Cow::Owned(normalize_content_type(content_type)?))
+        } else {
+            Ok(Cow::Borrowed(content_type))
+        }
+    }
+
+    /// `AppendLog` and `RingBuffer` states are arrays, so their initial value must be one.
+    fn validate_initial_value(strategy: &StateStrategy, value: &[u8]) -> Result<()> {
+        let kind = match strategy {
+            StateStrategy::AppendLog { .. } => "an AppendLog",
+            StateStrategy::RingBuffer { .. } => "a RingBuffer",
+            _ => return Ok(()),
+        };
+        if serde_json::from_slice::<Vec<serde_json::Value>>(value).is_err() {
+            return Err(StoreError::InvalidOperation(format!(
+                "Initial value of {} state must be a JSON array",
+                kind
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check that a state's value on `branch` fits the strategy it is
+    /// being migrated to.
+    fn check_migrated_value(
+        state_id: &str,
+        branch: &str,
+        strategy: &StateStrategy,
+        value: &[u8],
+    ) -> Result<()> {
+        let (kind, fits) = match strategy {
+            StateStrategy::AppendLog { .. } | StateStrategy::RingBuffer { .. } => (
+                "a JSON array",
+                serde_json::from_slice::<Vec<serde_json::Value>>(value).is_ok(),
+            ),
+            StateStrategy::Struct { .. } => (
+                "a JSON object",
+                serde_json::from_slice::<serde_json::Map<String, serde_json::Value>>(value).is_ok(),
+            ),
+            _ => return Ok(()),
+        };
+        if fits {
+            Ok(())
+        } else {
+            Err(StoreError::InvalidOperation(format!(
+                "Cannot migrate state {}: its value on branch {} is not {}",
+                state_id, branch, kind
+            )))
+        }
+    }
+
+    /// Item limit of a state's strategy (see `StateStrategy::capacity`).
+    fn state_capacity(&self, state_id: &str) -> Option<usize> {
+        self.state.get_strategy(state_id).and_then(|s| s.capacity())
+    }
+
+    /// Fail with `ReadOnly` on a read-only handle. Otherwise returns a
+    /// guard that holds off `compact`, kept while the caller changes the
+    /// store.
+    fn ensure_writable(&self) -> Result<RwLockReadGuard<'_, ()>> {
+        if self.config.read_only {
+            return Err(StoreError::ReadOnly);
+        }
+        // Recursive: callers nest, and must not wait behind a queued `compact`
+        Ok(self.maintenance_lock.read_recursive())
+    }
+
+    fn write_manifest(path: &Path, blob_shard_depth: u8, blob_hash: HashAlgorithm) -> Result<()> {
+        let version = match (blob_shard_depth, blob_hash) {
+            (1, HashAlgorithm::Sha256) => 1,
+            (_, HashAlgorithm::Sha256) => 2,
+            _ => STORE_VERSION,
+        };
+        Self::write_manifest_version(path, version, blob_shard_depth, blob_hash)
+    }
+
+    /// Write the manifest in a given format version.
+    ///
+    /// Written to a temporary file and renamed over the old one, so a crash
+    /// mid-migration leaves either the old or the new manifest.
+    pub(crate) fn write_manifest_version(
+        path: &Path,
+        version: u8,
+        blob_shard_depth: u8,
+        blob_hash: HashAlgorithm,
+    ) -> Result<()> {
+        use std::io::Write;
+
+        let tmp_path = path.join("MANIFEST.tmp");
+        let mut file = File::create(&tmp_path)?;
+
+        file.write_all(STORE_MAGIC)?;
+        match version {
+            1 => file.write_all(&[1])?,
+            2 => file.write_all(&[2, blob_shard_depth])?,
+            _ => file.write_all(&[version, blob_shard_depth, Self::hash_algorithm_byte(blob_hash)])?,
+        }
+        file.sync_all()?;
+        fs::rename(tmp_path, path.join("MANIFEST"))?;
+
+        Ok(())
+    }
+
+    /// Read the manifest's format version, blob shard depth and blob hash
+    /// algorithm.
+    pub(crate) fn verify_manifest(path: &Path) -> Result<(u8, u8, HashAlgorithm)> {
+        use std::io::Read;
+
+        let manifest_path = path.join("MANIFEST");
+        let mut file = File::open(manifest_path)?;
+
+        let mut magic = [0u8; 4];
+        file.read_exact(&mut magic)?;
+        if &magic != STORE_MAGIC {
+            return Err(StoreError::InvalidFormat("Invalid store magic".into()));
+        }
+
+        let mut version = [0u8; 1];
+        file.read_exact(&mut version)?;
+        match version[0] {
+            1 => Ok((1, 1, HashAlgorithm::Sha256)),
+            2 => {
+                let mut depth = [0u8; 1];
+                file.read_exact(&mut depth)?;
+                Ok((2, depth[0], HashAlgorithm::Sha256))
+            }
+            STORE_VERSION => {
+                let mut fields = [0u8; 2];
+                file.read_exact(&mut fields)?;
+                let blob_hash = match fields[1] {
+                    0 => HashAlgorithm::Sha256,
+                    1 => HashAlgorithm::Blake3,
+                    other => {
+                        return Err(StoreError::InvalidFormat(format!(
+                            "Unknown blob hash algorithm in manifest: {}",
+                            other
+                        )))
+                    }
+                };
+                Ok((STORE_VERSION, fields[0], blob_hash))
+            }
+            other => Err(StoreError::InvalidFormat(format!(
+                "Unsupported store version: {}",
+                other
+            ))),
+        }
+    }
+
+    /// How the manifest records a blob hash algorithm.
+    fn hash_algorithm_byte(algorithm: HashAlgorithm) -> u8 {
+        match algorithm {
+            HashAlgorithm::Sha256 => 0,
+            HashAlgorithm::Blake3 => 1,
+        }
+    }
+
+    fn acquire_lock(path: &Path, wait: Option<Duration>) -> Result<File> {
+        let lock_path = path.join("LOCK");
+        let lock_file = File::create(lock_path)?;
+
+        lock_file
+            .try_lock_exclusive()
+            .map_err(|_| StoreError::Locked)?;
+
+        // Refuse to start writing while read-only handles are open
+        let readers = Self::open_readers_file(path)?;
+        let deadline = wait.map(|w| Instant::now() + w);
+        while readers.try_lock_exclusive().is_err() {
+            match deadline {
+                Some(deadline) if Instant::now() < deadline => {
+                    std::thread::sleep(LOCK_POLL_INTERVAL);
+                }
+                _ => return Err(StoreError::Locked),
+            }
+        }
+        readers.unlock()?;
+
+        Ok(lock_file)
+    }
+
+    fn acquire_reader_lock(path: &Path) -> Result<File> {
+        let readers = Self::open_readers_file(path)?;
+        // Only blocks for the instant a writer spends probing for readers
+        readers.lock_shared()?;
+        Ok(readers)
+    }
+
+    fn open_readers_file(path: &Path) -> Result<File> {
+        Ok(fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path.join("READERS"))?)
+    }
+}
+
+/// Best-effort fallback for stores that weren't closed: errors from the
+/// final sync are ignored. Use `Store::close` to see them.
+impl Drop for Store {
+    fn drop(&mut self) {
+        // Stop the background syncer before the final sync
+        self.sync_worker.take();
+
+        // Best-effort sync on drop (read-only handles have nothing to flush)
+        if !self.config.read_only {
+            let _ = self.sync();
+        }
+    }
+}
+
+/// Sync the log and save the metadata files, then drop the journal
+/// entries whose effects that persisted, so the WAL shrinks. Run by
+/// `Store::sync` and the sync worker.
+fn persist(
+    log: &RecordLog,
+    state: &StateManager,
+    branches: &BranchManager,
+    blobs: &BlobStorage,
+    wal: Option<&WriteAheadLog>,
+    wal_unsynced: &Mutex<Vec<u64>>,
+) -> Result<()> {
+    // Whatever was applied before the files below are written is
+    // persisted by them
+    let persisted = std::mem::take(&mut *wal_unsynced.lock());
+    let saved = (|| {
+        // Sync the append-only log (O(1) - just fsync)
+        log.sync()?;
+        // Sync small metadata files (O(states) and O(branches), typically tiny)
+        state.save()?;
+        branches.save()?;
+        blobs.save()?;
+        match wal {
+            Some(wal) => wal.checkpoint(&persisted),
+            None => Ok(()),
+        }
+    })();
+    if saved.is_err() {
+        // Left for the next sync to drop
+        wal_unsynced.lock().extend(persisted);
+    }
+    saved
+}
+
+/// Background thread that syncs the store every interval.
+///
+/// Only touches the components' own locks, never `Store::write_lock`, so it
+/// can't deadlock with a writer. Stopped and joined on drop.
+struct SyncWorker {
+    stop: Option<crossbeam_channel::Sender<()>>,
+    handle: Option<std::thread::JoinHandle<()>>,
+}
+
+impl SyncWorker {
+    fn spawn(
+        interval: Duration,
+        log: Arc<RecordLog>,
+        state: Arc<StateManager>,
+        branches: Arc<BranchManager>,
+        blobs: Arc<BlobStorage>,
+        wal: Arc<WriteAheadLog>,
+        wal_unsynced: Arc<Mutex<Vec<u64>>>,
+    ) -> Result<Self> {
+        let (stop, stopped) = crossbeam_channel::bounded::<()>(0);
+        let handle = std::thread::Builder::new()
+            .name("chronicle-sync".into())
+            .spawn(move || {
+                // Wakes every interval until the sender is dropped
+                while let Err(crossbeam_channel::RecvTimeoutError::Timeout) =
+                    stopped.recv_timeout(interval)
+                {
+                    // Best-effort, like the sync on drop
+                    let _ = persist(&log, &state, &branches, &blobs, Some(&wal), &wal_unsynced);
+                }
+            })?;
+        Ok(Self {
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for SyncWorker {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Per-state locks ordering updates to each state's chain.
+///
+/// A state update holds its state's lock from validation until the update
+/// is written, and `Store::write_lock` only around the append itself, so
+/// updates to different states validate and encode concurrently while log
+/// appends, sequence numbers and chain heads stay serialized. Always taken
+/// before `write_lock`.
+#[derive(Default)]
+struct ChainLocks {
+    locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+impl ChainLocks {
+    /// The lock for one state's chain.
+    fn get(&self, state_id: &str) -> Arc<Mutex<()>> {
+        Arc::clone(self.locks.lock().entry(state_id.to_string()).or_default())
+    }
+}
+
+/// A `StateUpdateRecord` whose operation is already JSON, so the costly
+/// encoding can happen outside the write lock. Serializes the same way.
+#[derive(serde::Serialize)]
+struct EncodedStateUpdate<'a> {
+    record_id: RecordId,
+    global_sequence: Sequence,
+    state_id: &'a str,
+    prev_update_offset: Option<u64>,
+    operation: &'a RawValue,
+    timestamp: Timestamp,
+}
+
+/// Contents of `REPLICATION_FILE`.
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct ReplicationState {
+    /// This store's ID in change feeds.
+    node_id: String,
+    /// Highest `ChangeKey::seq` applied from each source node.
+    #[serde(default)]
+    applied: HashMap<String, u64>,
+}
+
+impl ReplicationState {
+    /// Load the file, creating it with a fresh node ID if it's missing.
+    ///
+    /// Read-only handles on stores without one get an ID that lasts until
+    /// they close.
+    fn load(path: &Path, writable: bool) -> Result<Self> {
+        match fs::read(path.join(REPLICATION_FILE)) {
+            Ok(data) => {
+                return serde_json::from_slice(&data)
+                    .map_err(|e| StoreError::Deserialization(e.to_string()));
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let seed = format!(
+            "{}:{}:{}",
+            path.display(),
+            std::process::id(),
+            Timestamp::now().0
+        );
+        let state = Self {
+            node_id: Hash::from_bytes(seed.as_bytes()).to_hex()[..16].to_string(),
+            applied: HashMap::new(),
+        };
+        if writable {
+            state.save(path)?;
+        }
+        Ok(state)
+    }
+
+    /// Write the file atomically.
+    fn save(&self, path: &Path) -> Result<()> {
+        let tmp_path = path.join(format!("{}.tmp", REPLICATION_FILE));
+        fs::write(&tmp_path, serde_json::to_vec(self)?)?;
+        fs::rename(tmp_path, path.join(REPLICATION_FILE))?;
+        Ok(())
+    }
+}
+
+/// Restores the store's previous auto-snapshot setting when dropped.
+///
+/// Returned by `Store::with_auto_snapshot`.
+#[must_use = "auto-snapshot is restored as soon as the guard is dropped"]
+pub struct AutoSnapshotGuard<'a> {
+    flag: &'a AtomicBool,
+    previous: bool,
+}
+
+impl Drop for AutoSnapshotGuard<'_> {
+    fn drop(&mut self) {
+        self.flag.store(self.previous, Ordering::Release);
+    }
+}
+
+/// An operation replayed by `Store::tail_from_recent_ops`, with its items parsed.
+enum TailOp {
+    Append(Vec<serde_json::Value>),
+    Edit { index: usize, value: serde_json::Value },
+    Redact { start: usize, end: usize },
+}
+
+/// Iterator over items in an AppendLog state.
+///
+/// This reconstructs items lazily, yielding them one at a time.
+/// Items are yielded in order (oldest first).
+pub struct StateItemIterator {
+    log: Arc<RecordLog>,
+    /// State being iterated
+    state_id: String,
+    /// Buffer of items to yield
+    items_buffer: Vec<serde_json::Value>,
+    /// Current index in items_buffer
+    current_index: usize,
+    /// Head offset to start from
+    head_offset: u64,
+    /// Item limit of a ring buffer state
+    capacity: Option<usize>,
+    /// Whether we've finished preparing
+    prepared: bool,
+}
+
+impl StateItemIterator {
+    fn new(log: Arc<RecordLog>, state_id: &str, head_offset: u64, capacity: Option<usize>) -> Self {
+        Self {
+            log,
+            state_id: state_id.to_string(),
+            items_buffer: Vec::new(),
+            current_index: 0,
+            head_offset,
+            capacity,
+            prepared: false,
+        }
+    }
+
+    /// Prepare by collecting and ordering all items.
+    fn prepare(&mut self) -> Result<()> {
+        if self.prepared {
+            return Ok(());
+        }
+
+        // Two-pass approach: collect operations, reverse, apply
+        let mut ops: Vec<StateOperation> = Vec::new();
+        let mut current_offset = Some(self.head_offset);
+        let mut hit_snapshot = false;
+
+        // Pass 1: Collect operations in reverse order
+        while let Some(offset) = current_offset {
+            let record = self.log.read_at(offset)?;
+            let update = decode_update(&record, &self.state_id, offset)?;
+
+            match &update.operation {
+                StateOperation::Snapshot(_) | StateOperation::Clear => {
+                    ops.push(update.operation.clone());
+                    break; // Full snapshot has everything
+                }
+                StateOperation::DeltaSnapshot(_) => {
+                    ops.push(update.operation.clone());
+                    hit_snapshot = true;
+                }
+                _ => {
+                    if !hit_snapshot {
+                        ops.push(update.operation.clone());
+                    }
+                }
+            }
+
+            current_offset = update.prev_update_offset;
+        }
+
+        // Pass 2: Apply operations in forward order
+        ops.reverse();
+        for op in ops {
+            match op {
+                StateOperation::Append(item) => {
+                    let value: serde_json::Value = serde_json::from_slice(&item)
+                        .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+                    self.items_buffer.push(value);
+                }
+                StateOperation::AppendMany(items) => {
+                    for item in items {
+                        let value: serde_json::Value = serde_json::from_slice(&item)
+                            .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+                        self.items_buffer.push(value);
+                    }
+                }
+                StateOperation::Prepend(item) => {
+                    let value: serde_json::Value = serde_json::from_slice(&item)
+                        .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+                    self.items_buffer.insert(0, value);
+                    // A full ring buffer drops its last item instead
+                    if let Some(capacity) = self.capacity {
+                        self.items_buffer.truncate(capacity);
+                    }
+                }
+                StateOperation::Snapshot(data) | StateOperation::DeltaSnapshot(data) => {
+                    let arr: Vec<serde_json::Value> = serde_json::from_slice(&data)
+                        .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+                    self.items_buffer.extend(arr);
+                }
+                StateOperation::Clear => self.items_buffer.clear(),
+                StateOperation::Redact { start, end } => {
+                    let start = start.min(self.items_buffer.len());
+                    let end = end.min(self.items_buffer.len());
+                    if start < end {
+                        self.items_buffer.drain(start..end);
+                    }
+                }
+                StateOperation::Edit { index, new_value } if index < self.items_buffer.len() => {
+                    let value: serde_json::Value = serde_json::from_slice(&new_value)
+                        .map_err(|e| StoreError::Deserialization(e.to_string()))?;
+                    self.items_buffer[index] = value;
+                }
+                _ => {}
+            }
+            if let Some(capacity) = self.capacity {
+                let excess = self.items_buffer.len().saturating_sub(capacity);
+                self.items_buffer.drain(..excess);
+            }
+        }
+
+        self.prepared = true;
+        Ok(())
+    }
+}
+
+impl Iterator for StateItemIterator {
+    type Item = Result<serde_json::Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.prepared {
+            if let Err(e) = self.prepare() {
+                return Some(Err(e));
+            }
+        }
+
+        if self.current_index >= self.items_buffer.len() {
+            None
+        } else {
+            let item = self.items_buffer[self.current_index].clone();
+            self.current_index += 1;
+            Some(Ok(item))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blobs::BlobCachePolicy;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    /// Copy a store directory as a crash would leave it: whatever is on disk.
+    fn copy_dir(from: &Path, to: &Path) {
+        fs::create_dir_all(to).unwrap();
+        for entry in fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            let target = to.join(entry.file_name());
+            if entry.file_type().unwrap().is_dir() {
+                copy_dir(&entry.path(), &target);
+            } else {
+                fs::copy(entry.path(), &target).unwrap();
+            }
+        }
+    }
+
+    fn test_config(dir: &TempDir) -> StoreConfig {
+        StoreConfig {
+            path: dir.path().join("store"),
+            blob_cache_size: 100,
+            create_if_missing: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_create_store() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        assert!(store.path().join("MANIFEST").exists());
+        assert!(store.path().join("records.log").exists());
+    }
+
+    #[test]
+    fn test_append_record() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        let input = RecordInput::json("message", &json!({"text": "Hello"})).unwrap();
+        let record = store.append(input).unwrap();
+
+        assert_eq!(record.sequence, Sequence(1));
+        assert_eq!(record.record_type, "message");
+    }
+
+    #[test]
+    fn test_append_with_offset() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        let mut appended = Vec::new();
+        for i in 0..5 {
+            let input = RecordInput::json("message", &json!({"n": i})).unwrap();
+            appended.push(store.append_with_offset(input).unwrap());
+        }
+
+        for (record, offset) in appended {
+            assert_eq!(store.index.get_offset_by_id(record.id), Some(offset));
+            let read = store.log.read_at(offset).unwrap();
+            assert_eq!(read.id, record.id);
+            assert_eq!(read.payload, record.payload);
+        }
+    }
+
+    #[test]
+    fn test_read_record_at() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        let mut appended = Vec::new();
+        for i in 0..5 {
+            let input = RecordInput::json("message", &json!({"n": i})).unwrap();
+            appended.push(store.append_with_offset(input).unwrap());
+        }
+        for (record, offset) in &appended {
+            let read = store.read_record_at(*offset).unwrap();
+            assert_eq!(read.id, record.id);
+            assert_eq!(read.sequence, record.sequence);
+            assert_eq!(read.payload, record.payload);
+        }
+
+        // Mid-record, past the end, and a payload that looks like a header
+        let (_, offset) = &appended[2];
+        assert!(matches!(store.read_record_at(offset + 1), Err(StoreError::InvalidFormat(_))));
+        assert!(matches!(store.read_record_at(store.log.size()), Err(StoreError::InvalidFormat(_))));
+
+        let log_bytes = std::fs::read(store.path().join("records.log")).unwrap();
+        let first = log_bytes[appended[0].1 as usize..appended[1].1 as usize].to_vec();
+        let (_, blob_offset) = store.append_with_offset(RecordInput::raw("blob", first.clone())).unwrap();
+        let log_bytes = std::fs::read(store.path().join("records.log")).unwrap();
+        let copy_offset = blob_offset as usize
+            + log_bytes[blob_offset as usize..]
+                .windows(first.len())
+                .position(|window| window == first.as_slice())
+                .unwrap();
+        assert!(store.log.read_at(copy_offset as u64).is_ok());
+        assert!(matches!(
+            store.read_record_at(copy_offset as u64),
+            Err(StoreError::InvalidFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_get_record() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        let input = RecordInput::json("message", &json!({"text": "Hello"})).unwrap();
+        let record = store.append(input).unwrap();
+
+        let retrieved = store.get_record(record.id).unwrap().unwrap();
+        assert_eq!(retrieved.id, record.id);
+        assert_eq!(retrieved.payload, record.payload);
+    }
+
+    #[test]
+    fn test_store_blob() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        let content = b"function hello() { return 'world'; }";
+        let hash = store.store_blob(content, "application/javascript").unwrap();
+
+        let blob = store.get_blob(&hash).unwrap().unwrap();
+        assert_eq!(blob.content, content);
+        assert_eq!(blob.content_type, "application/javascript");
+    }
+
+    #[test]
+    fn test_state_operations() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        // Register state
+        store.register_state(StateRegistration {
+            id: "counter".to_string(),
+            strategy: crate::types::StateStrategy::Snapshot,
+            initial_value: None,
+        }).unwrap();
+
+        // Update state
+        store.update_state("counter", StateOperation::Set(b"42".to_vec())).unwrap();
+
+        // Get state
+        let value = store.get_state("counter").unwrap().unwrap();
+        assert_eq!(value, b"42");
+    }
+
+    #[test]
+    fn test_branch_operations() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        // Append to main
+        let input = RecordInput::json("message", &json!({"text": "main"})).unwrap();
+        store.append(input).unwrap();
+
+        // Create branch
+        store.create_branch("feature", None).unwrap();
+        store.switch_branch("feature").unwrap();
+
+        // Append to feature
+        let input = RecordInput::json("message", &json!({"text": "feature"})).unwrap();
+        let record = store.append(input).unwrap();
+
+        assert_eq!(record.sequence, Sequence(2));
+
+        // Check branch list
+        let branches = store.list_branches();
+        assert_eq!(branches.len(), 2);
+    }
+
+    #[test]
+    fn test_branch_graph() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        // main -> feature -> sub-feature, plus sibling hotfix off main
+        store.append(RecordInput::raw("event", b"1".to_vec())).unwrap();
+        let feature = store.create_branch("feature", None).unwrap();
+        let sub = store.create_branch("sub-feature", Some("feature")).unwrap();
+        let hotfix = store.create_branch("hotfix", None).unwrap();
+        let main = store.current_branch();
+
+        let graph = store.branch_graph();
+        assert_eq!(graph.nodes.len(), 4);
+        assert!(graph.nodes.iter().all(|node| !node.orphan));
+        assert_eq!(graph.node(sub.id).unwrap().branch_point, Some(Sequence(1)));
+
+        let edges: Vec<_> = graph.edges.iter().map(|e| (e.child, e.parent)).collect();
+        assert_eq!(
+            edges,
+            vec![(feature.id, main.id), (sub.id, feature.id), (hotfix.id, main.id)]
+        );
+
+        assert_eq!(
+            graph.topological_order(),
+            vec![main.id, feature.id, sub.id, hotfix.id]
+        );
+
+        let json: serde_json::Value = serde_json::from_str(&graph.to_json().unwrap()).unwrap();
+        assert_eq!(json["nodes"].as_array().unwrap().len(), 4);
+        assert_eq!(json["edges"][1]["parent"], feature.id.0);
+    }
+
+    #[test]
+    fn test_iter_records_follows_branch() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        let payloads = |store: &Store| -> Vec<String> {
+            store
+                .iter_records()
+                .map(|r| String::from_utf8(r.unwrap().payload).unwrap())
+                .collect()
+        };
+
+        store.append(RecordInput::raw("event", b"main-1".to_vec())).unwrap();
+        store.append(RecordInput::raw("event", b"main-2".to_vec())).unwrap();
+        store.create_branch("feature", None).unwrap();
+        store.create_branch("sibling", None).unwrap();
+        store.append(RecordInput::raw("event", b"main-3".to_vec())).unwrap();
+
+        store.switch_branch("sibling").unwrap();
+        store.append(RecordInput::raw("event", b"sibling-3".to_vec())).unwrap();
+
+        store.switch_branch("feature").unwrap();
+        store.append(RecordInput::raw("event", b"feature-3".to_vec())).unwrap();
+        store.append(RecordInput::raw("event", b"feature-4".to_vec())).unwrap();
+
+        assert_eq!(payloads(&store), vec!["main-1", "main-2", "feature-3", "feature-4"]);
+        let sequences: Vec<_> = store.iter_records().map(|r| r.unwrap().sequence.0).collect();
+        assert_eq!(sequences, vec![1, 2, 3, 4]);
+
+        store.switch_branch("main").unwrap();
+        assert_eq!(payloads(&store), vec!["main-1", "main-2", "main-3"]);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let dir = TempDir::new().unwrap();
+        let config = test_config(&dir);
+
+        // Create and write
+        {
+            let store = Store::create(config.clone()).unwrap();
+
+            let input = RecordInput::json("message", &json!({"text": "Hello"})).unwrap();
+            store.append(input).unwrap();
+
+            store.store_blob(b"content", "text/plain").unwrap();
+
+            store.register_state(StateRegistration {
+                id: "test".to_string(),
+                strategy: crate::types::StateStrategy::Snapshot,
+                initial_value: None,
+            }).unwrap();
+
+            store.update_state("test", StateOperation::Set(b"value".to_vec())).unwrap();
+
+            store.create_branch("feature", None).unwrap();
+
+            store.sync().unwrap();
+        }
+
+        // Reopen and verify
+        {
+            let store = Store::open(config).unwrap();
+
+            assert_eq!(store.index.count(), 2); // message + state_update
+
+            let branches = store.list_branches();
+            assert_eq!(branches.len(), 2);
+
+            let state = store.get_state("test").unwrap().unwrap();
+            assert_eq!(state, b"value");
+        }
+    }
+
+    #[test]
+    fn test_store_lock() {
+        let dir = TempDir::new().unwrap();
+        let config = test_config(&dir);
+
+        let _store1 = Store::create(config.clone()).unwrap();
+
+        // Second store should fail to acquire lock
+        let result = Store::open(config);
+        assert!(matches!(result, Err(StoreError::Locked)));
+    }
+
+    #[test]
+    fn test_stats() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        let input = RecordInput::json("message", &json!({"text": "Hello"})).unwrap();
+        store.append(input).unwrap();
+
+        store.store_blob(b"content", "text/plain").unwrap();
+
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.record_count, 1);
+        assert_eq!(stats.blob_count, 1);
+        assert_eq!(stats.branch_count, 1);
+        assert!(stats.index_size_bytes > 0);
+    }
+
+    #[test]
+    fn test_fast_accessors() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        for i in 0..3 {
+            store
+                .append(RecordInput::json("message", &json!({ "n": i })).unwrap())
+                .unwrap();
+            store.store_blob(format!("blob {}", i).as_bytes(), "text/plain").unwrap();
+        }
+        store.create_branch("side", None).unwrap();
+        store.switch_branch("side").unwrap();
+        store
+            .append(RecordInput::json("message", &json!({ "n": 3 })).unwrap())
+            .unwrap();
+
+        let stats = store.stats().unwrap();
+        assert_eq!(store.record_count(), stats.record_count);
+        assert_eq!(store.blob_count().unwrap(), stats.blob_count);
+        assert_eq!(store.record_count(), 4);
+        assert_eq!(store.blob_count().unwrap(), 3);
+
+        assert_eq!(store.branch_head("main").unwrap(), Sequence(3));
+        assert_eq!(store.branch_head("side").unwrap(), store.current_branch().head);
+        assert_eq!(store.branch_head("side").unwrap(), Sequence(4));
+        assert!(matches!(
+            store.branch_head("missing"),
+            Err(StoreError::BranchNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_stats_state_breakdown() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        for (id, count) in [("a", 3), ("b", 7)] {
+            store
+                .register_state(StateRegistration {
+                    id: id.to_string(),
+                    strategy: crate::types::StateStrategy::AppendLog {
+                        delta_snapshot_every: 100,
+                        full_snapshot_every: 10,
+                    },
+                    initial_value: None,
+                })
+                .unwrap();
+            for i in 0..count {
+                store
+                    .update_state(id, StateOperation::Append(format!("{}", i).into_bytes()))
+                    .unwrap();
+            }
+        }
+
+        let stats = store.stats().unwrap();
+        assert!(stats.index_size_bytes > 0);
+        assert_eq!(stats.state_breakdown.len(), 2);
+
+        let summary = store.get_compaction_summary().unwrap();
+        let ops: u64 = stats.state_breakdown.iter().map(|s| s.operation_count).sum();
+        let bytes: u64 = stats.state_breakdown.iter().map(|s| s.bytes).sum();
+        assert_eq!(ops, summary.total_operations);
+        assert_eq!(ops, 10);
+        assert_eq!(bytes, summary.total_bytes);
+
+        // Blobs are cached when stored, so the first read is a hit
+        assert!(stats.blob_cache_hit_rate.is_none());
+        let hash = store.store_blob(b"content", "text/plain").unwrap();
+        store.get_blob(&hash).unwrap();
+        let stats = store.stats().unwrap();
+        assert_eq!(stats.blob_cache_hit_rate, Some(1.0));
+    }
+
+    #[test]
+    fn test_blob_cache_stats() {
+        let dir = TempDir::new().unwrap();
+        let config = StoreConfig {
+            blob_cache: Some(BlobCacheConfig {
+                max_entries: 100,
+                max_bytes: Some(250),
+                policy: BlobCachePolicy::Lru,
+            }),
+            ..test_config(&dir)
+        };
+        let store = Store::create(config).unwrap();
+
+        let small = store.store_blob(b"small", "text/plain").unwrap();
+        store.get_blob(&small).unwrap().unwrap();
+        store.get_blob(&small).unwrap().unwrap();
+        let stats = store.blob_cache_stats();
+        assert_eq!((stats.hits, stats.misses, stats.evictions), (2, 0, 0));
+        assert_eq!(stats.bytes, 5);
+
+        // Two 100-byte blobs fit alongside it; a third pushes the oldest out
+        let a = store.store_blob(&[1; 100], "application/octet-stream").unwrap();
+        let b = store.store_blob(&[2; 100], "application/octet-stream").unwrap();
+        assert_eq!(store.blob_cache_stats().evictions, 0);
+        store.get_blob(&small).unwrap();
+        let c = store.store_blob(&[3; 100], "application/octet-stream").unwrap();
+        let stats = store.blob_cache_stats();
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.bytes, 205);
+
+        // The evicted blob is read back from disk and re-cached
+        assert_eq!(store.get_blob(&a).unwrap().unwrap().content, vec![1; 100]);
+        let stats = store.blob_cache_stats();
+        assert_eq!((stats.hits, stats.misses), (3, 1));
+        assert!(stats.bytes <= 250);
+        store.get_blob(&c).unwrap();
+        store.get_blob(&b).unwrap();
+        assert_eq!(store.blob_cache_stats().misses, 2);
+
+        // A blob over the byte limit is never cached
+        let huge = store.store_blob(&[4; 300], "application/octet-stream").unwrap();
+        store.get_blob(&huge).unwrap().unwrap();
+        store.get_blob(&huge).unwrap().unwrap();
+        let stats = store.blob_cache_stats();
+        assert_eq!(stats.misses, 4);
+        assert!(stats.bytes <= 250);
+    }
+
+    #[test]
+    fn test_blob_cache_config_validation() {
+        let dir = TempDir::new().unwrap();
+        let config = StoreConfig {
+            blob_cache: Some(BlobCacheConfig {
+                max_bytes: Some(0),
+                ..Default::default()
+            }),
+            ..test_config(&dir)
+        };
+        assert!(matches!(Store::create(config), Err(StoreError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_blob_shard_depth() {
+        let dir = TempDir::new().unwrap();
+        let config = StoreConfig {
+            blob_shard_depth: Some(2),
+            ..test_config(&dir)
+        };
+
+        let hashes: Vec<Hash> = {
+            let store = Store::create(config.clone()).unwrap();
+            (0..20)
+                .map(|i| store.store_blob(format!("blob {}", i).as_bytes(), "text/plain").unwrap())
+                .collect()
+        };
+
+        let store = Store::open(config.clone()).unwrap();
+        for (i, hash) in hashes.iter().enumerate() {
+            let path = dir
+                .path()
+                .join("store/blobs")
+                .join(hex::encode(&hash.0[0..1]))
+                .join(hex::encode(&hash.0[1..2]))
+                .join(hash.to_hex());
+            assert!(path.is_file());
+            let blob = store.get_blob(hash).unwrap().unwrap();
+            assert_eq!(blob.content, format!("blob {}", i).into_bytes());
+        }
+        assert_eq!(store.blobs.list().unwrap().len(), 20);
+        assert!(store.blobs.total_size().unwrap() > 0);
+        drop(store);
+
+        // Left unset, the depth comes from the manifest
+        let store = Store::open(StoreConfig {
+            blob_shard_depth: None,
+            ..config.clone()
+        })
+        .unwrap();
+        assert_eq!(store.get_blob(&hashes[0]).unwrap().unwrap().content, b"blob 0");
+        drop(store);
+
+        // The layout can't change after creation
+        let result = Store::open(StoreConfig {
+            blob_shard_depth: Some(1),
+            ..config
+        });
+        assert!(matches!(result, Err(StoreError::InvalidOperation(_))));
+
+        let other = TempDir::new().unwrap();
+        let result = Store::create(StoreConfig {
+            blob_shard_depth: Some(3),
+            ..test_config(&other)
+        });
+        assert!(matches!(result, Err(StoreError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_blake3_blob_hash() {
+        let dir = TempDir::new().unwrap();
+        let config = StoreConfig {
+            blob_hash: HashAlgorithm::Blake3,
+            ..test_config(&dir)
+        };
+        let content: Vec<u8> = (0..300_000u32).flat_map(|i| i.to_le_bytes()).collect();
+
+        let (hash, chunked) = {
+            let store = Store::create(config.clone()).unwrap();
+            let hash = store.store_blob(b"hello", "text/plain").unwrap();
+            let chunked = store.store_blob_chunked(&content, "application/octet-stream").unwrap();
+            (hash, chunked)
+        };
+        assert_eq!(hash, Hash(*blake3::hash(b"hello").as_bytes(), HashAlgorithm::Blake3));
+        let hex = hash.to_hex();
+        assert_eq!(hex, format!("blake3:{}", hex::encode(hash.0)));
+        assert_eq!(Hash::from_hex(&hex).unwrap(), hash);
+        assert_eq!(
+            fs::read(dir.path().join("store/MANIFEST")).unwrap()[4..],
+            [STORE_VERSION, 1, 1]
+        );
+
+        let store = Store::open(config.clone()).unwrap();
+        assert_eq!(store.get_blob(&hash).unwrap().unwrap().content, b"hello");
+        assert_eq!(store.get_blob(&chunked).unwrap().unwrap().content, content);
+        assert_eq!(store.blobs.list().unwrap().len() as u64, store.blob_count().unwrap());
+        assert!(store.blobs.list().unwrap().iter().all(|hash| hash.1 == HashAlgorithm::Blake3));
+        // Still sharded by the first byte
+        assert!(dir
+            .path()
+            .join("store/blobs")
+            .join(hex::encode(&hash.0[0..1]))
+            .join(hex::encode(hash.0))
+            .is_file());
+
+        // SHA-256 hashes don't address blobs here
+        let sha = Hash::from_bytes(b"hello");
+        assert!(matches!(store.get_blob(&sha), Err(StoreError::InvalidOperation(_))));
+        assert!(!store.blob_exists(&sha));
+        drop(store);
+
+        // The algorithm can't change after creation
+        let result = Store::open(StoreConfig {
+            blob_hash: HashAlgorithm::Sha256,
+            ..config
+        });
+        assert!(matches!(result, Err(StoreError::InvalidOperation(_))));
+    }
+
+    #[test]
+    fn test_get_blob_range() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(StoreConfig {
+            blob_cache_size: 1,
+            ..test_config(&dir)
+        })
+        .unwrap();
+
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let content: Vec<u8> = (0..1024 * 1024)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect();
+        let plain = store.store_blob(&content, "application/octet-stream").unwrap();
+        let mut chunked_content = content.clone();
+        chunked_content[0] ^= 1;
+        let chunked = store.store_blob_chunked(&chunked_content, "application/octet-stream").unwrap();
+        // Push both out of the cache so the reads hit the files
+        store.store_blob(b"evict", "text/plain").unwrap();
+
+        let middle = 512 * 1024 - 100;
+        for (hash, expected) in [(plain, &content), (chunked, &chunked_content)] {
+            let range = store.get_blob_range(&hash, middle, 4096).unwrap().unwrap();
+            assert_eq!(range, expected[middle as usize..middle as usize + 4096]);
+
+            // Past the end: clamped, empty at the end, an error beyond it
+            let size = expected.len() as u64;
+            let tail = store.get_blob_range(&hash, size - 10, 4096).unwrap().unwrap();
+            assert_eq!(tail, expected[expected.len() - 10..]);
+            assert!(store.get_blob_range(&hash, size, 1).unwrap().unwrap().is_empty());
+            let result = store.get_blob_range(&hash, size + 1, 1);
+            assert!(matches!(result, Err(StoreError::InvalidOperation(_))));
+        }
+
+        let missing = Hash::from_bytes(b"missing");
+        assert!(store.get_blob_range(&missing, 0, 10).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_validate_content_type() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(StoreConfig {
+            validate_content_type: true,
+            ..test_config(&dir)
+        })
+        .unwrap();
+
+        let hash = store.store_blob(b"{}", " Application/JSON; Charset=utf-8").unwrap();
+        let info = store.blob_info(&hash).unwrap().unwrap();
+        assert_eq!(info.content_type, "application/json; charset=utf-8");
+
+        // The same content under another spelling dedups to the same type
+        let again = store.store_blob(b"{}", "application/json;charset=utf-8").unwrap();
+        assert_eq!(again, hash);
+        assert_eq!(store.blob_count().unwrap(), 1);
+        assert_eq!(
+            store.list_blobs_by_type("APPLICATION/JSON; charset=utf-8").unwrap(),
+            vec![hash]
+        );
+
+        let result = store.store_blob(b"x", "aplication json");
+        assert!(matches!(result, Err(StoreError::InvalidOperation(_))));
+        let result = store.store_blob_chunked(b"x", "text/");
+        assert!(matches!(result, Err(StoreError::InvalidOperation(_))));
+        assert_eq!(store.blob_count().unwrap(), 1);
+
+        // Off by default: types are stored as given
+        let other = TempDir::new().unwrap();
+        let store = Store::create(test_config(&other)).unwrap();
+        let hash = store.store_blob(b"{}", "Whatever").unwrap();
+        assert_eq!(store.blob_info(&hash).unwrap().unwrap().content_type, "Whatever");
+    }
+
+    #[test]
+    fn test_record_type_validation() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(StoreConfig {
+            max_record_type_len: 8,
+            ..test_config(&dir)
+        })
+        .unwrap();
+
+        for record_type in ["", "too-long-type", "nul\0type"] {
+            let result = store.append(RecordInput::raw(record_type, b"x".to_vec()));
+            assert!(matches!(result, Err(StoreError::InvalidOperation(_))), "{:?}", record_type);
+        }
+        assert_eq!(store.record_count(), 0);
+        assert!(store.list_record_types().is_empty());
+
+        let record = store.append(RecordInput::raw("message", b"x".to_vec())).unwrap();
+        assert_eq!(record.record_type, "message");
+
+        let other = TempDir::new().unwrap();
+        let result = Store::create(StoreConfig {
+            max_record_type_len: 0,
+            ..test_config(&other)
+        });
+        assert!(matches!(result, Err(StoreError::InvalidConfig(_))));
+    }
+
+    #[test]
+    fn test_payload_size_limits() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(StoreConfig {
+            max_record_payload_bytes: Some(16),
+            max_blob_bytes: Some(1024),
+            ..test_config(&dir)
+        })
+        .unwrap();
+
+        let result = store.append(RecordInput::raw("event", vec![b'x'; 17]));
+        assert!(matches!(result, Err(StoreError::PayloadTooLarge { size: 17, limit: 16 })));
+        assert_eq!(store.record_count(), 0);
+        store.append(RecordInput::raw("event", vec![b'x'; 16])).unwrap();
+
+        let result = store.store_blob(&[0u8; 1025], "application/octet-stream");
+        assert!(matches!(result, Err(StoreError::PayloadTooLarge { size: 1025, limit: 1024 })));
+
+        // A stream is cut off just past the limit and nothing is written
+        let endless = std::io::repeat(7);
+        let result = store.store_blob_from_reader(endless, "application/octet-stream");
+        assert!(matches!(result, Err(StoreError::PayloadTooLarge { size: 1025, limit: 1024 })));
+        assert_eq!(store.blob_count().unwrap(), 0);
+        let blob_files = fs::read_dir(dir.path().join("store/blobs"))
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().unwrap().is_dir())
+            .count();
+        assert_eq!(blob_files, 0);
+
+        let hash = store
+            .store_blob_from_reader(&[7u8; 1024][..], "application/octet-stream")
+            .unwrap();
+        assert_eq!(store.get_blob(&hash).unwrap().unwrap().content, vec![7u8; 1024]);
+    }
+
+    #[test]
+    fn test_auto_migrate_manifest() {
+        let dir = TempDir::new().unwrap();
+        let config = test_config(&dir);
+        let manifest = dir.path().join("store/MANIFEST");
+
+        let hash = {
+            let store = Store::create(config.clone()).unwrap();
+            store.store_blob(b"kept", "text/plain").unwrap()
+        };
+        assert_eq!(fs::read(&manifest).unwrap()[4..], [1]);
+
+        // Older formats still open as-is without auto_migrate
+        drop(Store::open(config.clone()).unwrap());
+        assert_eq!(fs::read(&manifest).unwrap()[4..], [1]);
+
+        // Read-only handles never migrate
+        drop(Store::open(StoreConfig { auto_migrate: true, read_only: true, ..config.clone() }).unwrap());
+        assert_eq!(fs::read(&manifest).unwrap()[4..], [1]);
+
+        let store = Store::open(StoreConfig { auto_migrate: true, ..config.clone() }).unwrap();
+        assert_eq!(fs::read(&manifest).unwrap()[4..], [STORE_VERSION, 1, 0]);
+        assert_eq!(store.get_blob(&hash).unwrap().unwrap().content, b"kept");
+        drop(store);
+
+        // Migrated stores reopen without migrating again
+        let store = Store::open(config).unwrap();
+        assert_eq!(store.get_blob(&hash).unwrap().unwrap().content, b"kept");
+    }
+
+    #[test]
+    fn test_blob_exists_checks_disk() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        let hash = store.store_blob(b"content", "text/plain").unwrap();
+        store.blobs.clear_cache();
+        assert!(store.blob_exists(&hash));
+
+        // Existence checks aren't cache reads
+        assert!(store.stats().unwrap().blob_cache_hit_rate.is_none());
+
+        // A cached blob whose file is gone doesn't exist
+        store.get_blob(&hash).unwrap();
+        let path = dir
+            .path()
+            .join("store/blobs")
+            .join(hash.shard_prefix())
+            .join(hash.to_hex());
+        fs::remove_file(path).unwrap();
+        assert!(!store.blob_exists(&hash));
+    }
+
+    #[test]
+    fn test_get_state_len() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        // Nonexistent state
+        assert_eq!(store.get_state_len("messages").unwrap(), None);
+
+        // Register state
+        store.register_state(StateRegistration {
+            id: "messages".to_string(),
+            strategy: crate::types::StateStrategy::AppendLog {
+                delta_snapshot_every: 100,
+                full_snapshot_every: 10,
+            },
+            initial_value: None,
+        }).unwrap();
+
+        // Empty state
+        assert_eq!(store.get_state_len("messages").unwrap(), None);
+
+        // Add items
+        for i in 1..=5 {
+            store.update_state("messages", StateOperation::Append(
+                serde_json::to_vec(&json!({"id": i})).unwrap()
+            )).unwrap();
+        }
+
+        assert_eq!(store.get_state_len("messages").unwrap(), Some(5));
+
+        // Snapshot states report the length of the array they hold
+        store.register_state(StateRegistration {
+            id: "tags".to_string(),
+            strategy: crate::types::StateStrategy::Snapshot,
+            initial_value: None,
+        }).unwrap();
+        assert_eq!(store.get_state_len("tags").unwrap(), None);
+        store.update_state("tags", StateOperation::Set(br#"["a","b","c"]"#.to_vec())).unwrap();
+        assert_eq!(store.get_state_len("tags").unwrap(), Some(3));
+        store.update_state("tags", StateOperation::Set(b"[]".to_vec())).unwrap();
+        assert_eq!(store.get_state_len("tags").unwrap(), Some(0));
+
+        // ...and fail for anything else
+        store.register_state(StateRegistration {
+            id: "config".to_string(),
+            strategy: crate::types::StateStrategy::Snapshot,
+            initial_value: Some(br#"{"a": 1}"#.to_vec()),
+        }).unwrap();
+        assert!(matches!(
+            store.get_state_len("config"),
+            Err(StoreError::InvalidOperation(_))
+        ));
+    }
+
+    #[test]
+    fn test_append_many() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        store.register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: crate::types::StateStrategy::AppendLog {
+                delta_snapshot_every: 1,
+                full_snapshot_every: 10,
+            },
+            initial_value: None,
+        }).unwrap();
+
+        let items: Vec<Vec<u8>> = (0..500).map(|i| serde_json::to_vec(&i).unwrap()).collect();
+        let record = store
+            .update_state("items", StateOperation::AppendMany(items))
+            .unwrap();
+
+        // One update record, plus the delta snapshot the batch triggered
+        assert_eq!(store.current_branch().head, Sequence(record.sequence.0 + 1));
+        assert_eq!(store.get_state_len("items").unwrap(), Some(500));
+
+        let expected: Vec<i32> = (0..500).collect();
+        let state: Vec<i32> = serde_json::from_slice(&store.get_state("items").unwrap().unwrap()).unwrap();
+        assert_eq!(state, expected);
+
+        let iterated: Vec<i32> = store
+            .iter_state_items("items")
+            .unwrap()
+            .unwrap()
+            .map(|item| serde_json::from_value(item.unwrap()).unwrap())
+            .collect();
+        assert_eq!(iterated, expected);
+
+        // Tail reads across the batch and a later single append
+        store
+            .update_state("items", StateOperation::AppendMany(vec![b"500".to_vec(), b"501".to_vec()]))
+            .unwrap();
+        store.update_state("items", StateOperation::Append(b"502".to_vec())).unwrap();
+        assert_eq!(store.get_state_len("items").unwrap(), Some(503));
+        let tail: Vec<i32> =
+            serde_json::from_slice(&store.get_state_tail("items", 4).unwrap().unwrap()).unwrap();
+        assert_eq!(tail, vec![499, 500, 501, 502]);
+    }
+
+    #[test]
+    fn test_corrupt_state_record_names_state_and_offset() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+        store.register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: crate::types::StateStrategy::AppendLog {
+                delta_snapshot_every: 100,
+                full_snapshot_every: 100,
+            },
+            initial_value: None,
+        }).unwrap();
+        for i in 0..3 {
+            store.update_state("items", StateOperation::Append(i.to_string().into_bytes())).unwrap();
+        }
+        let head = store.state.get_head(store.current_branch().id, "items").unwrap().head_offset;
+        let payload = store.log.read_at(head).unwrap().payload;
+        drop(store);
+
+        // Rename a field in the head update's payload and fix up its checksum,
+        // so the record reads fine but no longer decodes as a state update
+        let log_path = dir.path().join("store").join("records.log");
+        let mut bytes = std::fs::read(&log_path).unwrap();
+        let start = head as usize
+            + bytes[head as usize..]
+                .windows(payload.len())
+                .position(|window| window == payload.as_slice())
+                .unwrap();
+        let corrupted = String::from_utf8(payload.clone()).unwrap().replace("\"state_id\"", "\"state_iX\"");
+        assert_ne!(corrupted.as_bytes(), payload.as_slice());
+        bytes[start..start + payload.len()].copy_from_slice(corrupted.as_bytes());
+        // No caused_by or linked_to entries: two zero counts, then the checksum
+        let checksum_at = start + payload.len() + 4;
+        bytes[checksum_at..checksum_at + 4].copy_from_slice(&crc32fast::hash(corrupted.as_bytes()).to_le_bytes());
+        std::fs::write(&log_path, bytes).unwrap();
+
+        let store = Store::open(test_config(&dir)).unwrap();
+        let err = store.get_state("items").unwrap_err();
+        match &err {
+            StoreError::CorruptStateRecord { state_id, offset, .. } => {
+                assert_eq!(state_id, "items");
+                assert_eq!(*offset, head);
+            }
+            other => panic!("expected CorruptStateRecord, got {:?}", other),
+        }
+        let message = err.to_string();
+        assert!(message.contains("items") && message.contains(&head.to_string()));
+    }
+
+    #[test]
+    fn test_clear_state() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        store.register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: crate::types::StateStrategy::AppendLog {
+                delta_snapshot_every: 100,
+                full_snapshot_every: 100,
+            },
+            initial_value: None,
+        }).unwrap();
+        store.register_state(StateRegistration {
+            id: "config".to_string(),
+            strategy: crate::types::StateStrategy::Snapshot,
+            initial_value: None,
+        }).unwrap();
+        assert!(matches!(
+            store.update_state("config", StateOperation::Clear),
+            Err(StoreError::InvalidOperation(_))
+        ));
+
+        let append = |i: i32| store.update_state("items", StateOperation::Append(i.to_string().into_bytes())).unwrap();
+        for i in 1..=3 {
+            append(i);
+        }
+        let before_clear = append(4);
+        let cleared = store.update_state("items", StateOperation::Clear).unwrap();
+        assert_eq!(store.get_state_len("items").unwrap(), Some(0));
+        let state: Vec<i32> = store.get_state_as("items").unwrap().unwrap();
+        assert!(state.is_empty());
+
+        append(5);
+        let after_clear = append(6);
+        assert_eq!(store.get_state_len("items").unwrap(), Some(2));
+        let state: Vec<i32> = store.get_state_as("items").unwrap().unwrap();
+        assert_eq!(state, vec![5, 6]);
+        let tail: Vec<i32> = serde_json::from_slice(&store.get_state_tail("items", 1).unwrap().unwrap()).unwrap();
+        assert_eq!(tail, vec![6]);
+        let iterated: Vec<i32> = store
+            .iter_state_items("items")
+            .unwrap()
+            .unwrap()
+            .map(|item| serde_json::from_value(item.unwrap()).unwrap())
+            .collect();
+        assert_eq!(iterated, vec![5, 6]);
+
+        // History on either side of the clear
+        let state_at = |seq: Sequence| -> Vec<i32> {
+            serde_json::from_slice(&store.get_state_at("items", seq).unwrap().unwrap()).unwrap()
+        };
+        assert_eq!(state_at(before_clear.sequence), vec![1, 2, 3, 4]);
+        assert_eq!(state_at(cleared.sequence), Vec::<i32>::new());
+        assert_eq!(state_at(after_clear.sequence), vec![5, 6]);
+
+        // The clear counts as a full snapshot, so the appends behind it are compactable
+        let stats = store.get_chain_stats("items").unwrap().unwrap();
+        assert!(stats.has_full_snapshot);
+        assert_eq!(stats.operations_before_snapshot, 4);
+    }
+
+    #[test]
+    fn test_ring_buffer_state() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        store.register_state(StateRegistration {
+            id: "recent".to_string(),
+            strategy: crate::types::StateStrategy::RingBuffer {
+                capacity: 100,
+                delta_snapshot_every: 7,
+                full_snapshot_every: 3,
+            },
+            initial_value: None,
+        }).unwrap();
+
+        let mut middle = None;
+        for i in 0..1000 {
+            let record = store.update_state("recent", StateOperation::Append(i.to_string().into_bytes())).unwrap();
+            assert_eq!(store.get_state_len("recent").unwrap(), Some((i + 1).min(100)));
+            if i == 50 {
+                middle = Some(record.sequence);
+            }
+        }
+
+        let check = |store: &Store, expected: Vec<i32>| {
+            assert_eq!(store.get_state_len("recent").unwrap(), Some(expected.len()));
+            let state: Vec<i32> = store.get_state_as("recent").unwrap().unwrap();
+            assert_eq!(state, expected);
+            let tail: Vec<i32> = serde_json::from_slice(&store.get_state_tail("recent", 10).unwrap().unwrap()).unwrap();
+            assert_eq!(tail, expected[expected.len() - 10..]);
+            let iterated: Vec<i32> = store
+                .iter_state_items("recent")
+                .unwrap()
+                .unwrap()
+                .map(|item| serde_json::from_value(item.unwrap()).unwrap())
+                .collect();
+            assert_eq!(iterated, expected);
+        };
+        check(&store, (900..1000).collect());
+
+        // Before it filled up, history holds everything appended so far
+        let at_middle: Vec<i32> = serde_json::from_slice(&store.get_state_at("recent", middle.unwrap()).unwrap().unwrap()).unwrap();
+        assert_eq!(at_middle, (0..=50).collect::<Vec<_>>());
+
+        // Edits address the oldest item still held
+        store.update_state("recent", StateOperation::Edit { index: 2, new_value: b"-1".to_vec() }).unwrap();
+        store
+            .update_state("recent", StateOperation::AppendMany(vec![b"1000".to_vec(), b"1001".to_vec()]))
+            .unwrap();
+        let mut expected: Vec<i32> = (902..1002).collect();
+        expected[0] = -1;
+        check(&store, expected.clone());
+
+        store.update_state("recent", StateOperation::Redact { start: 0, end: 50 }).unwrap();
+        expected.drain(..50);
+        check(&store, expected.clone());
+
+        drop(store);
+        let store = Store::open(test_config(&dir)).unwrap();
+        check(&store, expected);
+    }
+
+    #[test]
+    fn test_max_reconstruct_bytes() {
+        let dir = TempDir::new().unwrap();
+        let config = StoreConfig {
+            max_reconstruct_bytes: Some(1000),
+            ..test_config(&dir)
+        };
+        let store = Store::create(config.clone()).unwrap();
+        store.register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: crate::types::StateStrategy::AppendLog {
+                delta_snapshot_every: 1000,
+                full_snapshot_every: 10,
+            },
+            initial_value: None,
+        }).unwrap();
+        let mut middle = None;
+        for i in 0..500 {
+            let record = store.update_state("items", StateOperation::Append(i.to_string().into_bytes())).unwrap();
+            if i == 10 {
+                middle = Some(record.sequence);
+            }
+        }
+        let full_len = serde_json::to_vec(&(0..500).collect::<Vec<_>>()).unwrap().len() as u64;
+
+        // Rebuilding stops as soon as the value passes the limit
+        match store.get_state("items") {
+            Err(StoreError::StateTooLarge { bytes, limit }) => {
+                assert_eq!(limit, 1000);
+                assert!(bytes > 1000 && bytes < full_len);
+            }
+            other => panic!("expected StateTooLarge, got {:?}", other),
+        }
+        assert!(matches!(
+            store.get_state_at("items", store.current_branch().head),
+            Err(StoreError::StateTooLarge { .. })
+        ));
+        // Smaller historical values are still fine
+        let early: Vec<i32> = serde_json::from_slice(&store.get_state_at("items", middle.unwrap()).unwrap().unwrap()).unwrap();
+        assert_eq!(early, (0..=10).collect::<Vec<_>>());
+
+        // Reading piece by piece isn't limited
+        let items: Vec<i32> = store
+            .iter_state_items("items")
+            .unwrap()
+            .unwrap()
+            .map(|item| serde_json::from_value(item.unwrap()).unwrap())
+            .collect();
+        assert_eq!(items, (0..500).collect::<Vec<_>>());
+        let tail: Vec<i32> = store.get_state_tail_as("items", 3).unwrap().unwrap();
+        assert_eq!(tail, vec![497, 498, 499]);
+
+        // Nor are internal rebuilds
+        store.compact_state("items").unwrap();
+        assert!(matches!(store.get_state("items"), Err(StoreError::StateTooLarge { .. })));
+    }
+
+    #[test]
+    fn test_prepend_state() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+        store.register_state(StateRegistration {
+            id: "feed".to_string(),
+            strategy: crate::types::StateStrategy::AppendLog {
+                delta_snapshot_every: 5,
+                full_snapshot_every: 2,
+            },
+            initial_value: None,
+        }).unwrap();
+        store.register_state(StateRegistration {
+            id: "recent".to_string(),
+            strategy: crate::types::StateStrategy::RingBuffer {
+                capacity: 4,
+                delta_snapshot_every: 3,
+                full_snapshot_every: 2,
+            },
+            initial_value: None,
+        }).unwrap();
+        store.register_state(StateRegistration {
+            id: "flag".to_string(),
+            strategy: crate::types::StateStrategy::Snapshot,
+            initial_value: None,
+        }).unwrap();
+
+        // Mix prepends and appends, remembering the value after each update
+        let mut expected: Vec<i32> = Vec::new();
+        let mut history = Vec::new();
+        for i in 0..40 {
+            let item = i.to_string().into_bytes();
+            let record = if i % 3 == 0 {
+                expected.push(i);
+                store.update_state("feed", StateOperation::Append(item)).unwrap()
+            } else {
+                expected.insert(0, i);
+                store.update_state("feed", StateOperation::Prepend(item)).unwrap()
+            };
+            history.push((record.sequence, expected.clone()));
+        }
+
+        let check = |store: &Store| {
+            assert_eq!(store.get_state_len("feed").unwrap(), Some(expected.len()));
+            let state: Vec<i32> = store.get_state_as("feed").unwrap().unwrap();
+            assert_eq!(state, expected);
+            let tail: Vec<i32> = serde_json::from_slice(&store.get_state_tail("feed", 5).unwrap().unwrap()).unwrap();
+            assert_eq!(tail, expected[expected.len() - 5..]);
+            let iterated: Vec<i32> = store
+                .iter_state_items("feed")
+                .unwrap()
+                .unwrap()
+                .map(|item| serde_json::from_value(item.unwrap()).unwrap())
+                .collect();
+            assert_eq!(iterated, expected);
+            for (seq, value) in &history {
+                let at: Vec<i32> = serde_json::from_slice(&store.get_state_at("feed", *seq).unwrap().unwrap()).unwrap();
+                assert_eq!(&at, value);
+            }
+        };
+        check(&store);
+        drop(store);
+        let store = Store::open(test_config(&dir)).unwrap();
+        check(&store);
+
+        // A full ring buffer keeps the prepended item and drops its last one
+        for i in 0..4 {
+            store.update_state("recent", StateOperation::Append(i.to_string().into_bytes())).unwrap();
+        }
+        store.update_state("recent", StateOperation::Prepend(b"9".to_vec())).unwrap();
+        let recent: Vec<i32> = store.get_state_as("recent").unwrap().unwrap();
+        assert_eq!(recent, vec![9, 0, 1, 2]);
+        assert_eq!(store.get_state_len("recent").unwrap(), Some(4));
+        let iterated: Vec<i32> = store
+            .iter_state_items("recent")
+            .unwrap()
+            .unwrap()
+            .map(|item| serde_json::from_value(item.unwrap()).unwrap())
+            .collect();
+        assert_eq!(iterated, recent);
+
+        let err = store.update_state("flag", StateOperation::Prepend(b"1".to_vec())).unwrap_err();
+        assert!(matches!(err, StoreError::InvalidOperation(_)));
+    }
+
+    #[test]
+    fn test_update_state_batch() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        store.register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: crate::types::StateStrategy::AppendLog {
+                delta_snapshot_every: 100,
+                full_snapshot_every: 10,
+            },
+            initial_value: None,
+        }).unwrap();
+        store.update_state("items", StateOperation::Append(b"0".to_vec())).unwrap();
+        let before = store.current_branch().head;
+
+        let records = store
+            .update_state_batch(
+                "items",
+                vec![
+                    StateOperation::Append(b"1".to_vec()),
+                    StateOperation::Append(b"2".to_vec()),
+                    StateOperation::Edit { index: 2, new_value: b"20".to_vec() },
+                    StateOperation::Edit { index: 0, new_value: b"10".to_vec() },
+                ],
+            )
+            .unwrap();
+
+        assert_eq!(records.len(), 4);
+        assert_eq!(store.current_branch().head, Sequence(before.0 + 4));
+        let sequences: Vec<_> = records.iter().map(|r| r.sequence.0).collect();
+        assert_eq!(sequences, vec![before.0 + 1, before.0 + 2, before.0 + 3, before.0 + 4]);
+
+        let state: Vec<i32> = serde_json::from_slice(&store.get_state("items").unwrap().unwrap()).unwrap();
+        assert_eq!(state, vec![10, 1, 20]);
+        assert_eq!(store.get_state_len("items").unwrap(), Some(3));
+
+        // An edit past the length the batch will have rejects the whole batch
+        let result = store.update_state_batch(
+            "items",
+            vec![
+                StateOperation::Append(b"3".to_vec()),
+                StateOperation::Edit { index: 4, new_value: b"40".to_vec() },
+            ],
+        );
+        assert!(matches!(result, Err(StoreError::InvalidOperation(_))));
+        assert_eq!(store.current_branch().head, Sequence(before.0 + 4));
+        assert_eq!(store.get_state_len("items").unwrap(), Some(3));
+    }
+
+    #[test]
+    fn test_update_state_batch_unwinds_partial_write() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+        store.update_state("items", StateOperation::Append(b"0".to_vec())).unwrap();
+        let branch = store.current_branch();
+        let start = store.log.size();
+        let indexed = store.index().unwrap().count();
+
+        // Two records of a batch that then fails
+        let encoded = serde_json::value::to_raw_value(&StateOperation::Append(b"1".to_vec())).unwrap();
+        let mut written = Vec::new();
+        let mut prev = store.state.get_head(branch.id, "items").map(|h| h.head_offset);
+        for seq in [branch.head.next(), branch.head.next().next()] {
+            let (record, offset) = store
+                .write_state_record(branch.id, "items", seq, prev, &encoded, None)
+                .unwrap();
+            prev = Some(offset);
+            written.push((record, offset));
+        }
+        store.unwind_state_records(start, &written).unwrap();
+
+        assert_eq!(store.log.size(), start);
+        assert_eq!(store.index().unwrap().count(), indexed);
+        assert!(store.get_record(written[0].0.id).unwrap().is_none());
+        assert!(store.check_consistency().unwrap().is_ok());
+
+        // The next update takes the sequence the batch would have used
+        let record = store.update_state("items", StateOperation::Append(b"2".to_vec())).unwrap();
+        assert_eq!(record.sequence, branch.head.next());
+        let state: Vec<i32> = serde_json::from_slice(&store.get_state("items").unwrap().unwrap()).unwrap();
+        assert_eq!(state, vec![0, 2]);
+        assert!(store.verify(VerifyOptions::default()).unwrap().is_ok());
+    }
+
+    #[test]
+    fn test_get_state_slice() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        store.register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: crate::types::StateStrategy::AppendLog {
+                delta_snapshot_every: 100,
+                full_snapshot_every: 10,
+            },
+            initial_value: None,
+        }).unwrap();
+
+        // Add items
+        for i in 1..=10 {
+            store.update_state("items", StateOperation::Append(
+                serde_json::to_vec(&i).unwrap()
+            )).unwrap();
+        }
+
+        // Get middle slice
+        let slice = store.get_state_slice("items", 3, 4).unwrap().unwrap();
+        let arr: Vec<i32> = serde_json::from_slice(&slice).unwrap();
+        assert_eq!(arr, vec![4, 5, 6, 7]);
+
+        // Get first slice
+        let slice = store.get_state_slice("items", 0, 3).unwrap().unwrap();
+        let arr: Vec<i32> = serde_json::from_slice(&slice).unwrap();
+        assert_eq!(arr, vec![1, 2, 3]);
+
+        // Get end slice
+        let slice = store.get_state_slice("items", 8, 10).unwrap().unwrap();
+        let arr: Vec<i32> = serde_json::from_slice(&slice).unwrap();
+        assert_eq!(arr, vec![9, 10]);
+
+        // Out of bounds
+        let slice = store.get_state_slice("items", 15, 5).unwrap().unwrap();
+        let arr: Vec<i32> = serde_json::from_slice(&slice).unwrap();
+        assert_eq!(arr, Vec::<i32>::new());
+    }
+
+    #[test]
+    fn test_get_state_tail() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        store.register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: crate::types::StateStrategy::AppendLog {
+                delta_snapshot_every: 100,
+                full_snapshot_every: 10,
+            },
+            initial_value: None,
+        }).unwrap();
+
+        // Add items
+        for i in 1..=10 {
+            store.update_state("items", StateOperation::Append(
+                serde_json::to_vec(&i).unwrap()
+            )).unwrap();
+        }
+
+        // Get last 3
+        let tail = store.get_state_tail("items", 3).unwrap().unwrap();
+        let arr: Vec<i32> = serde_json::from_slice(&tail).unwrap();
+        assert_eq!(arr, vec![8, 9, 10]);
+
+        // Get last 1
+        let tail = store.get_state_tail("items", 1).unwrap().unwrap();
+        let arr: Vec<i32> = serde_json::from_slice(&tail).unwrap();
+        assert_eq!(arr, vec![10]);
+
+        // Get more than exists
+        let tail = store.get_state_tail("items", 20).unwrap().unwrap();
+        let arr: Vec<i32> = serde_json::from_slice(&tail).unwrap();
+        assert_eq!(arr, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+
+        // Get zero
+        let tail = store.get_state_tail("items", 0).unwrap().unwrap();
+        let arr: Vec<i32> = serde_json::from_slice(&tail).unwrap();
+        assert_eq!(arr, Vec::<i32>::new());
+    }
+
+    #[test]
+    fn test_get_state_tail_with_edits_and_redacts() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        store.register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: crate::types::StateStrategy::AppendLog {
+                delta_snapshot_every: 1000,
+                full_snapshot_every: 10,
+            },
+            initial_value: None,
+        }).unwrap();
+
+        for i in 0..20 {
+            store.update_state("items", StateOperation::Append(
+                serde_json::to_vec(&i).unwrap()
+            )).unwrap();
+        }
+
+        let tail = |count: usize| -> Vec<i32> {
+            serde_json::from_slice(&store.get_state_tail("items", count).unwrap().unwrap()).unwrap()
+        };
+        let expected = |count: usize| -> Vec<i32> {
+            let all: Vec<i32> = serde_json::from_slice(&store.get_state("items").unwrap().unwrap()).unwrap();
+            all[all.len() - count..].to_vec()
+        };
+
+        // Edit of the last item lands inside the window
+        store.update_state("items", StateOperation::Edit {
+            index: 19,
+            new_value: serde_json::to_vec(&100).unwrap(),
+        }).unwrap();
+        assert_eq!(tail(3), vec![17, 18, 100]);
+
+        // Edit of an early item is outside the window
+        store.update_state("items", StateOperation::Edit {
+            index: 2,
+            new_value: serde_json::to_vec(&200).unwrap(),
+        }).unwrap();
+        assert_eq!(tail(3), vec![17, 18, 100]);
+
+        // Edits made before later appends still reach the window
+        store.update_state("items", StateOperation::Edit {
+            index: 18,
+            new_value: serde_json::to_vec(&300).unwrap(),
+        }).unwrap();
+        store.update_state("items", StateOperation::Append(serde_json::to_vec(&20).unwrap())).unwrap();
+        assert_eq!(tail(3), vec![300, 100, 20]);
+        assert_eq!(tail(3), expected(3));
+
+        // Redaction inside the window, then one before it that shifts earlier
+        // items into the window
+        store.update_state("items", StateOperation::Redact { start: 19, end: 20 }).unwrap();
+        assert_eq!(tail(3), vec![17, 300, 20]);
+        store.update_state("items", StateOperation::Redact { start: 1, end: 4 }).unwrap();
+        store.update_state("items", StateOperation::Append(serde_json::to_vec(&21).unwrap())).unwrap();
+        for count in 1..18 {
+            assert_eq!(tail(count), expected(count), "tail of {}", count);
+        }
+
+        // Redaction clamped at the end falls back to full reconstruction
+        store.update_state("items", StateOperation::Redact { start: 16, end: 50 }).unwrap();
+        for count in 1..16 {
+            assert_eq!(tail(count), expected(count), "tail of {}", count);
+        }
+    }
+
+    #[test]
+    fn test_iter_state_items() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        store.register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: crate::types::StateStrategy::AppendLog {
+                delta_snapshot_every: 100,
+                full_snapshot_every: 10,
+            },
+            initial_value: None,
+        }).unwrap();
+
+        // Add items
+        for i in 1..=5 {
+            store.update_state("items", StateOperation::Append(
+                serde_json::to_vec(&i).unwrap()
+            )).unwrap();
+        }
+
+        // Iterate
+        let iter = store.iter_state_items("items").unwrap().unwrap();
+        let collected: Vec<i32> = iter
+            .map(|r| serde_json::from_value(r.unwrap()).unwrap())
+            .collect();
+
+        assert_eq!(collected, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_lazy_loading_with_snapshots() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        store.register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: crate::types::StateStrategy::AppendLog {
+                delta_snapshot_every: 3,
+                full_snapshot_every: 2,
+            },
+            initial_value: None,
+        }).unwrap();
+
+        // Add items - this will trigger snapshots
+        for i in 1..=10 {
+            store.update_state("items", StateOperation::Append(
+                serde_json::to_vec(&i).unwrap()
+            )).unwrap();
+            // Create snapshot if needed
+            store.create_snapshot_if_needed("items").unwrap();
+        }
+
+        // Verify tail still works with snapshots
+        let tail = store.get_state_tail("items", 3).unwrap().unwrap();
+        let arr: Vec<i32> = serde_json::from_slice(&tail).unwrap();
+        assert_eq!(arr, vec![8, 9, 10]);
+
+        // Verify iteration still works
+        let iter = store.iter_state_items("items").unwrap().unwrap();
+        let collected: Vec<i32> = iter
+            .map(|r| serde_json::from_value(r.unwrap()).unwrap())
+            .collect();
+        assert_eq!(collected, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn test_compact_state() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        store.register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: crate::types::StateStrategy::AppendLog {
+                delta_snapshot_every: 100,
+                full_snapshot_every: 100,
+            },
+            initial_value: None,
+        }).unwrap();
+
+        // Add items without auto-snapshot
+        for i in 1..=10 {
+            store.update_state("items", StateOperation::Append(
+                serde_json::to_vec(&i).unwrap()
+            )).unwrap();
+        }
+
+        // Check chain stats before compaction
+        let stats_before = store.get_chain_stats("items").unwrap().unwrap();
+        assert_eq!(stats_before.total_operations, 10);
+        assert!(!stats_before.has_full_snapshot);
+
+        // Compact
+        let record = store.compact_state("items").unwrap().unwrap();
+        assert_eq!(record.record_type, "state_update");
+
+        // Check chain stats after compaction
+        let stats_after = store.get_chain_stats("items").unwrap().unwrap();
+        assert_eq!(stats_after.total_operations, 11); // 10 appends + 1 snapshot
+        assert!(stats_after.has_full_snapshot);
+        assert_eq!(stats_after.operations_before_snapshot, 10); // All old ops before snapshot
+
+        // State should still be correct
+        let state = store.get_state("items").unwrap().unwrap();
+        let arr: Vec<i32> = serde_json::from_slice(&state).unwrap();
+        assert_eq!(arr, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+    }
+
+    #[test]
+    fn test_compaction_summary() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        // Create two states
+        store.register_state(StateRegistration {
+            id: "state1".to_string(),
+            strategy: crate::types::StateStrategy::AppendLog {
+                delta_snapshot_every: 100,
+                full_snapshot_every: 100,
+            },
+            initial_value: None,
+        }).unwrap();
+
+        store.register_state(StateRegistration {
+            id: "state2".to_string(),
+            strategy: crate::types::StateStrategy::Snapshot,
+            initial_value: None,
+        }).unwrap();
+
+        // Add operations
+        for i in 1..=5 {
+            store.update_state("state1", StateOperation::Append(
+                serde_json::to_vec(&i).unwrap()
+            )).unwrap();
+        }
+
+        store.update_state("state2", StateOperation::Set(b"\"v1\"".to_vec())).unwrap();
+        store.update_state("state2", StateOperation::Set(b"\"v2\"".to_vec())).unwrap();
+
+        // Check summary
+        let summary = store.get_compaction_summary().unwrap();
+        assert_eq!(summary.total_operations, 7); // 5 appends + 2 sets
+        assert!(summary.compactable_operations > 0);
+
+        // Compact all
+        let compacted = store.compact_all_states().unwrap();
+        assert_eq!(compacted, 2);
 
-    fn test_config(dir: &TempDir) -> StoreConfig {
-        StoreConfig {
-            path: dir.path().join("store"),
-            blob_cache_size: 100,
-            create_if_missing: true,
-        }
+        // State values should be preserved
+        let state1 = store.get_state("state1").unwrap().unwrap();
+        let arr: Vec<i32> = serde_json::from_slice(&state1).unwrap();
+        assert_eq!(arr, vec![1, 2, 3, 4, 5]);
+
+        let state2 = store.get_state("state2").unwrap().unwrap();
+        assert_eq!(state2, b"\"v2\"");
     }
 
     #[test]
-    fn test_create_store() {
+    fn test_compact_all_states_truncating() {
         let dir = TempDir::new().unwrap();
         let store = Store::create(test_config(&dir)).unwrap();
 
-        assert!(store.path().join("MANIFEST").exists());
-        assert!(store.path().join("records.log").exists());
+        for i in 0..100 {
+            let id = format!("state{:03}", i);
+            store.register_state(StateRegistration {
+                id: id.clone(),
+                strategy: crate::types::StateStrategy::Snapshot,
+                initial_value: None,
+            }).unwrap();
+            for v in 0..3 {
+                store.update_state(&id, StateOperation::Set(format!("{}", v).into_bytes())).unwrap();
+            }
+        }
+
+        // Stop after the first ten states
+        let size_before = store.log.size();
+        let mut reports = Vec::new();
+        let partial = store
+            .compact_all_states_truncating(|progress| {
+                let stop = progress.state_index == 9;
+                reports.push(progress);
+                if stop { ControlFlow::Break(()) } else { ControlFlow::Continue(()) }
+            })
+            .unwrap();
+        assert_eq!(reports.len(), 10);
+        assert_eq!(partial.states_needing_compaction, 10);
+        assert_eq!(reports[0].state_id, "state000");
+        assert_eq!(reports[0].total_states, 100);
+        assert_eq!(reports[9].bytes_reclaimed, partial.compactable_bytes);
+        assert!(reports.windows(2).all(|w| w[0].bytes_reclaimed < w[1].bytes_reclaimed));
+        // Each state's three updates left the log behind its new snapshot
+        assert_eq!(partial.compactable_operations, 30);
+        assert!(store.log.size() < size_before);
+
+        // A second run resumes with the remaining states
+        let mut calls = 0;
+        let rest = store
+            .compact_all_states_truncating(|_| {
+                calls += 1;
+                ControlFlow::Continue(())
+            })
+            .unwrap();
+        assert_eq!(calls, 100);
+        assert_eq!(rest.states_needing_compaction, 90);
+        assert!(rest.compactable_bytes > partial.compactable_bytes);
+
+        let done = store
+            .compact_all_states_truncating(|_| ControlFlow::Continue(()))
+            .unwrap();
+        assert_eq!(done.states_needing_compaction, 0);
+        assert_eq!(done.compactable_bytes, 0);
+        assert_eq!(store.get_state("state042").unwrap().unwrap(), b"2");
+        assert_eq!(store.record_count(), 100);
+        drop(store);
+
+        // The smaller log reopens cleanly
+        let reopened = Store::open(test_config(&dir)).unwrap();
+        assert_eq!(reopened.get_state("state099").unwrap().unwrap(), b"2");
+        assert!(reopened.check_consistency().unwrap().is_ok());
     }
 
     #[test]
-    fn test_append_record() {
+    fn test_get_compaction_stats() {
         let dir = TempDir::new().unwrap();
         let store = Store::create(test_config(&dir)).unwrap();
 
-        let input = RecordInput::json("message", &json!({"text": "Hello"})).unwrap();
-        let record = store.append(input).unwrap();
+        store.register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: crate::types::StateStrategy::AppendLog {
+                delta_snapshot_every: 3,
+                full_snapshot_every: 2,
+            },
+            initial_value: None,
+        }).unwrap();
 
-        assert_eq!(record.sequence, Sequence(1));
-        assert_eq!(record.record_type, "message");
+        // No stats for state with no updates
+        assert!(store.get_compaction_stats("items").is_none());
+
+        // Add some operations - auto-snapshot kicks in at 3
+        for i in 1..=5 {
+            store.update_state("items", StateOperation::Append(
+                serde_json::to_vec(&i).unwrap()
+            )).unwrap();
+        }
+
+        // After 5 appends with delta_snapshot_every=3:
+        // - Appends 1,2,3 -> delta snapshot auto-created (ops=0, deltas=1)
+        // - Appends 4,5 -> ops=2
+        // So: ops_since_last_full = 2 ops + 1 delta = 3
+        let stats = store.get_compaction_stats("items").unwrap();
+        assert_eq!(stats.ops_since_last_full_snapshot, 3); // 2 ops + 1 delta
+        assert_eq!(stats.delta_snapshots_since_full, 1);
+        assert!(stats.last_full_snapshot_offset.is_none());
+        assert!(stats.last_delta_snapshot_offset.is_some());
     }
 
     #[test]
-    fn test_get_record() {
+    fn test_subscription_catch_up_records() {
+        use crate::subscriptions::{SubscriptionConfig, SubscriptionFilter, StoreEvent};
+        use std::time::Duration;
+
         let dir = TempDir::new().unwrap();
         let store = Store::create(test_config(&dir)).unwrap();
 
-        let input = RecordInput::json("message", &json!({"text": "Hello"})).unwrap();
-        let record = store.append(input).unwrap();
+        // Create some records first
+        for i in 1..=5 {
+            store
+                .append(RecordInput::json("message", &serde_json::json!({ "i": i })).unwrap())
+                .unwrap();
+        }
 
-        let retrieved = store.get_record(record.id).unwrap().unwrap();
-        assert_eq!(retrieved.id, record.id);
-        assert_eq!(retrieved.payload, record.payload);
-    }
+        // Subscribe from sequence 3
+        let config = SubscriptionConfig {
+            filter: SubscriptionFilter::records(),
+            from_sequence: Some(Sequence(3)),
+            ..Default::default()
+        };
+        let handle = store.subscribe(config);
 
-    #[test]
-    fn test_store_blob() {
-        let dir = TempDir::new().unwrap();
-        let store = Store::create(test_config(&dir)).unwrap();
+        // Perform catch-up
+        store.catch_up_subscription(handle.id).unwrap();
 
-        let content = b"function hello() { return 'world'; }";
-        let hash = store.store_blob(content, "application/javascript").unwrap();
+        // Should receive records 3, 4, 5 (sequences >= 3)
+        let mut received = Vec::new();
+        while let Ok(event) = handle.recv_timeout(Duration::from_millis(50)) {
+            match event {
+                StoreEvent::Record { record } => {
+                    received.push(record.sequence.0);
+                }
+                StoreEvent::CaughtUp => break,
+                _ => {}
+            }
+        }
 
-        let blob = store.get_blob(&hash).unwrap().unwrap();
-        assert_eq!(blob.content, content);
-        assert_eq!(blob.content_type, "application/javascript");
+        assert_eq!(received, vec![3, 4, 5]);
     }
 
     #[test]
-    fn test_state_operations() {
+    fn test_subscription_effects_of_record() {
+        use crate::subscriptions::{SubscriptionConfig, SubscriptionFilter, StoreEvent};
+        use std::time::Duration;
+
         let dir = TempDir::new().unwrap();
         let store = Store::create(test_config(&dir)).unwrap();
+        let event = |caused_by: Vec<RecordId>| {
+            RecordInput::raw("event", Vec::new()).with_caused_by(caused_by)
+        };
 
-        // Register state
-        store.register_state(StateRegistration {
-            id: "counter".to_string(),
-            strategy: crate::types::StateStrategy::Snapshot,
-            initial_value: None,
-        }).unwrap();
+        // root -> call -> result, plus an unrelated record
+        let root = store.append(event(vec![])).unwrap();
+        let call = store.append(event(vec![root.id])).unwrap();
+        store.append(event(vec![])).unwrap();
+        store.append(event(vec![call.id])).unwrap();
+        let retry = store.append(event(vec![root.id, call.id])).unwrap();
 
-        // Update state
-        store.update_state("counter", StateOperation::Set(b"42".to_vec())).unwrap();
+        let handle = store.subscribe(SubscriptionConfig {
+            filter: SubscriptionFilter::effects_of(vec![root.id]),
+            from_sequence: Some(Sequence(1)),
+            ..Default::default()
+        });
+        store.catch_up_subscription(handle.id).unwrap();
 
-        // Get state
-        let value = store.get_state("counter").unwrap().unwrap();
-        assert_eq!(value, b"42");
+        // Live: one more effect of the root and one of something else
+        let late = store.append(event(vec![root.id])).unwrap();
+        store.append(event(vec![call.id])).unwrap();
+
+        let mut received = Vec::new();
+        while let Ok(event) = handle.recv_timeout(Duration::from_millis(50)) {
+            if let StoreEvent::Record { record } = event {
+                received.push(RecordId(record.id));
+            }
+        }
+        assert_eq!(received, vec![call.id, retry.id, late.id]);
+
+        // Links filter the same way
+        let linked = store
+            .append(RecordInput::raw("note", Vec::new()).with_linked_to(vec![call.id]))
+            .unwrap();
+        let handle = store.subscribe(SubscriptionConfig {
+            filter: SubscriptionFilter {
+                linked_to: Some(vec![call.id]),
+                include_records: true,
+                ..Default::default()
+            },
+            from_sequence: Some(Sequence(1)),
+            ..Default::default()
+        });
+        store.catch_up_subscription(handle.id).unwrap();
+        let mut received = Vec::new();
+        while let Ok(event) = handle.recv_timeout(Duration::from_millis(50)) {
+            if let StoreEvent::Record { record } = event {
+                received.push(RecordId(record.id));
+            }
+        }
+        assert_eq!(received, vec![linked.id]);
     }
 
     #[test]
-    fn test_branch_operations() {
+    fn test_subscription_catch_up_other_branch() {
+        use crate::subscriptions::{SubscriptionConfig, SubscriptionFilter, StoreEvent};
+        use std::time::Duration;
+
         let dir = TempDir::new().unwrap();
         let store = Store::create(test_config(&dir)).unwrap();
+        let message = |text: &str| RecordInput::json("message", &serde_json::json!({ "text": text })).unwrap();
 
-        // Append to main
-        let input = RecordInput::json("message", &json!({"text": "main"})).unwrap();
-        store.append(input).unwrap();
-
-        // Create branch
+        store.append(message("shared")).unwrap();
         store.create_branch("feature", None).unwrap();
         store.switch_branch("feature").unwrap();
+        store.append(message("feature 1")).unwrap();
+        store.append(message("feature 2")).unwrap();
+        store.switch_branch("main").unwrap();
+        store.append(message("main")).unwrap();
 
-        // Append to feature
-        let input = RecordInput::json("message", &json!({"text": "feature"})).unwrap();
-        let record = store.append(input).unwrap();
-
-        assert_eq!(record.sequence, Sequence(2));
+        let config = |branch: &str| SubscriptionConfig {
+            filter: SubscriptionFilter::records(),
+            from_sequence: Some(Sequence(1)),
+            branch: Some(branch.to_string()),
+            ..Default::default()
+        };
+        let missing = store.subscribe(config("missing"));
+        assert!(matches!(
+            store.catch_up_subscription(missing.id),
+            Err(StoreError::BranchNotFound(_))
+        ));
+
+        // Only feature's records are replayed while main is current
+        let handle = store.subscribe(config("feature"));
+        store.catch_up_subscription(handle.id).unwrap();
+        let mut received = Vec::new();
+        while let Ok(event) = handle.recv_timeout(Duration::from_millis(50)) {
+            match event {
+                StoreEvent::Record { record } => received.push(record.payload.unwrap()),
+                StoreEvent::CaughtUp => break,
+                _ => {}
+            }
+        }
+        assert_eq!(
+            received,
+            vec![serde_json::json!({ "text": "feature 1" }), serde_json::json!({ "text": "feature 2" })]
+        );
 
-        // Check branch list
-        let branches = store.list_branches();
-        assert_eq!(branches.len(), 2);
+        // Live events follow the same branch
+        store.append(message("main again")).unwrap();
+        assert!(handle.recv_timeout(Duration::from_millis(50)).is_err());
+        store.switch_branch("feature").unwrap();
+        let appended = store.append(message("feature 3")).unwrap();
+        match handle.recv_timeout(Duration::from_millis(50)).unwrap() {
+            StoreEvent::Record { record } => assert_eq!(record.id, appended.id.0),
+            other => panic!("Expected Record, got {:?}", other),
+        }
     }
 
     #[test]
-    fn test_persistence() {
-        let dir = TempDir::new().unwrap();
-        let config = test_config(&dir);
-
-        // Create and write
-        {
-            let store = Store::create(config.clone()).unwrap();
-
-            let input = RecordInput::json("message", &json!({"text": "Hello"})).unwrap();
-            store.append(input).unwrap();
+    fn test_subscription_catch_up_state() {
+        use crate::subscriptions::{SubscriptionConfig, SubscriptionFilter, StoreEvent};
+        use std::time::Duration;
 
-            store.store_blob(b"content", "text/plain").unwrap();
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
 
-            store.register_state(StateRegistration {
-                id: "test".to_string(),
+        // Register and populate state
+        store
+            .register_state(StateRegistration {
+                id: "counter".to_string(),
                 strategy: crate::types::StateStrategy::Snapshot,
                 initial_value: None,
-            }).unwrap();
+            })
+            .unwrap();
 
-            store.update_state("test", StateOperation::Set(b"value".to_vec())).unwrap();
+        // Update state a few times
+        store
+            .update_state("counter", StateOperation::Set(b"10".to_vec()))
+            .unwrap();
+        let seq2 = store
+            .update_state("counter", StateOperation::Set(b"20".to_vec()))
+            .unwrap();
+        store
+            .update_state("counter", StateOperation::Set(b"30".to_vec()))
+            .unwrap();
 
-            store.create_branch("feature", None).unwrap();
+        // Subscribe to state from seq2
+        let config = SubscriptionConfig {
+            filter: SubscriptionFilter::states(vec!["counter".to_string()]),
+            from_sequence: Some(seq2.sequence),
+            ..Default::default()
+        };
+        let handle = store.subscribe(config);
 
-            store.sync().unwrap();
+        // Perform catch-up
+        store.catch_up_subscription(handle.id).unwrap();
+
+        // Should receive state snapshot at seq2 (value 20)
+        let event = handle.recv_timeout(Duration::from_millis(100)).unwrap();
+        match event {
+            StoreEvent::StateSnapshot { state_id, data, .. } => {
+                assert_eq!(state_id, "counter");
+                assert_eq!(data, serde_json::json!(20));
+            }
+            _ => panic!("Expected StateSnapshot, got {:?}", event),
         }
 
-        // Reopen and verify
-        {
-            let store = Store::open(config).unwrap();
+        // Then the later update as a delta
+        let event = handle.recv_timeout(Duration::from_millis(100)).unwrap();
+        match event {
+            StoreEvent::StateDelta { state_id, operation, .. } => {
+                assert_eq!(state_id, "counter");
+                assert!(matches!(operation, StateOperation::Set(ref v) if v == b"30"));
+            }
+            _ => panic!("Expected StateDelta, got {:?}", event),
+        }
 
-            assert_eq!(store.index.count(), 2); // message + state_update
+        // Then CaughtUp
+        let event = handle.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert!(matches!(event, StoreEvent::CaughtUp));
+    }
 
-            let branches = store.list_branches();
-            assert_eq!(branches.len(), 2);
+    #[test]
+    fn test_subscription_catch_up_no_from_sequence() {
+        use crate::subscriptions::{SubscriptionConfig, SubscriptionFilter, StoreEvent};
+        use std::time::Duration;
 
-            let state = store.get_state("test").unwrap().unwrap();
-            assert_eq!(state, b"value");
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+
+        // Create some records
+        for i in 1..=3 {
+            store
+                .append(RecordInput::json("message", &serde_json::json!({ "i": i })).unwrap())
+                .unwrap();
         }
-    }
 
-    #[test]
-    fn test_store_lock() {
-        let dir = TempDir::new().unwrap();
-        let config = test_config(&dir);
+        // Subscribe without from_sequence (live only)
+        let config = SubscriptionConfig {
+            filter: SubscriptionFilter::records(),
+            from_sequence: None,
+            ..Default::default()
+        };
+        let handle = store.subscribe(config);
 
-        let _store1 = Store::create(config.clone()).unwrap();
+        // Perform catch-up (should immediately mark as caught up)
+        store.catch_up_subscription(handle.id).unwrap();
 
-        // Second store should fail to acquire lock
-        let result = Store::open(config);
-        assert!(matches!(result, Err(StoreError::Locked)));
+        // Should receive CaughtUp immediately, no historical records
+        let event = handle.recv_timeout(Duration::from_millis(100)).unwrap();
+        assert!(matches!(event, StoreEvent::CaughtUp));
+
+        // No more events
+        let result = handle.recv_timeout(Duration::from_millis(50));
+        assert!(result.is_err());
     }
 
     #[test]
-    fn test_stats() {
+    fn test_subscription_branch_switched() {
+        use crate::subscriptions::{SubscriptionConfig, SubscriptionFilter, StoreEvent};
+        use std::time::Duration;
+
         let dir = TempDir::new().unwrap();
         let store = Store::create(test_config(&dir)).unwrap();
+        store.create_branch("feature", None).unwrap();
 
-        let input = RecordInput::json("message", &json!({"text": "Hello"})).unwrap();
-        store.append(input).unwrap();
+        let branches = store.subscribe(SubscriptionConfig {
+            filter: SubscriptionFilter::branches(),
+            ..Default::default()
+        });
+        let records = store.subscribe(SubscriptionConfig {
+            filter: SubscriptionFilter::records(),
+            ..Default::default()
+        });
+        store.mark_subscription_caught_up(branches.id).unwrap();
+        store.mark_subscription_caught_up(records.id).unwrap();
+        assert!(matches!(branches.recv_timeout(Duration::from_millis(50)), Ok(StoreEvent::CaughtUp)));
+        assert!(matches!(records.recv_timeout(Duration::from_millis(50)), Ok(StoreEvent::CaughtUp)));
 
-        store.store_blob(b"content", "text/plain").unwrap();
+        store.switch_branch("feature").unwrap();
 
-        let stats = store.stats().unwrap();
-        assert_eq!(stats.record_count, 1);
-        assert_eq!(stats.blob_count, 1);
-        assert_eq!(stats.branch_count, 1);
+        match branches.recv_timeout(Duration::from_millis(50)).unwrap() {
+            StoreEvent::BranchSwitched { from, to } => {
+                assert_eq!(from, "main");
+                assert_eq!(to, "feature");
+            }
+            other => panic!("Expected BranchSwitched, got {:?}", other),
+        }
+        assert!(records.recv_timeout(Duration::from_millis(50)).is_err());
     }
 
     #[test]
-    fn test_get_state_len() {
+    fn test_subscribe_blocking_iter() {
+        use crate::subscriptions::{SubscriptionConfig, SubscriptionFilter, StoreEvent};
+
         let dir = TempDir::new().unwrap();
         let store = Store::create(test_config(&dir)).unwrap();
 
-        // Nonexistent state
-        assert_eq!(store.get_state_len("messages").unwrap(), None);
-
-        // Register state
-        store.register_state(StateRegistration {
-            id: "messages".to_string(),
-            strategy: crate::types::StateStrategy::AppendLog {
-                delta_snapshot_every: 100,
-                full_snapshot_every: 10,
-            },
-            initial_value: None,
-        }).unwrap();
+        for i in 1..=3 {
+            store
+                .append(RecordInput::json("message", &serde_json::json!({ "i": i })).unwrap())
+                .unwrap();
+        }
+        store.append(RecordInput::raw("other", b"skip".to_vec())).unwrap();
 
-        // Empty state
-        assert_eq!(store.get_state_len("messages").unwrap(), None);
+        let events = store
+            .subscribe_blocking_iter(SubscriptionConfig {
+                filter: SubscriptionFilter::record_types(vec!["message".to_string()]),
+                from_sequence: Some(Sequence(1)),
+                ..Default::default()
+            })
+            .unwrap();
 
-        // Add items
-        for i in 1..=5 {
-            store.update_state("messages", StateOperation::Append(
-                serde_json::to_vec(&json!({"id": i})).unwrap()
-            )).unwrap();
+        let mut received = Vec::new();
+        for event in events {
+            match event {
+                StoreEvent::Record { record } => received.push(record.sequence.0),
+                StoreEvent::CaughtUp => break,
+                other => panic!("Unexpected event {:?}", other),
+            }
         }
-
-        assert_eq!(store.get_state_len("messages").unwrap(), Some(5));
+        assert_eq!(received, vec![1, 2, 3]);
+
+        // Ends after the Dropped event
+        let handle = store.subscribe(SubscriptionConfig::default());
+        store.unsubscribe(handle.id);
+        let rest: Vec<_> = handle.iter().collect();
+        assert_eq!(rest.len(), 1);
+        assert!(matches!(rest[0], StoreEvent::Dropped { .. }));
     }
 
     #[test]
-    fn test_get_state_slice() {
+    fn test_subscribe_coalesce_catchup_state() {
+        use crate::subscriptions::{SubscriptionConfig, SubscriptionFilter, StoreEvent};
+
         let dir = TempDir::new().unwrap();
         let store = Store::create(test_config(&dir)).unwrap();
 
-        store.register_state(StateRegistration {
-            id: "items".to_string(),
-            strategy: crate::types::StateStrategy::AppendLog {
-                delta_snapshot_every: 100,
-                full_snapshot_every: 10,
-            },
-            initial_value: None,
-        }).unwrap();
-
-        // Add items
-        for i in 1..=10 {
-            store.update_state("items", StateOperation::Append(
-                serde_json::to_vec(&i).unwrap()
-            )).unwrap();
+        store
+            .register_state(StateRegistration {
+                id: "log".to_string(),
+                strategy: crate::types::StateStrategy::AppendLog {
+                    delta_snapshot_every: 100,
+                    full_snapshot_every: 10,
+                },
+                initial_value: None,
+            })
+            .unwrap();
+        for i in 0..1000 {
+            store
+                .update_state("log", StateOperation::Append(format!("{}", i).into_bytes()))
+                .unwrap();
         }
 
-        // Get middle slice
-        let slice = store.get_state_slice("items", 3, 4).unwrap().unwrap();
-        let arr: Vec<i32> = serde_json::from_slice(&slice).unwrap();
-        assert_eq!(arr, vec![4, 5, 6, 7]);
-
-        // Get first slice
-        let slice = store.get_state_slice("items", 0, 3).unwrap().unwrap();
-        let arr: Vec<i32> = serde_json::from_slice(&slice).unwrap();
-        assert_eq!(arr, vec![1, 2, 3]);
-
-        // Get end slice
-        let slice = store.get_state_slice("items", 8, 10).unwrap().unwrap();
-        let arr: Vec<i32> = serde_json::from_slice(&slice).unwrap();
-        assert_eq!(arr, vec![9, 10]);
+        let catch_up = |coalesce: bool| {
+            let iter = store
+                .subscribe_blocking_iter(SubscriptionConfig {
+                    filter: SubscriptionFilter::states(vec!["log".to_string()]),
+                    from_sequence: Some(Sequence(1)),
+                    buffer_size: 2000,
+                    coalesce_catchup_state: coalesce,
+                    ..Default::default()
+                })
+                .unwrap();
+            let mut events = Vec::new();
+            for event in iter {
+                if matches!(event, StoreEvent::CaughtUp) {
+                    break;
+                }
+                events.push(event);
+            }
+            events
+        };
 
-        // Out of bounds
-        let slice = store.get_state_slice("items", 15, 5).unwrap().unwrap();
-        let arr: Vec<i32> = serde_json::from_slice(&slice).unwrap();
-        assert_eq!(arr, Vec::<i32>::new());
+        // Default: snapshot at sequence 1, then every later delta
+        let replayed = catch_up(false);
+        assert!(matches!(replayed[0], StoreEvent::StateSnapshot { .. }));
+        let appends = replayed
+            .iter()
+            .filter(|e| {
+                matches!(
+                    e,
+                    StoreEvent::StateDelta { operation: StateOperation::Append(_), .. }
+                )
+            })
+            .count();
+        assert_eq!(appends, 999);
+
+        // Coalesced: a single snapshot of the current value
+        let coalesced = catch_up(true);
+        assert_eq!(coalesced.len(), 1);
+        match &coalesced[0] {
+            StoreEvent::StateSnapshot { sequence, .. } => {
+                assert_eq!(*sequence, store.current_branch().head)
+            }
+            other => panic!("Unexpected event {:?}", other),
+        }
     }
 
     #[test]
-    fn test_get_state_tail() {
+    fn test_watch_state() {
         let dir = TempDir::new().unwrap();
         let store = Store::create(test_config(&dir)).unwrap();
+        store
+            .register_state(StateRegistration {
+                id: "log".to_string(),
+                strategy: crate::types::StateStrategy::AppendLog {
+                    delta_snapshot_every: 2,
+                    full_snapshot_every: 2,
+                },
+                initial_value: None,
+            })
+            .unwrap();
+        store.update_state("log", StateOperation::Append(b"0".to_vec())).unwrap();
 
-        store.register_state(StateRegistration {
-            id: "items".to_string(),
-            strategy: crate::types::StateStrategy::AppendLog {
-                delta_snapshot_every: 100,
-                full_snapshot_every: 10,
-            },
-            initial_value: None,
-        }).unwrap();
+        let mut watcher = store.watch_state("log").unwrap();
+        assert_eq!(watcher.value(), Some(&b"[0]"[..]));
+        let timeout = Duration::from_secs(5);
 
-        // Add items
-        for i in 1..=10 {
-            store.update_state("items", StateOperation::Append(
-                serde_json::to_vec(&i).unwrap()
-            )).unwrap();
+        // One value per change; auto-snapshots in between change nothing
+        for i in 1..=4 {
+            store
+                .update_state("log", StateOperation::Append(i.to_string().into_bytes()))
+                .unwrap();
+            let value = watcher.recv_timeout(timeout).unwrap().unwrap();
+            let expected: Vec<i32> = (0..=i).collect();
+            assert_eq!(value, serde_json::to_vec(&expected).unwrap());
+            assert_eq!(Some(value), store.get_state("log").unwrap());
         }
 
-        // Get last 3
-        let tail = store.get_state_tail("items", 3).unwrap().unwrap();
-        let arr: Vec<i32> = serde_json::from_slice(&tail).unwrap();
-        assert_eq!(arr, vec![8, 9, 10]);
-
-        // Get last 1
-        let tail = store.get_state_tail("items", 1).unwrap().unwrap();
-        let arr: Vec<i32> = serde_json::from_slice(&tail).unwrap();
-        assert_eq!(arr, vec![10]);
-
-        // Get more than exists
-        let tail = store.get_state_tail("items", 20).unwrap().unwrap();
-        let arr: Vec<i32> = serde_json::from_slice(&tail).unwrap();
-        assert_eq!(arr, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        // Updates made before the next read are coalesced into one value
+        for i in 5..=7 {
+            store
+                .update_state("log", StateOperation::Append(i.to_string().into_bytes()))
+                .unwrap();
+        }
+        store.update_state("log", StateOperation::Redact { start: 0, end: 2 }).unwrap();
+        assert_eq!(watcher.recv_timeout(timeout).unwrap().unwrap(), b"[2,3,4,5,6,7]");
+        assert_eq!(watcher.recv_timeout(Duration::from_millis(50)).unwrap(), None);
+
+        // Changes on other branches aren't followed
+        store.create_branch("side", None).unwrap();
+        store.switch_branch("side").unwrap();
+        store.update_state("log", StateOperation::Clear).unwrap();
+        assert_eq!(watcher.recv_timeout(Duration::from_millis(50)).unwrap(), None);
+        assert_eq!(watcher.value(), Some(&b"[2,3,4,5,6,7]"[..]));
+
+        assert!(matches!(store.watch_state("missing"), Err(StoreError::StateNotRegistered(_))));
+    }
 
-        // Get zero
-        let tail = store.get_state_tail("items", 0).unwrap().unwrap();
-        let arr: Vec<i32> = serde_json::from_slice(&tail).unwrap();
-        assert_eq!(arr, Vec::<i32>::new());
+    #[test]
+    fn test_sync_skips_unchanged_metadata() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+        store
+            .register_state(StateRegistration {
+                id: "counter".to_string(),
+                strategy: crate::types::StateStrategy::Snapshot,
+                initial_value: Some(b"0".to_vec()),
+            })
+            .unwrap();
+        store.sync().unwrap();
+        assert!(!store.state.is_dirty());
+        assert!(!store.branches.is_dirty());
+
+        let modified = |name: &str| fs::metadata(dir.path().join("store").join(name)).unwrap().modified().unwrap();
+        let before = (modified("state.bin"), modified("branches.bin"));
+
+        // Reads change nothing, so nothing is rewritten
+        std::thread::sleep(Duration::from_millis(20));
+        store.get_state("counter").unwrap();
+        store.list_branches();
+        store.sync().unwrap();
+        assert_eq!((modified("state.bin"), modified("branches.bin")), before);
+
+        // A state update moves both the state head and the branch head
+        store.update_state("counter", StateOperation::Set(b"1".to_vec())).unwrap();
+        assert!(store.state.is_dirty() && store.branches.is_dirty());
+        store.sync().unwrap();
+        assert_ne!(modified("state.bin"), before.0);
+        assert_ne!(modified("branches.bin"), before.1);
+
+        // Switching branches is saved too
+        store.create_branch("side", None).unwrap();
+        store.sync().unwrap();
+        store.switch_branch("side").unwrap();
+        assert!(store.branches.is_dirty());
+        store.sync().unwrap();
+        drop(store);
+        let store = Store::open(test_config(&dir)).unwrap();
+        assert_eq!(store.current_branch().name, "side");
+        assert!(!store.state.is_dirty());
     }
 
     #[test]
-    fn test_iter_state_items() {
+    fn test_lazy_index() {
         let dir = TempDir::new().unwrap();
         let store = Store::create(test_config(&dir)).unwrap();
+        let mut ids = Vec::new();
+        for i in 0..2000u32 {
+            ids.push(store.append(RecordInput::raw("event", i.to_le_bytes().to_vec())).unwrap().id);
+        }
+        let hash = store.store_blob(b"payload", "text/plain").unwrap();
+        drop(store);
 
-        store.register_state(StateRegistration {
-            id: "items".to_string(),
-            strategy: crate::types::StateStrategy::AppendLog {
-                delta_snapshot_every: 100,
-                full_snapshot_every: 10,
-            },
-            initial_value: None,
-        }).unwrap();
+        let store = Store::open(StoreConfig {
+            lazy_index: true,
+            ..test_config(&dir)
+        })
+        .unwrap();
+        assert_eq!(store.get_blob(&hash).unwrap().unwrap().content, b"payload");
+        assert!(!store.index_built.load(Ordering::Acquire));
+
+        // The first record read builds it
+        let record = store.get_record(ids[1234]).unwrap().unwrap();
+        assert_eq!(record.payload, 1234u32.to_le_bytes());
+        assert!(store.index_built.load(Ordering::Acquire));
+        assert_eq!(store.count_records_by_type("event"), 2000);
+
+        let next = store.append(RecordInput::raw("event", vec![])).unwrap();
+        assert_eq!(store.get_record(next.id).unwrap().unwrap().id, next.id);
+    }
 
-        // Add items
-        for i in 1..=5 {
-            store.update_state("items", StateOperation::Append(
-                serde_json::to_vec(&i).unwrap()
-            )).unwrap();
-        }
+    #[test]
+    fn test_sync_policy_crash_window() {
+        // Simulate a machine crash by keeping only the synced prefix of the log
+        let surviving = |policy: SyncPolicy| {
+            let dir = TempDir::new().unwrap();
+            let store = Store::create(StoreConfig {
+                sync_policy: policy,
+                ..test_config(&dir)
+            })
+            .unwrap();
+            for i in 0..50 {
+                store.append(RecordInput::raw("event", vec![i])).unwrap();
+            }
 
-        // Iterate
-        let iter = store.iter_state_items("items").unwrap().unwrap();
-        let collected: Vec<i32> = iter
-            .map(|r| serde_json::from_value(r.unwrap()).unwrap())
-            .collect();
+            let crashed = dir.path().join("crashed.log");
+            fs::copy(dir.path().join("store/records.log"), &crashed).unwrap();
+            let file = fs::OpenOptions::new().write(true).open(&crashed).unwrap();
+            file.set_len(store.log.synced_size()).unwrap();
 
-        assert_eq!(collected, vec![1, 2, 3, 4, 5]);
+            RecordLog::open_read_only(&crashed).unwrap().iter().count()
+        };
+
+        assert_eq!(surviving(SyncPolicy::EveryWrite), 50);
+        assert_eq!(surviving(SyncPolicy::EveryN(20)), 40);
+        assert_eq!(surviving(SyncPolicy::EveryN(1000)), 0);
     }
 
     #[test]
-    fn test_lazy_loading_with_snapshots() {
+    fn test_close() {
         let dir = TempDir::new().unwrap();
         let store = Store::create(test_config(&dir)).unwrap();
+        store.append(RecordInput::raw("event", vec![1])).unwrap();
+        store.close().unwrap();
 
-        store.register_state(StateRegistration {
-            id: "items".to_string(),
-            strategy: crate::types::StateStrategy::AppendLog {
-                delta_snapshot_every: 3,
-                full_snapshot_every: 2,
-            },
-            initial_value: None,
-        }).unwrap();
+        // The lock is released and the record is there
+        let store = Store::open(test_config(&dir)).unwrap();
+        assert_eq!(store.record_count(), 1);
 
-        // Add items - this will trigger snapshots
-        for i in 1..=10 {
-            store.update_state("items", StateOperation::Append(
-                serde_json::to_vec(&i).unwrap()
-            )).unwrap();
-            // Create snapshot if needed
-            store.create_snapshot_if_needed("items").unwrap();
-        }
+        // A state index that can't be written fails the close; dropping
+        // would have swallowed this. A directory in its place fails even
+        // for root, unlike a read-only permission. The state index is only
+        // rewritten when it changed, so change it.
+        store
+            .register_state(StateRegistration {
+                id: "counter".to_string(),
+                strategy: crate::types::StateStrategy::Snapshot,
+                initial_value: None,
+            })
+            .unwrap();
+        let state_path = dir.path().join("store/state.bin");
+        fs::remove_file(&state_path).unwrap();
+        fs::create_dir(&state_path).unwrap();
+        assert!(matches!(store.close(), Err(StoreError::Io(_))));
+        fs::remove_dir(&state_path).unwrap();
+        assert!(Store::open(test_config(&dir)).is_ok());
+    }
 
-        // Verify tail still works with snapshots
-        let tail = store.get_state_tail("items", 3).unwrap().unwrap();
-        let arr: Vec<i32> = serde_json::from_slice(&tail).unwrap();
-        assert_eq!(arr, vec![8, 9, 10]);
+    #[test]
+    fn test_sync_worker_bounds_crash_window() {
+        let dir = TempDir::new().unwrap();
+        let interval = Duration::from_millis(20);
+        let store = Store::create(StoreConfig {
+            sync_policy: SyncPolicy::Interval(interval),
+            ..test_config(&dir)
+        })
+        .unwrap();
 
-        // Verify iteration still works
-        let iter = store.iter_state_items("items").unwrap().unwrap();
-        let collected: Vec<i32> = iter
-            .map(|r| serde_json::from_value(r.unwrap()).unwrap())
-            .collect();
-        assert_eq!(collected, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        // Write continuously for a while, then go idle past the window
+        let start = Instant::now();
+        let mut written = 0u64;
+        while start.elapsed() < Duration::from_millis(200) {
+            store
+                .append(RecordInput::raw("event", written.to_le_bytes().to_vec()))
+                .unwrap();
+            written += 1;
+        }
+        std::thread::sleep(interval * 5);
+
+        // Simulate a crash: copy what's on disk, dropping the unsynced log tail
+        let crashed = dir.path().join("crashed");
+        copy_dir(&dir.path().join("store"), &crashed);
+        fs::remove_file(crashed.join("LOCK")).ok();
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(crashed.join("records.log"))
+            .unwrap();
+        file.set_len(store.log.synced_size()).unwrap();
+        drop(file);
+
+        let reopened = Store::open(StoreConfig {
+            path: crashed,
+            ..test_config(&dir)
+        })
+        .unwrap();
+        assert_eq!(reopened.current_branch().head, Sequence(written));
+        let last = reopened.query_range(None, None, 1, true, None).unwrap();
+        assert_eq!(last[0].sequence, Sequence(written));
     }
 
     #[test]
-    fn test_compact_state() {
+    fn test_wal_replays_unsynced_branch_changes() {
         let dir = TempDir::new().unwrap();
         let store = Store::create(test_config(&dir)).unwrap();
-
         store.register_state(StateRegistration {
-            id: "items".to_string(),
-            strategy: crate::types::StateStrategy::AppendLog {
-                delta_snapshot_every: 100,
-                full_snapshot_every: 100,
-            },
-            initial_value: None,
+            id: "counter".to_string(),
+            strategy: crate::types::StateStrategy::Snapshot,
+            initial_value: Some(b"1".to_vec()),
         }).unwrap();
+        store.sync().unwrap();
 
-        // Add items without auto-snapshot
-        for i in 1..=10 {
-            store.update_state("items", StateOperation::Append(
-                serde_json::to_vec(&i).unwrap()
-            )).unwrap();
-        }
-
-        // Check chain stats before compaction
-        let stats_before = store.get_chain_stats("items").unwrap().unwrap();
-        assert_eq!(stats_before.total_operations, 10);
-        assert!(!stats_before.has_full_snapshot);
-
-        // Compact
-        let record = store.compact_state("items").unwrap().unwrap();
-        assert_eq!(record.record_type, "state_update");
-
-        // Check chain stats after compaction
-        let stats_after = store.get_chain_stats("items").unwrap().unwrap();
-        assert_eq!(stats_after.total_operations, 11); // 10 appends + 1 snapshot
-        assert!(stats_after.has_full_snapshot);
-        assert_eq!(stats_after.operations_before_snapshot, 10); // All old ops before snapshot
-
-        // State should still be correct
-        let state = store.get_state("items").unwrap().unwrap();
-        let arr: Vec<i32> = serde_json::from_slice(&state).unwrap();
-        assert_eq!(arr, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        // Journaled but not yet persisted to the branch index
+        store.create_branch("feature", None).unwrap();
+        store.switch_branch("feature").unwrap();
+        let hash = store.store_blob(b"attachment", "text/plain").unwrap();
+        // Failed operations are rolled back, not replayed
+        assert!(store.create_branch("feature", Some("missing")).is_err());
+
+        // Crash: the branch index on disk predates the new branch
+        let crashed = dir.path().join("crashed");
+        copy_dir(&dir.path().join("store"), &crashed);
+        fs::remove_file(crashed.join("LOCK")).ok();
+        let stale = BranchManager::load(crashed.join("branches.bin")).unwrap();
+        assert!(stale.get_branch("feature").is_none());
+
+        let reopened = Store::open(StoreConfig {
+            path: crashed.clone(),
+            ..test_config(&dir)
+        })
+        .unwrap();
+        let feature = reopened.branches.get_branch("feature").unwrap();
+        assert_eq!(feature.branch_point, Some(Sequence(1)));
+        assert_eq!(reopened.current_branch().name, "feature");
+        // State heads are copied from the parent, as at creation
+        assert_eq!(reopened.get_state("counter").unwrap().unwrap(), b"1");
+        assert_eq!(reopened.list_blobs_by_type("text/plain").unwrap(), vec![hash]);
+        assert!(!reopened.wal.as_ref().unwrap().has_pending().unwrap());
+        drop(reopened);
+
+        // Recovery persisted what it replayed
+        let recovered = BranchManager::load(crashed.join("branches.bin")).unwrap();
+        assert!(recovered.get_branch("feature").is_some());
+
+        // A clean shutdown leaves nothing to replay
+        drop(store);
+        let wal = WriteAheadLog::open(dir.path().join("store").join(WAL_FILE)).unwrap();
+        assert!(!wal.has_pending().unwrap());
     }
 
     #[test]
-    fn test_compaction_summary() {
+    fn test_wal_entry_survives_failed_replay() {
         let dir = TempDir::new().unwrap();
-        let store = Store::create(test_config(&dir)).unwrap();
+        let path = dir.path().join("store");
+        drop(Store::create(test_config(&dir)).unwrap());
+
+        // Committed before a crash: a branch whose parent is gone, and a blob
+        let wal = WriteAheadLog::open(path.join(WAL_FILE)).unwrap();
+        let orphan = wal
+            .log(WalOperation::CreateBranch {
+                name: "orphan".to_string(),
+                from: Some("missing".to_string()),
+                at: None,
+                empty: false,
+            })
+            .unwrap();
+        wal.commit(orphan).unwrap();
+        let content = b"attachment".to_vec();
+        let blob = wal
+            .log(WalOperation::StoreBlob {
+                content: content.clone(),
+                content_type: "text/plain".to_string(),
+                chunked: false,
+            })
+            .unwrap();
+        wal.commit(blob).unwrap();
+        drop(wal);
+
+        // The blob's shard directory can't be created
+        let hash = test_config(&dir).blob_hash.digest(&content);
+        let blocker = path.join("blobs").join(hash.shard_prefix());
+        fs::write(&blocker, b"").unwrap();
+        assert!(Store::open(test_config(&dir)).is_err());
+        let wal = WriteAheadLog::open(path.join(WAL_FILE)).unwrap();
+        let unfinished: Vec<u64> = wal.get_unfinished_entries().unwrap().iter().map(|e| e.seq).collect();
+        // The branch can never apply and is rolled back; the blob is kept
+        assert_eq!(unfinished, vec![blob]);
+        drop(wal);
+
+        // Once the failure clears, the next open replays it
+        fs::remove_file(&blocker).unwrap();
+        let reopened = Store::open(test_config(&dir)).unwrap();
+        assert_eq!(reopened.get_blob(&hash).unwrap().unwrap().content, content);
+        assert!(reopened.branches.get_branch("orphan").is_none());
+        assert!(reopened.wal.as_ref().unwrap().get_unfinished_entries().unwrap().is_empty());
+    }
 
-        // Create two states
+    #[test]
+    fn test_wal_replays_unsynced_state_update() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(StoreConfig {
+            sync_policy: SyncPolicy::EveryN(1000),
+            ..test_config(&dir)
+        })
+        .unwrap();
         store.register_state(StateRegistration {
-            id: "state1".to_string(),
-            strategy: crate::types::StateStrategy::AppendLog {
-                delta_snapshot_every: 100,
-                full_snapshot_every: 100,
-            },
+            id: "counter".to_string(),
+            strategy: crate::types::StateStrategy::Snapshot,
             initial_value: None,
         }).unwrap();
+        store.update_state("counter", StateOperation::Set(b"1".to_vec())).unwrap();
+        store.sync().unwrap();
+        let synced = store.log.synced_size();
+
+        // Committed in the journal, but the log isn't synced yet
+        let update = store.update_state("counter", StateOperation::Set(b"2".to_vec())).unwrap();
+        let event = store.append(RecordInput::raw("event", vec![7])).unwrap();
+        let batch = store
+            .update_state_batch(
+                "counter",
+                vec![StateOperation::Set(b"3".to_vec()), StateOperation::Set(b"4".to_vec())],
+            )
+            .unwrap();
+        assert_eq!(store.log.synced_size(), synced);
+
+        // Crash: the unsynced log tail and the state and branch indices are
+        // lost, the journal isn't
+        let crashed = dir.path().join("crashed");
+        copy_dir(&dir.path().join("store"), &crashed);
+        fs::remove_file(crashed.join("LOCK")).ok();
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .open(crashed.join("records.log"))
+            .unwrap();
+        file.set_len(synced).unwrap();
+        drop(file);
+        // An append interrupted before it finished
+        let wal = WriteAheadLog::open(crashed.join(WAL_FILE)).unwrap();
+        assert!(wal.get_pending_entries().unwrap().is_empty());
+        wal.log(WalOperation::AppendRecord {
+            record_type: "event".to_string(),
+            payload: vec![8],
+            record: Some(JournaledRecord {
+                branch: MAIN_BRANCH.to_string(),
+                sequence: batch[1].sequence.next().0,
+                encoding: PayloadEncoding::Raw,
+                caused_by: Vec::new(),
+                linked_to: Vec::new(),
+                expires_at: None,
+                lamport: None,
+                idempotency_key: None,
+                schema: None,
+                squash: false,
+            }),
+        })
+        .unwrap();
+        drop(wal);
+
+        let reopened = Store::open(StoreConfig {
+            path: crashed.clone(),
+            ..test_config(&dir)
+        })
+        .unwrap();
+        assert_eq!(reopened.get_state("counter").unwrap().unwrap(), b"4");
+        assert_eq!(reopened.get_state_at("counter", update.sequence).unwrap().unwrap(), b"2");
+        assert_eq!(reopened.get_state_at("counter", batch[0].sequence).unwrap().unwrap(), b"3");
+        // Replayed in order, so the record keeps its ID; the interrupted
+        // append is discarded
+        assert_eq!(reopened.current_branch().head, batch[1].sequence);
+        assert_eq!(reopened.get_record(event.id).unwrap().unwrap().payload, vec![7]);
+        assert_eq!(reopened.get_record(batch[1].id).unwrap().unwrap().sequence, batch[1].sequence);
+        assert!(reopened.wal.as_ref().unwrap().get_unfinished_entries().unwrap().is_empty());
+
+        // Later updates chain onto the replayed ones, and it's all persisted
+        reopened.update_state("counter", StateOperation::Set(b"5".to_vec())).unwrap();
+        drop(reopened);
+        let reopened = Store::open(StoreConfig {
+            path: crashed,
+            ..test_config(&dir)
+        })
+        .unwrap();
+        assert_eq!(reopened.get_state("counter").unwrap().unwrap(), b"5");
+        assert_eq!(reopened.record_count(), 6);
+
+        // If the log did reach disk, the stale heads are brought up to it
+        let flushed = dir.path().join("flushed");
+        copy_dir(&dir.path().join("store"), &flushed);
+        fs::remove_file(flushed.join("LOCK")).ok();
+        drop(store);
+        let reopened = Store::open(StoreConfig {
+            path: flushed,
+            ..test_config(&dir)
+        })
+        .unwrap();
+        assert_eq!(reopened.get_state("counter").unwrap().unwrap(), b"4");
+        assert_eq!(reopened.current_branch().head, batch[1].sequence);
+        assert_eq!(reopened.record_count(), 5);
+        reopened.append(RecordInput::raw("event", vec![9])).unwrap();
+    }
 
+    #[test]
+    fn test_wal_replays_unsynced_truncate() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(StoreConfig {
+            sync_policy: SyncPolicy::EveryN(1000),
+            ..test_config(&dir)
+        })
+        .unwrap();
         store.register_state(StateRegistration {
-            id: "state2".to_string(),
+            id: "counter".to_string(),
             strategy: crate::types::StateStrategy::Snapshot,
             initial_value: None,
         }).unwrap();
-
-        // Add operations
-        for i in 1..=5 {
-            store.update_state("state1", StateOperation::Append(
-                serde_json::to_vec(&i).unwrap()
-            )).unwrap();
+        store.update_state("counter", StateOperation::Set(b"1".to_vec())).unwrap();
+        let kept = store.update_state("counter", StateOperation::Set(b"2".to_vec())).unwrap();
+        store.update_state("counter", StateOperation::Set(b"3".to_vec())).unwrap();
+        store.append(RecordInput::raw("event", vec![7])).unwrap();
+        store.sync().unwrap();
+        let path = dir.path().join("store");
+        let indices = ["state.bin", "branches.bin"].map(|file| (file, fs::read(path.join(file)).unwrap()));
+        store.truncate_branch(MAIN_BRANCH, kept.sequence).unwrap();
+
+        // Crash: the log was rewritten, but the indices on disk still hold
+        // offsets into the old one
+        let crashed = dir.path().join("crashed");
+        copy_dir(&path, &crashed);
+        fs::remove_file(crashed.join("LOCK")).ok();
+        for (file, data) in indices {
+            fs::write(crashed.join(file), data).unwrap();
         }
+        drop(store);
 
-        store.update_state("state2", StateOperation::Set(b"\"v1\"".to_vec())).unwrap();
-        store.update_state("state2", StateOperation::Set(b"\"v2\"".to_vec())).unwrap();
-
-        // Check summary
-        let summary = store.get_compaction_summary().unwrap();
-        assert_eq!(summary.total_operations, 7); // 5 appends + 2 sets
-        assert!(summary.compactable_operations > 0);
-
-        // Compact all
-        let compacted = store.compact_all_states().unwrap();
-        assert_eq!(compacted, 2);
-
-        // State values should be preserved
-        let state1 = store.get_state("state1").unwrap().unwrap();
-        let arr: Vec<i32> = serde_json::from_slice(&state1).unwrap();
-        assert_eq!(arr, vec![1, 2, 3, 4, 5]);
-
-        let state2 = store.get_state("state2").unwrap().unwrap();
-        assert_eq!(state2, b"\"v2\"");
+        let reopened = Store::open(StoreConfig {
+            path: crashed,
+            ..test_config(&dir)
+        })
+        .unwrap();
+        assert_eq!(reopened.get_state("counter").unwrap().unwrap(), b"2");
+        assert_eq!(reopened.current_branch().head, kept.sequence);
+        assert!(reopened.wal.as_ref().unwrap().get_unfinished_entries().unwrap().is_empty());
+        reopened.update_state("counter", StateOperation::Set(b"4".to_vec())).unwrap();
+        assert_eq!(reopened.get_state("counter").unwrap().unwrap(), b"4");
     }
 
     #[test]
-    fn test_get_compaction_stats() {
+    fn test_sync_checkpoints_wal() {
         let dir = TempDir::new().unwrap();
         let store = Store::create(test_config(&dir)).unwrap();
+        let wal = store.wal.as_ref().unwrap();
 
-        store.register_state(StateRegistration {
-            id: "items".to_string(),
-            strategy: crate::types::StateStrategy::AppendLog {
-                delta_snapshot_every: 3,
-                full_snapshot_every: 2,
-            },
-            initial_value: None,
-        }).unwrap();
-
-        // No stats for state with no updates
-        assert!(store.get_compaction_stats("items").is_none());
-
-        // Add some operations - auto-snapshot kicks in at 3
-        for i in 1..=5 {
-            store.update_state("items", StateOperation::Append(
-                serde_json::to_vec(&i).unwrap()
-            )).unwrap();
+        for i in 0..50 {
+            store.store_blob(format!("blob {}", i).as_bytes(), "text/plain").unwrap();
+            store.create_branch(&format!("branch{}", i), Some("main")).unwrap();
         }
-
-        // After 5 appends with delta_snapshot_every=3:
-        // - Appends 1,2,3 -> delta snapshot auto-created (ops=0, deltas=1)
-        // - Appends 4,5 -> ops=2
-        // So: ops_since_last_full = 2 ops + 1 delta = 3
-        let stats = store.get_compaction_stats("items").unwrap();
-        assert_eq!(stats.ops_since_last_full_snapshot, 3); // 2 ops + 1 delta
-        assert_eq!(stats.delta_snapshots_since_full, 1);
-        assert!(stats.last_full_snapshot_offset.is_none());
-        assert!(stats.last_delta_snapshot_offset.is_some());
+        let grown = wal.size().unwrap();
+        assert!(grown > 50 * 100);
+
+        store.sync().unwrap();
+        let checkpointed = wal.size().unwrap();
+        assert!(checkpointed < 16);
+
+        // Work after the checkpoint is still journaled and recoverable
+        store.create_branch("late", Some("main")).unwrap();
+        let crashed = dir.path().join("crashed");
+        copy_dir(&dir.path().join("store"), &crashed);
+        fs::remove_file(crashed.join("LOCK")).ok();
+
+        let reopened = Store::open(StoreConfig {
+            path: crashed,
+            ..test_config(&dir)
+        })
+        .unwrap();
+        assert_eq!(reopened.list_branches().len(), 52);
+        assert!(reopened.branches.get_branch("late").is_some());
+        assert_eq!(reopened.list_blobs_by_type("text/plain").unwrap().len(), 50);
+        assert_eq!(reopened.wal.as_ref().unwrap().size().unwrap(), checkpointed);
     }
 
     #[test]
-    fn test_subscription_catch_up_records() {
-        use crate::subscriptions::{SubscriptionConfig, SubscriptionFilter, StoreEvent};
-        use std::time::Duration;
-
+    fn test_wal_checkpoints_without_sync() {
         let dir = TempDir::new().unwrap();
         let store = Store::create(test_config(&dir)).unwrap();
+        let wal = store.wal.as_ref().unwrap();
 
-        // Create some records first
-        for i in 1..=5 {
-            store
-                .append(RecordInput::json("message", &serde_json::json!({ "i": i })).unwrap())
-                .unwrap();
+        let payload = vec![0u8; 1024];
+        for _ in 0..WAL_CHECKPOINT_ENTRIES - 1 {
+            store.append(RecordInput::raw("event", payload.clone())).unwrap();
         }
+        assert!(wal.size().unwrap() > (WAL_CHECKPOINT_ENTRIES * payload.len()) as u64 / 2);
 
-        // Subscribe from sequence 3
-        let config = SubscriptionConfig {
-            filter: SubscriptionFilter::records(),
-            from_sequence: Some(Sequence(3)),
-            ..Default::default()
-        };
-        let handle = store.subscribe(config);
+        // The write that reaches the limit syncs, dropping every entry
+        store.append(RecordInput::raw("event", payload.clone())).unwrap();
+        assert!(wal.size().unwrap() < 16);
+        assert!(store.wal_unsynced.lock().is_empty());
+        assert!(!store.state.is_dirty());
+    }
 
-        // Perform catch-up
-        store.catch_up_subscription(handle.id).unwrap();
+    #[test]
+    fn test_sync_worker_checkpoints_wal() {
+        let dir = TempDir::new().unwrap();
+        let interval = Duration::from_millis(20);
+        let store = Store::create(StoreConfig {
+            sync_policy: SyncPolicy::Interval(interval),
+            ..test_config(&dir)
+        })
+        .unwrap();
+        let wal = store.wal.as_ref().unwrap();
 
-        // Should receive records 3, 4, 5 (sequences >= 3)
-        let mut received = Vec::new();
-        while let Ok(event) = handle.recv_timeout(Duration::from_millis(50)) {
-            match event {
-                StoreEvent::Record { record } => {
-                    received.push(record.sequence.0);
-                }
-                StoreEvent::CaughtUp => break,
-                _ => {}
-            }
+        for i in 0..20 {
+            store.create_branch(&format!("branch{}", i), Some("main")).unwrap();
         }
-
-        assert_eq!(received, vec![3, 4, 5]);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while wal.size().unwrap() >= 16 && Instant::now() < deadline {
+            std::thread::sleep(interval);
+        }
+        assert!(wal.size().unwrap() < 16);
     }
 
     #[test]
-    fn test_subscription_catch_up_state() {
-        use crate::subscriptions::{SubscriptionConfig, SubscriptionFilter, StoreEvent};
-        use std::time::Duration;
-
+    fn test_compaction_installed_only_once_committed() {
         let dir = TempDir::new().unwrap();
         let store = Store::create(test_config(&dir)).unwrap();
+        store.append(RecordInput::raw("event", vec![1])).unwrap();
+        store.create_branch("scratch", None).unwrap();
+        store.switch_branch("scratch").unwrap();
+        store.append(RecordInput::raw("event", vec![2])).unwrap();
+        store.switch_branch("main").unwrap();
+        let stray = store.store_blob(b"stray", "text/plain").unwrap();
+        store.sync().unwrap();
+        let before = dir.path().join("before");
+        copy_dir(store.path(), &before);
+        fs::remove_file(before.join("LOCK")).ok();
+
+        let options = CompactOptions {
+            branch_gc: Some(BranchGcOptions {
+                delete_stale_older_than: Some(i64::MAX as u64),
+                name_patterns: Some(vec!["scratch".to_string()]),
+                ..Default::default()
+            }),
+            gc_blobs: true,
+            ..Default::default()
+        };
 
-        // Register and populate state
-        store
-            .register_state(StateRegistration {
-                id: "counter".to_string(),
-                strategy: crate::types::StateStrategy::Snapshot,
-                initial_value: None,
-            })
-            .unwrap();
+        // A compaction that can't be staged changes nothing
+        let staging = store.path().join(COMPACT_DIR);
+        fs::write(&staging, b"in the way").unwrap();
+        assert!(store.compact(options.clone()).is_err());
+        assert!(store.branches.get_branch("scratch").is_some());
+        assert!(store.get_blob(&stray).unwrap().is_some());
+        assert_eq!(
+            fs::read(store.path().join("records.log")).unwrap(),
+            fs::read(before.join("records.log")).unwrap()
+        );
+        fs::remove_file(&staging).unwrap();
+
+        let report = store.compact(options).unwrap();
+        assert_eq!((report.log.records_removed, report.blobs_deleted), (1, 1));
+        assert!(!staging.exists());
+        let compacted: Vec<_> = COMPACT_FILES
+            .iter()
+            .map(|name| fs::read(store.path().join(name)).unwrap())
+            .collect();
+        drop(store);
+
+        // Crash with the result staged: the next open installs it only if
+        // it was committed
+        for committed in [false, true] {
+            let crashed = dir.path().join(format!("crashed-{}", committed));
+            copy_dir(&before, &crashed);
+            let staging = crashed.join(COMPACT_DIR);
+            fs::create_dir(&staging).unwrap();
+            for (name, content) in COMPACT_FILES.iter().zip(&compacted) {
+                fs::write(staging.join(name), content).unwrap();
+            }
+            fs::write(staging.join(COMPACT_BLOBS_FILE), format!("{}\n", stray.to_hex())).unwrap();
+            if committed {
+                Store::commit_compaction(&staging).unwrap();
+            }
 
-        // Update state a few times
-        store
-            .update_state("counter", StateOperation::Set(b"10".to_vec()))
-            .unwrap();
-        let seq2 = store
-            .update_state("counter", StateOperation::Set(b"20".to_vec()))
-            .unwrap();
-        store
-            .update_state("counter", StateOperation::Set(b"30".to_vec()))
+            let reopened = Store::open(StoreConfig {
+                path: crashed.clone(),
+                ..test_config(&dir)
+            })
             .unwrap();
+            assert!(!staging.exists());
+            assert_eq!(reopened.branches.get_branch("scratch").is_none(), committed);
+            assert_eq!(reopened.get_blob(&stray).unwrap().is_none(), committed);
+            let events = reopened.get_records_by_type("event").len();
+            assert_eq!(events, if committed { 1 } else { 2 });
+            assert!(reopened.check_consistency().unwrap().is_ok());
+        }
+    }
 
-        // Subscribe to state from seq2
-        let config = SubscriptionConfig {
-            filter: SubscriptionFilter::states(vec!["counter".to_string()]),
-            from_sequence: Some(seq2.sequence),
-            ..Default::default()
-        };
-        let handle = store.subscribe(config);
-
-        // Perform catch-up
-        store.catch_up_subscription(handle.id).unwrap();
+    #[test]
+    fn test_check_consistency_flags_desynced_head() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+        for i in 0..5u8 {
+            store.append(RecordInput::raw("event", vec![i])).unwrap();
+        }
+        store.create_branch("feature", None).unwrap();
+        let report = store.check_consistency().unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert_eq!(report.branches_checked, 2);
+
+        // Head behind the index: the next append would collide
+        let main = store.current_branch();
+        store.branches.update_head(main.id, Sequence(3)).unwrap();
+        let report = store.check_consistency().unwrap();
+        assert_eq!(report.issues.len(), 1);
+        assert!(matches!(&report.issues[0].location, VerifyLocation::Branch(name) if name == "main"));
+        assert!(report.issues[0].message.contains("behind"));
+
+        store.branches.update_head(main.id, Sequence(5)).unwrap();
+        assert!(store.check_consistency().unwrap().is_ok());
+
+        // Head past the index after the branch's last records expired
+        store.append(RecordInput::raw("event", vec![5]).with_expires_at(Timestamp(1))).unwrap();
+        store.switch_branch("feature").unwrap();
+        store.append(RecordInput::raw("event", vec![6]).with_expires_at(Timestamp(1))).unwrap();
+        assert_eq!(store.expire_records(Timestamp::now()).unwrap(), 2);
+        let report = store.check_consistency().unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+    }
 
-        // Should receive state snapshot at seq2 (value 20)
-        let event = handle.recv_timeout(Duration::from_millis(100)).unwrap();
-        match event {
-            StoreEvent::StateSnapshot { state_id, data, .. } => {
-                assert_eq!(state_id, "counter");
-                assert_eq!(data, serde_json::json!(20));
-            }
-            _ => panic!("Expected StateSnapshot, got {:?}", event),
+    #[test]
+    fn test_append_refuses_desynced_head() {
+        let dir = TempDir::new().unwrap();
+        let store = Store::create(test_config(&dir)).unwrap();
+        for i in 0..5u8 {
+            store.append(RecordInput::raw("event", vec![i])).unwrap();
         }
+        let main = store.current_branch();
+        store.branches.update_head(main.id, Sequence(3)).unwrap();
 
-        // Then CaughtUp
-        let event = handle.recv_timeout(Duration::from_millis(100)).unwrap();
-        assert!(matches!(event, StoreEvent::CaughtUp));
+        // A recoverable error instead of a collision
+        let result = store.append(RecordInput::raw("event", vec![5]));
+        assert!(matches!(result, Err(StoreError::InvalidSequence(Sequence(5), Sequence(3)))));
+        assert_eq!(store.get_records_by_type("event").len(), 5);
     }
 
     #[test]
-    fn test_subscription_catch_up_no_from_sequence() {
-        use crate::subscriptions::{SubscriptionConfig, SubscriptionFilter, StoreEvent};
-        use std::time::Duration;
+    fn test_snapshot_events() {
+        use crate::subscriptions::{SubscriptionConfig, SubscriptionFilter};
 
         let dir = TempDir::new().unwrap();
         let store = Store::create(test_config(&dir)).unwrap();
 
-        // Create some records
-        for i in 1..=3 {
-            store
-                .append(RecordInput::json("message", &serde_json::json!({ "i": i })).unwrap())
-                .unwrap();
+        for i in 1..=6 {
+            let record_type = if i % 2 == 0 { "even" } else { "odd" };
+            store.append(RecordInput::raw(record_type, vec![i])).unwrap();
         }
 
-        // Subscribe without from_sequence (live only)
-        let config = SubscriptionConfig {
-            filter: SubscriptionFilter::records(),
-            from_sequence: None,
-            ..Default::default()
+        let sequences = |events: Vec<StoreEvent>| -> Vec<u64> {
+            events
+                .into_iter()
+                .map(|event| match event {
+                    StoreEvent::Record { record } => record.sequence.0,
+                    other => panic!("Unexpected event {:?}", other),
+                })
+                .collect()
         };
-        let handle = store.subscribe(config);
 
-        // Perform catch-up (should immediately mark as caught up)
-        store.catch_up_subscription(handle.id).unwrap();
+        let events = store
+            .snapshot_events(SubscriptionConfig {
+                filter: SubscriptionFilter::record_types(vec!["even".to_string()]),
+                from_sequence: Some(Sequence(3)),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(sequences(events), vec![4, 6]);
 
-        // Should receive CaughtUp immediately, no historical records
-        let event = handle.recv_timeout(Duration::from_millis(100)).unwrap();
-        assert!(matches!(event, StoreEvent::CaughtUp));
+        // Defaults to the start of the branch; the subscription is gone after
+        let events = store
+            .snapshot_events(SubscriptionConfig {
+                filter: SubscriptionFilter::records(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(sequences(events), vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(store.subscription_count(), 0);
 
-        // No more events
-        let result = handle.recv_timeout(Duration::from_millis(50));
-        assert!(result.is_err());
+        // History larger than the buffer is an error, not a silent truncation
+        let result = store.snapshot_events(SubscriptionConfig {
+            filter: SubscriptionFilter::records(),
+            buffer_size: 3,
+            ..Default::default()
+        });
+        assert!(matches!(result, Err(StoreError::SubscriptionDropped)));
+        assert_eq!(store.subscription_count(), 0);
     }
 }
@@ -1,11 +1,13 @@
 //! Subscription manager for broadcasting store events.
 
 use crate::error::{Result, StoreError};
+use crate::observer::{NoopObserver, StoreObserver};
 use crate::types::{Branch, Record, Sequence, StateOperation};
 use crossbeam_channel::{bounded, Sender};
 use parking_lot::RwLock;
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 use super::types::{
     BranchSummary, DropReason, RecordSummary, StoreEvent, SubscriptionConfig, SubscriptionFilter,
@@ -15,9 +17,11 @@ use super::types::{
 /// Default threshold for including payload in record events (bytes).
 const DEFAULT_PAYLOAD_THRESHOLD: usize = 4096;
 
+/// Active subscriptions by ID, shared with handles so they can update their filters.
+pub(super) type SubscriptionMap = RwLock<HashMap<SubscriptionId, Subscription>>;
+
 /// Internal subscription state.
-struct Subscription {
-    id: SubscriptionId,
+pub(super) struct Subscription {
     config: SubscriptionConfig,
     sender: Sender<StoreEvent>,
     /// Whether catch-up is complete.
@@ -36,20 +40,12 @@ impl Subscription {
 
     /// Check if this subscription matches a record.
     fn matches_record(&self, record: &Record) -> bool {
-        if !self.config.filter.include_records {
-            return false;
-        }
-
-        // Check record type filter
-        if let Some(ref types) = self.config.filter.record_types {
-            if !types.contains(&record.record_type) {
-                return false;
-            }
-        }
-
-        // TODO: Check branch filter
+        self.config.filter.matches_record(record)
+    }
 
-        true
+    /// Check if this subscription follows `branch`.
+    fn follows_branch(&self, branch: &str) -> bool {
+        self.config.branch.as_deref().is_none_or(|name| name == branch)
     }
 
     /// Check if this subscription matches a state change.
@@ -74,29 +70,41 @@ impl Subscription {
 /// Manages subscriptions and broadcasts events.
 pub struct SubscriptionManager {
     /// Active subscriptions by ID.
-    subscriptions: RwLock<HashMap<SubscriptionId, Subscription>>,
+    subscriptions: Arc<SubscriptionMap>,
     /// Counter for generating subscription IDs.
     next_id: AtomicU64,
     /// Threshold for including payload in record events.
     payload_threshold: usize,
+    /// Told when a subscription is removed.
+    observer: Arc<dyn StoreObserver>,
 }
 
 impl SubscriptionManager {
     /// Create a new subscription manager.
     pub fn new() -> Self {
         Self {
-            subscriptions: RwLock::new(HashMap::new()),
+            subscriptions: Arc::new(RwLock::new(HashMap::new())),
             next_id: AtomicU64::new(1),
             payload_threshold: DEFAULT_PAYLOAD_THRESHOLD,
+            observer: Arc::new(NoopObserver),
+        }
+    }
+
+    /// Create a new subscription manager that reports drops to `observer`.
+    pub fn with_observer(observer: Arc<dyn StoreObserver>) -> Self {
+        Self {
+            observer,
+            ..Self::new()
         }
     }
 
     /// Create a new subscription manager with custom payload threshold.
     pub fn with_payload_threshold(threshold: usize) -> Self {
         Self {
-            subscriptions: RwLock::new(HashMap::new()),
+            subscriptions: Arc::new(RwLock::new(HashMap::new())),
             next_id: AtomicU64::new(1),
             payload_threshold: threshold,
+            observer: Arc::new(NoopObserver),
         }
     }
 
@@ -109,7 +117,6 @@ impl SubscriptionManager {
         let (sender, receiver) = bounded(config.buffer_size);
 
         let subscription = Subscription {
-            id,
             config,
             sender,
             caught_up: false,
@@ -117,7 +124,12 @@ impl SubscriptionManager {
 
         self.subscriptions.write().insert(id, subscription);
 
-        SubscriptionHandle { id, receiver }
+        SubscriptionHandle::new(id, receiver, Arc::downgrade(&self.subscriptions))
+    }
+
+    /// Replace a subscription's filter (see `SubscriptionHandle::update_filter`).
+    pub fn update_filter(&self, id: SubscriptionId, filter: SubscriptionFilter) -> Result<()> {
+        replace_filter(&self.subscriptions, id, filter)
     }
 
     /// Unsubscribe and clean up.
@@ -125,9 +137,9 @@ impl SubscriptionManager {
         let mut subs = self.subscriptions.write();
         if let Some(sub) = subs.remove(&id) {
             // Send dropped event (best effort)
-            let _ = sub.sender.try_send(StoreEvent::Dropped {
-                reason: DropReason::Unsubscribed,
-            });
+            let reason = DropReason::Unsubscribed;
+            self.observer.on_subscription_drop(id, &reason);
+            let _ = sub.sender.try_send(StoreEvent::Dropped { reason });
         }
     }
 
@@ -138,6 +150,8 @@ impl SubscriptionManager {
             sub.caught_up = true;
             if !sub.try_send(StoreEvent::CaughtUp) {
                 subs.remove(&id);
+                self.observer
+                    .on_subscription_drop(id, &DropReason::BufferOverflow);
                 return Err(StoreError::SubscriptionDropped);
             }
         }
@@ -151,15 +165,23 @@ impl SubscriptionManager {
 
     // --- Broadcasting ---
 
-    /// Broadcast a new record to matching subscriptions.
-    pub fn broadcast_record(&self, record: &Record) {
+    /// Broadcast a new record on `branch` to matching subscriptions.
+    pub fn broadcast_record(&self, record: &Record, branch: &str) {
+        // Building the summary parses the payload; skip it with nobody listening
+        if self.subscriptions.read().is_empty() {
+            return;
+        }
         let summary = RecordSummary::from_record(record, self.payload_threshold);
         let event = StoreEvent::Record { record: summary };
 
-        self.broadcast(|sub| sub.caught_up && sub.matches_record(record), event);
+        self.broadcast(
+            |sub| sub.caught_up && sub.follows_branch(branch) && sub.matches_record(record),
+            event,
+        );
     }
 
     /// Broadcast a state snapshot to matching subscriptions.
+    #[allow(clippy::too_many_arguments)]
     pub fn broadcast_state_snapshot(
         &self,
         state_id: &str,
@@ -183,10 +205,11 @@ impl SubscriptionManager {
         self.broadcast(|sub| sub.matches_state(state_id), event);
     }
 
-    /// Broadcast a state delta to matching subscriptions.
+    /// Broadcast a state delta on `branch` to matching subscriptions.
     pub fn broadcast_state_delta(
         &self,
         state_id: &str,
+        branch: &str,
         operation: StateOperation,
         sequence: Sequence,
     ) {
@@ -196,7 +219,10 @@ impl SubscriptionManager {
             sequence,
         };
 
-        self.broadcast(|sub| sub.caught_up && sub.matches_state(state_id), event);
+        self.broadcast(
+            |sub| sub.caught_up && sub.follows_branch(branch) && sub.matches_state(state_id),
+            event,
+        );
     }
 
     /// Broadcast branch head update.
@@ -226,6 +252,16 @@ impl SubscriptionManager {
         self.broadcast(|sub| sub.caught_up && sub.wants_branch_events(), event);
     }
 
+    /// Broadcast branch switched event.
+    pub fn broadcast_branch_switched(&self, from: &str, to: &str) {
+        let event = StoreEvent::BranchSwitched {
+            from: from.to_string(),
+            to: to.to_string(),
+        };
+
+        self.broadcast(|sub| sub.caught_up && sub.wants_branch_events(), event);
+    }
+
     /// Internal broadcast helper. Drops subscribers that fail to receive.
     fn broadcast<F>(&self, filter: F, event: StoreEvent)
     where
@@ -236,10 +272,8 @@ impl SubscriptionManager {
         {
             let subs = self.subscriptions.read();
             for (id, sub) in subs.iter() {
-                if filter(sub) {
-                    if !sub.try_send(event.clone()) {
-                        to_remove.push(*id);
-                    }
+                if filter(sub) && !sub.try_send(event.clone()) {
+                    to_remove.push(*id);
                 }
             }
         }
@@ -249,10 +283,10 @@ impl SubscriptionManager {
             let mut subs = self.subscriptions.write();
             for id in to_remove {
                 if let Some(sub) = subs.remove(&id) {
+                    let reason = DropReason::BufferOverflow;
+                    self.observer.on_subscription_drop(id, &reason);
                     // Try to notify about the drop (might fail, that's ok)
-                    let _ = sub.sender.try_send(StoreEvent::Dropped {
-                        reason: DropReason::BufferOverflow,
-                    });
+                    let _ = sub.sender.try_send(StoreEvent::Dropped { reason });
                 }
             }
         }
@@ -283,9 +317,23 @@ impl Default for SubscriptionManager {
     }
 }
 
+/// Swap the filter of a live subscription under the map's write lock, so
+/// no broadcast sees a half-updated config.
+pub(super) fn replace_filter(
+    subscriptions: &SubscriptionMap,
+    id: SubscriptionId,
+    filter: SubscriptionFilter,
+) -> Result<()> {
+    let mut subs = subscriptions.write();
+    let sub = subs.get_mut(&id).ok_or(StoreError::SubscriptionDropped)?;
+    sub.config.filter = filter;
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::subscriptions::SubscriptionFilter;
     use crate::types::{BranchId, PayloadEncoding, RecordId, Timestamp};
     use std::time::Duration;
 
@@ -300,6 +348,11 @@ mod tests {
             linked_to: vec![],
             payload: b"{}".to_vec(),
             encoding: PayloadEncoding::Json,
+            squash: false,
+            expires_at: None,
+            lamport: None,
+            idempotency_key: None,
+            schema: None,
         }
     }
 
@@ -332,7 +385,7 @@ mod tests {
 
         // Broadcast matching record
         let record = make_test_record("message");
-        manager.broadcast_record(&record);
+        manager.broadcast_record(&record, "main");
 
         // Should receive
         let event = handle.recv_timeout(Duration::from_millis(100)).unwrap();
@@ -362,7 +415,7 @@ mod tests {
 
         // Broadcast non-matching record
         let record = make_test_record("tool-call");
-        manager.broadcast_record(&record);
+        manager.broadcast_record(&record, "main");
 
         // Should NOT receive (no more events after CaughtUp)
         let result = handle.recv_timeout(Duration::from_millis(50));
@@ -385,7 +438,7 @@ mod tests {
         for i in 0..10 {
             let mut record = make_test_record("message");
             record.id = RecordId(i);
-            manager.broadcast_record(&record);
+            manager.broadcast_record(&record, "main");
         }
 
         // Subscriber should be dropped
@@ -405,10 +458,97 @@ mod tests {
 
         // Broadcast record
         let record = make_test_record("message");
-        manager.broadcast_record(&record);
+        manager.broadcast_record(&record, "main");
 
         // Should NOT receive (not caught up yet)
         let result = handle.recv_timeout(Duration::from_millis(50));
         assert!(result.is_err());
     }
+    #[test]
+    fn test_forward() {
+        let manager = SubscriptionManager::new();
+        let handle = manager.subscribe(SubscriptionConfig {
+            filter: SubscriptionFilter::records(),
+            ..Default::default()
+        });
+        let id = handle.id;
+        manager.mark_caught_up(id).unwrap();
+
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let bridge = handle.forward(move |event| tx.send(event).unwrap()).unwrap();
+
+        manager.broadcast_record(&make_test_record("a"), "main");
+        manager.broadcast_record(&make_test_record("b"), "main");
+        let timeout = std::time::Duration::from_secs(5);
+        assert!(matches!(rx.recv_timeout(timeout), Ok(StoreEvent::CaughtUp)));
+        for expected in ["a", "b"] {
+            match rx.recv_timeout(timeout) {
+                Ok(StoreEvent::Record { record }) => assert_eq!(record.record_type, expected),
+                other => panic!("Expected Record event, got {:?}", other),
+            }
+        }
+
+        // Unsubscribing delivers the drop reason and ends the thread
+        manager.unsubscribe(id);
+        bridge.join().unwrap();
+        let rest: Vec<StoreEvent> = rx.try_iter().collect();
+        assert!(matches!(
+            rest.as_slice(),
+            [StoreEvent::Dropped { reason: DropReason::Unsubscribed }]
+        ));
+    }
+
+    #[test]
+    fn test_update_filter() {
+        let manager = SubscriptionManager::new();
+        let config = SubscriptionConfig {
+            filter: SubscriptionFilter::record_types(vec!["a".to_string()]),
+            ..Default::default()
+        };
+        let handle = manager.subscribe(config);
+        manager.mark_caught_up(handle.id).unwrap();
+        assert!(matches!(handle.try_recv(), Ok(StoreEvent::CaughtUp)));
+
+        let broadcast = |types: &[&str]| {
+            for record_type in types {
+                manager.broadcast_record(&make_test_record(record_type), "main");
+            }
+        };
+        let received = || -> Vec<String> {
+            handle
+                .receiver
+                .try_iter()
+                .map(|event| match event {
+                    StoreEvent::Record { record } => record.record_type,
+                    other => panic!("Expected Record event, got {:?}", other),
+                })
+                .collect()
+        };
+
+        broadcast(&["a", "b"]);
+        assert_eq!(received(), vec!["a"]);
+
+        // Only later events see the new filter; nothing is replayed
+        handle
+            .update_filter(SubscriptionFilter::record_types(vec!["b".to_string()]))
+            .unwrap();
+        assert!(received().is_empty());
+        broadcast(&["a", "b", "b"]);
+        assert_eq!(received(), vec!["b", "b"]);
+
+        manager.update_filter(handle.id, SubscriptionFilter::records()).unwrap();
+        broadcast(&["a", "b"]);
+        assert_eq!(received(), vec!["a", "b"]);
+
+        manager.unsubscribe(handle.id);
+        assert!(matches!(
+            handle.update_filter(SubscriptionFilter::records()),
+            Err(StoreError::SubscriptionDropped)
+        ));
+        drop(manager);
+        assert!(matches!(
+            handle.update_filter(SubscriptionFilter::records()),
+            Err(StoreError::SubscriptionDropped)
+        ));
+    }
 }
@@ -9,6 +9,7 @@
 //! - Filtering by record type, state ID, etc.
 //! - Historical catch-up from a given sequence
 //! - Bounded buffers with slow-subscriber dropping
+//! - Watching a state's materialized value (`StateWatcher`)
 //!
 //! # Example
 //!
@@ -36,9 +37,11 @@
 
 mod manager;
 mod types;
+mod watcher;
 
 pub use manager::SubscriptionManager;
 pub use types::{
-    BranchSummary, DropReason, RecordSummary, StoreEvent, SubscriptionConfig, SubscriptionFilter,
+    BlockingEventIter, BranchSummary, DropReason, RecordSummary, StoreEvent, SubscriptionConfig, SubscriptionFilter,
     SubscriptionHandle, SubscriptionId,
 };
+pub use watcher::StateWatcher;
@@ -1,7 +1,9 @@
 //! Subscription types for live store updates.
 
-use crate::types::{Branch, BranchId, Record, Sequence, StateOperation};
+use super::manager::{replace_filter, SubscriptionMap};
+use crate::types::{Branch, BranchId, Record, RecordId, Sequence, StateOperation};
 use serde::{Deserialize, Serialize};
+use std::sync::Weak;
 
 /// Configuration for a subscription.
 #[derive(Clone, Debug)]
@@ -19,6 +21,16 @@ pub struct SubscriptionConfig {
 
     /// Filter criteria.
     pub filter: SubscriptionFilter,
+
+    /// During catch-up, send each subscribed state as one snapshot of its
+    /// current value instead of a snapshot at `from_sequence` followed by
+    /// every delta since. Live deltas stream as usual afterward.
+    pub coalesce_catchup_state: bool,
+
+    /// Branch to catch up on and follow (None = the current branch for
+    /// catch-up, every branch live). Live record and state delta events
+    /// from other branches are not delivered.
+    pub branch: Option<String>,
 }
 
 impl Default for SubscriptionConfig {
@@ -28,6 +40,8 @@ impl Default for SubscriptionConfig {
             max_snapshot_bytes: 10 * 1024 * 1024, // 10MB
             from_sequence: None,
             filter: SubscriptionFilter::default(),
+            coalesce_catchup_state: false,
+            branch: None,
         }
     }
 }
@@ -52,9 +66,52 @@ pub struct SubscriptionFilter {
 
     /// Include branch events.
     pub include_branch_events: bool,
+
+    /// Only records directly caused by one of these (None = any).
+    pub caused_by: Option<Vec<RecordId>>,
+
+    /// Only records linked to one of these (None = any).
+    pub linked_to: Option<Vec<RecordId>>,
 }
 
 impl SubscriptionFilter {
+    /// Check whether a record event passes this filter.
+    pub fn matches_record(&self, record: &Record) -> bool {
+        if !self.include_records {
+            return false;
+        }
+
+        // Check record type filter
+        if let Some(ref types) = self.record_types {
+            if !types.contains(&record.record_type) {
+                return false;
+            }
+        }
+
+        // Check causation filters
+        if let Some(ref ids) = self.caused_by {
+            if !record.caused_by.iter().any(|id| ids.contains(id)) {
+                return false;
+            }
+        }
+        if let Some(ref ids) = self.linked_to {
+            if !record.linked_to.iter().any(|id| ids.contains(id)) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Subscribe to the records directly caused by any of `ids`.
+    pub fn effects_of(ids: Vec<RecordId>) -> Self {
+        Self {
+            caused_by: Some(ids),
+            include_records: true,
+            ..Default::default()
+        }
+    }
+
     /// Subscribe to all records on current branch.
     pub fn records() -> Self {
         Self {
@@ -152,6 +209,12 @@ pub enum StoreEvent {
         name: String,
     },
 
+    /// The store's current branch changed.
+    BranchSwitched {
+        from: String,
+        to: String,
+    },
+
     // --- Lifecycle Events ---
     /// Finished historical catch-up, now streaming live.
     CaughtUp,
@@ -249,9 +312,38 @@ pub struct SubscriptionHandle {
     pub id: SubscriptionId,
     /// Channel to receive events.
     pub receiver: crossbeam_channel::Receiver<StoreEvent>,
+    /// The manager's subscriptions, for `update_filter`.
+    subscriptions: Weak<SubscriptionMap>,
 }
 
 impl SubscriptionHandle {
+    pub(super) fn new(
+        id: SubscriptionId,
+        receiver: crossbeam_channel::Receiver<StoreEvent>,
+        subscriptions: Weak<SubscriptionMap>,
+    ) -> Self {
+        Self {
+            id,
+            receiver,
+            subscriptions,
+        }
+    }
+
+    /// Replace the subscription's filter.
+    ///
+    /// Only events broadcast after the swap are filtered by the new one:
+    /// nothing is replayed for types that are newly included, and events
+    /// already buffered stay in the channel. Fails with
+    /// `SubscriptionDropped` if the subscription is gone (unsubscribed,
+    /// dropped for overflow, or its store closed).
+    pub fn update_filter(&self, filter: SubscriptionFilter) -> crate::error::Result<()> {
+        let subscriptions = self
+            .subscriptions
+            .upgrade()
+            .ok_or(crate::error::StoreError::SubscriptionDropped)?;
+        replace_filter(&subscriptions, self.id, filter)
+    }
+
     /// Receive the next event (blocking).
     pub fn recv(&self) -> Result<StoreEvent, crossbeam_channel::RecvError> {
         self.receiver.recv()
@@ -269,4 +361,84 @@ impl SubscriptionHandle {
     ) -> Result<StoreEvent, crossbeam_channel::RecvTimeoutError> {
         self.receiver.recv_timeout(timeout)
     }
+
+    /// Iterate over events, blocking for each one.
+    pub fn iter(&self) -> BlockingEventIter {
+        BlockingEventIter {
+            receiver: self.receiver.clone(),
+            done: false,
+        }
+    }
+
+    /// Pass every event to `callback` on a background thread.
+    ///
+    /// The thread ends after delivering a `Dropped` event (which includes
+    /// unsubscribing) or when the channel disconnects, so joining the
+    /// returned handle after `unsubscribe` waits for the last callback.
+    pub fn forward(
+        self,
+        mut callback: impl FnMut(StoreEvent) + Send + 'static,
+    ) -> std::io::Result<std::thread::JoinHandle<()>> {
+        std::thread::Builder::new()
+            .name(format!("chronicle-subscription-{}", self.id.0))
+            .spawn(move || {
+                for event in self {
+                    callback(event);
+                }
+            })
+    }
+}
+
+impl IntoIterator for SubscriptionHandle {
+    type Item = StoreEvent;
+    type IntoIter = BlockingEventIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        BlockingEventIter {
+            receiver: self.receiver,
+            done: false,
+        }
+    }
+}
+
+impl IntoIterator for &SubscriptionHandle {
+    type Item = StoreEvent;
+    type IntoIter = BlockingEventIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Blocking iterator over subscription events.
+///
+/// Each `next` waits for the following event. Iteration ends after a
+/// `Dropped` event (which is still yielded, so the reason isn't lost) or
+/// when the subscription's channel disconnects.
+pub struct BlockingEventIter {
+    receiver: crossbeam_channel::Receiver<StoreEvent>,
+    done: bool,
+}
+
+impl Iterator for BlockingEventIter {
+    type Item = StoreEvent;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        match self.receiver.recv() {
+            Ok(event) => {
+                if matches!(event, StoreEvent::Dropped { .. }) {
+                    self.done = true;
+                }
+                Some(event)
+            }
+            Err(_) => {
+                self.done = true;
+                None
+            }
+        }
+    }
 }
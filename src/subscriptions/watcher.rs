@@ -0,0 +1,123 @@
+//! Materialized state values on top of a state subscription.
+
+use super::types::{StoreEvent, SubscriptionHandle};
+use crate::error::{Result, StoreError};
+use crate::state::apply_operation_with_capacity;
+use crate::types::{Sequence, StateOperation};
+use std::time::{Duration, Instant};
+
+/// Follows one state and yields its whole value each time it changes.
+///
+/// Created by `Store::watch_state`. Deltas are applied to the value the
+/// state had when the watcher was created, so readers never see raw
+/// operations. Changes that are already buffered when the watcher wakes up
+/// are coalesced into a single value, and updates that leave the value as
+/// it was (e.g. snapshots) yield nothing. Only changes on the branch that
+/// was current at creation are followed.
+pub struct StateWatcher {
+    handle: SubscriptionHandle,
+    state_id: String,
+    /// Current value; None until the state's first update.
+    value: Option<Vec<u8>>,
+    /// Sequence the value reflects; older deltas are already in it.
+    sequence: Sequence,
+    /// RingBuffer capacity, to trim appends like reconstruction does.
+    capacity: Option<usize>,
+}
+
+impl StateWatcher {
+    pub(crate) fn new(
+        handle: SubscriptionHandle,
+        state_id: &str,
+        value: Option<Vec<u8>>,
+        sequence: Sequence,
+        capacity: Option<usize>,
+    ) -> Self {
+        Self {
+            handle,
+            state_id: state_id.to_string(),
+            value,
+            sequence,
+            capacity,
+        }
+    }
+
+    /// The watched state's id.
+    pub fn state_id(&self) -> &str {
+        &self.state_id
+    }
+
+    /// The latest value seen, without waiting. None if the state has no
+    /// updates yet.
+    pub fn value(&self) -> Option<&[u8]> {
+        self.value.as_deref()
+    }
+
+    /// Wait for the value to change and return it.
+    ///
+    /// Fails with `SubscriptionDropped` once the subscription is gone
+    /// (dropped for overflow, or its store closed).
+    pub fn recv(&mut self) -> Result<Vec<u8>> {
+        loop {
+            let event = self
+                .handle
+                .recv()
+                .map_err(|_| StoreError::SubscriptionDropped)?;
+            if let Some(value) = self.apply_and_drain(event)? {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Like `recv`, but gives up after `timeout` and returns None.
+    pub fn recv_timeout(&mut self, timeout: Duration) -> Result<Option<Vec<u8>>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let event = match self.handle.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(event) => event,
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => return Ok(None),
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                    return Err(StoreError::SubscriptionDropped);
+                }
+            };
+            if let Some(value) = self.apply_and_drain(event)? {
+                return Ok(Some(value));
+            }
+        }
+    }
+
+    /// Apply `event` and every event already buffered behind it. Returns
+    /// the new value if it differs from the one before.
+    fn apply_and_drain(&mut self, event: StoreEvent) -> Result<Option<Vec<u8>>> {
+        let before = self.value.clone();
+        self.apply(event)?;
+        while let Ok(event) = self.handle.try_recv() {
+            self.apply(event)?;
+        }
+        match &self.value {
+            Some(value) if self.value != before => Ok(Some(value.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    fn apply(&mut self, event: StoreEvent) -> Result<()> {
+        match event {
+            StoreEvent::StateDelta {
+                state_id,
+                operation,
+                sequence,
+            } if state_id == self.state_id && sequence > self.sequence => {
+                self.sequence = sequence;
+                // Delta snapshots repeat appends the value already has
+                if matches!(operation, StateOperation::DeltaSnapshot(_)) {
+                    return Ok(());
+                }
+                let state = self.value.take().unwrap_or_default();
+                self.value = Some(apply_operation_with_capacity(state, operation, self.capacity)?);
+                Ok(())
+            }
+            StoreEvent::Dropped { .. } => Err(StoreError::SubscriptionDropped),
+            _ => Ok(()),
+        }
+    }
+}
@@ -1,9 +1,11 @@
 //! Core types for the record store.
 
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::fmt;
+use std::str::FromStr;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Unique identifier for a record.
@@ -56,30 +58,74 @@ impl fmt::Debug for BranchId {
     }
 }
 
-/// Content hash for blobs (SHA-256).
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
-pub struct Hash(pub [u8; 32]);
+/// Content hash for blobs.
+///
+/// SHA-256 hashes are written as 64 hex digits; other algorithms prefix
+/// them with the algorithm's name (`blake3:...`).
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Hash(pub [u8; 32], pub HashAlgorithm);
+
+/// Hash function blobs are addressed by (`StoreConfig::blob_hash`).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    #[default]
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgorithm {
+    /// Hash `data` with this algorithm.
+    pub fn digest(self, data: &[u8]) -> Hash {
+        let bytes = match self {
+            HashAlgorithm::Sha256 => Sha256::digest(data).into(),
+            HashAlgorithm::Blake3 => *blake3::hash(data).as_bytes(),
+        };
+        Hash(bytes, self)
+    }
+
+    /// Prefix of hex-encoded hashes.
+    fn hex_prefix(self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha256 => "",
+            HashAlgorithm::Blake3 => "blake3:",
+        }
+    }
+}
 
 impl Hash {
-    /// Compute hash from bytes.
+    /// Compute the SHA-256 hash of bytes.
     pub fn from_bytes(data: &[u8]) -> Self {
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        Hash(hasher.finalize().into())
+        HashAlgorithm::Sha256.digest(data)
     }
 
-    /// Convert to hex string.
+    /// Convert to hex string, prefixed for algorithms other than SHA-256.
     pub fn to_hex(&self) -> String {
-        hex::encode(self.0)
+        format!("{}{}", self.1.hex_prefix(), hex::encode(self.0))
     }
 
-    /// Parse from hex string.
-    pub fn from_hex(s: &str) -> Result<Self, hex::FromHexError> {
-        let bytes = hex::decode(s)?;
-        let arr: [u8; 32] = bytes
-            .try_into()
-            .map_err(|_| hex::FromHexError::InvalidStringLength)?;
-        Ok(Hash(arr))
+    /// Wrap a raw 32-byte SHA-256 digest (no hashing; see `from_bytes` for that).
+    pub fn from_array(bytes: [u8; 32]) -> Self {
+        Hash(bytes, HashAlgorithm::Sha256)
+    }
+
+    /// Parse from a 64-character hex string, optionally prefixed with an
+    /// algorithm (`blake3:`).
+    pub fn from_hex(s: &str) -> crate::error::Result<Self> {
+        let (s, algorithm) = match s.strip_prefix(HashAlgorithm::Blake3.hex_prefix()) {
+            Some(rest) => (rest, HashAlgorithm::Blake3),
+            None => (s, HashAlgorithm::Sha256),
+        };
+        if s.len() != 64 {
+            return Err(crate::error::StoreError::InvalidFormat(format!(
+                "Hash hex must be 64 characters, got {}",
+                s.len()
+            )));
+        }
+        let mut arr = [0u8; 32];
+        hex::decode_to_slice(s, &mut arr).map_err(|e| {
+            crate::error::StoreError::InvalidFormat(format!("Invalid hash hex {:?}: {}", s, e))
+        })?;
+        Ok(Hash(arr, algorithm))
     }
 
     /// Get the first two characters of the hex (for sharding).
@@ -100,8 +146,48 @@ impl fmt::Display for Hash {
     }
 }
 
+impl FromStr for Hash {
+    type Err = crate::error::StoreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_hex(s)
+    }
+}
+
+// SHA-256 hashes serialize as before the algorithm tag existed, as bare
+// digests; others as (digest, algorithm).
+impl Serialize for Hash {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.1 {
+            HashAlgorithm::Sha256 => serializer.serialize_newtype_struct("Hash", &self.0),
+            algorithm => (self.0, algorithm).serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Hash {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Encoded {
+            Digest([u8; 32]),
+            Tagged([u8; 32], HashAlgorithm),
+        }
+        Ok(match Encoded::deserialize(deserializer)? {
+            Encoded::Digest(bytes) => Hash(bytes, HashAlgorithm::Sha256),
+            Encoded::Tagged(bytes, algorithm) => Hash(bytes, algorithm),
+        })
+    }
+}
+
+impl From<[u8; 32]> for Hash {
+    fn from(bytes: [u8; 32]) -> Self {
+        Self::from_array(bytes)
+    }
+}
+
 /// Microseconds since Unix epoch.
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Default)]
 pub struct Timestamp(pub i64);
 
 impl Timestamp {
@@ -121,16 +207,47 @@ impl fmt::Debug for Timestamp {
 }
 
 /// Payload encoding format.
-#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PayloadEncoding {
+    #[default]
     Json,
     MessagePack,
     Raw,
 }
 
-impl Default for PayloadEncoding {
-    fn default() -> Self {
-        PayloadEncoding::Json
+/// A payload field value usable as a secondary index key.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum FieldValue {
+    String(String),
+    Integer(i64),
+}
+
+impl FieldValue {
+    /// Convert a JSON value, if it is a string or an integer that fits in i64.
+    pub fn from_json(value: &serde_json::Value) -> Option<Self> {
+        match value {
+            serde_json::Value::String(s) => Some(FieldValue::String(s.clone())),
+            serde_json::Value::Number(n) => n.as_i64().map(FieldValue::Integer),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for FieldValue {
+    fn from(value: &str) -> Self {
+        FieldValue::String(value.to_string())
+    }
+}
+
+impl From<String> for FieldValue {
+    fn from(value: String) -> Self {
+        FieldValue::String(value)
+    }
+}
+
+impl From<i64> for FieldValue {
+    fn from(value: i64) -> Self {
+        FieldValue::Integer(value)
     }
 }
 
@@ -163,6 +280,48 @@ pub struct Record {
 
     /// Related records.
     pub linked_to: Vec<RecordId>,
+
+    /// Summary written by `Store::squash_records`: the records in
+    /// `linked_to` are replaced by this one in sequence order.
+    pub squash: bool,
+
+    /// When the record stops being visible (see `Store::expire_records`).
+    pub expires_at: Option<Timestamp>,
+
+    /// Lamport timestamp for ordering across replicas (see
+    /// `Store::current_lamport`). None for records written before the log
+    /// carried one.
+    #[serde(default)]
+    pub lamport: Option<u64>,
+
+    /// Key the record was appended under (see
+    /// `RecordInput::with_idempotency_key`).
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+
+    /// Blob describing the payload's type (see `RecordInput::with_schema`).
+    #[serde(default)]
+    pub schema: Option<Hash>,
+}
+
+impl Record {
+    /// Whether the record's TTL has passed at `now`.
+    pub fn is_expired(&self, now: Timestamp) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Deserialize the payload according to its stored encoding.
+    ///
+    /// Raw payloads are opaque and return `InvalidOperation`.
+    pub fn decode<T: DeserializeOwned>(&self) -> crate::error::Result<T> {
+        match self.encoding {
+            PayloadEncoding::Json => Ok(serde_json::from_slice(&self.payload)?),
+            PayloadEncoding::MessagePack => Ok(rmp_serde::from_slice(&self.payload)?),
+            PayloadEncoding::Raw => Err(crate::error::StoreError::InvalidOperation(
+                "Raw payloads cannot be decoded".into(),
+            )),
+        }
+    }
 }
 
 /// Input for creating a new record (before id/sequence assigned).
@@ -173,6 +332,18 @@ pub struct RecordInput {
     pub encoding: PayloadEncoding,
     pub caused_by: Vec<RecordId>,
     pub linked_to: Vec<RecordId>,
+    pub expires_at: Option<Timestamp>,
+    /// Lamport timestamp of a replicated record. None stamps the next tick
+    /// of the local clock.
+    pub lamport: Option<u64>,
+    /// Retry key: appending again under a key already in the store returns
+    /// the record first written with it.
+    pub idempotency_key: Option<String>,
+    /// Blob describing the payload's type.
+    pub schema: Option<Hash>,
+    /// Append without checking the payload against the schema registered
+    /// for its type.
+    pub skip_schema_validation: bool,
 }
 
 impl RecordInput {
@@ -184,9 +355,52 @@ impl RecordInput {
             encoding: PayloadEncoding::Json,
             caused_by: Vec::new(),
             linked_to: Vec::new(),
+            expires_at: None,
+            lamport: None,
+            idempotency_key: None,
+            schema: None,
+            skip_schema_validation: false,
+        })
+    }
+
+    /// Create a new record input with MessagePack payload.
+    pub fn msgpack(
+        record_type: impl Into<String>,
+        payload: &impl Serialize,
+    ) -> Result<Self, rmp_serde::encode::Error> {
+        Ok(Self {
+            record_type: record_type.into(),
+            payload: rmp_serde::to_vec_named(payload)?,
+            encoding: PayloadEncoding::MessagePack,
+            caused_by: Vec::new(),
+            linked_to: Vec::new(),
+            expires_at: None,
+            lamport: None,
+            idempotency_key: None,
+            schema: None,
+            skip_schema_validation: false,
         })
     }
 
+    /// Create a new record input with whichever of JSON or MessagePack
+    /// encodes the payload smaller (JSON on ties).
+    pub fn auto(
+        record_type: impl Into<String>,
+        payload: &impl Serialize,
+    ) -> crate::error::Result<Self> {
+        let json = Self::json(record_type, payload)?;
+        let packed = rmp_serde::to_vec_named(payload)?;
+        if packed.len() < json.payload.len() {
+            Ok(Self {
+                payload: packed,
+                encoding: PayloadEncoding::MessagePack,
+                ..json
+            })
+        } else {
+            Ok(json)
+        }
+    }
+
     /// Create a new record input with raw bytes.
     pub fn raw(record_type: impl Into<String>, payload: Vec<u8>) -> Self {
         Self {
@@ -195,9 +409,37 @@ impl RecordInput {
             encoding: PayloadEncoding::Raw,
             caused_by: Vec::new(),
             linked_to: Vec::new(),
+            expires_at: None,
+            lamport: None,
+            idempotency_key: None,
+            schema: None,
+            skip_schema_validation: false,
         }
     }
 
+    /// Set the payload encoding, for payloads encoded by the caller.
+    pub fn with_encoding(mut self, encoding: PayloadEncoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Reference the blob that describes the payload's type.
+    ///
+    /// The hash is kept with the record, so a reader can fetch the schema
+    /// with `Store::get_blob` and validate or interpret the payload without
+    /// knowing its type in advance. The blob isn't checked to exist.
+    pub fn with_schema(mut self, schema: Hash) -> Self {
+        self.schema = Some(schema);
+        self
+    }
+
+    /// Skip the check against the schema registered for the record type
+    /// (see `Store::register_record_schema`), for trusted bulk loads.
+    pub fn without_schema_validation(mut self) -> Self {
+        self.skip_schema_validation = true;
+        self
+    }
+
     /// Add caused_by links.
     pub fn with_caused_by(mut self, ids: Vec<RecordId>) -> Self {
         self.caused_by = ids;
@@ -209,6 +451,33 @@ impl RecordInput {
         self.linked_to = ids;
         self
     }
+
+    /// Expire the record at the given time.
+    pub fn with_expires_at(mut self, expires_at: Timestamp) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    /// Keep the Lamport timestamp a replicated record was written with.
+    ///
+    /// The store's clock moves up to it, so later local appends order
+    /// after the received record.
+    pub fn with_lamport(mut self, lamport: u64) -> Self {
+        self.lamport = Some(lamport);
+        self
+    }
+
+    /// Append under a retry key.
+    ///
+    /// If a record was already appended with `key`, `Store::append` returns
+    /// that record instead of writing another. Keys are global to the
+    /// store, not per branch, and are kept in the log, so they still match
+    /// after a reopen. A key stops matching once its record is expired or
+    /// compacted away.
+    pub fn with_idempotency_key(mut self, key: impl Into<String>) -> Self {
+        self.idempotency_key = Some(key.into());
+        self
+    }
 }
 
 /// Branch metadata.
@@ -230,10 +499,22 @@ pub struct Blob {
     pub content_type: String,
 }
 
+/// Blob metadata, readable without loading the content.
+#[derive(Clone, Debug)]
+pub struct BlobInfo {
+    pub hash: Hash,
+    /// Content size in bytes.
+    pub size: u64,
+    pub content_type: String,
+    /// When the blob was first written.
+    pub created: Timestamp,
+}
+
 /// How state is stored and reconstructed.
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 pub enum StateStrategy {
     /// Store full value on each change.
+    #[default]
     Snapshot,
 
     /// Store deltas with periodic snapshots.
@@ -254,15 +535,33 @@ pub enum StateStrategy {
         full_snapshot_every: u64,
     },
 
+    /// An `AppendLog` that keeps only its newest `capacity` items.
+    ///
+    /// Appending past capacity drops items from the front, so the state
+    /// never holds more than `capacity` items. Indices in `Redact` and
+    /// `Edit` count from the oldest item still held.
+    RingBuffer {
+        /// Maximum number of items kept.
+        capacity: usize,
+        /// Create a delta snapshot after this many operations.
+        delta_snapshot_every: u64,
+        /// Create a full snapshot after this many delta snapshots.
+        full_snapshot_every: u64,
+    },
+
     /// Nested structure with per-field strategies.
     Struct {
         fields: HashMap<String, Box<StateStrategy>>,
     },
 }
 
-impl Default for StateStrategy {
-    fn default() -> Self {
-        StateStrategy::Snapshot
+impl StateStrategy {
+    /// Item limit of a `RingBuffer` state; None for every other strategy.
+    pub fn capacity(&self) -> Option<usize> {
+        match self {
+            StateStrategy::RingBuffer { capacity, .. } => Some(*capacity),
+            _ => None,
+        }
     }
 }
 
@@ -272,12 +571,27 @@ pub enum StateOperation {
     /// Set entire value (Snapshot strategy).
     Set(Vec<u8>),
 
+    /// Set entire value only if the state's latest update on the current
+    /// branch is at `expected_head` (Snapshot strategy). Fails with
+    /// `ConcurrentModification` otherwise; `Sequence(0)` expects a state
+    /// with no updates yet. Written to the log as a plain `Set`.
+    SetIfSequence { expected_head: Sequence, value: Vec<u8> },
+
     /// Apply delta (Delta strategy).
     Delta { old_hash: Hash, new_value: Vec<u8> },
 
     /// Append to collection (AppendLog).
     Append(Vec<u8>),
 
+    /// Append several items in one update (AppendLog).
+    AppendMany(Vec<Vec<u8>>),
+
+    /// Insert an item at the front of the collection (AppendLog, RingBuffer),
+    /// shifting every existing item back by one. A full ring buffer drops
+    /// its last item to make room, so newest-first states keep their newest
+    /// items.
+    Prepend(Vec<u8>),
+
     /// Remove range from collection (AppendLog).
     Redact { start: usize, end: usize },
 
@@ -293,6 +607,11 @@ pub enum StateOperation {
     /// During reconstruction, delta snapshots are concatenated.
     DeltaSnapshot(Vec<u8>),
 
+    /// Empty the collection (AppendLog).
+    /// Reconstruction stops here like at a full snapshot, without storing
+    /// an empty array.
+    Clear,
+
     /// Update specific field (Struct strategy).
     Field {
         name: String,
@@ -333,6 +652,65 @@ pub struct StateRegistration {
     pub initial_value: Option<Vec<u8>>,
 }
 
+/// Position in a store's change feed (see `Store::changes_since`).
+///
+/// The default cursor starts from the beginning.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeCursor {
+    /// Highest record ID already returned.
+    pub record_id: u64,
+    /// Highest branch ID already returned.
+    pub branch_id: u64,
+    /// Creation time of the newest blob already returned.
+    pub blob_created: Timestamp,
+}
+
+/// Identifies a replicated record across stores: the `Store::node_id` of
+/// the store whose feed sent it and the record's ID there, which increases
+/// through the feed.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ChangeKey {
+    pub source: String,
+    pub seq: u64,
+}
+
+/// A mutation in a store's change feed, replayable on another store with
+/// `Store::apply_changes`.
+///
+/// Branches are named rather than referenced by ID, since IDs differ
+/// between stores that create their own branches.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Change {
+    /// A blob was stored.
+    StoreBlob { content: Vec<u8>, content_type: String },
+    /// A branch was created from `parent` at sequence `at`.
+    CreateBranch { name: String, parent: String, at: Sequence },
+    /// A state was registered. Sent ahead of its first update in a batch.
+    RegisterState { registration: StateRegistration },
+    /// A record was appended to `branch` at `record.sequence`.
+    Append { key: ChangeKey, branch: String, record: Record },
+    /// A state was updated on `branch` at `sequence`.
+    UpdateState {
+        key: ChangeKey,
+        branch: String,
+        sequence: Sequence,
+        state_id: String,
+        operation: StateOperation,
+        lamport: Option<u64>,
+    },
+}
+
+impl Change {
+    /// The dedup key of a record or state update; None for the other
+    /// changes, which are idempotent by content or name.
+    pub fn key(&self) -> Option<&ChangeKey> {
+        match self {
+            Change::Append { key, .. } | Change::UpdateState { key, .. } => Some(key),
+            _ => None,
+        }
+    }
+}
+
 /// Store statistics.
 #[derive(Clone, Debug, Default)]
 pub struct StoreStats {
@@ -342,7 +720,22 @@ pub struct StoreStats {
     pub state_slot_count: u64,
     pub total_size_bytes: u64,
     pub blob_size_bytes: u64,
+    /// Estimated in-memory footprint of the record indices.
     pub index_size_bytes: u64,
+    /// Per-state chain sizes on the current branch.
+    pub state_breakdown: Vec<StateSizeInfo>,
+    /// Fraction of blob reads served from cache (None before any reads).
+    pub blob_cache_hit_rate: Option<f64>,
+}
+
+/// Size of a single state's chain.
+#[derive(Clone, Debug, Default)]
+pub struct StateSizeInfo {
+    pub state_id: String,
+    /// Number of update records in the chain.
+    pub operation_count: u64,
+    /// Payload bytes of those records in the log.
+    pub bytes: u64,
 }
 
 #[cfg(test)]
@@ -358,6 +751,50 @@ mod tests {
         assert_eq!(hash, parsed);
     }
 
+    #[test]
+    fn test_hash_parse_errors() {
+        let hash = Hash::from_array([0xab; 32]);
+        assert_eq!(hash.to_string().parse::<Hash>().unwrap(), hash);
+        assert_eq!(Hash::from([0xab; 32]), hash);
+
+        // Too short
+        assert!(matches!(
+            Hash::from_hex("abcd"),
+            Err(crate::error::StoreError::InvalidFormat(_))
+        ));
+
+        // Right length, but not hex
+        let bad = format!("{}zz", "0".repeat(62));
+        assert!(matches!(
+            bad.parse::<Hash>(),
+            Err(crate::error::StoreError::InvalidFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_hash_algorithm_tag() {
+        let sha = Hash::from_bytes(b"data");
+        let blake = HashAlgorithm::Blake3.digest(b"data");
+        assert_ne!(sha.0, blake.0);
+        assert_eq!(blake.0, *blake3::hash(b"data").as_bytes());
+
+        let hex = blake.to_hex();
+        assert!(hex.starts_with("blake3:"));
+        assert_eq!(hex.len(), 7 + 64);
+        assert_eq!(Hash::from_hex(&hex).unwrap(), blake);
+        assert_ne!(Hash::from_hex(&hex[7..]).unwrap(), blake);
+
+        // SHA-256 keeps its old encoding; both read back tagged
+        let encoded = serde_json::to_string(&sha).unwrap();
+        assert_eq!(encoded, serde_json::to_string(&sha.0).unwrap());
+        for hash in [sha, blake] {
+            let encoded = rmp_serde::to_vec(&hash).unwrap();
+            assert_eq!(rmp_serde::from_slice::<Hash>(&encoded).unwrap(), hash);
+            let encoded = serde_json::to_vec(&hash).unwrap();
+            assert_eq!(serde_json::from_slice::<Hash>(&encoded).unwrap(), hash);
+        }
+    }
+
     #[test]
     fn test_hash_shard_prefix() {
         let hash = Hash::from_bytes(b"test");
@@ -391,4 +828,16 @@ mod tests {
         assert_eq!(input.record_type, "test");
         assert_eq!(input.encoding, PayloadEncoding::Json);
     }
+
+    #[test]
+    fn test_record_input_auto() {
+        // Numeric arrays pack much tighter than their JSON text
+        let numbers: Vec<u32> = (100_000..100_100).collect();
+        let input = RecordInput::auto("numbers", &numbers).unwrap();
+        assert_eq!(input.encoding, PayloadEncoding::MessagePack);
+
+        // A small integer is one byte either way, so JSON wins the tie
+        let input = RecordInput::auto("count", &5u8).unwrap();
+        assert_eq!(input.encoding, PayloadEncoding::Json);
+    }
 }
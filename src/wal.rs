@@ -1,15 +1,25 @@
 //! Write-Ahead Log for crash recovery.
 //!
-//! The WAL ensures durability by writing operations to a separate log
-//! before they are committed to the main store. On recovery, uncommitted
-//! operations can be replayed.
+//! Every mutation is written here before it is applied to the main store,
+//! then marked `Committed` once applied. Committed entries stay until a
+//! checkpoint confirms the store has persisted them. On recovery, committed
+//! entries whose effect didn't reach the store are replayed; pending ones
+//! were interrupted before they finished and are discarded.
+//!
+//! Entries are fsynced as the `SyncPolicy` the WAL was opened with says,
+//! like the record log. Each entry carries a full copy of what it
+//! journals (record payloads, blob contents), so the WAL grows about as
+//! fast as the data written until a checkpoint trims it.
 
 use crate::error::{Result, StoreError};
+use crate::records::SyncPolicy;
+use crate::types::{Hash, PayloadEncoding, RecordId, Timestamp};
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 /// Magic bytes for WAL file.
 const WAL_MAGIC: &[u8; 4] = b"WAL\0";
@@ -17,14 +27,18 @@ const WAL_MAGIC: &[u8; 4] = b"WAL\0";
 /// Current WAL format version.
 const WAL_VERSION: u8 = 1;
 
+/// Magic plus version byte.
+const WAL_HEADER_LEN: u64 = 5;
+
 /// WAL entry status.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum WalEntryStatus {
-    /// Entry has been written but not yet committed.
+    /// Entry has been written but its operation hasn't finished.
     Pending,
-    /// Entry has been committed to the main store.
+    /// Entry's operation has been applied to the main store, which may not
+    /// have persisted it yet.
     Committed,
-    /// Entry was rolled back (not used, but reserved).
+    /// Entry was rolled back and must not be replayed.
     RolledBack,
 }
 
@@ -41,6 +55,15 @@ pub struct WalEntry {
     pub timestamp: u64,
 }
 
+/// A `WalEntry` borrowing its operation, written in the same format.
+#[derive(Serialize)]
+struct WalEntryRef<'a> {
+    seq: u64,
+    status: WalEntryStatus,
+    operation: &'a WalOperation,
+    timestamp: u64,
+}
+
 /// Operations that can be recorded in the WAL.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum WalOperation {
@@ -48,22 +71,90 @@ pub enum WalOperation {
     AppendRecord {
         record_type: String,
         payload: Vec<u8>,
+        /// Where the record goes and the rest of its input. None in status
+        /// markers.
+        #[serde(default)]
+        record: Option<JournaledRecord>,
     },
     /// Update a state.
     UpdateState {
         state_id: String,
         operation_data: Vec<u8>, // Serialized StateOperation
+        /// Branch the update goes on.
+        #[serde(default)]
+        branch: String,
+        /// Sequence the update takes on `branch`.
+        #[serde(default)]
+        sequence: u64,
+        /// Lamport timestamp of a replicated update.
+        #[serde(default)]
+        lamport: Option<u64>,
     },
     /// Store a blob.
     StoreBlob {
         content: Vec<u8>,
         content_type: String,
+        /// Stored with `store_chunked`.
+        #[serde(default)]
+        chunked: bool,
     },
     /// Create a branch.
     CreateBranch {
         name: String,
         from: Option<String>,
+        /// Parent head the branch was created at, so a replay branches from
+        /// the same point even if the parent has moved on.
+        #[serde(default)]
+        at: Option<u64>,
+        /// Created without copying the parent's state.
+        #[serde(default)]
+        empty: bool,
+    },
+    /// Switch the current branch.
+    SwitchBranch {
+        name: String,
+    },
+    /// Delete a branch.
+    DeleteBranch {
+        name: String,
+    },
+    /// Apply several operations to one state as consecutive updates.
+    UpdateStateBatch {
+        state_id: String,
+        /// Serialized StateOperations, in order.
+        operations: Vec<Vec<u8>>,
+        /// Branch the updates go on.
+        branch: String,
+        /// Sequence the first update takes on `branch`.
+        sequence: u64,
     },
+    /// Discard a branch's records after `keep_through`.
+    TruncateBranch {
+        name: String,
+        keep_through: u64,
+        /// First record the truncation removes; while it's still in the
+        /// log, the truncation hasn't happened.
+        first_removed: Option<RecordId>,
+    },
+}
+
+/// Where a journaled record goes, and the parts of its input besides the
+/// type and payload, so recovery can append it again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JournaledRecord {
+    pub branch: String,
+    /// Sequence the record takes on `branch`; a replay skips it if the log
+    /// already has a record there.
+    pub sequence: u64,
+    pub encoding: PayloadEncoding,
+    pub caused_by: Vec<RecordId>,
+    pub linked_to: Vec<RecordId>,
+    pub expires_at: Option<Timestamp>,
+    pub lamport: Option<u64>,
+    pub idempotency_key: Option<String>,
+    pub schema: Option<Hash>,
+    /// Appended as a squash summary.
+    pub squash: bool,
 }
 
 /// Write-Ahead Log manager.
@@ -74,11 +165,21 @@ pub struct WriteAheadLog {
     next_seq: Mutex<u64>,
     /// Write handle.
     writer: Mutex<Option<BufWriter<File>>>,
+    /// When entries and markers are fsynced.
+    sync_policy: SyncPolicy,
+    /// Entries and markers written since the last fsync, and when that was.
+    unsynced: Mutex<(u64, Instant)>,
 }
 
 impl WriteAheadLog {
-    /// Create or open a WAL file.
+    /// Create or open a WAL file that fsyncs every entry and marker.
     pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::open_with_sync_policy(path, SyncPolicy::EveryWrite)
+    }
+
+    /// Create or open a WAL file, fsyncing entries and markers as
+    /// `sync_policy` says.
+    pub fn open_with_sync_policy(path: impl AsRef<Path>, sync_policy: SyncPolicy) -> Result<Self> {
         let path = path.as_ref().to_path_buf();
 
         let (next_seq, writer) = if path.exists() {
@@ -132,16 +233,24 @@ impl WriteAheadLog {
             path,
             next_seq: Mutex::new(next_seq),
             writer: Mutex::new(writer),
+            sync_policy,
+            unsynced: Mutex::new((0, Instant::now())),
         })
     }
 
     /// Log an operation (returns sequence number).
     pub fn log(&self, operation: WalOperation) -> Result<u64> {
+        self.log_ref(&operation)
+    }
+
+    /// `log` for an operation the caller keeps, so its data needn't be
+    /// copied into the entry.
+    pub fn log_ref(&self, operation: &WalOperation) -> Result<u64> {
         let mut next_seq = self.next_seq.lock();
         let seq = *next_seq;
         *next_seq += 1;
 
-        let entry = WalEntry {
+        let entry = WalEntryRef {
             seq,
             status: WalEntryStatus::Pending,
             operation,
@@ -155,8 +264,7 @@ impl WriteAheadLog {
         if let Some(ref mut w) = *writer {
             Self::write_entry(w, &entry)?;
             w.flush()?;
-            // fsync for durability
-            w.get_ref().sync_all()?;
+            self.sync_if_due(w)?;
         }
 
         Ok(seq)
@@ -164,16 +272,22 @@ impl WriteAheadLog {
 
     /// Mark an entry as committed.
     pub fn commit(&self, seq: u64) -> Result<()> {
-        // For simplicity, we'll write a new commit marker entry
+        self.write_marker(seq, WalEntryStatus::Committed)
+    }
+
+    /// Append a status marker for an earlier entry.
+    fn write_marker(&self, seq: u64, status: WalEntryStatus) -> Result<()> {
+        // For simplicity, we'll write a new marker entry
         // A more sophisticated implementation would update in place
         let mut writer = self.writer.lock();
         if let Some(ref mut w) = *writer {
             let marker = WalEntry {
                 seq,
-                status: WalEntryStatus::Committed,
+                status,
                 operation: WalOperation::AppendRecord {
                     record_type: "_commit".to_string(),
                     payload: vec![],
+                    record: None,
                 },
                 timestamp: std::time::SystemTime::now()
                     .duration_since(std::time::UNIX_EPOCH)
@@ -181,40 +295,84 @@ impl WriteAheadLog {
                     .as_secs(),
             };
             Self::write_entry(w, &marker)?;
+            w.flush()?;
+            self.sync_if_due(w)?;
+        }
+        Ok(())
+    }
+
+    /// Count a write and fsync if the sync policy says it's time.
+    fn sync_if_due(&self, writer: &BufWriter<File>) -> Result<()> {
+        let mut unsynced = self.unsynced.lock();
+        unsynced.0 += 1;
+        let due = match self.sync_policy {
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::EveryN(n) => unsynced.0 >= n,
+            SyncPolicy::Interval(interval) => unsynced.1.elapsed() >= interval,
+        };
+        if due {
+            writer.get_ref().sync_all()?;
+            *unsynced = (0, Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Fsync everything written so far.
+    pub fn sync(&self) -> Result<()> {
+        let mut writer = self.writer.lock();
+        if let Some(ref mut w) = *writer {
             w.flush()?;
             w.get_ref().sync_all()?;
         }
+        *self.unsynced.lock() = (0, Instant::now());
         Ok(())
     }
 
-    /// Get all pending (uncommitted) entries.
+    /// Mark an entry as rolled back: it was not applied and must not be
+    /// replayed.
+    pub fn rollback(&self, seq: u64) -> Result<()> {
+        self.write_marker(seq, WalEntryStatus::RolledBack)
+    }
+
+    /// Get all pending (unfinished) entries.
     pub fn get_pending_entries(&self) -> Result<Vec<WalEntry>> {
+        let mut entries = self.get_unfinished_entries()?;
+        entries.retain(|entry| entry.status == WalEntryStatus::Pending);
+        Ok(entries)
+    }
+
+    /// Get the entries still in the WAL that weren't rolled back, pending
+    /// or committed, oldest first. Each carries its latest status.
+    pub fn get_unfinished_entries(&self) -> Result<Vec<WalEntry>> {
         let mut file = File::open(&self.path)?;
 
         // Skip header
-        file.seek(SeekFrom::Start(5))?;
+        file.seek(SeekFrom::Start(WAL_HEADER_LEN))?;
 
         let mut reader = BufReader::new(file);
-        let mut entries = std::collections::HashMap::new();
-        let mut committed = std::collections::HashSet::new();
+        let mut entries: std::collections::HashMap<u64, WalEntry> = std::collections::HashMap::new();
 
-        // Read all entries
+        // Read all entries; a marker updates the status of its entry, and
+        // an entry a checkpoint kept is written with its status
         while let Ok(entry) = Self::read_entry(&mut reader) {
-            if entry.status == WalEntryStatus::Committed {
-                committed.insert(entry.seq);
-            } else if entry.status == WalEntryStatus::Pending {
-                entries.insert(entry.seq, entry);
+            match entries.get_mut(&entry.seq) {
+                Some(existing) if entry.status != WalEntryStatus::Pending => {
+                    existing.status = entry.status;
+                }
+                _ => {
+                    entries.insert(entry.seq, entry);
+                }
             }
         }
 
-        // Filter out committed entries
-        let pending: Vec<_> = entries
-            .into_iter()
-            .filter(|(seq, _)| !committed.contains(seq))
-            .map(|(_, entry)| entry)
+        // Filter out rolled back entries, oldest first
+        let mut unfinished: Vec<_> = entries
+            .into_values()
+            .filter(|entry| entry.status != WalEntryStatus::RolledBack)
             .collect();
+        unfinished.sort_by_key(|entry| entry.seq);
 
-        Ok(pending)
+        Ok(unfinished)
     }
 
     /// Clear the WAL (called after successful checkpoint).
@@ -241,12 +399,58 @@ impl WriteAheadLog {
         Ok(())
     }
 
+    /// Drop rolled back entries and the ones in `persisted`, whose effect
+    /// the store has saved, keeping the rest.
+    ///
+    /// The surviving entries are written to a new file that atomically
+    /// replaces the old one, so a crash during a checkpoint leaves either
+    /// the full old WAL or the compacted one, never a mix. Surviving entries
+    /// keep their sequence numbers and status, and new entries continue
+    /// after them.
+    pub fn checkpoint(&self, persisted: &[u64]) -> Result<()> {
+        // Hold the writer so no entry lands in the file being replaced
+        let mut writer = self.writer.lock();
+        if let Some(ref mut w) = *writer {
+            w.flush()?;
+        }
+        if self.size()? <= WAL_HEADER_LEN {
+            return Ok(());
+        }
+
+        let persisted: std::collections::HashSet<u64> = persisted.iter().copied().collect();
+        let mut surviving = self.get_unfinished_entries()?;
+        surviving.retain(|entry| !persisted.contains(&entry.seq));
+        let tmp_path = self.path.with_extension("wal.tmp");
+        {
+            let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+            tmp.write_all(WAL_MAGIC)?;
+            tmp.write_all(&[WAL_VERSION])?;
+            for entry in &surviving {
+                Self::write_entry(&mut tmp, entry)?;
+            }
+            tmp.flush()?;
+            tmp.get_ref().sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.path)?;
+
+        *writer = Some(BufWriter::new(
+            OpenOptions::new().append(true).open(&self.path)?,
+        ));
+        *self.unsynced.lock() = (0, Instant::now());
+        Ok(())
+    }
+
+    /// Current size of the WAL file in bytes.
+    pub fn size(&self) -> Result<u64> {
+        Ok(fs::metadata(&self.path)?.len())
+    }
+
     /// Check if WAL has any pending entries.
     pub fn has_pending(&self) -> Result<bool> {
         Ok(!self.get_pending_entries()?.is_empty())
     }
 
-    fn write_entry(writer: &mut BufWriter<File>, entry: &WalEntry) -> Result<()> {
+    fn write_entry(writer: &mut BufWriter<File>, entry: &impl Serialize) -> Result<()> {
         let encoded =
             rmp_serde::to_vec(entry).map_err(|e| StoreError::Serialization(e.to_string()))?;
 
@@ -303,6 +507,7 @@ mod tests {
             .log(WalOperation::AppendRecord {
                 record_type: "test".to_string(),
                 payload: b"hello".to_vec(),
+                record: None,
             })
             .unwrap();
 
@@ -331,6 +536,7 @@ mod tests {
             .log(WalOperation::AppendRecord {
                 record_type: "test1".to_string(),
                 payload: b"one".to_vec(),
+                record: None,
             })
             .unwrap();
 
@@ -338,6 +544,7 @@ mod tests {
             .log(WalOperation::AppendRecord {
                 record_type: "test2".to_string(),
                 payload: b"two".to_vec(),
+                record: None,
             })
             .unwrap();
 
@@ -345,6 +552,7 @@ mod tests {
             .log(WalOperation::AppendRecord {
                 record_type: "test3".to_string(),
                 payload: b"three".to_vec(),
+                record: None,
             })
             .unwrap();
 
@@ -373,6 +581,7 @@ mod tests {
             wal.log(WalOperation::StoreBlob {
                 content: b"blob content".to_vec(),
                 content_type: "text/plain".to_string(),
+                chunked: false,
             })
             .unwrap();
             // Drop without committing
@@ -401,6 +610,7 @@ mod tests {
         wal.log(WalOperation::AppendRecord {
             record_type: "test".to_string(),
             payload: vec![],
+            record: None,
         })
         .unwrap();
 
@@ -416,10 +626,92 @@ mod tests {
             .log(WalOperation::AppendRecord {
                 record_type: "after_clear".to_string(),
                 payload: vec![],
+                record: None,
             })
             .unwrap();
 
         // Sequence should reset
         assert_eq!(seq, 1);
     }
+
+    #[test]
+    fn test_wal_sync_policy() {
+        let dir = TempDir::new().unwrap();
+        let wal = WriteAheadLog::open_with_sync_policy(dir.path().join("test.wal"), SyncPolicy::EveryN(3)).unwrap();
+        let branch = |name: &str| WalOperation::CreateBranch {
+            name: name.to_string(),
+            from: None,
+            at: None,
+            empty: false,
+        };
+
+        // An entry and its marker count as two writes
+        let seq = wal.log(branch("a")).unwrap();
+        wal.commit(seq).unwrap();
+        assert_eq!(wal.unsynced.lock().0, 2);
+        wal.log(branch("b")).unwrap();
+        assert_eq!(wal.unsynced.lock().0, 0);
+
+        wal.log(branch("c")).unwrap();
+        wal.sync().unwrap();
+        assert_eq!(wal.unsynced.lock().0, 0);
+        assert_eq!(wal.get_unfinished_entries().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_wal_checkpoint() {
+        let dir = TempDir::new().unwrap();
+        let wal_path = dir.path().join("test.wal");
+        let wal = WriteAheadLog::open(&wal_path).unwrap();
+
+        for i in 0..100u64 {
+            let seq = wal
+                .log(WalOperation::AppendRecord {
+                    record_type: "test".to_string(),
+                    payload: i.to_le_bytes().to_vec(),
+                    record: None,
+                })
+                .unwrap();
+            // Leave two entries pending
+            if i != 10 && i != 90 {
+                wal.commit(seq).unwrap();
+            }
+        }
+        let before = wal.size().unwrap();
+
+        // Entry 51 is applied but not yet persisted by the store
+        let persisted: Vec<u64> = (1..=100).filter(|seq| ![11, 51, 91].contains(seq)).collect();
+        wal.checkpoint(&persisted).unwrap();
+        assert!(wal.size().unwrap() < before / 20);
+        let pending = wal.get_pending_entries().unwrap();
+        assert_eq!(pending.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![11, 91]);
+        let unfinished = wal.get_unfinished_entries().unwrap();
+        assert_eq!(unfinished.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![11, 51, 91]);
+        assert_eq!(unfinished[1].status, WalEntryStatus::Committed);
+
+        // Writes continue after the surviving entries, also after reopening
+        let seq = wal.log(WalOperation::SwitchBranch { name: "main".to_string() }).unwrap();
+        assert_eq!(seq, 101);
+        wal.commit(11).unwrap();
+        drop(wal);
+
+        let wal = WriteAheadLog::open(&wal_path).unwrap();
+        let pending = wal.get_pending_entries().unwrap();
+        assert_eq!(pending.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![91, 101]);
+        let unfinished = wal.get_unfinished_entries().unwrap();
+        assert_eq!(unfinished.iter().map(|e| e.seq).collect::<Vec<_>>(), vec![11, 51, 91, 101]);
+        assert_eq!(
+            wal.log(WalOperation::SwitchBranch { name: "main".to_string() }).unwrap(),
+            102
+        );
+
+        // Rolled back entries go without being listed
+        wal.rollback(102).unwrap();
+        for seq in [91, 101] {
+            wal.commit(seq).unwrap();
+        }
+        wal.checkpoint(&[11, 51, 91, 101]).unwrap();
+        assert_eq!(wal.size().unwrap(), WAL_HEADER_LEN);
+        assert!(wal.get_unfinished_entries().unwrap().is_empty());
+    }
 }
@@ -365,6 +365,126 @@ console.log('  Elapsed time:', elapsed, 'ms (expected ~50ms)');
 store2.unsubscribe(subId3);
 console.log('✓ pollSubscriptionTimeout works correctly');
 
+// Test 41: Partial reads of a large AppendLog state
+console.log('\n41. Testing state tail/slice/iteration on 1000 items...');
+store2.registerState({
+  id: 'big',
+  strategy: 'append_log',
+  deltaSnapshotEvery: 50,
+  fullSnapshotEvery: 5
+});
+for (let i = 0; i < 1000; i++) {
+  store2.appendToStateJson('big', { n: i });
+}
+if (store2.getStateLen('big') !== 1000) {
+  throw new Error('getStateLen should be 1000');
+}
+const bigTail = store2.getStateTailJson('big', 3);
+if (JSON.stringify(bigTail) !== JSON.stringify([{ n: 997 }, { n: 998 }, { n: 999 }])) {
+  throw new Error('Unexpected tail: ' + JSON.stringify(bigTail));
+}
+const bigSlice = JSON.parse(store2.getStateSlice('big', 500, 2).toString());
+if (bigSlice[0].n !== 500 || bigSlice[1].n !== 501 || bigSlice.length !== 2) {
+  throw new Error('Unexpected slice: ' + JSON.stringify(bigSlice));
+}
+if (store2.getStateSliceJson('big', 990, 100).length !== 10) {
+  throw new Error('Slice past the end should be clamped');
+}
+const iter = store2.iterStateItems('big');
+let iterated = 0;
+for (let batch = iter.nextBatch(128); batch.length > 0; batch = iter.nextBatch(128)) {
+  for (const item of batch) {
+    if (item.n !== iterated) {
+      throw new Error('Iterator out of order at ' + iterated);
+    }
+    iterated++;
+  }
+}
+if (iterated !== 1000 || iter.nextItem() !== null) {
+  throw new Error('Iterator should yield 1000 items then null');
+}
+let negativeRejected = false;
+try {
+  store2.getStateTail('big', -1);
+} catch (e) {
+  negativeRejected = true;
+}
+if (!negativeRejected) {
+  throw new Error('Negative counts should be rejected');
+}
+console.log('✓ Tail, slice and batched iteration match');
+
+// Test 42: Callback subscriptions
+console.log('\n42. Testing subscribeWithCallback...');
+const callbackEvents = [];
+const callbackSubId = store2.subscribeWithCallback(
+  { filter: { recordTypes: ['test.callback'], includeRecords: true } },
+  (event) => callbackEvents.push(event)
+);
+store2.catchUpSubscription(callbackSubId);
+store2.appendJson('test.callback', { n: 1 });
+store2.appendJson('test.other', { n: 2 });
+store2.appendJson('test.callback', { n: 3 });
+const waitFor = async (done) => {
+  for (let i = 0; i < 100 && !done(); i++) {
+    await new Promise((resolve) => setTimeout(resolve, 10));
+  }
+};
+await waitFor(() => callbackEvents.filter((e) => e.eventType === 'record').length === 2);
+const forwarded = callbackEvents
+  .filter((e) => e.eventType === 'record')
+  .map((e) => JSON.parse(e.data).record.record_type);
+if (JSON.stringify(forwarded) !== JSON.stringify(['test.callback', 'test.callback'])) {
+  throw new Error('Unexpected forwarded records: ' + JSON.stringify(forwarded));
+}
+store2.unsubscribe(callbackSubId);
+await waitFor(() => callbackEvents.some((e) => e.eventType === 'dropped'));
+if (callbackEvents[callbackEvents.length - 1].eventType !== 'dropped') {
+  throw new Error('Last callback event should be dropped');
+}
+const countAfterUnsubscribe = callbackEvents.length;
+store2.appendJson('test.callback', { n: 4 });
+await new Promise((resolve) => setTimeout(resolve, 50));
+if (callbackEvents.length !== countAfterUnsubscribe) {
+  throw new Error('Callback should not be called after unsubscribe');
+}
+console.log('✓ Callback received', forwarded.length, 'records, then the drop');
+
+// Test 43: Clearing an AppendLog state
+console.log('\n43. Testing clearState...');
+const clearRecord = store2.clearState('big');
+store2.appendToStateJson('big', { n: 'fresh' });
+if (store2.getStateLen('big') !== 1) {
+  throw new Error('getStateLen should be 1 after clear and append');
+}
+if (JSON.stringify(store2.getStateJson('big')) !== JSON.stringify([{ n: 'fresh' }])) {
+  throw new Error('Unexpected state after clear: ' + JSON.stringify(store2.getStateJson('big')));
+}
+if (JSON.parse(store2.getStateAt('big', clearRecord.sequence).toString()).length !== 0) {
+  throw new Error('State at the clear should be empty');
+}
+console.log('✓ clearState empties the state');
+
+// Test 44: RingBuffer state keeps only its newest items
+console.log('\n44. Testing ring_buffer strategy...');
+store2.registerState({
+  id: 'recent',
+  strategy: 'ring_buffer',
+  capacity: 5,
+  deltaSnapshotEvery: 3,
+  fullSnapshotEvery: 2,
+});
+for (let i = 0; i < 20; i++) {
+  store2.appendToStateJson('recent', i);
+}
+if (store2.getStateLen('recent') !== 5) {
+  throw new Error('Ring buffer should hold 5 items, got ' + store2.getStateLen('recent'));
+}
+if (JSON.stringify(store2.getStateJson('recent')) !== JSON.stringify([15, 16, 17, 18, 19])) {
+  throw new Error('Unexpected ring buffer contents: ' + JSON.stringify(store2.getStateJson('recent')));
+}
+console.log('✓ Ring buffer holds the last 5 of 20 items');
+
 store2.close();
 
 console.log('\n✅ All tests passed!');
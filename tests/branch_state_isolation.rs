@@ -8,7 +8,7 @@
 //! 5. Empty branches work correctly
 
 use chronicle::{
-    StateOperation, StateRegistration, StateStrategy, Store, StoreConfig,
+    StateOperation, StateRegistration, StateStrategy, Store, StoreConfig, StoreError,
 };
 use tempfile::TempDir;
 
@@ -17,6 +17,7 @@ fn test_store(dir: &TempDir) -> Store {
         path: dir.path().join("store"),
         blob_cache_size: 100,
         create_if_missing: true,
+        ..Default::default()
     })
     .unwrap()
 }
@@ -26,6 +27,7 @@ fn open_store(dir: &TempDir) -> Store {
         path: dir.path().join("store"),
         blob_cache_size: 100,
         create_if_missing: false,
+        ..Default::default()
     })
     .unwrap()
 }
@@ -1396,3 +1398,83 @@ fn test_empty_state_on_branches() {
     let state = store.get_state("optional").unwrap();
     assert!(state.is_none());
 }
+
+// =============================================================================
+// INITIAL VALUE TESTS
+// =============================================================================
+
+#[test]
+fn test_initial_value_visible_without_update() {
+    let dir = TempDir::new().unwrap();
+    {
+        let store = test_store(&dir);
+        store
+            .register_state(StateRegistration {
+                id: "config".to_string(),
+                strategy: StateStrategy::Snapshot,
+                initial_value: Some(br#"{"theme":"dark"}"#.to_vec()),
+            })
+            .unwrap();
+
+        let state = store.get_state("config").unwrap().unwrap();
+        assert_eq!(state, br#"{"theme":"dark"}"#);
+        store.sync().unwrap();
+    }
+
+    let store = open_store(&dir);
+    let state = store.get_state("config").unwrap().unwrap();
+    assert_eq!(state, br#"{"theme":"dark"}"#);
+
+    // Updates replace the seeded value as usual
+    store
+        .update_state("config", StateOperation::Set(br#"{"theme":"light"}"#.to_vec()))
+        .unwrap();
+    let state = store.get_state("config").unwrap().unwrap();
+    assert_eq!(state, br#"{"theme":"light"}"#);
+}
+
+#[test]
+fn test_initial_value_append_log_is_branch_isolated() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+
+    store.create_empty_branch("blank", None).unwrap();
+    store
+        .register_state(StateRegistration {
+            id: "messages".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 10, full_snapshot_every: 50 },
+            initial_value: Some(br#"["a","b"]"#.to_vec()),
+        })
+        .unwrap();
+    assert_eq!(store.get_state_len("messages").unwrap(), Some(2));
+
+    store.update_state("messages", StateOperation::Append(b"\"c\"".to_vec())).unwrap();
+    let messages: Vec<String> =
+        serde_json::from_slice(&store.get_state("messages").unwrap().unwrap()).unwrap();
+    assert_eq!(messages, vec!["a", "b", "c"]);
+
+    // A seeded state can't be seeded again
+    let result = store.set_initial_value("messages", b"[]".to_vec());
+    assert!(matches!(result, Err(StoreError::InvalidOperation(_))));
+
+    // A branch created before registration has no value, and can be seeded separately
+    store.switch_branch("blank").unwrap();
+    assert!(store.get_state("messages").unwrap().is_none());
+    store.set_initial_value("messages", br#"["x"]"#.to_vec()).unwrap();
+    assert_eq!(store.get_state_len("messages").unwrap(), Some(1));
+
+    store.switch_branch("main").unwrap();
+    assert_eq!(store.get_state_len("messages").unwrap(), Some(3));
+
+    // AppendLog initial values must be arrays
+    let result = store.register_state(StateRegistration {
+        id: "bad".to_string(),
+        strategy: StateStrategy::AppendLog { delta_snapshot_every: 10, full_snapshot_every: 50 },
+        initial_value: Some(b"42".to_vec()),
+    });
+    assert!(matches!(result, Err(StoreError::InvalidOperation(_))));
+    assert!(matches!(
+        store.set_initial_value("missing", b"1".to_vec()),
+        Err(StoreError::StateNotRegistered(_))
+    ));
+}
@@ -1,7 +1,8 @@
 //! Error handling and edge case tests.
 
 use chronicle::{
-    RecordId, StateOperation, StateRegistration, StateStrategy, Store, StoreConfig, StoreError,
+    RecordId, RecordInput, RepairOptions, Sequence, StateOperation, StateRegistration, StateStrategy, Store,
+    StoreConfig, StoreError, SyncPolicy, VerifyLocation, VerifyOptions,
 };
 use tempfile::TempDir;
 
@@ -10,6 +11,7 @@ fn test_store(dir: &TempDir) -> Store {
         path: dir.path().join("store"),
         blob_cache_size: 100,
         create_if_missing: true,
+        ..Default::default()
     })
     .unwrap()
 }
@@ -30,22 +32,51 @@ fn test_get_unregistered_state() {
 fn test_register_duplicate_state() {
     let dir = TempDir::new().unwrap();
     let store = test_store(&dir);
+    let registration = StateRegistration {
+        id: "test".to_string(),
+        strategy: StateStrategy::Snapshot,
+        initial_value: Some(b"1".to_vec()),
+    };
 
+    assert!(!store.is_state_registered("test"));
+    store.register_state(registration.clone()).unwrap();
+    assert!(store.is_state_registered("test"));
     store
-        .register_state(StateRegistration {
-            id: "test".to_string(),
-            strategy: StateStrategy::Snapshot,
+        .update_state("test", StateOperation::Set(b"2".to_vec()))
+        .unwrap();
+
+    // Same strategy again is a no-op; the initial value isn't rewritten
+    store.register_state(registration).unwrap();
+    assert_eq!(store.get_state("test").unwrap(), Some(b"2".to_vec()));
+}
+
+#[test]
+fn test_register_conflicting_strategy() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    let register = |strategy| {
+        store.register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy,
             initial_value: None,
         })
-        .unwrap();
+    };
+    let append_log = StateStrategy::AppendLog {
+        delta_snapshot_every: 10,
+        full_snapshot_every: 5,
+    };
 
-    let result = store.register_state(StateRegistration {
-        id: "test".to_string(),
-        strategy: StateStrategy::Snapshot,
-        initial_value: None,
-    });
+    // Without data the strategy can still change
+    register(StateStrategy::Snapshot).unwrap();
+    register(append_log.clone()).unwrap();
+    store
+        .update_state("items", StateOperation::Append(b"1".to_vec()))
+        .unwrap();
 
-    assert!(matches!(result, Err(StoreError::StateExists(_))));
+    let result = register(StateStrategy::Snapshot);
+    assert!(matches!(result, Err(StoreError::InvalidOperation(_))));
+    register(append_log).unwrap();
+    assert_eq!(store.get_state("items").unwrap(), Some(b"[1]".to_vec()));
 }
 
 #[test]
@@ -83,6 +114,112 @@ fn test_edit_out_of_bounds() {
     assert_eq!(arr, vec![1]);
 }
 
+#[test]
+fn test_append_on_snapshot_state_rejected() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+
+    store
+        .register_state(StateRegistration {
+            id: "config".to_string(),
+            strategy: StateStrategy::Snapshot,
+            initial_value: None,
+        })
+        .unwrap();
+
+    store
+        .update_state("config", StateOperation::Set(b"{\"key\": \"value\"}".to_vec()))
+        .unwrap();
+
+    let result = store.update_state("config", StateOperation::Append(b"1".to_vec()));
+    match result {
+        Err(StoreError::InvalidOperation(msg)) => {
+            assert!(msg.contains("Append"), "message should name the operation: {}", msg);
+            assert!(msg.contains("Snapshot"), "message should name the strategy: {}", msg);
+        }
+        other => panic!("Expected InvalidOperation, got {:?}", other),
+    }
+
+    // Nothing was written
+    let state = store.get_state("config").unwrap().unwrap();
+    let value: serde_json::Value = serde_json::from_slice(&state).unwrap();
+    assert_eq!(value, serde_json::json!({"key": "value"}));
+}
+
+#[test]
+fn test_set_on_append_log_state_rejected() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+
+    store
+        .register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 10, full_snapshot_every: 5 },
+            initial_value: None,
+        })
+        .unwrap();
+
+    store
+        .update_state("items", StateOperation::Append(b"1".to_vec()))
+        .unwrap();
+
+    let result = store.update_state("items", StateOperation::Set(b"[2, 3]".to_vec()));
+    match result {
+        Err(StoreError::InvalidOperation(msg)) => {
+            assert!(msg.contains("Set"), "message should name the operation: {}", msg);
+            assert!(msg.contains("AppendLog"), "message should name the strategy: {}", msg);
+        }
+        other => panic!("Expected InvalidOperation, got {:?}", other),
+    }
+
+    // Internal snapshot operations remain allowed
+    store
+        .update_state("items", StateOperation::Snapshot(b"[1]".to_vec()))
+        .unwrap();
+
+    let state = store.get_state("items").unwrap().unwrap();
+    let arr: Vec<i32> = serde_json::from_slice(&state).unwrap();
+    assert_eq!(arr, vec![1]);
+}
+
+#[test]
+fn test_rejected_operation_not_logged() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+
+    store
+        .register_state(StateRegistration {
+            id: "config".to_string(),
+            strategy: StateStrategy::Snapshot,
+            initial_value: None,
+        })
+        .unwrap();
+    store
+        .update_state("config", StateOperation::Set(b"{}".to_vec()))
+        .unwrap();
+    store.sync().unwrap();
+
+    let log_path = dir.path().join("store").join("records.log");
+    let log_len = std::fs::metadata(&log_path).unwrap().len();
+    let records = store.stats().unwrap().record_count;
+    let head = store.current_branch().head;
+
+    for operation in [
+        StateOperation::Append(b"1".to_vec()),
+        StateOperation::Edit { index: 0, new_value: b"1".to_vec() },
+        StateOperation::Redact { start: 0, end: 1 },
+    ] {
+        let result = store.update_state("config", operation);
+        assert!(matches!(result, Err(StoreError::InvalidOperation(_))));
+    }
+    store.sync().unwrap();
+
+    assert_eq!(std::fs::metadata(&log_path).unwrap().len(), log_len);
+    assert_eq!(store.stats().unwrap().record_count, records);
+    assert_eq!(store.current_branch().head, head);
+    assert_eq!(store.get_records_by_type("state_update").len(), 1);
+}
+
 // --- Branch Errors ---
 
 #[test]
@@ -134,6 +271,60 @@ fn test_get_nonexistent_record() {
     assert!(result.is_none());
 }
 
+#[test]
+fn test_record_exists() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+
+    let record = store.append(RecordInput::raw("event", vec![])).unwrap();
+    assert!(store.record_exists(record.id));
+    assert!(!store.record_exists(RecordId(999)));
+}
+
+#[test]
+fn test_append_with_missing_link_rejected() {
+    let dir = TempDir::new().unwrap();
+    let store = Store::create(StoreConfig {
+        path: dir.path().join("store"),
+        validate_links: true,
+        ..Default::default()
+    })
+    .unwrap();
+
+    let first = store.append(RecordInput::raw("event", vec![])).unwrap();
+    let result = store.append(RecordInput::raw("event", vec![]).with_caused_by(vec![first.id, RecordId(999)]));
+    assert!(matches!(result, Err(StoreError::RecordNotFound(RecordId(999)))));
+    let result = store.append(RecordInput::raw("event", vec![]).with_linked_to(vec![RecordId(998)]));
+    assert!(matches!(result, Err(StoreError::RecordNotFound(RecordId(998)))));
+    assert_eq!(store.current_branch().head.0, 1);
+
+    // Existing references are accepted
+    let second = store.append(RecordInput::raw("event", vec![]).with_caused_by(vec![first.id])).unwrap();
+    assert_eq!(second.caused_by, vec![first.id]);
+
+    // Unchecked by default
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    store.append(RecordInput::raw("event", vec![]).with_caused_by(vec![RecordId(999)])).unwrap();
+}
+
+#[test]
+fn test_append_too_many_links() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+
+    let first = store.append(RecordInput::raw("event", vec![])).unwrap();
+
+    let causes = vec![first.id; 70_000];
+    let result = store.append(RecordInput::raw("event", vec![]).with_caused_by(causes));
+    assert!(matches!(result, Err(StoreError::InvalidOperation(_))));
+
+    // Nothing was written and the store remains usable
+    let next = store.append(RecordInput::raw("event", vec![])).unwrap();
+    assert_eq!(next.id, RecordId(first.id.0 + 1));
+    assert_eq!(store.current_branch().head.0, 2);
+}
+
 // --- Blob Errors ---
 
 #[test]
@@ -152,13 +343,84 @@ fn test_get_nonexistent_blob() {
 fn test_open_nonexistent_store() {
     let dir = TempDir::new().unwrap();
 
-    let result = Store::open(StoreConfig {
-        path: dir.path().join("nonexistent"),
+    let missing = dir.path().join("nonexistent");
+    let config = StoreConfig {
+        path: missing.clone(),
         blob_cache_size: 100,
         create_if_missing: false,
-    });
+        ..Default::default()
+    };
 
-    assert!(result.is_err());
+    match Store::open(config.clone()) {
+        Err(StoreError::NotInitialized(path)) => assert_eq!(path, missing),
+        other => panic!("Expected NotInitialized, got {:?}", other.err()),
+    }
+    assert!(matches!(
+        Store::open_or_create(config),
+        Err(StoreError::NotInitialized(_))
+    ));
+}
+
+#[test]
+fn test_invalid_store_config() {
+    let dir = TempDir::new().unwrap();
+    let valid = StoreConfig {
+        path: dir.path().join("store"),
+        blob_cache_size: 100,
+        create_if_missing: true,
+        ..Default::default()
+    };
+    assert!(valid.validate().is_ok());
+
+    let invalid = [
+        StoreConfig {
+            path: "".into(),
+            ..valid.clone()
+        },
+        StoreConfig {
+            blob_cache_size: 0,
+            ..valid.clone()
+        },
+        StoreConfig {
+            blob_shard_depth: Some(0),
+            ..valid.clone()
+        },
+        StoreConfig {
+            sync_policy: SyncPolicy::EveryN(0),
+            ..valid.clone()
+        },
+        StoreConfig {
+            sync_policy: SyncPolicy::Interval(std::time::Duration::ZERO),
+            ..valid.clone()
+        },
+    ];
+    for config in invalid {
+        assert!(matches!(config.validate(), Err(StoreError::InvalidConfig(_))));
+        assert!(matches!(Store::create(config), Err(StoreError::InvalidConfig(_))));
+    }
+
+    // The path must sit under a writable directory
+    let file = dir.path().join("file");
+    std::fs::write(&file, b"").unwrap();
+    let under_file = StoreConfig {
+        path: file.join("store"),
+        ..valid.clone()
+    };
+    assert!(matches!(Store::create(under_file), Err(StoreError::InvalidConfig(_))));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let locked = dir.path().join("locked");
+        std::fs::create_dir(&locked).unwrap();
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o555)).unwrap();
+        let result = Store::create(StoreConfig {
+            path: locked.join("store"),
+            ..valid
+        });
+        assert!(matches!(result, Err(StoreError::InvalidConfig(_))));
+        std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
 }
 
 #[test]
@@ -168,6 +430,7 @@ fn test_concurrent_store_access() {
         path: dir.path().join("store"),
         blob_cache_size: 100,
         create_if_missing: true,
+        ..Default::default()
     };
 
     let _store1 = Store::create(config.clone()).unwrap();
@@ -177,22 +440,267 @@ fn test_concurrent_store_access() {
     assert!(matches!(result, Err(StoreError::Locked)));
 }
 
-// --- JSON Parsing Errors ---
+#[test]
+fn test_writer_blocked_by_shared_readers() {
+    let dir = TempDir::new().unwrap();
+    let config = StoreConfig {
+        path: dir.path().join("store"),
+        blob_cache_size: 100,
+        create_if_missing: true,
+        ..Default::default()
+    };
+
+    drop(Store::create(config.clone()).unwrap());
+
+    // Any number of readers can share the store
+    let reader1 = Store::open_read_only(config.clone()).unwrap();
+    let reader2 = Store::open_read_only(config.clone()).unwrap();
+
+    // A writer can't open while they are active
+    let result = Store::open(config.clone());
+    assert!(matches!(result, Err(StoreError::Locked)));
+
+    // With a timeout, the writer waits for the readers to go away
+    let closer = std::thread::spawn(move || {
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        drop(reader1);
+        drop(reader2);
+    });
+    let writer = Store::open(StoreConfig {
+        lock_timeout: Some(std::time::Duration::from_secs(5)),
+        ..config.clone()
+    })
+    .unwrap();
+    closer.join().unwrap();
+
+    // Readers may still join an active writer
+    let _reader3 = Store::open_read_only(config).unwrap();
+    drop(writer);
+}
+
+// --- Corruption Detection ---
 
 #[test]
-fn test_append_to_non_array_state() {
+fn test_verify_reports_corrupt_blob_and_record() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+
+    for i in 1..=3 {
+        store
+            .append(RecordInput::raw("event", format!("record {}", i).into_bytes()))
+            .unwrap();
+    }
+    let good = store.store_blob(b"intact", "text/plain").unwrap();
+    let bad = store.store_blob(b"will be damaged", "text/plain").unwrap();
+    store.sync().unwrap();
+
+    assert!(store.verify(VerifyOptions::default()).unwrap().is_ok());
+
+    // Flip a byte in the middle record's payload
+    let log_path = dir.path().join("store").join("records.log");
+    let mut log = std::fs::read(&log_path).unwrap();
+    let pos = log.windows(8).position(|w| w == b"record 2").unwrap();
+    log[pos] ^= 0xFF;
+    std::fs::write(&log_path, log).unwrap();
+
+    // Flip a byte in one blob's content
+    let blob_path = dir
+        .path()
+        .join("store")
+        .join("blobs")
+        .join(bad.shard_prefix())
+        .join(bad.to_hex());
+    let mut blob = std::fs::read(&blob_path).unwrap();
+    let pos = blob.windows(4).position(|w| w == b"will").unwrap();
+    blob[pos] ^= 0xFF;
+    std::fs::write(&blob_path, blob).unwrap();
+
+    let report = store.verify(VerifyOptions::default()).unwrap();
+    assert_eq!(report.records_checked, 3);
+    assert_eq!(report.blobs_checked, 2);
+    assert!(report
+        .issues
+        .iter()
+        .any(|issue| matches!(issue.location, VerifyLocation::Record { .. })));
+    assert!(report
+        .issues
+        .iter()
+        .any(|issue| issue.location == VerifyLocation::Blob(bad)));
+    assert!(!report
+        .issues
+        .iter()
+        .any(|issue| issue.location == VerifyLocation::Blob(good)));
+
+    let report = store
+        .verify(VerifyOptions {
+            stop_on_first_error: true,
+        })
+        .unwrap();
+    assert_eq!(report.issues.len(), 1);
+}
+
+#[test]
+fn test_verify_blob_detects_damaged_file() {
     let dir = TempDir::new().unwrap();
     let store = test_store(&dir);
 
+    let good = store.store_blob(b"intact", "text/plain").unwrap();
+    let bad = store.store_blob(b"will be damaged", "text/plain").unwrap();
+    // Load into the cache so verification has to go back to disk
+    store.get_blob(&bad).unwrap();
+
+    assert!(store.verify_blob(&good).unwrap());
+    assert!(store.verify_blob(&bad).unwrap());
+    assert!(store.verify_all_blobs().unwrap().is_empty());
+
+    let blob_path = dir
+        .path()
+        .join("store")
+        .join("blobs")
+        .join(bad.shard_prefix())
+        .join(bad.to_hex());
+    let mut blob = std::fs::read(&blob_path).unwrap();
+    let pos = blob.windows(4).position(|w| w == b"will").unwrap();
+    blob[pos] ^= 0xFF;
+    std::fs::write(&blob_path, blob).unwrap();
+
+    assert!(store.verify_blob(&good).unwrap());
+    assert!(!store.verify_blob(&bad).unwrap());
+    assert_eq!(store.verify_all_blobs().unwrap(), vec![bad]);
+
+    let missing = chronicle::Hash::from_bytes(b"never stored");
+    assert!(matches!(
+        store.verify_blob(&missing),
+        Err(StoreError::BlobNotFound(_))
+    ));
+}
+
+#[test]
+fn test_repair_truncated_log_tail() {
+    let dir = TempDir::new().unwrap();
+    let config = StoreConfig {
+        path: dir.path().join("store"),
+        blob_cache_size: 100,
+        create_if_missing: true,
+        ..Default::default()
+    };
+    let store = Store::create(config.clone()).unwrap();
+
     store
         .register_state(StateRegistration {
-            id: "obj".to_string(),
-            strategy: StateStrategy::Snapshot,
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog {
+                delta_snapshot_every: 100,
+                full_snapshot_every: 10,
+            },
             initial_value: None,
         })
         .unwrap();
+    store.append(RecordInput::raw("event", b"start".to_vec())).unwrap();
+    for i in 0..3 {
+        store
+            .update_state("items", StateOperation::Append(format!("{}", i).into_bytes()))
+            .unwrap();
+    }
+    store.sync().unwrap();
+
+    // Healthy store: nothing to do
+    assert!(store.repair(RepairOptions::default()).unwrap().is_noop());
+    let before = store.stats().unwrap();
+    let state_before = store.get_state("items").unwrap();
+    let head_before = store.current_branch().head;
+    let log_path = dir.path().join("store").join("records.log");
+    let intact_len = std::fs::metadata(&log_path).unwrap().len() as usize;
+
+    // A torn write: the last update only partially reached disk
+    store
+        .update_state("items", StateOperation::Append(b"3".to_vec()))
+        .unwrap();
+    store.sync().unwrap();
+    let len = std::fs::metadata(&log_path).unwrap().len();
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_path)
+        .unwrap()
+        .set_len(len - 5)
+        .unwrap();
+    assert!(!store.verify(VerifyOptions::default()).unwrap().is_ok());
+    let torn = std::fs::read(&log_path).unwrap()[intact_len..].to_vec();
+
+    let report = store.repair(RepairOptions { quarantine: true }).unwrap();
+    assert!(report.truncated_bytes > 0);
+    assert_eq!(std::fs::read(report.quarantine_path.as_ref().unwrap()).unwrap(), torn);
+    assert_eq!(report.state_heads_repaired, 1);
+    assert_eq!(report.branch_heads_repaired, 1);
+
+    // Back to the state before the torn write, and consistent
+    let after = store.stats().unwrap();
+    assert_eq!(after.record_count, before.record_count);
+    assert_eq!(store.current_branch().head, head_before);
+    assert_eq!(store.get_state("items").unwrap(), state_before);
+    assert!(store.verify(VerifyOptions::default()).unwrap().is_ok());
+    assert!(store.repair(RepairOptions::default()).unwrap().is_noop());
+
+    // And usable
+    store
+        .update_state("items", StateOperation::Append(b"3".to_vec()))
+        .unwrap();
+    drop(store);
+
+    let store = Store::open(config).unwrap();
+    assert_eq!(store.stats().unwrap().record_count, before.record_count + 1);
+    assert_eq!(store.get_state_len("items").unwrap(), Some(4));
+}
+
+#[test]
+fn test_failed_truncate_branch_keeps_state_heads() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    store
+        .register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog {
+                delta_snapshot_every: 100,
+                full_snapshot_every: 10,
+            },
+            initial_value: None,
+        })
+        .unwrap();
+
+    store.append(RecordInput::raw("event", b"damaged later".to_vec())).unwrap();
+    for i in 0..5 {
+        store
+            .update_state("items", StateOperation::Append(format!("{}", i).into_bytes()))
+            .unwrap();
+    }
+    store.sync().unwrap();
+    let head_before = store.current_branch().head;
+
+    // An unreadable record makes the log rewrite fail
+    let log_path = dir.path().join("store").join("records.log");
+    let mut log = std::fs::read(&log_path).unwrap();
+    let pos = log.windows(7).position(|w| w == b"damaged").unwrap();
+    log[pos] ^= 0xFF;
+    std::fs::write(&log_path, log).unwrap();
+
+    assert!(matches!(
+        store.truncate_branch("main", Sequence(3)),
+        Err(StoreError::ChecksumMismatch { .. })
+    ));
+    assert_eq!(store.current_branch().head, head_before);
+    assert_eq!(store.get_state_len("items").unwrap(), Some(5));
+    let items: Vec<i32> = store.get_state_as("items").unwrap().unwrap();
+    assert_eq!(items, vec![0, 1, 2, 3, 4]);
+}
+
+// --- JSON Parsing Errors ---
+
+#[test]
+fn test_append_to_non_array_state() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
 
-    // Set as object, not array
+    // Unregistered states have no strategy to check against
     store
         .update_state("obj", StateOperation::Set(b"{\"key\": \"value\"}".to_vec()))
         .unwrap();
@@ -1,9 +1,15 @@
 //! Integration tests for the record store.
 
 use chronicle::{
-    RecordInput, Sequence, StateOperation, StateRegistration, StateStrategy, Store, StoreConfig,
+    BranchGcOptions, BranchSizeInfo, Change, CompactOptions, DropReason, Hash, PayloadEncoding, Record, RecordId, RecordInput, Sequence, SnapshotEvent, SnapshotNeeded, SnapshotPolicy,
+    StateOperation, StateRegistration, StateStrategy, Store, StoreConfig, StoreError, StoreObserver,
+    SubscriptionConfig, SubscriptionFilter, SubscriptionId, Timestamp,
 };
+use serde::Deserialize;
 use serde_json::json;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tempfile::TempDir;
 
 fn test_store(dir: &TempDir) -> Store {
@@ -11,6 +17,7 @@ fn test_store(dir: &TempDir) -> Store {
         path: dir.path().join("store"),
         blob_cache_size: 100,
         create_if_missing: true,
+        ..Default::default()
     })
     .unwrap()
 }
@@ -132,7 +139,7 @@ fn test_code_storage_and_retrieval() {
     let payload: serde_json::Value = serde_json::from_slice(&retrieved.payload).unwrap();
 
     let main_blob = store
-        .get_blob(&chronicle::Hash::from_hex(&payload["main"].as_str().unwrap()).unwrap())
+        .get_blob(&chronicle::Hash::from_hex(payload["main"].as_str().unwrap()).unwrap())
         .unwrap()
         .unwrap();
     assert_eq!(main_blob.content, js_code);
@@ -338,6 +345,7 @@ fn test_reopen_after_writes() {
         path: dir.path().join("store"),
         blob_cache_size: 100,
         create_if_missing: true,
+        ..Default::default()
     };
 
     // First session: create and write
@@ -392,17 +400,18 @@ fn test_blob_deduplication_across_sessions() {
         path: dir.path().join("store"),
         blob_cache_size: 100,
         create_if_missing: true,
+        ..Default::default()
     };
 
     let content = b"deduplicated content";
-    let mut hash1 = None;
 
     // First session
-    {
+    let hash1 = {
         let store = Store::create(config.clone()).unwrap();
-        hash1 = Some(store.store_blob(content, "text/plain").unwrap());
+        let hash = store.store_blob(content, "text/plain").unwrap();
         store.sync().unwrap();
-    }
+        hash
+    };
 
     // Second session - same content
     {
@@ -410,7 +419,7 @@ fn test_blob_deduplication_across_sessions() {
         let hash2 = store.store_blob(content, "text/plain").unwrap();
 
         // Should be the same hash (deduplicated)
-        assert_eq!(hash1.unwrap(), hash2);
+        assert_eq!(hash1, hash2);
 
         // Should still be retrievable
         let blob = store.get_blob(&hash2).unwrap().unwrap();
@@ -418,6 +427,109 @@ fn test_blob_deduplication_across_sessions() {
     }
 }
 
+#[test]
+fn test_json_blob_deduplication() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+
+    let first: serde_json::Value = serde_json::from_str(r#"{"name": "a", "size": 2, "tags": ["x"]}"#).unwrap();
+    let second: serde_json::Value = serde_json::from_str(r#"{"tags": ["x"], "size": 2.0, "name": "a"}"#).unwrap();
+
+    // Raw bytes differ, so the raw path stores two blobs
+    let raw_first = store.store_blob(br#"{"name": "a", "size": 2, "tags": ["x"]}"#, "application/json").unwrap();
+    let raw_second = store.store_blob(br#"{"tags": ["x"], "size": 2.0, "name": "a"}"#, "application/json").unwrap();
+    assert_ne!(raw_first, raw_second);
+
+    let canonical_first = store.store_json_blob(&first, "application/json").unwrap();
+    let canonical_second = store.store_json_blob(&second, "application/json").unwrap();
+    assert_eq!(canonical_first, canonical_second);
+
+    let blob = store.get_blob(&canonical_first).unwrap().unwrap();
+    assert_eq!(blob.content, br#"{"name":"a","size":2,"tags":["x"]}"#);
+}
+
+#[test]
+fn test_snapshot_to_dir() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    store
+        .register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 4, full_snapshot_every: 2 },
+            initial_value: None,
+        })
+        .unwrap();
+
+    let blob = store.store_blob(b"attachment", "text/plain").unwrap();
+    let content: Vec<u8> = (0..200_000u32).flat_map(|i| i.to_le_bytes()).collect();
+    let chunked = store.store_blob_chunked(&content, "application/octet-stream").unwrap();
+    for i in 0..10 {
+        store.append(RecordInput::raw("event", format!("main {}", i).into_bytes())).unwrap();
+        store.update_state("items", StateOperation::Append(i.to_string().into_bytes())).unwrap();
+    }
+    store.create_branch("side", None).unwrap();
+    store.switch_branch("side").unwrap();
+    store.append(RecordInput::raw("event", b"side".to_vec())).unwrap();
+
+    // Keep writing from another thread while the snapshot is taken
+    let snapshot_dir = dir.path().join("snapshot");
+    let (expected_records, expected_state) = std::thread::scope(|scope| {
+        let writer = scope.spawn(|| {
+            for i in 0..200u32 {
+                store.append(RecordInput::raw("background", i.to_le_bytes().to_vec())).unwrap();
+                store.store_blob(&i.to_be_bytes(), "application/octet-stream").unwrap();
+            }
+        });
+        std::thread::sleep(Duration::from_millis(1));
+        store.snapshot_to_dir(&snapshot_dir).unwrap();
+        writer.join().unwrap();
+        // Note what the snapshot holds before the source changes further
+        let copy = Store::open(StoreConfig {
+            path: snapshot_dir.clone(),
+            ..Default::default()
+        })
+        .unwrap();
+        let records = copy.record_count();
+        let state = copy.get_state("items").unwrap();
+        copy.close().unwrap();
+        (records, state)
+    });
+
+    // Writes after the snapshot don't reach it
+    store.update_state("items", StateOperation::Append(b"99".to_vec())).unwrap();
+    store.append(RecordInput::raw("event", b"late".to_vec())).unwrap();
+
+    let copy = Store::open(StoreConfig {
+        path: snapshot_dir.clone(),
+        ..Default::default()
+    })
+    .unwrap();
+    assert!(copy.verify(Default::default()).unwrap().is_ok());
+    assert_eq!(copy.record_count(), expected_records);
+    assert_eq!(copy.get_state("items").unwrap(), expected_state);
+    assert_eq!(copy.current_branch().name, "side");
+    assert_eq!(copy.list_branches().len(), 2);
+    assert_eq!(copy.get_blob(&blob).unwrap().unwrap().content, b"attachment");
+    assert_eq!(copy.get_blob(&chunked).unwrap().unwrap().content, content);
+
+    // Every background record in the snapshot is whole and in order
+    let background = copy.get_records_by_type("background");
+    for (i, id) in background.iter().enumerate() {
+        let record = copy.get_record(*id).unwrap().unwrap();
+        assert_eq!(record.payload, (i as u32).to_le_bytes());
+    }
+
+    copy.switch_branch("main").unwrap();
+    let main_state = copy.get_state("items").unwrap().unwrap();
+    let main_state: serde_json::Value = serde_json::from_slice(&main_state).unwrap();
+    assert_eq!(main_state, json!([0, 1, 2, 3, 4, 5, 6, 7, 8, 9]));
+
+    // The source is untouched and the destination must be empty
+    assert_eq!(store.get_records_by_type("background").len(), 200);
+    let result = store.snapshot_to_dir(&snapshot_dir);
+    assert!(matches!(result, Err(StoreError::InvalidOperation(_))));
+}
+
 // --- Stress Tests ---
 
 #[test]
@@ -440,6 +552,30 @@ fn test_many_records() {
     assert!(!records.is_empty());
 }
 
+#[test]
+fn test_iter_from_missing_sequence() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+
+    let event = |n: i32| RecordInput::json("event", &json!({ "n": n })).unwrap();
+    store.append(event(1)).unwrap();
+    store.append(event(2)).unwrap();
+    store.append(event(3).with_expires_at(Timestamp(1))).unwrap();
+    store.append(event(4)).unwrap();
+    store.append(event(5)).unwrap();
+    assert_eq!(store.expire_records(Timestamp::now()).unwrap(), 1);
+
+    // Sequence 3 is gone; iteration starts at 4 rather than the log start
+    let sequences: Vec<u64> = store
+        .iter_from(Sequence(3))
+        .map(|result| result.unwrap().1.sequence.0)
+        .collect();
+    assert_eq!(sequences, vec![4, 5]);
+
+    // Past the head there is nothing to yield
+    assert_eq!(store.iter_from(Sequence(6)).count(), 0);
+}
+
 #[test]
 fn test_interleaved_multi_state_updates() {
     let dir = TempDir::new().unwrap();
@@ -580,8 +716,8 @@ fn test_get_state_at_with_edits() {
         .unwrap();
 
     // Add [1, 2, 3]
-    let r1 = store.update_state("list", StateOperation::Append(b"1".to_vec())).unwrap();
-    let r2 = store.update_state("list", StateOperation::Append(b"2".to_vec())).unwrap();
+    store.update_state("list", StateOperation::Append(b"1".to_vec())).unwrap();
+    store.update_state("list", StateOperation::Append(b"2".to_vec())).unwrap();
     let r3 = store.update_state("list", StateOperation::Append(b"3".to_vec())).unwrap();
 
     // Edit index 1: [1, 2, 3] -> [1, 99, 3]
@@ -600,6 +736,459 @@ fn test_get_state_at_with_edits() {
     assert_eq!(after_edit, vec![1, 99, 3]);
 }
 
+#[test]
+fn test_get_state_at_time() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    store
+        .register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 100, full_snapshot_every: 100 },
+            initial_value: None,
+        })
+        .unwrap();
+
+    // A timestamp taken between updates falls after one and before the next
+    let tick = || {
+        std::thread::sleep(Duration::from_millis(2));
+        let at = Timestamp::now();
+        std::thread::sleep(Duration::from_millis(2));
+        at
+    };
+    let before_any = tick();
+    let mut times = Vec::new();
+    for i in 1..=4 {
+        store.update_state("items", StateOperation::Append(i.to_string().into_bytes())).unwrap();
+        times.push(tick());
+    }
+
+    let items_at = |at: Timestamp| -> Option<Vec<i32>> {
+        store
+            .get_state_at_time("items", at)
+            .unwrap()
+            .map(|data| serde_json::from_slice(&data).unwrap())
+    };
+    assert_eq!(items_at(before_any), None);
+    assert_eq!(items_at(times[1]), Some(vec![1, 2]));
+    assert_eq!(items_at(times[3]), Some(vec![1, 2, 3, 4]));
+
+    // A snapshot written later is skipped for earlier times, even though it
+    // follows every update in sequence order
+    store.compact_state("items").unwrap();
+    assert_eq!(items_at(times[1]), Some(vec![1, 2]));
+    assert_eq!(items_at(Timestamp::now()), Some(vec![1, 2, 3, 4]));
+    assert!(store.get_state_at_time("missing", Timestamp::now()).unwrap().is_none());
+}
+
+#[test]
+fn test_get_state_diff_between() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+
+    store
+        .register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 100, full_snapshot_every: 100 },
+            initial_value: None,
+        })
+        .unwrap();
+
+    for i in 1..=3 {
+        store
+            .update_state("items", StateOperation::Append(format!("{}", i).into_bytes()))
+            .unwrap();
+    }
+    let from = store.current_branch().head;
+
+    store.update_state("items", StateOperation::Append(b"4".to_vec())).unwrap();
+    store
+        .update_state("items", StateOperation::Edit { index: 0, new_value: b"10".to_vec() })
+        .unwrap();
+    let to = store
+        .update_state("items", StateOperation::Redact { start: 1, end: 3 })
+        .unwrap()
+        .sequence;
+    // Outside the window
+    store.update_state("items", StateOperation::Append(b"5".to_vec())).unwrap();
+
+    let diff = store.get_state_diff_between("items", from, to).unwrap();
+    assert_eq!(diff.operations.len(), 3);
+    assert!(matches!(diff.operations[0], (_, StateOperation::Append(_))));
+    assert!(matches!(diff.operations[1], (_, StateOperation::Edit { index: 0, .. })));
+    assert!(matches!(diff.operations[2], (_, StateOperation::Redact { start: 1, end: 3 })));
+    assert_eq!(diff.operations[2].0, to);
+    assert!(!diff.baseline_reset);
+    assert_eq!((diff.items_added, diff.items_edited, diff.items_redacted), (1, 1, 2));
+
+    // An empty window and a reversed one
+    let empty = store.get_state_diff_between("items", to, to).unwrap();
+    assert!(empty.operations.is_empty());
+    assert!(matches!(
+        store.get_state_diff_between("items", to, from),
+        Err(StoreError::InvalidOperation(_))
+    ));
+}
+
+#[test]
+fn test_rollback_state_to() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+
+    // Unrelated record so the state doesn't exist at sequence 1
+    let before = store.append(RecordInput::json("event", &json!({})).unwrap()).unwrap();
+    store
+        .register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 100, full_snapshot_every: 100 },
+            initial_value: None,
+        })
+        .unwrap();
+
+    let mut seqs = Vec::new();
+    for i in 1..=10 {
+        let record = store
+            .update_state("items", StateOperation::Append(format!("{}", i).into_bytes()))
+            .unwrap();
+        seqs.push(record.sequence);
+    }
+    let tenth = seqs[9];
+
+    store.rollback_state_to("items", seqs[4]).unwrap();
+    let items: Vec<i32> = store.get_state_as("items").unwrap().unwrap();
+    assert_eq!(items, vec![1, 2, 3, 4, 5]);
+    assert_eq!(store.get_state_len("items").unwrap(), Some(5));
+
+    // Appends continue from the rolled-back value
+    store.update_state("items", StateOperation::Append(b"6".to_vec())).unwrap();
+    let items: Vec<i32> = store.get_state_as("items").unwrap().unwrap();
+    assert_eq!(items, vec![1, 2, 3, 4, 5, 6]);
+
+    // History is preserved
+    let items: Vec<i32> =
+        serde_json::from_slice(&store.get_state_at("items", tenth).unwrap().unwrap()).unwrap();
+    assert_eq!(items.len(), 10);
+
+    assert!(matches!(
+        store.rollback_state_to("items", before.sequence),
+        Err(StoreError::InvalidOperation(_))
+    ));
+}
+
+#[test]
+fn test_prune_history() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    for id in ["items", "other"] {
+        store
+            .register_state(StateRegistration {
+                id: id.to_string(),
+                strategy: StateStrategy::AppendLog { delta_snapshot_every: 3, full_snapshot_every: 100 },
+                initial_value: None,
+            })
+            .unwrap();
+    }
+
+    let mut records = Vec::new();
+    for i in 1..=10 {
+        records.push(
+            store
+                .update_state("items", StateOperation::Append(format!("{}", i).into_bytes()))
+                .unwrap(),
+        );
+    }
+    store.update_state("other", StateOperation::Append(b"0".to_vec())).unwrap();
+    store.create_branch("before-prune", None).unwrap();
+    let eleventh = store.update_state("items", StateOperation::Append(b"11".to_vec())).unwrap();
+
+    assert!(matches!(
+        store.prune_history(&["missing"]),
+        Err(StoreError::StateNotRegistered(_))
+    ));
+    let report = store.prune_history(&["items"]).unwrap();
+    assert_eq!(report.states_pruned, 1);
+    assert_eq!(report.snapshots_created, 1);
+    // Only the last append is unique to main; "before-prune" still uses the
+    // ten earlier appends and three delta snapshots
+    assert_eq!(report.records_pruned, 1);
+    assert_eq!(report.records_kept, 13);
+    assert!(store.get_record(eleventh.id).unwrap().is_none());
+    assert!(store.get_record(records[0].id).unwrap().is_some());
+
+    // The current value is intact, earlier history is gone
+    let items: Vec<i32> = store.get_state_as("items").unwrap().unwrap();
+    assert_eq!(items, (1..=11).collect::<Vec<_>>());
+    assert!(matches!(
+        store.get_state_at("items", records[4].sequence),
+        Err(StoreError::HistoryPruned { ref state_id, .. }) if state_id == "items"
+    ));
+    assert_eq!(store.get_chain_stats("items").unwrap().unwrap().total_operations, 1);
+    assert_eq!(store.get_state_len("other").unwrap(), Some(1));
+
+    // Later history stays readable
+    let twelfth = store.update_state("items", StateOperation::Append(b"12".to_vec())).unwrap();
+    let items: Vec<i32> =
+        serde_json::from_slice(&store.get_state_at("items", twelfth.sequence).unwrap().unwrap()).unwrap();
+    assert_eq!(items.len(), 12);
+
+    // The other branch keeps its own history
+    store.switch_branch("before-prune").unwrap();
+    assert!(store.get_state_at("items", records[4].sequence).unwrap().is_some());
+    store.switch_branch("main").unwrap();
+
+    // Pruning again drops the previous snapshot and what came after it
+    let report = store.prune_history(&["items"]).unwrap();
+    assert_eq!(report.records_pruned, 2);
+    assert!(store.get_record(twelfth.id).unwrap().is_none());
+    drop(store);
+
+    // Pruning survives a reopen
+    let store = Store::open(StoreConfig {
+        path: dir.path().join("store"),
+        ..Default::default()
+    })
+    .unwrap();
+    assert!(store.get_record(eleventh.id).unwrap().is_none());
+    assert!(store.get_record(twelfth.id).unwrap().is_none());
+    assert!(matches!(
+        store.get_state_at("items", twelfth.sequence),
+        Err(StoreError::HistoryPruned { .. })
+    ));
+    assert_eq!(store.get_state_len("items").unwrap(), Some(12));
+    assert!(store.check_consistency().unwrap().is_ok());
+}
+
+// --- Typed State Access Tests ---
+
+#[derive(Debug, PartialEq, Deserialize)]
+struct Message {
+    author: String,
+    text: String,
+}
+
+#[derive(Debug, PartialEq, Deserialize)]
+struct Settings {
+    theme: String,
+    font_size: u32,
+}
+
+#[test]
+fn test_get_state_as() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+
+    store
+        .register_state(StateRegistration {
+            id: "messages".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 10, full_snapshot_every: 5 },
+            initial_value: None,
+        })
+        .unwrap();
+    for i in 0..4 {
+        let message = json!({"author": "ann", "text": format!("hi {}", i)});
+        store
+            .update_state("messages", StateOperation::Append(serde_json::to_vec(&message).unwrap()))
+            .unwrap();
+    }
+
+    let messages: Vec<Message> = store.get_state_as("messages").unwrap().unwrap();
+    assert_eq!(messages.len(), 4);
+    assert_eq!(messages[3], Message { author: "ann".into(), text: "hi 3".into() });
+    let tail: Vec<Message> = store.get_state_tail_as("messages", 2).unwrap().unwrap();
+    assert_eq!(tail.iter().map(|m| m.text.as_str()).collect::<Vec<_>>(), vec!["hi 2", "hi 3"]);
+    let slice: Vec<Message> = store.get_state_slice_as("messages", 1, 1).unwrap().unwrap();
+    assert_eq!(slice[0].text, "hi 1");
+
+    store
+        .register_state(StateRegistration {
+            id: "settings".to_string(),
+            strategy: StateStrategy::Snapshot,
+            initial_value: Some(br#"{"theme":"dark","font_size":14}"#.to_vec()),
+        })
+        .unwrap();
+    let settings: Settings = store.get_state_as("settings").unwrap().unwrap();
+    assert_eq!(settings, Settings { theme: "dark".into(), font_size: 14 });
+
+    // Missing states are None; mismatched shapes name the state
+    assert!(store.get_state_as::<Settings>("missing").unwrap().is_none());
+    match store.get_state_as::<Vec<Message>>("settings") {
+        Err(StoreError::Deserialization(message)) => assert!(message.contains("settings")),
+        other => panic!("expected Deserialization, got {:?}", other),
+    }
+}
+
+// --- Strategy Migration Tests ---
+
+#[test]
+fn test_migrate_snapshot_to_append_log() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    let append_log = StateStrategy::AppendLog { delta_snapshot_every: 10, full_snapshot_every: 5 };
+
+    store
+        .register_state(StateRegistration {
+            id: "tags".to_string(),
+            strategy: StateStrategy::Snapshot,
+            initial_value: None,
+        })
+        .unwrap();
+    store.update_state("tags", StateOperation::Set(br#"["a"]"#.to_vec())).unwrap();
+    let before = store.update_state("tags", StateOperation::Set(br#"["a","b","c"]"#.to_vec())).unwrap();
+
+    store.migrate_state_strategy("tags", append_log.clone()).unwrap();
+    assert_eq!(store.get_state_len("tags").unwrap(), Some(3));
+    let items: Vec<_> = store.iter_state_items("tags").unwrap().unwrap().map(|i| i.unwrap()).collect();
+    assert_eq!(items, vec![json!("a"), json!("b"), json!("c")]);
+
+    // Now takes appends, and keeps its history
+    store.update_state("tags", StateOperation::Append(br#""d""#.to_vec())).unwrap();
+    assert_eq!(store.get_state_len("tags").unwrap(), Some(4));
+    assert!(store.update_state("tags", StateOperation::Set(b"[]".to_vec())).is_err());
+    assert_eq!(store.get_state_at("tags", before.sequence).unwrap(), Some(br#"["a","b","c"]"#.to_vec()));
+
+    // And survives a reopen
+    drop(store);
+    let store = Store::open(StoreConfig { path: dir.path().join("store"), ..Default::default() }).unwrap();
+    store.register_state(StateRegistration { id: "tags".to_string(), strategy: append_log, initial_value: None }).unwrap();
+    let tags: Vec<String> = store.get_state_as("tags").unwrap().unwrap();
+    assert_eq!(tags, vec!["a", "b", "c", "d"]);
+
+    // And back: the whole array becomes one value
+    store.migrate_state_strategy("tags", StateStrategy::Snapshot).unwrap();
+    store.update_state("tags", StateOperation::Set(br#"["z"]"#.to_vec())).unwrap();
+    assert_eq!(store.get_state("tags").unwrap(), Some(br#"["z"]"#.to_vec()));
+}
+
+#[test]
+fn test_migrate_rejects_incompatible_value() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+
+    store
+        .register_state(StateRegistration {
+            id: "settings".to_string(),
+            strategy: StateStrategy::Snapshot,
+            initial_value: Some(br#"{"theme":"dark"}"#.to_vec()),
+        })
+        .unwrap();
+    let head = store.current_branch().head;
+
+    let result = store.migrate_state_strategy(
+        "settings",
+        StateStrategy::AppendLog { delta_snapshot_every: 10, full_snapshot_every: 5 },
+    );
+    match result {
+        Err(StoreError::InvalidOperation(message)) => assert!(message.contains("JSON array")),
+        other => panic!("expected InvalidOperation, got {:?}", other),
+    }
+    // Nothing written, registration unchanged
+    assert_eq!(store.current_branch().head, head);
+    assert!(store.update_state("settings", StateOperation::Set(b"{}".to_vec())).is_ok());
+
+    assert!(matches!(
+        store.migrate_state_strategy("missing", StateStrategy::Snapshot),
+        Err(StoreError::StateNotRegistered(_))
+    ));
+}
+
+// --- Optimistic Concurrency Tests ---
+
+#[test]
+fn test_set_if_sequence() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    store
+        .register_state(StateRegistration {
+            id: "plan".to_string(),
+            strategy: StateStrategy::Snapshot,
+            initial_value: None,
+        })
+        .unwrap();
+    assert_eq!(store.state_head_sequence("plan").unwrap(), None);
+
+    // Sequence(0) expects a state with no updates yet
+    let first = store
+        .update_state("plan", StateOperation::SetIfSequence { expected_head: Sequence(0), value: b"1".to_vec() })
+        .unwrap();
+    assert_eq!(store.state_head_sequence("plan").unwrap(), Some(first.sequence));
+
+    // Two writers read the same head; the first CAS wins
+    let second = store
+        .update_state("plan", StateOperation::SetIfSequence { expected_head: first.sequence, value: b"2".to_vec() })
+        .unwrap();
+    store.append(RecordInput::json("note", &json!({})).unwrap()).unwrap();
+    let err = store
+        .update_state("plan", StateOperation::SetIfSequence { expected_head: first.sequence, value: b"3".to_vec() })
+        .unwrap_err();
+    match err {
+        StoreError::ConcurrentModification { expected, actual } => {
+            assert_eq!(expected, first.sequence);
+            assert_eq!(actual, second.sequence);
+        }
+        other => panic!("expected ConcurrentModification, got {:?}", other),
+    }
+    assert_eq!(store.get_state("plan").unwrap(), Some(b"2".to_vec()));
+
+    // Retrying against the reported head succeeds
+    store
+        .update_state("plan", StateOperation::SetIfSequence { expected_head: second.sequence, value: b"3".to_vec() })
+        .unwrap();
+    assert_eq!(store.get_state("plan").unwrap(), Some(b"3".to_vec()));
+
+    // Only Snapshot states take it
+    store
+        .register_state(StateRegistration {
+            id: "log".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 10, full_snapshot_every: 5 },
+            initial_value: None,
+        })
+        .unwrap();
+    assert!(matches!(
+        store.update_state("log", StateOperation::SetIfSequence { expected_head: Sequence(0), value: b"[]".to_vec() }),
+        Err(StoreError::InvalidOperation(_))
+    ));
+}
+
+// --- Lamport Clock Tests ---
+
+#[test]
+fn test_lamport_clock() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    assert_eq!(store.current_lamport(), 0);
+
+    let first = store.append(RecordInput::json("event", &json!({})).unwrap()).unwrap();
+    let second = store.append(RecordInput::json("event", &json!({})).unwrap()).unwrap();
+    assert_eq!((first.lamport, second.lamport), (Some(1), Some(2)));
+    assert_eq!(store.current_lamport(), 2);
+
+    // A replicated record from a replica that is ahead keeps its stamp
+    let received = store
+        .append(RecordInput::json("event", &json!({"from": "peer"})).unwrap().with_lamport(40))
+        .unwrap();
+    assert_eq!(received.lamport, Some(40));
+    assert_eq!(store.current_lamport(), 40);
+
+    // Local appends continue past it; one from behind doesn't move the clock back
+    let local = store.append(RecordInput::json("event", &json!({})).unwrap()).unwrap();
+    assert_eq!(local.lamport, Some(41));
+    store
+        .append(RecordInput::json("event", &json!({"from": "peer"})).unwrap().with_lamport(7))
+        .unwrap();
+    assert_eq!(store.current_lamport(), 41);
+    let stored = store.get_record(received.id).unwrap().unwrap();
+    assert_eq!(stored.lamport, Some(40));
+    drop(store);
+
+    let store = Store::open(StoreConfig {
+        path: dir.path().join("store"),
+        ..Default::default()
+    })
+    .unwrap();
+    assert_eq!(store.current_lamport(), 41);
+    let next = store.append(RecordInput::json("event", &json!({})).unwrap()).unwrap();
+    assert_eq!(next.lamport, Some(42));
+}
+
 // --- Causation Link Tests ---
 
 #[test]
@@ -700,6 +1289,7 @@ fn test_causation_chain_persists_across_reopen() {
         path: dir.path().join("store"),
         blob_cache_size: 100,
         create_if_missing: false,
+        ..Default::default()
     }).unwrap();
 
     let response = store.get_record(response_id).unwrap().unwrap();
@@ -709,3 +1299,1716 @@ fn test_causation_chain_persists_across_reopen() {
     let effects = store.get_effects(msg_id);
     assert_eq!(effects, vec![response_id]);
 }
+
+#[test]
+fn test_append_following() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    let step = |n: i32| RecordInput::json("step", &json!({ "n": n })).unwrap();
+
+    let first = store.append_following(step(0)).unwrap();
+    assert!(first.caused_by.is_empty());
+    let mut chain = vec![first];
+    for n in 1..4 {
+        chain.push(store.append_following(step(n)).unwrap());
+    }
+    for pair in chain.windows(2) {
+        assert_eq!(pair[1].caused_by, vec![pair[0].id]);
+        assert_eq!(store.get_effects(pair[0].id), vec![pair[1].id]);
+    }
+
+    // Explicit causes are kept alongside
+    let other = store.append(step(10)).unwrap();
+    let joined = store.append_caused_by(step(11), &[chain[0].id]).unwrap();
+    assert_eq!(joined.caused_by, vec![chain[0].id]);
+    let next = store.append_following(step(12).with_caused_by(vec![other.id])).unwrap();
+    assert_eq!(next.caused_by, vec![other.id, joined.id]);
+
+    // Each branch follows its own appends
+    store.create_branch("side", None).unwrap();
+    store.switch_branch("side").unwrap();
+    let side_first = store.append_following(step(20)).unwrap();
+    assert!(side_first.caused_by.is_empty());
+    let side_second = store.append_following(step(21)).unwrap();
+    assert_eq!(side_second.caused_by, vec![side_first.id]);
+    store.switch_branch("main").unwrap();
+    let main_next = store.append_following(step(13)).unwrap();
+    assert_eq!(main_next.caused_by, vec![next.id]);
+}
+
+#[test]
+fn test_causation_tree() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    let append = |caused_by: Vec<RecordId>| {
+        store.append(RecordInput::raw("step", Vec::new()).with_caused_by(caused_by)).unwrap().id
+    };
+
+    // root -> a -> b -> c, and a diamond root -> {x, y} -> z -> c
+    let root = append(vec![]);
+    let a = append(vec![root]);
+    let b = append(vec![a]);
+    let x = append(vec![root]);
+    let y = append(vec![root]);
+    let z = append(vec![x, y]);
+    let c = append(vec![b, z]);
+    append(vec![]);
+
+    let tree = store.get_causation_tree(root, None);
+    assert_eq!(tree.root, root);
+    assert!(!tree.truncated);
+    let ids: Vec<RecordId> = tree.nodes.iter().map(|node| node.id).collect();
+    assert_eq!(ids, vec![root, a, x, y, b, z, c]);
+    let depth = |id| tree.get(id).unwrap().depth;
+    assert_eq!([depth(root), depth(a), depth(x), depth(y), depth(b), depth(z), depth(c)], [0, 1, 1, 1, 2, 2, 3]);
+    assert_eq!(tree.get(z).unwrap().causes, vec![x, y]);
+    assert_eq!(tree.get(c).unwrap().causes, vec![b, z]);
+    assert!(tree.get(root).unwrap().causes.is_empty());
+
+    let tree = store.get_causation_tree(root, Some(1));
+    assert!(tree.truncated);
+    assert_eq!(tree.nodes.len(), 4);
+
+    // A cycle: p is caused by q, which is appended after it
+    let p = append(vec![RecordId(c.0 + 3)]);
+    let q = append(vec![p]);
+    assert_eq!(q, RecordId(c.0 + 3));
+    let tree = store.get_causation_tree(p, None);
+    let ids: Vec<RecordId> = tree.nodes.iter().map(|node| node.id).collect();
+    assert_eq!(ids, vec![p, q]);
+    assert_eq!(tree.get(p).unwrap().causes, vec![q]);
+}
+
+// --- Record Type Count Tests ---
+
+#[test]
+fn test_count_records_by_type() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+
+    let types = ["message", "tool_call", "tool_result"];
+    for i in 0..3000 {
+        let record_type = types[i % 7 % 3];
+        store
+            .append(RecordInput::raw(record_type, i.to_string().into_bytes()))
+            .unwrap();
+    }
+
+    for record_type in types {
+        assert_eq!(
+            store.count_records_by_type(record_type),
+            store.get_records_by_type(record_type).len()
+        );
+    }
+    assert_eq!(store.count_records_by_type("missing"), 0);
+
+    let listed = store.list_record_types();
+    let names: Vec<_> = listed.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, types);
+    assert_eq!(listed.iter().map(|(_, count)| count).sum::<usize>(), 3000);
+
+    // Expired records drop out of the counts like they do from lookups
+    let past = Timestamp(Timestamp::now().0 - 1);
+    store
+        .append(RecordInput::raw("cache", vec![]).with_expires_at(past))
+        .unwrap();
+    assert_eq!(store.count_records_by_type("cache"), 0);
+    assert!(store.list_record_types().iter().all(|(name, _)| name != "cache"));
+}
+
+#[test]
+fn test_records_by_type_on_branch() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    let message = |text: &str| RecordInput::raw("message", text.as_bytes().to_vec());
+
+    let m1 = store.append(message("main 1")).unwrap().id;
+    store.append(RecordInput::raw("tool_call", vec![])).unwrap();
+    store.create_branch("feature", None).unwrap();
+    let m2 = store.append(message("main 2")).unwrap().id;
+
+    store.switch_branch("feature").unwrap();
+    let f1 = store.append(message("feature 1")).unwrap().id;
+    store.create_branch("other", None).unwrap();
+    store.switch_branch("other").unwrap();
+    let o1 = store.append(message("other 1")).unwrap().id;
+
+    // The global index sees every branch
+    assert_eq!(store.get_records_by_type("message").len(), 4);
+
+    assert_eq!(store.get_records_by_type_on_branch("message", "feature").unwrap(), vec![m1, f1]);
+    assert_eq!(store.get_records_by_type_on_branch("message", "main").unwrap(), vec![m1, m2]);
+    assert_eq!(store.get_records_by_type_on_branch("message", "other").unwrap(), vec![m1, f1, o1]);
+    assert!(store.get_records_by_type_on_branch("missing", "feature").unwrap().is_empty());
+    assert!(matches!(
+        store.get_records_by_type_on_branch("message", "nope"),
+        Err(StoreError::BranchNotFound(_))
+    ));
+}
+
+#[test]
+fn test_branches_containing_sequence() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    for i in 0..3 {
+        store.append(RecordInput::raw("event", vec![i])).unwrap();
+    }
+    store.create_branch("feature", None).unwrap();
+    let main_4 = store.append(RecordInput::raw("event", vec![4])).unwrap();
+
+    // Before the fork both branches see it; after it only main does
+    assert_eq!(store.branches_containing_sequence(Sequence(2)), vec!["feature", "main"]);
+    assert_eq!(store.branches_containing_sequence(main_4.sequence), vec!["main"]);
+
+    // The same sequence on the child is its own record
+    store.switch_branch("feature").unwrap();
+    let feature_4 = store.append(RecordInput::raw("event", vec![40])).unwrap();
+    assert_eq!(feature_4.sequence, main_4.sequence);
+    assert_eq!(store.branches_containing_sequence(feature_4.sequence), vec!["feature"]);
+
+    // A grandchild inherits through its parent
+    store.create_branch("nested", None).unwrap();
+    assert_eq!(
+        store.branches_containing_sequence(Sequence(1)),
+        vec!["feature", "main", "nested"]
+    );
+    assert_eq!(store.branches_containing_sequence(feature_4.sequence), vec!["feature", "nested"]);
+    assert!(store.branches_containing_sequence(Sequence(99)).is_empty());
+}
+
+// --- Field Index Tests ---
+
+#[test]
+fn test_field_index() {
+    let dir = TempDir::new().unwrap();
+    let config = StoreConfig {
+        path: dir.path().join("store"),
+        blob_cache_size: 100,
+        create_if_missing: true,
+        ..Default::default()
+    };
+    let store = Store::create(config.clone()).unwrap();
+
+    // Records from before the index exists are backfilled
+    let early = store
+        .append(RecordInput::json("message", &json!({"conversation_id": "abc", "n": 1})).unwrap())
+        .unwrap();
+    assert!(matches!(
+        store.get_records_by_field("conversation_id", "abc"),
+        Err(StoreError::InvalidOperation(_))
+    ));
+    store.add_field_index("conversation_id").unwrap();
+    store.add_field_index("user").unwrap();
+
+    let mut abc = vec![early.id];
+    for i in 0..6 {
+        let conversation = if i % 2 == 0 { "abc" } else { "xyz" };
+        let record = store
+            .append(
+                RecordInput::msgpack("message", &json!({"conversation_id": conversation, "user": i % 3}))
+                    .unwrap(),
+            )
+            .unwrap();
+        if conversation == "abc" {
+            abc.push(record.id);
+        }
+    }
+    // Missing, non-scalar and non-JSON payloads are skipped
+    store.append(RecordInput::json("message", &json!({"conversation_id": ["abc"]})).unwrap()).unwrap();
+    store.append(RecordInput::json("message", &json!({"text": "no field"})).unwrap()).unwrap();
+    store.append(RecordInput::raw("blob", b"conversation_id".to_vec())).unwrap();
+
+    assert_eq!(store.get_records_by_field("conversation_id", "abc").unwrap(), abc);
+    assert_eq!(store.get_records_by_field("conversation_id", "xyz").unwrap().len(), 3);
+    assert!(store.get_records_by_field("conversation_id", "none").unwrap().is_empty());
+    assert_eq!(store.get_records_by_field("user", 0).unwrap().len(), 2);
+    // Integers and strings are distinct keys
+    assert!(store.get_records_by_field("user", "0").unwrap().is_empty());
+    drop(store);
+
+    let store = Store::open(StoreConfig { create_if_missing: false, ..config }).unwrap();
+    assert_eq!(store.get_records_by_field("conversation_id", "abc").unwrap(), abc);
+    assert_eq!(store.get_records_by_field("user", 2).unwrap().len(), 2);
+}
+
+// --- Record Expiry Tests ---
+
+#[test]
+fn test_record_expiry() {
+    let dir = TempDir::new().unwrap();
+    let config = StoreConfig {
+        path: dir.path().join("store"),
+        blob_cache_size: 100,
+        create_if_missing: true,
+        ..Default::default()
+    };
+    let store = Store::create(config.clone()).unwrap();
+
+    let now = Timestamp::now();
+    let later = Timestamp(now.0 + 3_600_000_000);
+
+    let stale = store
+        .append(RecordInput::raw("cache", b"stale".to_vec()).with_expires_at(Timestamp(now.0 - 1)))
+        .unwrap();
+    let fresh = store
+        .append(RecordInput::raw("cache", b"fresh".to_vec()).with_expires_at(later))
+        .unwrap();
+    let kept = store.append(RecordInput::raw("cache", b"kept".to_vec())).unwrap();
+    assert_eq!(fresh.expires_at, Some(later));
+
+    // Filtered on read before any sweep
+    assert!(store.get_record(stale.id).unwrap().is_none());
+    assert_eq!(store.get_records_by_type("cache"), vec![fresh.id, kept.id]);
+    assert_eq!(store.query_range(None, None, 10, false, None).unwrap().len(), 2);
+    assert_eq!(store.stats().unwrap().record_count, 3);
+
+    // The sweep removes it from the log
+    let log_path = config.path.join("records.log");
+    let log_before = std::fs::metadata(&log_path).unwrap().len();
+    assert_eq!(store.expire_records(Timestamp::now()).unwrap(), 1);
+    assert_eq!(store.stats().unwrap().record_count, 2);
+    assert!(std::fs::metadata(&log_path).unwrap().len() < log_before);
+    assert_eq!(store.get_record(fresh.id).unwrap().unwrap().payload, b"fresh");
+    assert_eq!(store.expire_records(Timestamp::now()).unwrap(), 0);
+
+    // Expiry survives a reopen
+    drop(store);
+    let store = Store::open(config).unwrap();
+    assert_eq!(store.stats().unwrap().record_count, 2);
+    assert_eq!(
+        store.get_record(fresh.id).unwrap().unwrap().expires_at,
+        Some(later)
+    );
+    assert_eq!(store.expire_records(Timestamp(later.0 + 1)).unwrap(), 1);
+    assert_eq!(store.get_records_by_type("cache"), vec![kept.id]);
+}
+
+// --- Idempotency Key Tests ---
+
+#[test]
+fn test_append_with_idempotency_key() {
+    let dir = TempDir::new().unwrap();
+    let config = StoreConfig {
+        path: dir.path().join("store"),
+        blob_cache_size: 100,
+        create_if_missing: true,
+        ..Default::default()
+    };
+    let store = Store::create(config.clone()).unwrap();
+    let log_size = || std::fs::metadata(dir.path().join("store/records.log")).unwrap().len();
+
+    let first = store
+        .append(RecordInput::raw("order", b"first".to_vec()).with_idempotency_key("order-1"))
+        .unwrap();
+    assert_eq!(first.idempotency_key.as_deref(), Some("order-1"));
+    let size = log_size();
+
+    // A retry gets the first record back and writes nothing
+    let retry = store
+        .append(RecordInput::raw("order", b"retry".to_vec()).with_idempotency_key("order-1"))
+        .unwrap();
+    assert_eq!(retry.id, first.id);
+    assert_eq!(retry.payload, b"first");
+    assert_eq!(store.record_count(), 1);
+    assert_eq!(store.current_branch().head, first.sequence);
+    assert_eq!(log_size(), size);
+
+    // Other keys, and no key, append as usual
+    let other = store
+        .append(RecordInput::raw("order", b"other".to_vec()).with_idempotency_key("order-2"))
+        .unwrap();
+    assert_ne!(other.id, first.id);
+    store.append(RecordInput::raw("order", b"plain".to_vec())).unwrap();
+    assert_eq!(store.record_count(), 3);
+
+    // Keys are global, and survive a reopen
+    drop(store);
+    let store = Store::open(config).unwrap();
+    store.create_branch("retry", None).unwrap();
+    store.switch_branch("retry").unwrap();
+    let (id, _) = store
+        .append_fast(RecordInput::raw("order", b"again".to_vec()).with_idempotency_key("order-2"))
+        .unwrap();
+    assert_eq!(id, other.id);
+    assert_eq!(store.record_count(), 3);
+}
+
+// --- Schema Reference Tests ---
+
+#[test]
+fn test_record_schema_reference() {
+    let dir = TempDir::new().unwrap();
+    let config = StoreConfig {
+        path: dir.path().join("store"),
+        blob_cache_size: 100,
+        create_if_missing: true,
+        ..Default::default()
+    };
+    let store = Store::create(config.clone()).unwrap();
+    let schema = store
+        .store_json_blob(&json!({"required": ["user", "amount"]}), "application/schema+json")
+        .unwrap();
+
+    let payment = store
+        .append(
+            RecordInput::raw("payment", br#"{"user":"ana","amount":5}"#.to_vec())
+                .with_encoding(PayloadEncoding::Json)
+                .with_schema(schema),
+        )
+        .unwrap();
+    assert_eq!(payment.schema, Some(schema));
+    let plain = store.append(RecordInput::raw("note", b"hi".to_vec())).unwrap();
+    assert_eq!(plain.schema, None);
+
+    drop(store);
+    let store = Store::open(config).unwrap();
+    let record = store.get_record(payment.id).unwrap().unwrap();
+    assert_eq!(record.encoding, PayloadEncoding::Json);
+    assert_eq!(record.schema, Some(schema));
+    assert_eq!(store.get_record(plain.id).unwrap().unwrap().schema, None);
+
+    // The schema is enough to check the payload without knowing its type
+    let blob = store.get_blob(&record.schema.unwrap()).unwrap().unwrap();
+    let schema_value: serde_json::Value = serde_json::from_slice(&blob.content).unwrap();
+    let payload: serde_json::Value = record.decode().unwrap();
+    for field in schema_value["required"].as_array().unwrap() {
+        assert!(payload.get(field.as_str().unwrap()).is_some());
+    }
+
+    // Records after it still get fresh IDs
+    let next = store.append(RecordInput::raw("note", b"later".to_vec())).unwrap();
+    assert!(next.id.0 > plain.id.0);
+}
+
+// --- Record Schema Tests ---
+
+#[test]
+fn test_record_schema_validation() {
+    let dir = TempDir::new().unwrap();
+    let config = StoreConfig {
+        path: dir.path().join("store"),
+        blob_cache_size: 100,
+        create_if_missing: true,
+        ..Default::default()
+    };
+    let store = Store::create(config.clone()).unwrap();
+    let schema = json!({
+        "type": "object",
+        "required": ["role", "text"],
+        "properties": {
+            "role": {"enum": ["user", "assistant"]},
+            "text": {"type": "string"}
+        }
+    });
+    let hash = store.register_record_schema("message", &schema).unwrap();
+    assert_eq!(store.record_schema("message"), Some(hash));
+
+    // A conforming record is appended and references its schema
+    let ok = store
+        .append(RecordInput::json("message", &json!({"role": "user", "text": "hi"})).unwrap())
+        .unwrap();
+    assert_eq!(ok.schema, Some(hash));
+
+    // A non-conforming one is rejected and nothing is written
+    let err = store
+        .append(RecordInput::json("message", &json!({"role": "user"})).unwrap())
+        .unwrap_err();
+    assert!(matches!(err, StoreError::SchemaViolation { ref record_type, .. } if record_type == "message"));
+    assert!(matches!(
+        store.append(RecordInput::raw("message", b"opaque".to_vec())),
+        Err(StoreError::SchemaViolation { .. })
+    ));
+    assert_eq!(store.record_count(), 1);
+
+    // Other types aren't checked, and trusted loads can opt out
+    store.append(RecordInput::raw("note", b"anything".to_vec())).unwrap();
+    let trusted = store
+        .append(RecordInput::json("message", &json!({"role": "system"})).unwrap().without_schema_validation())
+        .unwrap();
+    assert_eq!(trusted.schema, None);
+
+    // The registration survives a reopen
+    drop(store);
+    let store = Store::open(config).unwrap();
+    assert_eq!(store.record_schema("message"), Some(hash));
+    let stored: serde_json::Value = serde_json::from_slice(&store.get_blob(&hash).unwrap().unwrap().content).unwrap();
+    assert_eq!(stored, schema);
+    assert!(matches!(
+        store.append(RecordInput::json("message", &json!({"role": "bot", "text": "hi"})).unwrap()),
+        Err(StoreError::SchemaViolation { .. })
+    ));
+    store
+        .append(RecordInput::msgpack("message", &json!({"role": "assistant", "text": "hello"})).unwrap())
+        .unwrap();
+
+    assert!(store.unregister_record_schema("message").unwrap());
+    assert!(!store.unregister_record_schema("message").unwrap());
+    store.append(RecordInput::json("message", &json!({"role": "bot"})).unwrap()).unwrap();
+}
+
+#[test]
+fn test_record_schema_kept_by_compact_and_snapshot() {
+    let dir = TempDir::new().unwrap();
+    let config = StoreConfig {
+        path: dir.path().join("store"),
+        blob_cache_size: 100,
+        create_if_missing: true,
+        ..Default::default()
+    };
+    let store = Store::create(config.clone()).unwrap();
+    let schema = json!({"type": "object", "required": ["text"]});
+    let hash = store.register_record_schema("message", &schema).unwrap();
+    let unused = store.store_blob(b"unreferenced", "text/plain").unwrap();
+
+    // Registered schemas count as referenced, unlike the stray blob
+    let report = store.compact(CompactOptions { gc_blobs: true, ..Default::default() }).unwrap();
+    assert_eq!(report.blobs_deleted, 1);
+    assert!(store.get_blob(&unused).unwrap().is_none());
+    assert!(store.get_blob(&hash).unwrap().is_some());
+
+    // Backups carry the registration
+    let backup = dir.path().join("backup");
+    store.snapshot_to_dir(&backup).unwrap();
+    drop(store);
+    for path in [config.path.clone(), backup] {
+        let store = Store::open(StoreConfig { path, ..config.clone() }).unwrap();
+        assert_eq!(store.record_schema("message"), Some(hash));
+        assert!(matches!(
+            store.append(RecordInput::json("message", &json!({})).unwrap()),
+            Err(StoreError::SchemaViolation { .. })
+        ));
+    }
+}
+
+// --- Squash Tests ---
+
+#[test]
+fn test_squash_records() {
+    let dir = TempDir::new().unwrap();
+    let config = StoreConfig {
+        path: dir.path().join("store"),
+        blob_cache_size: 100,
+        create_if_missing: true,
+        ..Default::default()
+    };
+    let store = Store::create(config.clone()).unwrap();
+
+    store
+        .register_state(StateRegistration {
+            id: "status".to_string(),
+            strategy: StateStrategy::Snapshot,
+            initial_value: None,
+        })
+        .unwrap();
+
+    // seq 1-5: low-value events, seq 6: state update, seq 7-8: a cause/effect pair
+    let mut events = Vec::new();
+    for i in 0..5 {
+        events.push(store.append(RecordInput::json("event", &json!({"tick": i})).unwrap()).unwrap());
+    }
+    store
+        .update_state("status", StateOperation::Set(br#"{"phase": "running"}"#.to_vec()))
+        .unwrap();
+    let cause = store.append(RecordInput::json("event", &json!({"tick": 5})).unwrap()).unwrap();
+    let effect = store
+        .append(
+            RecordInput::json("event", &json!({"tick": 6})).unwrap()
+                .with_caused_by(vec![cause.id]),
+        )
+        .unwrap();
+
+    // Refused: state updates in range, or causes referenced from outside
+    let summary = || RecordInput::json("summary", &json!({"ticks": 5})).unwrap();
+    assert!(matches!(
+        store.squash_records("main", Sequence(5), Sequence(6), summary()),
+        Err(StoreError::InvalidOperation(_))
+    ));
+    assert!(matches!(
+        store.squash_records("main", Sequence(7), Sequence(7), summary()),
+        Err(StoreError::InvalidOperation(_))
+    ));
+
+    let squashed = store.squash_records("main", Sequence(1), Sequence(5), summary()).unwrap();
+    let ids: Vec<_> = events.iter().map(|r| r.id).collect();
+    assert_eq!(squashed.linked_to, ids);
+    assert_eq!(squashed.sequence, Sequence(9));
+
+    let check = |store: &Store| {
+        // The range is gone from sequence order and type lookups
+        let seqs: Vec<_> = store
+            .query_range(None, None, 100, false, None)
+            .unwrap()
+            .iter()
+            .map(|r| r.sequence.0)
+            .collect();
+        assert_eq!(seqs, vec![6, 7, 8, 9]);
+        assert_eq!(store.get_records_by_type("event"), vec![cause.id, effect.id]);
+
+        // Links into the squashed range still resolve
+        for id in &ids {
+            assert!(store.get_record(*id).unwrap().is_some());
+            assert_eq!(store.get_links_to(*id), vec![squashed.id]);
+        }
+        assert_eq!(store.get_effects(cause.id), vec![effect.id]);
+
+        let state = store.get_state("status").unwrap().unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&state).unwrap();
+        assert_eq!(value["phase"], "running");
+    };
+    check(&store);
+
+    // Hiding is rebuilt from the log on reopen
+    drop(store);
+    let store = Store::open(config).unwrap();
+    check(&store);
+}
+
+// --- Branch Record Count Tests ---
+
+#[test]
+fn test_branch_record_counts() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    let append = |n: usize| {
+        for i in 0..n {
+            store.append(RecordInput::raw("event", vec![i as u8])).unwrap();
+        }
+    };
+    let counts = |store: &Store| {
+        let mut counts: Vec<(String, u64)> = store.branch_record_counts().unwrap().into_iter().collect();
+        counts.sort();
+        counts
+    };
+    let expect = |pairs: &[(&str, u64)]| -> Vec<(String, u64)> {
+        pairs.iter().map(|(name, count)| (name.to_string(), *count)).collect()
+    };
+
+    // main: 3 records, then "feature" forks and adds 2, and main adds 1
+    append(3);
+    assert_eq!(counts(&store), expect(&[("main", 3)]));
+    store.create_branch("feature", None).unwrap();
+    store.switch_branch("feature").unwrap();
+    append(2);
+    store.switch_branch("main").unwrap();
+    append(1);
+    assert_eq!(counts(&store), expect(&[("feature", 5), ("main", 4)]));
+
+    // A fork of a fork sees both ancestors; one from the past sees less
+    store.create_branch("child", Some("feature")).unwrap();
+    store.create_branch_at("early", "main", Sequence(2)).unwrap();
+    store.switch_branch("child").unwrap();
+    append(1);
+    store.switch_branch("early").unwrap();
+    append(3);
+    assert_eq!(
+        counts(&store),
+        expect(&[("child", 6), ("early", 5), ("feature", 5), ("main", 4)])
+    );
+
+    // Swept records stop counting for every branch that saw them
+    store.switch_branch("main").unwrap();
+    let now = Timestamp::now();
+    store
+        .append(RecordInput::raw("event", vec![]).with_expires_at(Timestamp(now.0 - 1)))
+        .unwrap();
+    store.create_branch("late", None).unwrap();
+    assert_eq!(
+        counts(&store),
+        expect(&[("child", 6), ("early", 5), ("feature", 5), ("late", 5), ("main", 5)])
+    );
+    store.expire_records(Timestamp::now()).unwrap();
+    assert_eq!(
+        counts(&store),
+        expect(&[("child", 6), ("early", 5), ("feature", 5), ("late", 4), ("main", 4)])
+    );
+
+    // Children of a deleted branch keep only their own records
+    store.delete_branch("feature").unwrap();
+    assert_eq!(
+        counts(&store),
+        expect(&[("child", 1), ("early", 5), ("late", 4), ("main", 4)])
+    );
+
+    // Counts don't depend on the store having been open when records were written
+    drop(store);
+    let store = Store::open(StoreConfig { path: dir.path().join("store"), ..Default::default() }).unwrap();
+    assert_eq!(
+        counts(&store),
+        expect(&[("child", 1), ("early", 5), ("late", 4), ("main", 4)])
+    );
+}
+
+// --- Branch Duplication Tests ---
+
+#[test]
+fn test_duplicate_branch() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    store
+        .register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 3, full_snapshot_every: 100 },
+            initial_value: None,
+        })
+        .unwrap();
+
+    let event = |n: i32| RecordInput::json("event", &json!({ "n": n })).unwrap();
+    let first = store.append(event(0)).unwrap();
+    let mut updates = Vec::new();
+    for i in 1..=3 {
+        updates.push(store.update_state("items", StateOperation::Append(i.to_string().into_bytes())).unwrap());
+    }
+    store.create_branch("feature", None).unwrap();
+    store.switch_branch("feature").unwrap();
+    store.append(event(1).with_caused_by(vec![first.id])).unwrap();
+    for i in 4..=6 {
+        updates.push(store.update_state("items", StateOperation::Append(i.to_string().into_bytes())).unwrap());
+    }
+    store.switch_branch("main").unwrap();
+    store.append(event(99)).unwrap();
+
+    assert!(matches!(
+        store.duplicate_branch("missing", "copy"),
+        Err(StoreError::BranchNotFound(_))
+    ));
+    let copy = store.duplicate_branch("feature", "copy").unwrap();
+    assert_eq!(copy.head, store.branch_head("feature").unwrap());
+
+    // Same records, none of main's later ones, links point within the copy
+    store.switch_branch("copy").unwrap();
+    let copied: Vec<Record> = store.iter_records().collect::<Result<_, _>>().unwrap();
+    store.switch_branch("feature").unwrap();
+    let original: Vec<Record> = store.iter_records().collect::<Result<_, _>>().unwrap();
+    assert_eq!(copied.len(), original.len());
+    for (copy, original) in copied.iter().zip(&original) {
+        assert_ne!(copy.id, original.id);
+        assert_eq!(copy.record_type, original.record_type);
+        assert_eq!(copy.sequence, original.sequence);
+    }
+    let caused: Vec<&Record> = copied.iter().filter(|record| !record.caused_by.is_empty()).collect();
+    assert_eq!(caused.len(), 1);
+    assert_eq!(caused[0].caused_by, vec![copied[0].id]);
+    assert_eq!(caused[0].payload, event(1).payload);
+
+    // Pruning the source leaves the copy's history intact
+    store.prune_history(&["items"]).unwrap();
+    assert!(matches!(
+        store.get_state_at("items", updates[1].sequence),
+        Err(StoreError::HistoryPruned { .. })
+    ));
+    store.switch_branch("copy").unwrap();
+    let items: Vec<i32> = store.get_state_as("items").unwrap().unwrap();
+    assert_eq!(items, (1..=6).collect::<Vec<_>>());
+    let early: Vec<i32> =
+        serde_json::from_slice(&store.get_state_at("items", updates[1].sequence).unwrap().unwrap()).unwrap();
+    assert_eq!(early, vec![1, 2]);
+
+    // So does deleting it and compacting the log
+    store.delete_branch("feature").unwrap();
+    store.compact_log_by_branch().unwrap();
+    store.update_state("items", StateOperation::Append(b"7".to_vec())).unwrap();
+    drop(store);
+
+    let store = Store::open(StoreConfig {
+        path: dir.path().join("store"),
+        ..Default::default()
+    })
+    .unwrap();
+    store.switch_branch("copy").unwrap();
+    let items: Vec<i32> = store.get_state_as("items").unwrap().unwrap();
+    assert_eq!(items, (1..=7).collect::<Vec<_>>());
+    assert!(store.check_consistency().unwrap().is_ok());
+}
+
+#[test]
+fn test_branch_size() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    store
+        .register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 100, full_snapshot_every: 100 },
+            initial_value: None,
+        })
+        .unwrap();
+
+    let event = |n: i32| RecordInput::json("event", &json!({ "n": n })).unwrap();
+    for i in 0..10 {
+        store.append(event(i)).unwrap();
+    }
+    store.update_state("items", StateOperation::Append(b"0".to_vec())).unwrap();
+    store.create_branch("feature", None).unwrap();
+    store.switch_branch("feature").unwrap();
+    assert_eq!(store.branch_size("feature").unwrap(), BranchSizeInfo::default());
+
+    let (_, start) = store.append_with_offset(event(100)).unwrap();
+    for i in 101..120 {
+        store.append(event(i)).unwrap();
+    }
+    for i in 1..=3 {
+        store.update_state("items", StateOperation::Append(i.to_string().into_bytes())).unwrap();
+    }
+    store.switch_branch("main").unwrap();
+    let (_, end) = store.append_with_offset(event(10)).unwrap();
+
+    let size = store.branch_size("feature").unwrap();
+    assert_eq!(size.records, 23);
+    assert_eq!(size.state_operations, 3);
+    assert_eq!(size.bytes, end - start);
+
+    // Main's own records, including the one appended after the fork
+    let main = store.branch_size("main").unwrap();
+    assert_eq!(main.records, 12);
+    assert_eq!(main.state_operations, 1);
+
+    assert!(matches!(store.branch_size("missing"), Err(StoreError::BranchNotFound(_))));
+}
+
+// --- Log Compaction Tests ---
+
+#[test]
+fn test_compact_log_by_branch() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    store
+        .register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 2, full_snapshot_every: 2 },
+            initial_value: None,
+        })
+        .unwrap();
+
+    let event = |n: i32| RecordInput::json("event", &json!({ "n": n })).unwrap();
+    let mut main_records = Vec::new();
+    for i in 0..3 {
+        main_records.push(store.append(event(i)).unwrap());
+        store.update_state("items", StateOperation::Append(i.to_string().into_bytes())).unwrap();
+    }
+
+    // A throwaway branch, and a branch whose parent gets deleted
+    store.create_branch("scratch", None).unwrap();
+    store.create_branch("parent", None).unwrap();
+    store.switch_branch("scratch").unwrap();
+    let scratch: Vec<Record> = (10..15).map(|i| store.append(event(i)).unwrap()).collect();
+    store.update_state("items", StateOperation::Append(b"99".to_vec())).unwrap();
+    store.switch_branch("parent").unwrap();
+    let shared = store.append(event(20)).unwrap();
+    store.create_branch("child", None).unwrap();
+    let unshared = store.append(event(21)).unwrap();
+    store.switch_branch("main").unwrap();
+    main_records.push(store.append(event(3)).unwrap());
+    store.update_state("items", StateOperation::Append(b"3".to_vec())).unwrap();
+
+    store.delete_branch("scratch").unwrap();
+    store.delete_branch("parent").unwrap();
+
+    let size_before = std::fs::metadata(dir.path().join("store/records.log")).unwrap().len();
+    let stats = store.compact_log_by_branch().unwrap();
+    // Scratch's five events, its update and the delta snapshot that
+    // followed, and the parent's record after the child branched off
+    assert_eq!(stats.records_removed, 8);
+    assert!(stats.bytes_reclaimed > 0);
+    let size_after = std::fs::metadata(dir.path().join("store/records.log")).unwrap().len();
+    assert_eq!(size_before - size_after, stats.bytes_reclaimed);
+
+    // Orphaned records are gone, main's and the child's view remain
+    for record in scratch.iter().chain([&unshared]) {
+        assert!(store.get_record(record.id).unwrap().is_none());
+    }
+    for record in main_records.iter().chain([&shared]) {
+        assert_eq!(store.get_record(record.id).unwrap().unwrap().payload, record.payload);
+    }
+    let items: Vec<i32> = store.get_state_as("items").unwrap().unwrap();
+    assert_eq!(items, vec![0, 1, 2, 3]);
+    store.switch_branch("child").unwrap();
+    let items: Vec<i32> = store.get_state_as("items").unwrap().unwrap();
+    assert_eq!(items, vec![0, 1, 2]);
+    store.switch_branch("main").unwrap();
+
+    // Nothing left to remove, and appends carry on
+    assert_eq!(store.compact_log_by_branch().unwrap().records_removed, 0);
+    store.update_state("items", StateOperation::Append(b"4".to_vec())).unwrap();
+    drop(store);
+
+    let store = Store::open(StoreConfig {
+        path: dir.path().join("store"),
+        ..Default::default()
+    })
+    .unwrap();
+    let items: Vec<i32> = store.get_state_as("items").unwrap().unwrap();
+    assert_eq!(items, vec![0, 1, 2, 3, 4]);
+    assert!(store.get_record(scratch[0].id).unwrap().is_none());
+    assert!(store.check_consistency().unwrap().is_ok());
+}
+
+#[test]
+fn test_truncate_branch() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    store
+        .register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 100, full_snapshot_every: 100 },
+            initial_value: None,
+        })
+        .unwrap();
+
+    let updates: Vec<Record> = (1..=10)
+        .map(|i| store.update_state("items", StateOperation::Append(i.to_string().into_bytes())).unwrap())
+        .collect();
+    store.create_branch_at("fork", "main", Sequence(7)).unwrap();
+
+    assert!(matches!(
+        store.truncate_branch("main", Sequence(11)),
+        Err(StoreError::InvalidSequence(..))
+    ));
+    assert!(matches!(
+        store.truncate_branch("main", Sequence(5)),
+        Err(StoreError::InvalidOperation(_))
+    ));
+    assert!(matches!(
+        store.truncate_branch("fork", Sequence(6)),
+        Err(StoreError::InvalidOperation(_))
+    ));
+    store.delete_branch("fork").unwrap();
+
+    store.truncate_branch("main", Sequence(5)).unwrap();
+    assert_eq!(store.branch_head("main").unwrap(), Sequence(5));
+    let items: Vec<i32> = store.get_state_as("items").unwrap().unwrap();
+    assert_eq!(items, vec![1, 2, 3, 4, 5]);
+    let early: Vec<i32> =
+        serde_json::from_slice(&store.get_state_at("items", Sequence(3)).unwrap().unwrap()).unwrap();
+    assert_eq!(early, vec![1, 2, 3]);
+    assert!(store.get_record(updates[4].id).unwrap().is_some());
+    assert!(store.get_record(updates[5].id).unwrap().is_none());
+    assert_eq!(store.get_state_len("items").unwrap(), Some(5));
+
+    // Appends carry on from the new head
+    let next = store.update_state("items", StateOperation::Append(b"60".to_vec())).unwrap();
+    assert_eq!(next.sequence, Sequence(6));
+    drop(store);
+
+    let store = Store::open(StoreConfig {
+        path: dir.path().join("store"),
+        ..Default::default()
+    })
+    .unwrap();
+    let items: Vec<i32> = store.get_state_as("items").unwrap().unwrap();
+    assert_eq!(items, vec![1, 2, 3, 4, 5, 60]);
+    assert!(store.check_consistency().unwrap().is_ok());
+}
+
+#[test]
+fn test_compact_store() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    store
+        .register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 100, full_snapshot_every: 100 },
+            initial_value: None,
+        })
+        .unwrap();
+
+    let kept = store.store_blob(b"kept", "text/plain").unwrap();
+    let content: Vec<u8> = (0..200_000u32).flat_map(|i| i.to_le_bytes()).collect();
+    let chunked = store.store_blob_chunked(&content, "application/octet-stream").unwrap();
+    let scratch_only = store.store_blob(b"only on scratch", "text/plain").unwrap();
+    let unreferenced = store.store_blob(b"unreferenced", "text/plain").unwrap();
+
+    let mut main_records = Vec::new();
+    for (i, hash) in [kept, chunked].iter().enumerate() {
+        let input = RecordInput::json("event", &json!({ "n": i, "blob": hash.to_hex() })).unwrap();
+        main_records.push(store.append(input).unwrap());
+        store.update_state("items", StateOperation::Append(i.to_string().into_bytes())).unwrap();
+    }
+
+    store.create_branch("scratch", None).unwrap();
+    store.switch_branch("scratch").unwrap();
+    let input = RecordInput::json("event", &json!({ "blob": scratch_only.to_hex() })).unwrap();
+    let scratch_record = store.append(input).unwrap();
+    for i in 0..20 {
+        store.update_state("items", StateOperation::Append(format!("{}", 100 + i).into_bytes())).unwrap();
+    }
+    store.switch_branch("main").unwrap();
+    store.update_state("items", StateOperation::Append(b"2".to_vec())).unwrap();
+
+    let log_path = dir.path().join("store/records.log");
+    let log_before = std::fs::metadata(&log_path).unwrap().len();
+    let blobs_before = store.blob_count().unwrap();
+
+    let report = store
+        .compact(CompactOptions {
+            snapshot_states: true,
+            branch_gc: Some(BranchGcOptions {
+                delete_stale_older_than: Some(i64::MAX as u64),
+                name_patterns: Some(vec!["scratch".to_string()]),
+                ..Default::default()
+            }),
+            gc_blobs: true,
+        })
+        .unwrap();
+    assert_eq!(report.states_snapshotted, 1);
+    assert_eq!(report.branches.deleted, vec!["scratch".to_string()]);
+    // The scratch record and its 20 state updates
+    assert_eq!(report.log.records_removed, 21);
+    assert_eq!(report.blobs_deleted, 2);
+    assert!(report.blob_bytes_reclaimed > 0);
+    assert!(std::fs::metadata(&log_path).unwrap().len() < log_before);
+    assert_eq!(store.blob_count().unwrap(), blobs_before - 2);
+
+    // Everything still reachable reads back the same
+    for record in &main_records {
+        assert_eq!(store.get_record(record.id).unwrap().unwrap().payload, record.payload);
+    }
+    assert!(store.get_record(scratch_record.id).unwrap().is_none());
+    let items: Vec<i32> = store.get_state_as("items").unwrap().unwrap();
+    assert_eq!(items, vec![0, 1, 2]);
+    assert_eq!(store.get_blob(&kept).unwrap().unwrap().content, b"kept");
+    assert_eq!(store.get_blob(&chunked).unwrap().unwrap().content, content);
+    assert!(store.get_blob(&scratch_only).unwrap().is_none());
+    assert!(store.get_blob(&unreferenced).unwrap().is_none());
+    assert!(store.list_branches().iter().all(|branch| branch.name != "scratch"));
+    assert!(store.check_consistency().unwrap().is_ok());
+
+    // A second run has nothing left to do
+    let report = store.compact(CompactOptions { snapshot_states: true, gc_blobs: true, ..Default::default() }).unwrap();
+    assert_eq!(report.states_snapshotted, 0);
+    assert_eq!(report.bytes_reclaimed(), 0);
+}
+
+#[test]
+fn test_compact_blob_gc_references() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    let blob = |content: &str| store.store_blob(content.as_bytes(), "text/plain").unwrap();
+
+    // References blob GC recognizes
+    let as_hex = blob("as hex");
+    let as_json_hash = blob("as a serialized hash in JSON");
+    let as_msgpack_hash = blob("as a serialized hash in MessagePack");
+    store.append(RecordInput::json("event", &json!({ "blob": as_hex.to_hex() })).unwrap()).unwrap();
+    store.append(RecordInput::json("event", &json!({ "nested": [{ "blob": as_json_hash }] })).unwrap()).unwrap();
+    store.append(RecordInput::msgpack("event", &json!({ "blob": as_msgpack_hash })).unwrap()).unwrap();
+
+    // References it can't see, so their blobs are deleted
+    let in_longer_hex = blob("inside a longer hex run");
+    let as_raw_bytes = blob("as raw bytes");
+    let input = RecordInput::json("event", &json!({ "id": format!("{}ff", in_longer_hex.to_hex()) })).unwrap();
+    store.append(input).unwrap();
+    store.append(RecordInput::raw("event", as_raw_bytes.0.to_vec())).unwrap();
+
+    let report = store.compact(CompactOptions { gc_blobs: true, ..Default::default() }).unwrap();
+    assert_eq!(report.blobs_deleted, 2);
+    for hash in [as_hex, as_json_hash, as_msgpack_hash] {
+        assert!(store.get_blob(&hash).unwrap().is_some());
+    }
+    for hash in [in_longer_hex, as_raw_bytes] {
+        assert!(store.get_blob(&hash).unwrap().is_none());
+    }
+}
+
+// --- Replication Tests ---
+
+fn replica_store(dir: &TempDir) -> Store {
+    Store::create(StoreConfig {
+        path: dir.path().join("replica"),
+        create_if_missing: true,
+        ..Default::default()
+    })
+    .unwrap()
+}
+
+/// A branch's records, leaving out state update payloads (they carry the
+/// time they were written).
+fn branch_records(store: &Store, branch: &str) -> Vec<(Sequence, String, Vec<u8>)> {
+    let current = store.current_branch().name;
+    store.switch_branch(branch).unwrap();
+    let records = store
+        .iter_records()
+        .map(|record| {
+            let record = record.unwrap();
+            let payload = if record.record_type == "state_update" { Vec::new() } else { record.payload };
+            (record.sequence, record.record_type, payload)
+        })
+        .collect();
+    store.switch_branch(&current).unwrap();
+    records
+}
+
+#[test]
+fn test_changes_since_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let source = test_store(&dir);
+    let replica = replica_store(&dir);
+
+    source
+        .register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 2, full_snapshot_every: 2 },
+            initial_value: None,
+        })
+        .unwrap();
+    source
+        .register_state(StateRegistration {
+            id: "config".to_string(),
+            strategy: StateStrategy::Snapshot,
+            initial_value: None,
+        })
+        .unwrap();
+
+    let hash = source.store_blob(b"fn main() {}", "text/rust").unwrap();
+    let first = source.append(RecordInput::json("event", &json!({"n": 1})).unwrap()).unwrap();
+    source
+        .append(RecordInput::json("event", &json!({"n": 2})).unwrap().with_caused_by(vec![first.id]))
+        .unwrap();
+    for i in 0..5 {
+        source.update_state("items", StateOperation::Append(i.to_string().into_bytes())).unwrap();
+    }
+    source.update_state("config", StateOperation::Set(br#"{"v":1}"#.to_vec())).unwrap();
+    source.create_branch("feature", None).unwrap();
+    source.switch_branch("feature").unwrap();
+    source.append(RecordInput::json("event", &json!({"n": 3})).unwrap()).unwrap();
+    source.update_state("items", StateOperation::Append(b"50".to_vec())).unwrap();
+    source.switch_branch("main").unwrap();
+    source.create_branch("idle", None).unwrap();
+
+    // The feed is serializable for transport
+    let (changes, cursor) = source.changes_since(Default::default()).unwrap();
+    let wire = serde_json::to_vec(&changes).unwrap();
+    replica.apply_changes(serde_json::from_slice(&wire).unwrap()).unwrap();
+
+    let names = |store: &Store| {
+        let mut names: Vec<String> = store.list_branches().into_iter().map(|b| b.name).collect();
+        names.sort();
+        names
+    };
+    assert_eq!(names(&replica), names(&source));
+    assert_eq!(replica.get_blob(&hash).unwrap().unwrap().content, b"fn main() {}");
+    for branch in ["main", "feature", "idle"] {
+        assert_eq!(branch_records(&replica, branch), branch_records(&source, branch));
+        source.switch_branch(branch).unwrap();
+        replica.switch_branch(branch).unwrap();
+        for state in ["items", "config"] {
+            assert_eq!(replica.get_state(state).unwrap(), source.get_state(state).unwrap());
+        }
+    }
+    source.switch_branch("main").unwrap();
+    replica.switch_branch("main").unwrap();
+    let effects = replica.get_effects(first.id);
+    assert_eq!(effects, source.get_effects(first.id));
+    assert_eq!(replica.current_lamport(), source.current_lamport());
+
+    // Nothing new: an empty batch and the same cursor
+    let (changes, again) = source.changes_since(cursor).unwrap();
+    assert!(changes.is_empty());
+    assert_eq!(again, cursor);
+
+    // Later changes only; replaying a batch twice doesn't duplicate it
+    source.update_state("items", StateOperation::Append(b"5".to_vec())).unwrap();
+    source.switch_branch("idle").unwrap();
+    source.append(RecordInput::json("event", &json!({"n": 4})).unwrap()).unwrap();
+    source.switch_branch("main").unwrap();
+    let (changes, _) = source.changes_since(cursor).unwrap();
+    assert!(matches!(
+        changes.as_slice(),
+        [Change::RegisterState { .. }, Change::UpdateState { .. }, Change::Append { .. }]
+    ));
+    replica.apply_changes(changes.clone()).unwrap();
+    replica.apply_changes(changes).unwrap();
+    assert_eq!(replica.get_state("items").unwrap(), source.get_state("items").unwrap());
+    assert_eq!(branch_records(&replica, "idle"), branch_records(&source, "idle"));
+    assert_eq!(replica.record_count(), source.record_count());
+}
+
+#[test]
+fn test_apply_changes_dedup_and_conflict() {
+    let dir = TempDir::new().unwrap();
+    let source = test_store(&dir);
+    let replica = replica_store(&dir);
+    assert_ne!(source.node_id(), replica.node_id());
+
+    source
+        .register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 10, full_snapshot_every: 10 },
+            initial_value: None,
+        })
+        .unwrap();
+    source.append(RecordInput::json("event", &json!({"n": 1})).unwrap()).unwrap();
+    source.update_state("items", StateOperation::Append(b"1".to_vec())).unwrap();
+    let last = source.append(RecordInput::json("event", &json!({"n": 2})).unwrap()).unwrap();
+
+    let (changes, _) = source.changes_since(Default::default()).unwrap();
+    assert!(changes
+        .iter()
+        .filter_map(Change::key)
+        .all(|key| key.source == source.node_id()));
+
+    // Applying twice, or again after a reopen, doesn't duplicate anything
+    replica.apply_changes(changes.clone()).unwrap();
+    replica.apply_changes(changes.clone()).unwrap();
+    assert_eq!(replica.record_count(), 3);
+    assert_eq!(replica.applied_watermark(&source.node_id()), Some(last.id.0));
+    let replica_id = replica.node_id();
+    drop(replica);
+    let replica = Store::open(StoreConfig {
+        path: dir.path().join("replica"),
+        ..Default::default()
+    })
+    .unwrap();
+    assert_eq!(replica.node_id(), replica_id);
+    assert_eq!(replica.applied_watermark(&source.node_id()), Some(last.id.0));
+    replica.apply_changes(changes.clone()).unwrap();
+    assert_eq!(replica.record_count(), 3);
+
+    // Without a watermark (say it was lost), matching records are still
+    // recognised as applied
+    let relabeled: Vec<Change> = changes
+        .iter()
+        .cloned()
+        .map(|mut change| {
+            match &mut change {
+                Change::Append { key, .. } | Change::UpdateState { key, .. } => {
+                    key.source = "elsewhere".to_string();
+                }
+                _ => {}
+            }
+            change
+        })
+        .collect();
+    replica.apply_changes(relabeled).unwrap();
+    assert_eq!(replica.record_count(), 3);
+    assert_eq!(replica.applied_watermark("elsewhere"), Some(last.id.0));
+
+    // A store that wrote its own record at the same sequence has diverged
+    let diverged = Store::create(StoreConfig {
+        path: dir.path().join("diverged"),
+        create_if_missing: true,
+        ..Default::default()
+    })
+    .unwrap();
+    diverged.append(RecordInput::json("event", &json!({"n": 100})).unwrap()).unwrap();
+    let err = diverged.apply_changes(changes).unwrap_err();
+    assert!(matches!(
+        err,
+        StoreError::ReplicationConflict { ref branch, sequence: Sequence(1), .. } if branch == "main"
+    ));
+    assert_eq!(diverged.record_count(), 1);
+    assert_eq!(diverged.applied_watermark(&source.node_id()), None);
+}
+
+#[test]
+fn test_apply_changes_retries_failed_change() {
+    let dir = TempDir::new().unwrap();
+    let source = test_store(&dir);
+    let config = StoreConfig {
+        path: dir.path().join("replica"),
+        create_if_missing: true,
+        max_record_payload_bytes: Some(16),
+        ..Default::default()
+    };
+    let replica = Store::create(config.clone()).unwrap();
+
+    let first = source.append(RecordInput::json("event", &json!({"n": 1})).unwrap()).unwrap();
+    let large = source
+        .append(RecordInput::json("event", &json!({"text": "longer than the replica allows"})).unwrap())
+        .unwrap();
+    source.append(RecordInput::json("event", &json!({"n": 3})).unwrap()).unwrap();
+    let (changes, _) = source.changes_since(Default::default()).unwrap();
+
+    // The oversized record fails; only the one before it counts as applied
+    assert!(replica.apply_changes(changes.clone()).is_err());
+    assert_eq!(replica.record_count(), 1);
+    assert_eq!(replica.applied_watermark(&source.node_id()), Some(first.id.0));
+    drop(replica);
+
+    // Once the limit allows it, a retry writes the change and the rest
+    let replica = Store::open(StoreConfig {
+        max_record_payload_bytes: None,
+        ..config
+    })
+    .unwrap();
+    replica.apply_changes(changes).unwrap();
+    assert_eq!(replica.record_count(), 3);
+    assert_eq!(branch_records(&replica, "main"), branch_records(&source, "main"));
+    assert!(replica.get_record(large.id).unwrap().is_some());
+}
+
+#[test]
+fn test_export_branch_round_trip() {
+    let dir = TempDir::new().unwrap();
+    let source = test_store(&dir);
+
+    source
+        .register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 2, full_snapshot_every: 2 },
+            initial_value: None,
+        })
+        .unwrap();
+    let shared = source.store_blob(b"shared", "text/plain").unwrap();
+    let cause = source
+        .append(RecordInput::json("upload", &json!({"blob": shared.to_hex()})).unwrap())
+        .unwrap();
+    source.update_state("items", StateOperation::Append(b"1".to_vec())).unwrap();
+
+    source.create_branch("feature", None).unwrap();
+    source.switch_branch("feature").unwrap();
+    let attachment = source.store_blob(b"attachment", "text/plain").unwrap();
+    source
+        .append(
+            RecordInput::json("note", &json!({"blob": attachment.to_hex()}))
+                .unwrap()
+                .with_caused_by(vec![cause.id]),
+        )
+        .unwrap();
+    for i in 2..=4 {
+        source.update_state("items", StateOperation::Append(i.to_string().into_bytes())).unwrap();
+    }
+
+    // Not visible from the feature branch
+    source.switch_branch("main").unwrap();
+    let unrelated = source.store_blob(b"unrelated", "text/plain").unwrap();
+    source
+        .append(RecordInput::json("upload", &json!({"blob": unrelated.to_hex()})).unwrap())
+        .unwrap();
+    source.update_state("items", StateOperation::Append(b"99".to_vec())).unwrap();
+
+    let mut stream = Vec::new();
+    let manifest = source.export_branch("feature", &mut stream).unwrap();
+    assert_eq!(manifest.branch, "feature");
+    assert_eq!((manifest.records, manifest.blobs, manifest.states), (2, 2, 1));
+
+    let imported = replica_store(&dir);
+    imported.import_branch(&stream[..]).unwrap();
+    assert_eq!(branch_records(&imported, "main"), branch_records(&source, "feature"));
+    source.switch_branch("feature").unwrap();
+    assert_eq!(imported.get_state("items").unwrap(), source.get_state("items").unwrap());
+    assert_eq!(imported.get_state_len("items").unwrap(), Some(4));
+    assert!(imported.blob_exists(&shared) && imported.blob_exists(&attachment));
+    assert!(!imported.blob_exists(&unrelated));
+
+    // Links between exported records carry over
+    let notes = imported.get_records_by_type("note");
+    let note = imported.get_record(notes[0]).unwrap().unwrap();
+    let upload = imported.get_record(note.caused_by[0]).unwrap().unwrap();
+    assert_eq!(upload.record_type, "upload");
+    assert_eq!(imported.get_effects(upload.id), vec![note.id]);
+
+    // Only into an empty store
+    assert!(matches!(
+        imported.import_branch(&stream[..]),
+        Err(StoreError::InvalidOperation(_))
+    ));
+}
+
+// --- Concurrency Tests ---
+
+#[test]
+fn test_concurrent_state_updates() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    let states: Vec<String> = (0..50).map(|i| format!("state-{}", i)).collect();
+    for id in &states {
+        store
+            .register_state(StateRegistration {
+                id: id.clone(),
+                strategy: StateStrategy::AppendLog { delta_snapshot_every: 7, full_snapshot_every: 3 },
+                initial_value: None,
+            })
+            .unwrap();
+    }
+
+    // Every thread appends to every state, so states are contended too
+    let threads = 8;
+    let rounds = 10;
+    std::thread::scope(|scope| {
+        for t in 0..threads {
+            let store = &store;
+            let states = &states;
+            scope.spawn(move || {
+                for n in 0..rounds {
+                    for id in states {
+                        let item = serde_json::to_vec(&json!({ "t": t, "n": n })).unwrap();
+                        store.update_state(id, StateOperation::Append(item)).unwrap();
+                    }
+                }
+            });
+        }
+    });
+
+    #[derive(Deserialize)]
+    struct Item {
+        t: usize,
+        n: usize,
+    }
+    let check = |store: &Store| {
+        for id in &states {
+            let items: Vec<Item> = store.get_state_as(id).unwrap().unwrap();
+            assert_eq!(items.len(), threads * rounds);
+            assert_eq!(store.get_state_len(id).unwrap(), Some(threads * rounds));
+            // Each thread's appends land in the order it made them
+            for t in 0..threads {
+                let ns: Vec<usize> = items.iter().filter(|item| item.t == t).map(|item| item.n).collect();
+                assert_eq!(ns, (0..rounds).collect::<Vec<_>>());
+            }
+        }
+
+        // Sequences are unique and gapless
+        let sequences: Vec<u64> = store.iter_records().map(|record| record.unwrap().sequence.0).collect();
+        let head = store.current_branch().head.0;
+        assert_eq!(sequences, (1..=head).collect::<Vec<_>>());
+        assert!(store.check_consistency().unwrap().is_ok());
+    };
+    check(&store);
+    drop(store);
+
+    let store = Store::open(StoreConfig {
+        path: dir.path().join("store"),
+        ..Default::default()
+    })
+    .unwrap();
+    check(&store);
+}
+
+// --- Read-Only Access Tests ---
+
+#[test]
+fn test_read_only_handle_alongside_writer() {
+    let dir = TempDir::new().unwrap();
+    let writer = test_store(&dir);
+
+    let first = writer.append(RecordInput::json("message", &json!({"text": "one"})).unwrap()).unwrap();
+    let second = writer.append(RecordInput::json("message", &json!({"text": "two"})).unwrap()).unwrap();
+    writer.sync().unwrap();
+
+    // Writer still holds the exclusive lock
+    let reader = Store::open_read_only(StoreConfig {
+        path: dir.path().join("store"),
+        blob_cache_size: 100,
+        create_if_missing: false,
+        ..Default::default()
+    })
+    .unwrap();
+    assert!(reader.is_read_only());
+
+    let record = reader.get_record(first.id).unwrap().unwrap();
+    let payload: serde_json::Value = serde_json::from_slice(&record.payload).unwrap();
+    assert_eq!(payload["text"], "one");
+    assert_eq!(reader.get_records_by_type("message"), vec![first.id, second.id]);
+    assert_eq!(reader.current_branch().head, second.sequence);
+
+    // Every mutating entry point is rejected
+    let result = reader.append(RecordInput::json("message", &json!({"text": "three"})).unwrap());
+    assert!(matches!(result, Err(StoreError::ReadOnly)));
+    assert!(matches!(
+        reader.update_state("log", StateOperation::Append(b"1".to_vec())),
+        Err(StoreError::ReadOnly)
+    ));
+    assert!(matches!(reader.create_branch("feature", None), Err(StoreError::ReadOnly)));
+    assert!(matches!(reader.store_blob(b"data", "text/plain"), Err(StoreError::ReadOnly)));
+    assert!(matches!(reader.sync(), Err(StoreError::ReadOnly)));
+
+    // Writer is unaffected
+    writer.append(RecordInput::json("message", &json!({"text": "three"})).unwrap()).unwrap();
+    assert_eq!(writer.get_records_by_type("message").len(), 3);
+}
+
+// --- Snapshot Policy Tests ---
+
+#[test]
+fn test_bulk_import_without_auto_snapshot() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+
+    store
+        .register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 100, full_snapshot_every: 10 },
+            initial_value: None,
+        })
+        .unwrap();
+
+    {
+        let _guard = store.with_auto_snapshot(false);
+        for i in 1..=10_000usize {
+            store
+                .update_state("items", StateOperation::Append(i.to_string().into_bytes()))
+                .unwrap();
+
+            if i % 2500 == 0 {
+                // One unbroken chain, still reconstructed correctly
+                let chain = store.get_chain_stats("items").unwrap().unwrap();
+                assert_eq!(chain.total_operations, i as u64);
+                assert!(!chain.has_full_snapshot);
+                assert_eq!(store.get_state_len("items").unwrap(), Some(i));
+                let items: Vec<usize> = store.get_state_as("items").unwrap().unwrap();
+                assert_eq!(items, (1..=i).collect::<Vec<_>>());
+            }
+        }
+        assert_eq!(store.snapshot_needed("items"), Some(SnapshotNeeded::Delta));
+    }
+
+    // Re-enabled: the overdue snapshot is taken on request
+    assert!(store.create_snapshot_if_needed("items").unwrap().is_some());
+    assert_eq!(store.snapshot_needed("items"), None);
+    let items: Vec<usize> = store.get_state_as("items").unwrap().unwrap();
+    assert_eq!(items, (1..=10_000).collect::<Vec<_>>());
+
+    // ...and automatically again from here on
+    for i in 10_001..=10_100usize {
+        store
+            .update_state("items", StateOperation::Append(i.to_string().into_bytes()))
+            .unwrap();
+    }
+    assert_eq!(store.get_compaction_stats("items").unwrap().delta_snapshots_since_full, 2);
+    let tail: Vec<usize> = store.get_state_tail_as("items", 2).unwrap().unwrap();
+    assert_eq!(tail, vec![10_099, 10_100]);
+}
+
+#[test]
+fn test_set_snapshot_policy() {
+    let dir = TempDir::new().unwrap();
+    let append = |store: &Store, n: usize| {
+        for i in 0..n {
+            store
+                .update_state("log", StateOperation::Append(i.to_string().into_bytes()))
+                .unwrap();
+        }
+    };
+
+    {
+        let store = test_store(&dir);
+        store
+            .register_state(StateRegistration {
+                id: "log".to_string(),
+                strategy: StateStrategy::AppendLog { delta_snapshot_every: 100, full_snapshot_every: 10 },
+                initial_value: None,
+            })
+            .unwrap();
+        assert!(matches!(
+            store.set_snapshot_policy("missing", SnapshotPolicy::default()),
+            Err(StoreError::StateNotRegistered(_))
+        ));
+
+        // Tighter thresholds take effect without re-registering
+        store
+            .set_snapshot_policy(
+                "log",
+                SnapshotPolicy { delta_snapshot_every: Some(5), ..Default::default() },
+            )
+            .unwrap();
+        append(&store, 5);
+        assert!(store.get_compaction_stats("log").unwrap().last_delta_snapshot_offset.is_some());
+
+        store
+            .set_snapshot_policy("log", SnapshotPolicy { auto_snapshot: false, ..Default::default() })
+            .unwrap();
+    }
+
+    // Persisted across reopen
+    let store = Store::open(StoreConfig {
+        path: dir.path().join("store"),
+        ..Default::default()
+    })
+    .unwrap();
+    assert!(!store.snapshot_policy("log").unwrap().auto_snapshot);
+    append(&store, 150);
+    assert_eq!(store.get_compaction_stats("log").unwrap().delta_snapshots_since_full, 1);
+
+    // Branches inherit the policy but change it independently
+    store.create_branch("import", None).unwrap();
+    store.switch_branch("import").unwrap();
+    assert!(!store.snapshot_policy("log").unwrap().auto_snapshot);
+    store.set_snapshot_policy("log", SnapshotPolicy::default()).unwrap();
+    append(&store, 1);
+    assert_eq!(store.get_compaction_stats("log").unwrap().delta_snapshots_since_full, 2);
+
+    store.switch_branch("main").unwrap();
+    assert!(!store.snapshot_policy("log").unwrap().auto_snapshot);
+    assert_eq!(store.get_state_len("log").unwrap(), Some(155));
+}
+
+// --- Observer Tests ---
+
+#[derive(Default)]
+struct CountingObserver {
+    appends: AtomicUsize,
+    record_reads: AtomicUsize,
+    record_misses: AtomicUsize,
+    state_updates: AtomicUsize,
+    state_operations: AtomicUsize,
+    snapshots: AtomicUsize,
+    blob_stores: AtomicUsize,
+    blob_cache_hits: AtomicUsize,
+    catch_ups: AtomicUsize,
+    overflow_drops: AtomicUsize,
+    unsubscribes: AtomicUsize,
+}
+
+impl StoreObserver for CountingObserver {
+    fn on_append(&self, _record: &Record, _elapsed: Duration) {
+        self.appends.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn on_record_read(&self, _id: RecordId, found: bool) {
+        self.record_reads.fetch_add(1, Ordering::SeqCst);
+        if !found {
+            self.record_misses.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn on_state_update(&self, _state_id: &str, operations: usize, _elapsed: Duration) {
+        self.state_updates.fetch_add(1, Ordering::SeqCst);
+        self.state_operations.fetch_add(operations, Ordering::SeqCst);
+    }
+
+    fn on_snapshot(&self, _state_id: &str, _kind: SnapshotNeeded, _size: usize) {
+        self.snapshots.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn on_blob_store(&self, _hash: &Hash, _size: usize) {
+        self.blob_stores.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn on_blob_read(&self, _hash: &Hash, cache_hit: bool) {
+        if cache_hit {
+            self.blob_cache_hits.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    fn on_catch_up(&self, _id: SubscriptionId, _elapsed: Duration) {
+        self.catch_ups.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn on_subscription_drop(&self, _id: SubscriptionId, reason: &DropReason) {
+        match reason {
+            DropReason::BufferOverflow => self.overflow_drops.fetch_add(1, Ordering::SeqCst),
+            _ => self.unsubscribes.fetch_add(1, Ordering::SeqCst),
+        };
+    }
+}
+
+#[test]
+fn test_store_observer_callbacks() {
+    let dir = TempDir::new().unwrap();
+    let observer = Arc::new(CountingObserver::default());
+    let store = Store::create(StoreConfig {
+        path: dir.path().join("store"),
+        blob_cache_size: 100,
+        create_if_missing: true,
+        observer: Some(observer.clone()),
+        ..Default::default()
+    })
+    .unwrap();
+
+    // Records
+    let first = store.append(RecordInput::json("event", &json!({"n": 1})).unwrap()).unwrap();
+    store.append(RecordInput::json("event", &json!({"n": 2})).unwrap()).unwrap();
+    store.get_record(first.id).unwrap().unwrap();
+    assert!(store.get_record(RecordId(999)).unwrap().is_none());
+
+    // State updates, with a delta snapshot after every 5 operations
+    store
+        .register_state(StateRegistration {
+            id: "log".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 5, full_snapshot_every: 100 },
+            initial_value: None,
+        })
+        .unwrap();
+    for i in 0..10 {
+        store.update_state("log", StateOperation::Append(format!("{}", i).into_bytes())).unwrap();
+    }
+    let batch = vec![
+        StateOperation::Append(b"10".to_vec()),
+        StateOperation::Append(b"11".to_vec()),
+        StateOperation::Append(b"12".to_vec()),
+    ];
+    store.update_state_batch("log", batch).unwrap();
+    store.compact_state("log").unwrap().unwrap();
+
+    // Blobs: stored content is cached, so reads hit
+    let hash = store.store_blob(b"blob", "text/plain").unwrap();
+    store.get_blob(&hash).unwrap().unwrap();
+    store.get_blob(&hash).unwrap().unwrap();
+
+    // A caught-up subscriber, and one whose buffer only fits CaughtUp
+    let catching = store.subscribe(SubscriptionConfig {
+        from_sequence: Some(Sequence(1)),
+        buffer_size: 1000,
+        ..Default::default()
+    });
+    store.catch_up_subscription(catching.id).unwrap();
+    let slow = store.subscribe(SubscriptionConfig {
+        filter: SubscriptionFilter::records(),
+        buffer_size: 1,
+        ..Default::default()
+    });
+    store.catch_up_subscription(slow.id).unwrap();
+    store.append(RecordInput::json("event", &json!({"n": 3})).unwrap()).unwrap();
+    store.unsubscribe(catching.id);
+
+    let snapshots = observer.snapshots.load(Ordering::SeqCst);
+    assert_eq!(observer.appends.load(Ordering::SeqCst), 3);
+    assert_eq!(observer.record_reads.load(Ordering::SeqCst), 2);
+    assert_eq!(observer.record_misses.load(Ordering::SeqCst), 1);
+    // Two automatic delta snapshots plus the compaction
+    assert_eq!(snapshots, 3);
+    assert_eq!(observer.state_updates.load(Ordering::SeqCst), 10 + 1 + snapshots);
+    assert_eq!(observer.state_operations.load(Ordering::SeqCst), 13 + snapshots);
+    assert_eq!(observer.blob_stores.load(Ordering::SeqCst), 1);
+    assert_eq!(observer.blob_cache_hits.load(Ordering::SeqCst), 2);
+    assert_eq!(observer.catch_ups.load(Ordering::SeqCst), 1);
+    assert_eq!(observer.overflow_drops.load(Ordering::SeqCst), 1);
+    assert_eq!(observer.unsubscribes.load(Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_snapshot_hook() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    store
+        .register_state(StateRegistration {
+            id: "log".to_string(),
+            strategy: StateStrategy::AppendLog { delta_snapshot_every: 3, full_snapshot_every: 2 },
+            initial_value: None,
+        })
+        .unwrap();
+
+    let events: Arc<Mutex<Vec<SnapshotEvent>>> = Arc::default();
+    let sink = events.clone();
+    store.set_snapshot_hook(move |event| sink.lock().unwrap().push(event));
+
+    for i in 0..20 {
+        store.update_state("log", StateOperation::Append(format!("{}", i).into_bytes())).unwrap();
+    }
+
+    // Deltas every 3 appends, a full snapshot after every second delta
+    let fired = std::mem::take(&mut *events.lock().unwrap());
+    let kinds: Vec<SnapshotNeeded> = fired.iter().map(|e| e.kind).collect();
+    use SnapshotNeeded::{Delta, Full};
+    assert_eq!(kinds, vec![Delta, Delta, Full, Delta, Delta, Full, Delta, Delta]);
+
+    // Each event names the snapshot record it reports
+    let records = store.query_range(None, None, 100, false, Some(&["state_update".to_string()])).unwrap();
+    for event in &fired {
+        assert_eq!(event.state_id, "log");
+        let record = records.iter().find(|r| r.sequence == event.sequence).unwrap();
+        let update: serde_json::Value = serde_json::from_slice(&record.payload).unwrap();
+        let expected = match event.kind {
+            Delta => "DeltaSnapshot",
+            Full => "Snapshot",
+        };
+        assert!(update["operation"].get(expected).is_some());
+        assert!(event.size > 0);
+    }
+
+    // Cleared hooks stop firing
+    store.clear_snapshot_hook();
+    store.create_snapshot_if_needed("log").unwrap();
+    for i in 20..30 {
+        store.update_state("log", StateOperation::Append(format!("{}", i).into_bytes())).unwrap();
+    }
+    assert!(events.lock().unwrap().is_empty());
+}
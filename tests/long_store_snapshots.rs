@@ -12,7 +12,7 @@
 //! so `snapshot_needed()` typically returns None after an update.
 
 use chronicle::{
-    StateOperation, StateRegistration, StateStrategy, Store, StoreConfig,
+    RecordId, RecordInput, Sequence, StateOperation, StateRegistration, StateStrategy, Store, StoreConfig,
 };
 use tempfile::TempDir;
 
@@ -21,6 +21,7 @@ fn test_store(dir: &TempDir) -> Store {
         path: dir.path().join("store"),
         blob_cache_size: 100,
         create_if_missing: true,
+        ..Default::default()
     })
     .unwrap()
 }
@@ -30,6 +31,7 @@ fn open_store(dir: &TempDir) -> Store {
         path: dir.path().join("store"),
         blob_cache_size: 100,
         create_if_missing: false,
+        ..Default::default()
     })
     .unwrap()
 }
@@ -152,7 +154,7 @@ fn test_multiple_snapshot_cycles() {
         for delta in 0..3 {
             for op in 0..5 {
                 let val = cycle * 15 + delta * 5 + op + 1;
-                expected.push(val as i32);
+                expected.push(val);
                 store
                     .update_state("log", StateOperation::Append(format!("{}", val).into_bytes()))
                     .unwrap();
@@ -581,6 +583,66 @@ fn test_redact_with_auto_snapshots() {
     assert_eq!(arr.len(), 15, "Should have 15 messages after redacting 5, got {}", arr.len());
 }
 
+#[test]
+fn test_edits_and_redacts_survive_delta_snapshots() {
+    let dir = TempDir::new().unwrap();
+    let store = test_store(&dir);
+    store
+        .register_state(StateRegistration {
+            id: "items".to_string(),
+            strategy: StateStrategy::AppendLog {
+                delta_snapshot_every: 3,
+                full_snapshot_every: 100,
+            },
+            initial_value: None,
+        })
+        .unwrap();
+
+    // The same operations applied to a plain Vec, for comparison
+    let mut expected: Vec<i32> = Vec::new();
+    let append = |expected: &mut Vec<i32>, n: i32| {
+        store.update_state("items", StateOperation::Append(n.to_string().into_bytes())).unwrap();
+        expected.push(n);
+    };
+    let check = |store: &Store, expected: &[i32]| {
+        let state: Vec<i32> = serde_json::from_slice(&store.get_state("items").unwrap().unwrap()).unwrap();
+        assert_eq!(state, expected);
+    };
+
+    for n in 1..=3 {
+        append(&mut expected, n);
+    }
+    append(&mut expected, 4);
+    store
+        .update_state("items", StateOperation::Edit { index: 0, new_value: b"10".to_vec() })
+        .unwrap();
+    expected[0] = 10;
+
+    // A branch taken here starts its snapshot accounting afresh, so the
+    // edit behind it must not be folded into a delta
+    let head = store.current_branch().head;
+    store.create_branch_at("feature", "main", head).unwrap();
+    store.switch_branch("feature").unwrap();
+    for n in 5..=7 {
+        append(&mut expected, n);
+    }
+    check(&store, &expected);
+    store.update_state("items", StateOperation::Redact { start: 1, end: 3 }).unwrap();
+    expected.drain(1..3);
+    let head = store.current_branch().head;
+    store.create_branch_at("later", "feature", head).unwrap();
+    store.switch_branch("later").unwrap();
+    for n in 8..=10 {
+        append(&mut expected, n);
+    }
+    check(&store, &expected);
+
+    drop(store);
+    let store = open_store(&dir);
+    store.switch_branch("later").unwrap();
+    check(&store, &expected);
+}
+
 // =============================================================================
 // PERFORMANCE TESTS
 // =============================================================================
@@ -650,6 +712,56 @@ fn test_millisecond_latency_operations() {
     assert!(single_append.as_millis() < 10, "Single append should be sub-10ms");
 }
 
+#[test]
+fn test_append_fast_matches_append() {
+    use std::time::Instant;
+
+    const WRITES: u64 = 50_000;
+    let input = |i: u64| {
+        let input = RecordInput::raw("event", format!("{{\"n\":{}}}", i).into_bytes());
+        match i % 100 {
+            0 => input.with_idempotency_key(format!("key-{}", i)),
+            n if n % 10 == 0 => input.with_caused_by(vec![RecordId(i - 1)]),
+            _ => input,
+        }
+    };
+
+    let full_dir = TempDir::new().unwrap();
+    let full = test_store(&full_dir);
+    let start = Instant::now();
+    for i in 1..=WRITES {
+        let record = full.append(input(i)).unwrap();
+        assert_eq!(record.sequence, Sequence(i));
+    }
+    let append_time = start.elapsed();
+
+    let fast_dir = TempDir::new().unwrap();
+    let fast = test_store(&fast_dir);
+    let start = Instant::now();
+    for i in 1..=WRITES {
+        let (_, sequence) = fast.append_fast(input(i)).unwrap();
+        assert_eq!(sequence, Sequence(i));
+    }
+    let fast_time = start.elapsed();
+    println!("{} appends: append {:?}, append_fast {:?}", WRITES, append_time, fast_time);
+
+    // Same records on disk, apart from when they were written
+    drop(full);
+    drop(fast);
+    let full = open_store(&full_dir);
+    let fast = open_store(&fast_dir);
+    assert_eq!(fast.record_count(), WRITES);
+    let mut count = 0;
+    for (a, b) in full.iter_from(Sequence(1)).zip(fast.iter_from(Sequence(1))) {
+        let ((_, a), (_, b)) = (a.unwrap(), b.unwrap());
+        assert_eq!((a.id, a.sequence, a.branch, a.lamport), (b.id, b.sequence, b.branch, b.lamport));
+        assert_eq!((a.record_type, a.payload, a.encoding), (b.record_type, b.payload, b.encoding));
+        assert_eq!((a.caused_by, a.idempotency_key), (b.caused_by, b.idempotency_key));
+        count += 1;
+    }
+    assert_eq!(count, WRITES);
+}
+
 // =============================================================================
 // STRESS TESTS
 // =============================================================================
@@ -755,8 +867,8 @@ fn test_mixed_operations_stress() {
     let arr: Vec<i32> = serde_json::from_slice(&state).unwrap();
 
     // First 5 should be edited to 999
-    for i in 0..5 {
-        assert_eq!(arr[i], 999, "Item {} should be 999", i);
+    for (i, item) in arr.iter().take(5).enumerate() {
+        assert_eq!(*item, 999, "Item {} should be 999", i);
     }
 }
 
@@ -23,6 +23,7 @@ fn test_config(dir: &TempDir) -> StoreConfig {
         path: dir.path().to_path_buf(),
         blob_cache_size: 100,
         create_if_missing: true,
+        ..Default::default()
     }
 }
 
@@ -451,7 +452,8 @@ fn test_scaling_subscriptions() {
         from_sequence: Some(Sequence(1)),
         buffer_size: 1000,
         max_snapshot_bytes: 1024 * 1024, // 1MB
-        ..Default::default()
+        coalesce_catchup_state: true,
+        branch: None,
     };
     let handle3 = store.subscribe(config);
     store.catch_up_subscription(handle3.id).unwrap();
@@ -905,7 +907,8 @@ fn test_scaling_mixed_workload() {
         from_sequence: Some(Sequence(halfway)),
         buffer_size: 30000,
         max_snapshot_bytes: 10 * 1024 * 1024,
-        ..Default::default()
+        coalesce_catchup_state: true,
+        branch: None,
     };
     let handle = store.subscribe(config);
     store.catch_up_subscription(handle.id).unwrap();